@@ -0,0 +1,394 @@
+//! Command line tool for the swap program, e.g., to smoke test a deployment.
+//!
+//! Amounts are given in whole tokens, e.g., `1.5`, and converted with the
+//! mint's decimals. Minimum exchange rates are derived from a quote of the
+//! orderbook and the slippage given, which must also cover the DEX's and the
+//! protocol's fees, as quotes are before fees.
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use serum_swap::{ExchangeRate, Side};
+use serum_swap_client::{self as client, book, Market, Quote, RpcClient, Wallets};
+use solana_sdk::instruction::Instruction;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{Keypair, Signer};
+
+#[derive(Parser)]
+#[clap(
+    name = "swap-cli",
+    about = "Swaps on the Serum DEX via the swap program"
+)]
+struct Opts {
+    /// JSON RPC URL of the cluster.
+    #[clap(long, default_value = "http://127.0.0.1:8899")]
+    url: String,
+    /// Keypair paying for, and signing, the transactions.
+    #[clap(long, default_value = "~/.config/solana/id.json")]
+    keypair: String,
+    /// Compute unit limit of each transaction.
+    #[clap(long, default_value = "400000")]
+    compute_unit_limit: u32,
+    /// Priority fee, in micro-lamports per compute unit.
+    #[clap(long, default_value = "0")]
+    priority_fee: u64,
+    /// Times a transaction is sent, with a fresh blockhash, before giving up.
+    #[clap(long, default_value = "3")]
+    max_attempts: usize,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Parser)]
+enum Command {
+    /// Creates and initializes an open orders account on a market.
+    InitOpenOrders {
+        #[clap(long)]
+        market: Pubkey,
+    },
+    /// Closes an open orders account, refunding its rent.
+    CloseOpenOrders {
+        #[clap(long)]
+        market: Pubkey,
+        #[clap(long)]
+        open_orders: Pubkey,
+    },
+    /// Quotes a swap on a market, against the orders resting on its book.
+    Quote {
+        #[clap(long)]
+        market: Pubkey,
+        /// "bid" to buy the base currency, "ask" to sell it.
+        #[clap(long, parse(try_from_str = parse_side))]
+        side: Side,
+        /// Amount of the *from* token, in whole tokens.
+        #[clap(long)]
+        amount: String,
+    },
+    /// Swaps on a single market.
+    Swap {
+        #[clap(long)]
+        market: Pubkey,
+        #[clap(long)]
+        open_orders: Pubkey,
+        /// "bid" to buy the base currency, "ask" to sell it.
+        #[clap(long, parse(try_from_str = parse_side))]
+        side: Side,
+        /// Amount of the *from* token, in whole tokens.
+        #[clap(long)]
+        amount: String,
+        /// Maximum shortfall from the quoted output, in basis points.
+        #[clap(long)]
+        slippage_bps: u64,
+        /// Aborts if the book's expected price impact exceeds this.
+        #[clap(long)]
+        max_price_impact_bps: Option<u16>,
+        /// Base currency wallet. Defaults to the associated token account.
+        #[clap(long)]
+        coin_wallet: Option<Pubkey>,
+        /// Quote currency wallet. Defaults to the associated token account.
+        #[clap(long)]
+        pc_wallet: Option<Pubkey>,
+    },
+    /// Swaps the base currency of one market for another's, via their
+    /// common quote currency.
+    SwapTransitive {
+        #[clap(long)]
+        from_market: Pubkey,
+        #[clap(long)]
+        to_market: Pubkey,
+        #[clap(long)]
+        from_open_orders: Pubkey,
+        #[clap(long)]
+        to_open_orders: Pubkey,
+        /// Amount of the *from* token, in whole tokens.
+        #[clap(long)]
+        amount: String,
+        /// Maximum shortfall from the quoted output, in basis points.
+        #[clap(long)]
+        slippage_bps: u64,
+        /// Wallets. Default to the associated token accounts.
+        #[clap(long)]
+        from_wallet: Option<Pubkey>,
+        #[clap(long)]
+        to_wallet: Option<Pubkey>,
+        #[clap(long)]
+        pc_wallet: Option<Pubkey>,
+    },
+}
+
+fn main() -> Result<()> {
+    let opts = Opts::parse();
+    let cli = Cli {
+        rpc: RpcClient::new(opts.url.clone()),
+        payer: client::read_keypair(&opts.keypair)?,
+        compute_unit_limit: opts.compute_unit_limit,
+        priority_fee: opts.priority_fee,
+        max_attempts: opts.max_attempts,
+    };
+    match opts.command {
+        Command::InitOpenOrders { market } => cli.init_open_orders(&market),
+        Command::CloseOpenOrders {
+            market,
+            open_orders,
+        } => cli.close_open_orders(&market, &open_orders),
+        Command::Quote {
+            market,
+            side,
+            amount,
+        } => cli.quote(&market, side, &amount),
+        Command::Swap {
+            market,
+            open_orders,
+            side,
+            amount,
+            slippage_bps,
+            max_price_impact_bps,
+            coin_wallet,
+            pc_wallet,
+        } => {
+            let market = client::fetch_market(&cli.rpc, &market)?;
+            let wallets = Wallets {
+                authority: cli.payer.pubkey(),
+                owner: cli.payer.pubkey(),
+                open_orders,
+                coin_wallet: cli.wallet(coin_wallet, &market.coin_mint),
+                pc_wallet: cli.wallet(pc_wallet, &market.pc_mint),
+            };
+            cli.swap(
+                &market,
+                &wallets,
+                side,
+                &amount,
+                slippage_bps,
+                max_price_impact_bps,
+            )
+        }
+        Command::SwapTransitive {
+            from_market,
+            to_market,
+            from_open_orders,
+            to_open_orders,
+            amount,
+            slippage_bps,
+            from_wallet,
+            to_wallet,
+            pc_wallet,
+        } => {
+            let from = client::fetch_market(&cli.rpc, &from_market)?;
+            let to = client::fetch_market(&cli.rpc, &to_market)?;
+            if from.pc_mint != to.pc_mint {
+                return Err(anyhow!("the markets must have the same quote currency"));
+            }
+            let pc_wallet = cli.wallet(pc_wallet, &from.pc_mint);
+            let from_wallets = Wallets {
+                authority: cli.payer.pubkey(),
+                owner: cli.payer.pubkey(),
+                open_orders: from_open_orders,
+                coin_wallet: cli.wallet(from_wallet, &from.coin_mint),
+                pc_wallet,
+            };
+            let to_wallets = Wallets {
+                authority: cli.payer.pubkey(),
+                owner: cli.payer.pubkey(),
+                open_orders: to_open_orders,
+                coin_wallet: cli.wallet(to_wallet, &to.coin_mint),
+                pc_wallet,
+            };
+            cli.swap_transitive(
+                &from,
+                &to,
+                &from_wallets,
+                &to_wallets,
+                &amount,
+                slippage_bps,
+            )
+        }
+    }
+}
+
+struct Cli {
+    rpc: RpcClient,
+    payer: Keypair,
+    compute_unit_limit: u32,
+    priority_fee: u64,
+    max_attempts: usize,
+}
+
+impl Cli {
+    fn init_open_orders(&self, market: &Pubkey) -> Result<()> {
+        let market = client::fetch_market(&self.rpc, market)?;
+        let open_orders = Keypair::new();
+        let ix = client::init_account(
+            &market,
+            &open_orders.pubkey(),
+            &self.payer.pubkey(),
+            &self.payer.pubkey(),
+        );
+        self.send(ix, &[&open_orders])?;
+        println!("Open orders account: {}", open_orders.pubkey());
+        Ok(())
+    }
+
+    fn close_open_orders(&self, market: &Pubkey, open_orders: &Pubkey) -> Result<()> {
+        let market = client::fetch_market(&self.rpc, market)?;
+        let ix = client::close_account(
+            &market,
+            open_orders,
+            &self.payer.pubkey(),
+            &self.payer.pubkey(),
+        );
+        self.send(ix, &[])
+    }
+
+    fn quote(&self, market: &Pubkey, side: Side, amount: &str) -> Result<()> {
+        let market = client::fetch_market(&self.rpc, market)?;
+        let (from_decimals, to_decimals) = self.decimals(&market, side)?;
+        let quote = self.fetch_quote(&market, side, parse_amount(amount, from_decimals)?)?;
+        println!(
+            "From amount: {}",
+            format_amount(quote.from_amount, from_decimals)
+        );
+        println!("To amount: {}", format_amount(quote.to_amount, to_decimals));
+        println!("Price impact: {} bps", quote.price_impact_bps);
+        Ok(())
+    }
+
+    fn swap(
+        &self,
+        market: &Market,
+        wallets: &Wallets,
+        side: Side,
+        amount: &str,
+        slippage_bps: u64,
+        max_price_impact_bps: Option<u16>,
+    ) -> Result<()> {
+        let (from_decimals, _) = self.decimals(market, side)?;
+        let amount = parse_amount(amount, from_decimals)?;
+        let quote = self.fetch_quote(market, side, amount)?;
+        let min_exchange_rate = ExchangeRate {
+            rate: book::min_rate(
+                quote.from_amount,
+                quote.to_amount,
+                from_decimals,
+                slippage_bps,
+            )?,
+            from_decimals,
+            quote_decimals: 0,
+            strict: false,
+        };
+        let config = client::fetch_config(&self.rpc)?;
+        let ix = client::swap(
+            &config,
+            market,
+            wallets,
+            side,
+            amount,
+            min_exchange_rate,
+            max_price_impact_bps,
+        );
+        self.send(ix, &[])
+    }
+
+    fn swap_transitive(
+        &self,
+        from: &Market,
+        to: &Market,
+        from_wallets: &Wallets,
+        to_wallets: &Wallets,
+        amount: &str,
+        slippage_bps: u64,
+    ) -> Result<()> {
+        let from_decimals = client::fetch_decimals(&self.rpc, &from.coin_mint)?;
+        let quote_decimals = client::fetch_decimals(&self.rpc, &from.pc_mint)?;
+        let amount = parse_amount(amount, from_decimals)?;
+        let sell = self.fetch_quote(from, Side::Ask, amount)?;
+        let buy = self.fetch_quote(to, Side::Bid, sell.to_amount)?;
+        let min_exchange_rate = ExchangeRate {
+            rate: book::min_rate(sell.from_amount, buy.to_amount, from_decimals, slippage_bps)?,
+            from_decimals,
+            quote_decimals,
+            strict: false,
+        };
+        let config = client::fetch_config(&self.rpc)?;
+        let ix = client::swap_transitive(
+            &config,
+            from,
+            to,
+            from_wallets,
+            to_wallets,
+            amount,
+            min_exchange_rate,
+        );
+        self.send(ix, &[])
+    }
+
+    fn fetch_quote(&self, market: &Market, side: Side, amount: u64) -> Result<Quote> {
+        let orderbook = client::fetch_orderbook(&self.rpc, market, side)?;
+        Ok(book::quote(market, orderbook, side, amount))
+    }
+
+    // Returns the decimals of the *from* and *to* mints of a swap.
+    fn decimals(&self, market: &Market, side: Side) -> Result<(u8, u8)> {
+        let coin_decimals = client::fetch_decimals(&self.rpc, &market.coin_mint)?;
+        let pc_decimals = client::fetch_decimals(&self.rpc, &market.pc_mint)?;
+        Ok(match side {
+            Side::Bid => (pc_decimals, coin_decimals),
+            Side::Ask => (coin_decimals, pc_decimals),
+        })
+    }
+
+    fn wallet(&self, wallet: Option<Pubkey>, mint: &Pubkey) -> Pubkey {
+        wallet.unwrap_or_else(|| client::associated_token_address(&self.payer.pubkey(), mint))
+    }
+
+    // Sends the instruction, signed by the payer and the `signers`.
+    fn send(&self, ix: Instruction, signers: &[&Keypair]) -> Result<()> {
+        let mut instructions = vec![client::set_compute_unit_limit(self.compute_unit_limit)];
+        if self.priority_fee > 0 {
+            instructions.push(client::set_compute_unit_price(self.priority_fee));
+        }
+        instructions.push(ix);
+        let mut all_signers = vec![&self.payer];
+        all_signers.extend_from_slice(signers);
+        let signature =
+            self.rpc
+                .send_and_confirm(&instructions, &all_signers, self.max_attempts)?;
+        println!("Signature: {}", signature);
+        Ok(())
+    }
+}
+
+fn parse_side(side: &str) -> Result<Side> {
+    match side {
+        "bid" => Ok(Side::Bid),
+        "ask" => Ok(Side::Ask),
+        _ => Err(anyhow!("side must be \"bid\" or \"ask\"")),
+    }
+}
+
+// Converts an amount in whole tokens, e.g., "1.5", into native units.
+fn parse_amount(amount: &str, decimals: u8) -> Result<u64> {
+    let invalid = || anyhow!("invalid amount {}", amount);
+    let (whole, fraction) = amount.split_once('.').unwrap_or((amount, ""));
+    if fraction.len() > decimals.into() {
+        return Err(anyhow!(
+            "amount {} has more than {} decimals",
+            amount,
+            decimals
+        ));
+    }
+    let padded = format!("{}{:0<width$}", whole, fraction, width = decimals.into());
+    padded.parse().map_err(|_| invalid())
+}
+
+fn format_amount(amount: u64, decimals: u8) -> String {
+    let scale = 10u64.pow(decimals.into());
+    match decimals {
+        0 => amount.to_string(),
+        _ => format!(
+            "{}.{:0width$}",
+            amount / scale,
+            amount % scale,
+            width = decimals.into()
+        ),
+    }
+}
@@ -0,0 +1,147 @@
+//! Quotes of taker orders against a market's orderbook, read via RPC.
+
+use crate::Market;
+use anchor_spl::dex::serum_dex::critbit::{LeafNode, Slab};
+use anyhow::{anyhow, Result};
+use serum_swap::Side;
+use std::convert::TryFrom;
+
+// Every DEX account starts with b"serum", followed by the account flags, and
+// ends with b"padding".
+const HEAD_LEN: usize = 5 + 8;
+const TAIL_PADDING: usize = 7;
+
+/// Expected result of a taker order, before fees.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Quote {
+    /// Amount given, in native units of the *from* mint.
+    pub from_amount: u64,
+    /// Amount received, in native units of the *to* mint.
+    pub to_amount: u64,
+    /// Difference between the average and best prices, in basis points of
+    /// the best price.
+    pub price_impact_bps: u64,
+}
+
+/// Orders resting on one side of a market.
+pub struct Orderbook {
+    // The slab's nodes are read as `u64`s, so they're aligned.
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl Orderbook {
+    /// Copies the `data` of a bids or asks account.
+    pub fn new(data: &[u8]) -> Result<Self> {
+        let slab = data
+            .get(HEAD_LEN..data.len().saturating_sub(TAIL_PADDING))
+            .ok_or_else(|| anyhow!("not an orderbook"))?;
+        let mut words = vec![0u64; slab.len().div_ceil(8)];
+        bytemuck::cast_slice_mut::<u64, u8>(&mut words)[..slab.len()].copy_from_slice(slab);
+        Ok(Orderbook {
+            words,
+            len: slab.len(),
+        })
+    }
+
+    // Visits the orders best first, i.e., from the highest price down if
+    // `descending`, until `fill` returns false.
+    fn walk(mut self, descending: bool, mut fill: impl FnMut(&LeafNode) -> bool) {
+        let len = self.len;
+        let slab = Slab::new(&mut bytemuck::cast_slice_mut::<u64, u8>(&mut self.words)[..len]);
+        loop {
+            let leaf = match descending {
+                true => slab.remove_max(),
+                false => slab.remove_min(),
+            };
+            match leaf {
+                Some(leaf) if fill(&leaf) => continue,
+                _ => return,
+            }
+        }
+    }
+}
+
+/// Quotes swapping `amount` on the `market`, buying from the `asks` for
+/// bids and selling into the `bids` for asks.
+pub fn quote(market: &Market, book: Orderbook, side: Side, amount: u64) -> Quote {
+    let mut best_price = 0;
+    let mut coin_lots: u64 = 0;
+    let mut pc_lots: u128 = 0;
+    match side {
+        Side::Bid => {
+            let max_pc_lots = u128::from(amount / market.pc_lot_size);
+            book.walk(false, |leaf| {
+                let price = leaf.price().get();
+                if coin_lots == 0 {
+                    best_price = price;
+                }
+                let affordable = (max_pc_lots - pc_lots) / u128::from(price);
+                let lots = leaf
+                    .quantity()
+                    .min(u64::try_from(affordable).unwrap_or(u64::MAX));
+                coin_lots += lots;
+                pc_lots += u128::from(price) * u128::from(lots);
+                lots > 0 && lots == leaf.quantity()
+            });
+        }
+        Side::Ask => {
+            let max_coin_lots = amount / market.coin_lot_size;
+            book.walk(true, |leaf| {
+                let price = leaf.price().get();
+                if coin_lots == 0 {
+                    best_price = price;
+                }
+                let lots = leaf.quantity().min(max_coin_lots - coin_lots);
+                coin_lots += lots;
+                pc_lots += u128::from(price) * u128::from(lots);
+                lots > 0 && lots == leaf.quantity()
+            });
+        }
+    }
+
+    let coin_amount = coin_lots * market.coin_lot_size;
+    let pc_amount = (pc_lots * u128::from(market.pc_lot_size)) as u64;
+    let (from_amount, to_amount) = match side {
+        Side::Bid => (pc_amount, coin_amount),
+        Side::Ask => (coin_amount, pc_amount),
+    };
+    let price_impact_bps = match coin_lots {
+        0 => 0,
+        _ => {
+            let best = u128::from(best_price) * u128::from(coin_lots);
+            (pc_lots.abs_diff(best) * u128::from(serum_swap::BPS_DENOMINATOR)).div_ceil(best)
+        }
+    };
+    Quote {
+        from_amount,
+        to_amount,
+        price_impact_bps: price_impact_bps as u64,
+    }
+}
+
+/// Returns the minimum exchange rate, in native *to* units per whole *from*
+/// token, of receiving at least `to_amount` for `from_amount`, less the
+/// slippage.
+pub fn min_rate(
+    from_amount: u64,
+    to_amount: u64,
+    from_decimals: u8,
+    slippage_bps: u64,
+) -> Result<u64> {
+    if from_amount == 0 || to_amount == 0 {
+        return Err(anyhow!("nothing would fill"));
+    }
+    if slippage_bps > serum_swap::BPS_DENOMINATOR {
+        return Err(anyhow!(
+            "slippage can't exceed {} bps",
+            serum_swap::BPS_DENOMINATOR
+        ));
+    }
+    let rate = u128::from(to_amount)
+        * 10u128.pow(from_decimals.into())
+        * u128::from(serum_swap::BPS_DENOMINATOR - slippage_bps)
+        / u128::from(from_amount)
+        / u128::from(serum_swap::BPS_DENOMINATOR);
+    u64::try_from(rate).map_err(|_| anyhow!("exchange rate overflows"))
+}
@@ -0,0 +1,229 @@
+//! Decoding of the program's events, for indexers and other off-chain
+//! consumers.
+//!
+//! Events are passed to the program's own `log_event` instruction via CPI,
+//! which unlike logs can't be truncated. `SwapFailed`, whose transaction
+//! fails, and all of them when the program is built with the `log-events`
+//! feature, are logged by `emit!` as base64. Either way, an event is its 8
+//! byte discriminator followed by its borsh serialization.
+
+use crate::rpc::TransactionMeta;
+use anchor_lang::{AnchorDeserialize, Discriminator, InstructionData};
+use serum_swap::instruction;
+use solana_sdk::pubkey::Pubkey;
+
+// Prefixes of the logs of `emit!`, i.e., `msg!`, and of `sol_log_data` as
+// used by later versions of Anchor.
+const LOG_PREFIXES: [&str; 2] = ["Program log: ", "Program data: "];
+
+macro_rules! events {
+    ($($name:ident),* $(,)?) => {
+        /// Event emitted by the program.
+        pub enum Event {
+            $($name(serum_swap::$name),)*
+        }
+
+        /// Decodes an event's discriminator and borsh serialization, or none
+        /// if it isn't one of the program's events.
+        pub fn decode(data: &[u8]) -> Option<Event> {
+            let discriminator = data.get(..8)?;
+            let mut body = &data[8..];
+            $(
+                if discriminator == serum_swap::$name::discriminator() {
+                    return AnchorDeserialize::deserialize(&mut body).ok().map(Event::$name);
+                }
+            )*
+            None
+        }
+    };
+}
+
+events!(
+    OpenOrdersInitialized,
+    OpenOrdersClosed,
+    OrdersCancelled,
+    ReferralShared,
+    DidSwap,
+    SwapFailed,
+    DidSwapLeg,
+    DidArbitrage,
+    SpillTransferred,
+    OutputShared,
+    PaymentSettled,
+    FlashSwapped,
+    EventsConsumed,
+);
+
+/// Decodes the event of a log line, or none if it isn't one.
+pub fn decode_log(log: &str) -> Option<Event> {
+    let encoded = LOG_PREFIXES
+        .iter()
+        .find_map(|prefix| log.strip_prefix(prefix))?;
+    decode(&base64::decode(encoded).ok()?)
+}
+
+/// Decodes the events logged by the program, skipping the logs of the
+/// programs it invokes and of those invoking it.
+pub fn parse_logs(logs: &[String]) -> Vec<Event> {
+    // Whether each program on the invocation stack is this one.
+    let mut stack: Vec<bool> = vec![];
+    let mut events = vec![];
+    for log in logs {
+        if LOG_PREFIXES.iter().any(|prefix| log.starts_with(prefix)) {
+            if stack.last() == Some(&true) {
+                events.extend(decode_log(log));
+            }
+            continue;
+        }
+        let words: Vec<&str> = log.split_whitespace().collect();
+        match words.as_slice() {
+            ["Program", program_id, "invoke", _] => {
+                stack.push(*program_id == serum_swap::ID.to_string());
+            }
+            ["Program", _, "success"] | ["Program", _, "failed:", ..] => {
+                stack.pop();
+            }
+            _ => {}
+        }
+    }
+    events
+}
+
+/// Decodes the event of an instruction's data, if it's the program's
+/// `log_event`.
+pub fn decode_log_event(program_id: &Pubkey, data: &[u8]) -> Option<Event> {
+    if *program_id != serum_swap::ID {
+        return None;
+    }
+    // The instruction's argument is the event, with its length prefix.
+    let discriminator = instruction::LogEvent { _event: vec![] }.data();
+    let event = data.strip_prefix(&discriminator[..8])?.get(4..)?;
+    decode(event)
+}
+
+/// Decodes the events of a transaction, from its inner instructions.
+pub fn parse_transaction(transaction: &TransactionMeta) -> Vec<Event> {
+    transaction
+        .inner_instructions
+        .iter()
+        .filter_map(|(program_id, data)| decode_log_event(program_id, data))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_lang::AnchorSerialize;
+    use serum_swap::{OpenOrdersClosed, OpenOrdersInitialized};
+
+    fn initialized(market: Pubkey) -> Vec<u8> {
+        let event = OpenOrdersInitialized {
+            authority: Pubkey::new_unique(),
+            market,
+            open_orders: Pubkey::new_unique(),
+        };
+        [
+            &OpenOrdersInitialized::discriminator()[..],
+            &event.try_to_vec().unwrap(),
+        ]
+        .concat()
+    }
+
+    fn closed(rent_refunded: u64) -> Vec<u8> {
+        let event = OpenOrdersClosed {
+            authority: Pubkey::new_unique(),
+            market: Pubkey::new_unique(),
+            open_orders: Pubkey::new_unique(),
+            destination: Pubkey::new_unique(),
+            rent_refunded,
+        };
+        [
+            &OpenOrdersClosed::discriminator()[..],
+            &event.try_to_vec().unwrap(),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn decodes_log_lines() {
+        let market = Pubkey::new_unique();
+        let encoded = base64::encode(initialized(market));
+        for prefix in LOG_PREFIXES {
+            let event = decode_log(&format!("{}{}", prefix, encoded));
+            assert!(matches!(event, Some(Event::OpenOrdersInitialized(e)) if e.market == market));
+        }
+        assert!(decode_log(&format!("Program return: {}", encoded)).is_none());
+        assert!(decode_log("Program log: not base64!").is_none());
+        // Not one of the program's events.
+        assert!(decode_log(&format!("Program log: {}", base64::encode([0u8; 40]))).is_none());
+        // Truncated.
+        assert!(decode(&initialized(market)[..40]).is_none());
+    }
+
+    #[test]
+    fn parses_only_the_programs_logs() {
+        let program = serum_swap::ID.to_string();
+        let other = Pubkey::new_unique().to_string();
+        let log = |data: Vec<u8>| format!("Program log: {}", base64::encode(data));
+        let logs = vec![
+            format!("Program {} invoke [1]", other),
+            // Logged by the invoking program.
+            log(closed(1)),
+            format!("Program {} invoke [2]", program),
+            log(closed(2)),
+            format!("Program {} invoke [3]", other),
+            // Logged by a program it invokes.
+            log(closed(3)),
+            format!("Program {} consumed 100 of 200000 compute units", other),
+            format!("Program {} success", other),
+            "Program log: Instruction: Swap".to_string(),
+            log(closed(4)),
+            format!("Program {} success", program),
+            log(closed(5)),
+            format!("Program {} invoke [2]", program),
+            log(closed(6)),
+            format!("Program {} failed: custom program error: 0x1", program),
+            log(closed(7)),
+            format!("Program {} success", other),
+        ];
+        let refunds: Vec<u64> = parse_logs(&logs)
+            .into_iter()
+            .map(|event| match event {
+                Event::OpenOrdersClosed(closed) => closed.rent_refunded,
+                _ => panic!("unexpected event"),
+            })
+            .collect();
+        assert_eq!(refunds, vec![2, 4, 6]);
+    }
+
+    #[test]
+    fn decodes_log_event_instructions() {
+        let market = Pubkey::new_unique();
+        let data = instruction::LogEvent {
+            _event: initialized(market),
+        }
+        .data();
+        let event = decode_log_event(&serum_swap::ID, &data);
+        assert!(matches!(event, Some(Event::OpenOrdersInitialized(e)) if e.market == market));
+        // Invoked on another program.
+        assert!(decode_log_event(&Pubkey::new_unique(), &data).is_none());
+        // Another of the program's instructions.
+        let data = instruction::LogEvent { _event: vec![] }.data();
+        let other = [&[0u8; 8][..], &data[8..]].concat();
+        assert!(decode_log_event(&serum_swap::ID, &other).is_none());
+        assert!(decode_log_event(&serum_swap::ID, &data[..10]).is_none());
+
+        let transaction = TransactionMeta {
+            log_messages: vec![],
+            inner_instructions: vec![
+                (Pubkey::new_unique(), vec![1, 2, 3]),
+                (
+                    serum_swap::ID,
+                    instruction::LogEvent { _event: closed(8) }.data(),
+                ),
+            ],
+        };
+        let events = parse_transaction(&transaction);
+        assert!(matches!(events[..], [Event::OpenOrdersClosed(ref e)] if e.rent_refunded == 8));
+    }
+}
@@ -0,0 +1,1081 @@
+//! Off-chain client of the swap program: derives its accounts, builds its
+//! instructions, and sends them over JSON RPC. Used by the keeper cranking
+//! scheduled orders, and by the command line tool.
+
+use anchor_lang::{
+    AccountDeserialize, AnchorSerialize, Discriminator, InstructionData, ToAccountMetas,
+};
+use anchor_spl::associated_token::get_associated_token_address;
+use anyhow::{anyhow, Result};
+use serum_swap::{
+    accounts, instruction, Config, Dca, ExchangeRate, Side, SwapArgsV2, SwapTransitiveArgsV2, Twap,
+};
+use solana_sdk::hash::hashv;
+use solana_sdk::instruction::{AccountMeta, Instruction};
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{read_keypair_file, Keypair, Signature, Signer};
+use solana_sdk::transaction::Transaction;
+use solana_sdk::{system_program, sysvar};
+use std::convert::TryInto;
+use std::thread;
+use std::time::Duration;
+
+pub mod book;
+pub mod events;
+pub mod market;
+pub mod rpc;
+
+pub use book::{Orderbook, Quote};
+pub use events::Event;
+pub use market::Market;
+pub use rpc::{Memcmp, RpcClient, TransactionMeta};
+
+/// Program executing compute budget instructions.
+pub mod compute_budget {
+    solana_sdk::declare_id!("ComputeBudget111111111111111111111111111111");
+}
+
+// Byte offsets into an SPL token account.
+const TOKEN_ACCOUNT_MINT_OFFSET: usize = 0;
+const TOKEN_ACCOUNT_AMOUNT_OFFSET: usize = 64;
+
+// Byte offset of the decimals of an SPL mint.
+const MINT_DECIMALS_OFFSET: usize = 44;
+
+// Time between polls of a sent transaction's status.
+const CONFIRM_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+pub fn config_address() -> Pubkey {
+    Pubkey::find_program_address(&[serum_swap::CONFIG_SEED], &serum_swap::ID).0
+}
+
+pub fn whitelist_address() -> Pubkey {
+    Pubkey::find_program_address(&[serum_swap::WHITELIST_SEED], &serum_swap::ID).0
+}
+
+pub fn crank_tip_address() -> Pubkey {
+    Pubkey::find_program_address(&[serum_swap::CRANK_TIP_SEED], &serum_swap::ID).0
+}
+
+pub fn crank_tip_vault_address() -> Pubkey {
+    Pubkey::find_program_address(&[serum_swap::CRANK_TIP_VAULT_SEED], &serum_swap::ID).0
+}
+
+pub fn fee_vault_address(mint: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(
+        &[serum_swap::FEE_VAULT_SEED, mint.as_ref()],
+        &serum_swap::ID,
+    )
+    .0
+}
+
+pub fn flash_vault_address(mint: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(
+        &[serum_swap::FLASH_VAULT_SEED, mint.as_ref()],
+        &serum_swap::ID,
+    )
+    .0
+}
+
+pub fn circuit_breaker_address(market: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(
+        &[serum_swap::CIRCUIT_BREAKER_SEED, market.as_ref()],
+        &serum_swap::ID,
+    )
+    .0
+}
+
+pub fn spending_policy_address(owner: &Pubkey, authority: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(
+        &[
+            serum_swap::SPENDING_POLICY_SEED,
+            owner.as_ref(),
+            authority.as_ref(),
+        ],
+        &serum_swap::ID,
+    )
+    .0
+}
+
+pub fn permit_authority_address(signer: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(
+        &[serum_swap::PERMIT_AUTHORITY_SEED, signer.as_ref()],
+        &serum_swap::ID,
+    )
+    .0
+}
+
+pub fn event_authority_address() -> Pubkey {
+    Pubkey::find_program_address(&[serum_swap::EVENT_AUTHORITY_SEED], &serum_swap::ID).0
+}
+
+/// Reads the keypair file at `path`, which may start with `~/`.
+pub fn read_keypair(path: &str) -> Result<Keypair> {
+    let expanded = match (path.strip_prefix("~/"), std::env::var("HOME")) {
+        (Some(rest), Ok(home)) => format!("{}/{}", home, rest),
+        _ => path.to_string(),
+    };
+    read_keypair_file(&expanded).map_err(|err| anyhow!("failed to read keypair {}: {}", path, err))
+}
+
+/// Returns the `wallet`'s associated token account for `mint`.
+pub fn associated_token_address(wallet: &Pubkey, mint: &Pubkey) -> Pubkey {
+    get_associated_token_address(wallet, mint)
+}
+
+/// Returns the mint and balance of the token account `data`.
+pub fn decode_token_account(data: &[u8]) -> Result<(Pubkey, u64)> {
+    if data.len() < TOKEN_ACCOUNT_AMOUNT_OFFSET + 8 {
+        return Err(anyhow!("not a token account"));
+    }
+    let mut mint = [0u8; 32];
+    mint.copy_from_slice(&data[TOKEN_ACCOUNT_MINT_OFFSET..TOKEN_ACCOUNT_MINT_OFFSET + 32]);
+    let mut amount = [0u8; 8];
+    amount.copy_from_slice(&data[TOKEN_ACCOUNT_AMOUNT_OFFSET..TOKEN_ACCOUNT_AMOUNT_OFFSET + 8]);
+    Ok((Pubkey::new_from_array(mint), u64::from_le_bytes(amount)))
+}
+
+/// Returns every account of type `T` owned by the swap program, skipping
+/// those failing to deserialize, e.g., created by an older version.
+pub fn fetch_all<T: AccountDeserialize + Discriminator>(
+    rpc: &RpcClient,
+) -> Result<Vec<(Pubkey, T)>> {
+    let filters = [Memcmp {
+        offset: 0,
+        bytes: T::discriminator().to_vec(),
+    }];
+    Ok(rpc
+        .get_program_accounts(&serum_swap::ID, &filters)?
+        .into_iter()
+        .filter_map(|(address, data)| {
+            T::try_deserialize(&mut data.as_slice())
+                .ok()
+                .map(|account| (address, account))
+        })
+        .collect())
+}
+
+/// Fetches and decodes the market at `address`.
+pub fn fetch_market(rpc: &RpcClient, address: &Pubkey) -> Result<Market> {
+    let (owner, data) = rpc
+        .get_account(address)?
+        .ok_or_else(|| anyhow!("market {} not found", address))?;
+    Market::decode(*address, owner, &data)
+}
+
+/// Fetches and decodes the swap program's config. Swap instructions are
+/// built against it, leaving out the accounts of the features it doesn't
+/// enable, so they must be rebuilt once the admin enables one.
+pub fn fetch_config(rpc: &RpcClient) -> Result<Config> {
+    let (_, data) = rpc
+        .get_account(&config_address())?
+        .ok_or_else(|| anyhow!("config not found"))?;
+    Ok(Config::try_deserialize(&mut data.as_slice())?)
+}
+
+/// Fetches the number of decimals of the `mint`.
+pub fn fetch_decimals(rpc: &RpcClient, mint: &Pubkey) -> Result<u8> {
+    let (_, data) = rpc
+        .get_account(mint)?
+        .ok_or_else(|| anyhow!("mint {} not found", mint))?;
+    data.get(MINT_DECIMALS_OFFSET)
+        .copied()
+        .ok_or_else(|| anyhow!("account {} isn't a mint", mint))
+}
+
+/// Fetches the orders resting on the `market`'s side a swap of `side` fills
+/// against, i.e., the asks for bids and the bids for asks.
+pub fn fetch_orderbook(rpc: &RpcClient, market: &Market, side: Side) -> Result<Orderbook> {
+    let address = match side {
+        Side::Bid => market.asks,
+        Side::Ask => market.bids,
+    };
+    let (_, data) = rpc
+        .get_account(&address)?
+        .ok_or_else(|| anyhow!("orderbook {} not found", address))?;
+    Orderbook::new(&data)
+}
+
+/// Returns the instruction creating, if needed, and initializing the
+/// `open_orders` account of the `authority` on the `market`.
+pub fn init_account(
+    market: &Market,
+    open_orders: &Pubkey,
+    authority: &Pubkey,
+    payer: &Pubkey,
+) -> Instruction {
+    let accounts = accounts::InitAccount {
+        open_orders: *open_orders,
+        authority: *authority,
+        payer: *payer,
+        market: market.address,
+        dex_program: market.dex_program,
+        rent: sysvar::rent::ID,
+        system_program: system_program::ID,
+        event_cpi: event_cpi(),
+    };
+    Instruction {
+        program_id: serum_swap::ID,
+        accounts: accounts.to_account_metas(None),
+        data: instruction::InitAccount {}.data(),
+    }
+}
+
+/// Returns the instruction cancelling every order of the `wallets`' open
+/// orders account on the `market` and settling its funds, then, if `close`,
+/// closing it, refunding its rent to `destination`.
+pub fn cancel_all_and_settle(
+    market: &Market,
+    wallets: &Wallets,
+    destination: &Pubkey,
+    close: bool,
+) -> Instruction {
+    let accounts = accounts::CancelAllAndSettle {
+        market: market.address,
+        open_orders: wallets.open_orders,
+        authority: wallets.authority,
+        bids: market.bids,
+        asks: market.asks,
+        event_queue: market.event_queue,
+        coin_vault: market.coin_vault,
+        pc_vault: market.pc_vault,
+        vault_signer: market.vault_signer,
+        coin_wallet: wallets.coin_wallet,
+        pc_wallet: wallets.pc_wallet,
+        destination: *destination,
+        dex_program: market.dex_program,
+        token_program: anchor_spl::token::ID,
+        event_cpi: event_cpi(),
+    };
+    Instruction {
+        program_id: serum_swap::ID,
+        accounts: accounts.to_account_metas(None),
+        data: instruction::CancelAllAndSettle { close }.data(),
+    }
+}
+
+/// Returns the instruction consuming up to `limit` events of the `open_orders`
+/// accounts from the `market`'s event queue, paying the crank tip to the
+/// `destination`, a token account of its mint.
+pub fn consume_events(
+    market: &Market,
+    open_orders: &[Pubkey],
+    destination: &Pubkey,
+    limit: u16,
+) -> Instruction {
+    let mut accounts = accounts::ConsumeEvents {
+        crank_tip: crank_tip_address(),
+        market: market.address,
+        event_queue: market.event_queue,
+        tip_vault: crank_tip_vault_address(),
+        destination: *destination,
+        dex_program: market.dex_program,
+        token_program: anchor_spl::token::ID,
+        event_cpi: event_cpi(),
+    }
+    .to_account_metas(None);
+    // The DEX binary searches the open orders accounts by their address as
+    // 64 bit little-endian words.
+    let mut open_orders = open_orders.to_vec();
+    open_orders.sort_by_key(|key| {
+        key.to_bytes()
+            .chunks(8)
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+            .collect::<Vec<u64>>()
+    });
+    accounts.extend(
+        open_orders
+            .into_iter()
+            .map(|open_orders| AccountMeta::new(open_orders, false)),
+    );
+    Instruction {
+        program_id: serum_swap::ID,
+        accounts,
+        data: instruction::ConsumeEvents { limit }.data(),
+    }
+}
+
+/// Returns the instruction creating, if needed, the `authority`'s associated
+/// token accounts for the base and quote mints of the `market`, funded by the
+/// `payer`.
+pub fn init_swap_wallets(market: &Market, authority: &Pubkey, payer: &Pubkey) -> Instruction {
+    let accounts = accounts::InitSwapWallets {
+        authority: *authority,
+        payer: *payer,
+        market: market.address,
+        coin_mint: market.coin_mint,
+        pc_mint: market.pc_mint,
+        coin_wallet: associated_token_address(authority, &market.coin_mint),
+        pc_wallet: associated_token_address(authority, &market.pc_mint),
+        associated_token_program: anchor_spl::associated_token::ID,
+        token_program: anchor_spl::token::ID,
+        system_program: system_program::ID,
+        rent: sysvar::rent::ID,
+    };
+    Instruction {
+        program_id: serum_swap::ID,
+        accounts: accounts.to_account_metas(None),
+        data: instruction::InitSwapWallets {}.data(),
+    }
+}
+
+/// Returns the instruction closing the `open_orders` account of the
+/// `authority` on the `market`, refunding its rent to `destination`.
+pub fn close_account(
+    market: &Market,
+    open_orders: &Pubkey,
+    authority: &Pubkey,
+    destination: &Pubkey,
+) -> Instruction {
+    let accounts = accounts::CloseAccount {
+        open_orders: *open_orders,
+        authority: *authority,
+        destination: *destination,
+        market: market.address,
+        dex_program: market.dex_program,
+        event_cpi: event_cpi(),
+    };
+    Instruction {
+        program_id: serum_swap::ID,
+        accounts: accounts.to_account_metas(None),
+        data: instruction::CloseAccount {}.data(),
+    }
+}
+
+/// Wallets and open orders account of the authority of a swap on a market.
+pub struct Wallets {
+    pub authority: Pubkey,
+    /// Owner of the wallets, whose spending policy over the authority is
+    /// enforced. The authority itself, unless it's a delegate.
+    pub owner: Pubkey,
+    pub open_orders: Pubkey,
+    pub coin_wallet: Pubkey,
+    pub pc_wallet: Pubkey,
+}
+
+/// Returns the instruction swapping `amount` on the `market`, aborting
+/// below the `min_exchange_rate`, via `swap_v2`. Stats aren't tracked, and
+/// there's no platform fee.
+pub fn swap(
+    config: &Config,
+    market: &Market,
+    wallets: &Wallets,
+    side: Side,
+    amount: u64,
+    min_exchange_rate: ExchangeRate,
+    max_price_impact_bps: Option<u16>,
+) -> Instruction {
+    let args = SwapArgsV2 {
+        side,
+        amount,
+        min_exchange_rate,
+        max_quote_amount: None,
+        platform_fee_bps: None,
+        oracle_band: None,
+        tag: None,
+        hook_accounts: None,
+        allow_zero_output: None,
+        allow_partial: None,
+        max_price_impact_bps,
+        limit_price: None,
+        deadline: None,
+        max_event_queue_len: None,
+        output_split: None,
+    };
+    swap_v2(config, market, wallets, args)
+}
+
+/// Returns the hash `commit_swap` commits to, of a `swap_v2` on the `market`
+/// with the `args`, salted with the `salt` later revealed along with them.
+pub fn swap_commitment_hash(market: &Pubkey, args: &SwapArgsV2, salt: &[u8; 32]) -> [u8; 32] {
+    let args = args.try_to_vec().unwrap();
+    hashv(&[market.as_ref(), &args, salt]).to_bytes()
+}
+
+/// Returns the instruction swapping on the `market` with the `args` of
+/// `swap_v2`. Stats aren't tracked, and the platform fee, if any, is paid
+/// back to the authority's wallet.
+pub fn swap_v2(
+    config: &Config,
+    market: &Market,
+    wallets: &Wallets,
+    args: SwapArgsV2,
+) -> Instruction {
+    Instruction {
+        program_id: serum_swap::ID,
+        accounts: swap_accounts(config, market, wallets, args.side),
+        data: instruction::SwapV2 { args }.data(),
+    }
+}
+
+/// Returns the instruction paying the `merchant`'s token account exactly
+/// `pay_amount`, swapped from at most `max_amount` on the `market`, with the
+/// `reference` key identifying the payment.
+#[allow(clippy::too_many_arguments)]
+pub fn pay_with_swap(
+    config: &Config,
+    market: &Market,
+    wallets: &Wallets,
+    side: Side,
+    pay_amount: u64,
+    max_amount: u64,
+    merchant: &Pubkey,
+    reference: &Pubkey,
+) -> Instruction {
+    let mut accounts = swap_accounts(config, market, wallets, side);
+    accounts.push(AccountMeta::new(*merchant, false));
+    accounts.push(AccountMeta::new_readonly(*reference, false));
+    Instruction {
+        program_id: serum_swap::ID,
+        accounts,
+        data: instruction::PayWithSwap {
+            side,
+            pay_amount,
+            max_amount,
+        }
+        .data(),
+    }
+}
+
+/// Returns the instruction flash swapping on the `market`: `amount` is
+/// delivered first, lent by the flash vault of the *to* mint, then the
+/// `callback_program` is invoked with the `callback_accounts`, after which at
+/// most `max_amount` is swapped to repay it.
+#[allow(clippy::too_many_arguments)]
+pub fn flash_swap(
+    config: &Config,
+    market: &Market,
+    wallets: &Wallets,
+    side: Side,
+    amount: u64,
+    max_amount: u64,
+    callback_program: &Pubkey,
+    callback_accounts: &[AccountMeta],
+) -> Instruction {
+    let to_mint = match side {
+        Side::Bid => market.coin_mint,
+        Side::Ask => market.pc_mint,
+    };
+    let mut accounts = swap_accounts(config, market, wallets, side);
+    accounts.push(AccountMeta::new(flash_vault_address(&to_mint), false));
+    accounts.push(AccountMeta::new_readonly(*callback_program, false));
+    accounts.extend_from_slice(callback_accounts);
+    Instruction {
+        program_id: serum_swap::ID,
+        accounts,
+        data: instruction::FlashSwap {
+            side,
+            amount,
+            max_amount,
+            num_callback_accounts: callback_accounts.len().try_into().unwrap(),
+        }
+        .data(),
+    }
+}
+
+// Accounts of `swap`, `swap_v2` and, followed by their own, `flash_swap` and
+// `pay_with_swap`.
+fn swap_accounts(
+    config: &Config,
+    market: &Market,
+    wallets: &Wallets,
+    side: Side,
+) -> Vec<AccountMeta> {
+    let (order_payer, to_wallet, to_mint) = match side {
+        Side::Bid => (wallets.pc_wallet, wallets.coin_wallet, market.coin_mint),
+        Side::Ask => (wallets.coin_wallet, wallets.pc_wallet, market.pc_mint),
+    };
+    accounts::Swap {
+        market: market_accounts(
+            config,
+            market,
+            &wallets.open_orders,
+            &order_payer,
+            &wallets.coin_wallet,
+        ),
+        authority: wallets.authority,
+        pc_wallet: wallets.pc_wallet,
+        config: config_address(),
+        whitelist: whitelist_account(config),
+        user_stats: serum_swap::ID,
+        spending_policy: spending_policy_account(wallets),
+        fee_vault: fee_vault_account(config, &to_mint),
+        platform_fee_account: to_wallet,
+        referral: serum_swap::ID,
+        srm_msrm_discount: serum_swap::ID,
+        dex_program: market.dex_program,
+        token_program: anchor_spl::token::ID,
+        rent: sysvar::rent::ID,
+        event_cpi: event_cpi(),
+    }
+    .to_account_metas(None)
+}
+
+/// Returns the instruction swapping `amount` of the `from` market's base
+/// currency for the `to` market's, via their common quote currency,
+/// aborting below the `min_exchange_rate`. Stats aren't tracked, and
+/// there's no platform fee.
+///
+/// The `coin_wallet`s of the wallets are the base currency wallets of each
+/// market, and their `pc_wallet`s must be the same.
+pub fn swap_transitive(
+    config: &Config,
+    from: &Market,
+    to: &Market,
+    from_wallets: &Wallets,
+    to_wallets: &Wallets,
+    amount: u64,
+    min_exchange_rate: ExchangeRate,
+) -> Instruction {
+    Instruction {
+        program_id: serum_swap::ID,
+        accounts: swap_transitive_accounts(config, from, to, from_wallets, to_wallets)
+            .to_account_metas(None),
+        data: instruction::SwapTransitive {
+            amount,
+            min_exchange_rate,
+        }
+        .data(),
+    }
+}
+
+/// Returns the instruction swapping from the `from` market's base currency
+/// to the `to` market's with the `args` of `swap_transitive_v2`, with the
+/// same wallets as `swap_transitive`. Stats aren't tracked, and the platform
+/// fee, if any, is paid back to the authority's wallet.
+pub fn swap_transitive_v2(
+    config: &Config,
+    from: &Market,
+    to: &Market,
+    from_wallets: &Wallets,
+    to_wallets: &Wallets,
+    args: SwapTransitiveArgsV2,
+) -> Instruction {
+    Instruction {
+        program_id: serum_swap::ID,
+        accounts: swap_transitive_accounts(config, from, to, from_wallets, to_wallets)
+            .to_account_metas(None),
+        data: instruction::SwapTransitiveV2 { args }.data(),
+    }
+}
+
+// Accounts of `swap_transitive` and `swap_transitive_v2`.
+fn swap_transitive_accounts(
+    config: &Config,
+    from: &Market,
+    to: &Market,
+    from_wallets: &Wallets,
+    to_wallets: &Wallets,
+) -> accounts::SwapTransitive {
+    accounts::SwapTransitive {
+        from: market_accounts(
+            config,
+            from,
+            &from_wallets.open_orders,
+            &from_wallets.coin_wallet,
+            &from_wallets.coin_wallet,
+        ),
+        to: market_accounts(
+            config,
+            to,
+            &to_wallets.open_orders,
+            &to_wallets.pc_wallet,
+            &to_wallets.coin_wallet,
+        ),
+        authority: from_wallets.authority,
+        pc_wallet: from_wallets.pc_wallet,
+        config: config_address(),
+        whitelist: whitelist_account(config),
+        user_stats: serum_swap::ID,
+        spending_policy: spending_policy_account(from_wallets),
+        fee_vault: fee_vault_account(config, &to.coin_mint),
+        platform_fee_account: to_wallets.coin_wallet,
+        referral: serum_swap::ID,
+        srm_msrm_discount: serum_swap::ID,
+        dex_program: from.dex_program,
+        token_program: anchor_spl::token::ID,
+        rent: sysvar::rent::ID,
+        event_cpi: event_cpi(),
+    }
+}
+
+/// Returns the instruction buying the base currency with `amount` of the
+/// quote currency on the `buy` market and selling it on the `sell` market,
+/// aborting unless it gains more than `min_profit`. Stats aren't tracked.
+///
+/// The wallets of both markets must be the same but for the open orders
+/// accounts.
+pub fn arbitrage(
+    config: &Config,
+    buy: &Market,
+    sell: &Market,
+    buy_wallets: &Wallets,
+    sell_wallets: &Wallets,
+    amount: u64,
+    min_profit: u64,
+) -> Instruction {
+    let accounts = accounts::Arbitrage {
+        buy: market_accounts(
+            config,
+            buy,
+            &buy_wallets.open_orders,
+            &buy_wallets.pc_wallet,
+            &buy_wallets.coin_wallet,
+        ),
+        sell: market_accounts(
+            config,
+            sell,
+            &sell_wallets.open_orders,
+            &sell_wallets.coin_wallet,
+            &sell_wallets.coin_wallet,
+        ),
+        authority: buy_wallets.authority,
+        pc_wallet: buy_wallets.pc_wallet,
+        config: config_address(),
+        whitelist: whitelist_account(config),
+        user_stats: serum_swap::ID,
+        spending_policy: spending_policy_account(buy_wallets),
+        fee_vault: fee_vault_account(config, &buy.pc_mint),
+        dex_program: buy.dex_program,
+        token_program: anchor_spl::token::ID,
+        rent: sysvar::rent::ID,
+        event_cpi: event_cpi(),
+    };
+    Instruction {
+        program_id: serum_swap::ID,
+        accounts: accounts.to_account_metas(None),
+        data: instruction::Arbitrage { amount, min_profit }.data(),
+    }
+}
+
+/// Returns the instruction swapping `amount` of the `from` market's quote
+/// currency for the `to` market's, via their common base currency,
+/// aborting below the `min_exchange_rate`. Stats aren't tracked, and
+/// there's no platform fee.
+///
+/// The `coin_wallet`s of the wallets must be the same, and their
+/// `pc_wallet`s are the quote currency wallets of each market.
+pub fn swap_quote_to_quote(
+    config: &Config,
+    from: &Market,
+    to: &Market,
+    from_wallets: &Wallets,
+    to_wallets: &Wallets,
+    amount: u64,
+    min_exchange_rate: ExchangeRate,
+) -> Instruction {
+    let accounts = accounts::SwapQuoteToQuote {
+        from: market_accounts(
+            config,
+            from,
+            &from_wallets.open_orders,
+            &from_wallets.pc_wallet,
+            &from_wallets.coin_wallet,
+        ),
+        to: market_accounts(
+            config,
+            to,
+            &to_wallets.open_orders,
+            &to_wallets.coin_wallet,
+            &to_wallets.coin_wallet,
+        ),
+        authority: from_wallets.authority,
+        from_pc_wallet: from_wallets.pc_wallet,
+        to_pc_wallet: to_wallets.pc_wallet,
+        config: config_address(),
+        whitelist: whitelist_account(config),
+        user_stats: serum_swap::ID,
+        spending_policy: spending_policy_account(from_wallets),
+        fee_vault: fee_vault_account(config, &to.pc_mint),
+        platform_fee_account: to_wallets.pc_wallet,
+        dex_program: from.dex_program,
+        token_program: anchor_spl::token::ID,
+        rent: sysvar::rent::ID,
+        event_cpi: event_cpi(),
+    };
+    Instruction {
+        program_id: serum_swap::ID,
+        accounts: accounts.to_account_metas(None),
+        data: instruction::SwapQuoteToQuote {
+            amount,
+            min_exchange_rate,
+            platform_fee_bps: 0,
+            tag: None,
+        }
+        .data(),
+    }
+}
+
+/// Returns the instruction executing the next swap of the DCA schedule at
+/// `address`, paying the crank fee into `cranker_wallet`.
+pub fn execute_dca(
+    config: &Config,
+    address: &Pubkey,
+    dca: &Dca,
+    market: &Market,
+    cranker_wallet: &Pubkey,
+) -> Instruction {
+    let accounts = accounts::ExecuteDca {
+        dca: *address,
+        input_vault: dca.input_vault,
+        output_vault: dca.output_vault,
+        destination: dca.destination,
+        cranker_wallet: *cranker_wallet,
+        swap: scheduled_swap(
+            config,
+            &dca.side,
+            &dca.input_vault,
+            &dca.output_vault,
+            &dca.open_orders,
+            market,
+        ),
+        token_program: anchor_spl::token::ID,
+    };
+    Instruction {
+        program_id: serum_swap::ID,
+        accounts: accounts.to_account_metas(None),
+        data: instruction::ExecuteDca {}.data(),
+    }
+}
+
+/// Returns the instruction executing the next slice of the TWAP order at
+/// `address`.
+pub fn execute_twap_slice(
+    config: &Config,
+    address: &Pubkey,
+    twap: &Twap,
+    market: &Market,
+) -> Instruction {
+    let accounts = accounts::ExecuteTwapSlice {
+        twap: *address,
+        input_vault: twap.input_vault,
+        output_vault: twap.output_vault,
+        destination: twap.destination,
+        swap: scheduled_swap(
+            config,
+            &twap.side,
+            &twap.input_vault,
+            &twap.output_vault,
+            &twap.open_orders,
+            market,
+        ),
+        token_program: anchor_spl::token::ID,
+    };
+    Instruction {
+        program_id: serum_swap::ID,
+        accounts: accounts.to_account_metas(None),
+        data: instruction::ExecuteTwapSlice {}.data(),
+    }
+}
+
+// Accounts of the swap of a schedule from its `input_vault` into its
+// `output_vault`. Stats aren't tracked, and there's no platform fee.
+fn scheduled_swap(
+    config: &Config,
+    side: &Side,
+    input_vault: &Pubkey,
+    output_vault: &Pubkey,
+    open_orders: &Pubkey,
+    market: &Market,
+) -> accounts::ScheduledSwap {
+    let (pc_wallet, coin_wallet, to_mint) = match side {
+        Side::Bid => (*input_vault, *output_vault, market.coin_mint),
+        Side::Ask => (*output_vault, *input_vault, market.pc_mint),
+    };
+    accounts::ScheduledSwap {
+        market: market_accounts(config, market, open_orders, input_vault, &coin_wallet),
+        pc_wallet,
+        config: config_address(),
+        whitelist: whitelist_account(config),
+        user_stats: serum_swap::ID,
+        // The schedule owns its vaults.
+        spending_policy: serum_swap::ID,
+        fee_vault: fee_vault_account(config, &to_mint),
+        platform_fee_account: *output_vault,
+        srm_msrm_discount: serum_swap::ID,
+        dex_program: market.dex_program,
+        token_program: anchor_spl::token::ID,
+        rent: sysvar::rent::ID,
+        event_cpi: event_cpi(),
+    }
+}
+
+// Accounts of an order on the `market`, paid from `order_payer`. Market
+// stats aren't tracked, and the circuit breaker is skipped while the config
+// counts none.
+fn market_accounts(
+    config: &Config,
+    market: &Market,
+    open_orders: &Pubkey,
+    order_payer: &Pubkey,
+    coin_wallet: &Pubkey,
+) -> accounts::MarketAccounts {
+    accounts::MarketAccounts {
+        market: market.address,
+        open_orders: *open_orders,
+        request_queue: market.request_queue,
+        event_queue: market.event_queue,
+        bids: market.bids,
+        asks: market.asks,
+        order_payer_token_account: *order_payer,
+        coin_vault: market.coin_vault,
+        pc_vault: market.pc_vault,
+        vault_signer: market.vault_signer,
+        coin_wallet: *coin_wallet,
+        circuit_breaker: match config.circuit_breakers {
+            0 => serum_swap::ID,
+            _ => circuit_breaker_address(&market.address),
+        },
+        market_stats: serum_swap::ID,
+    }
+}
+
+// Returns the spending policy of the owner of the `wallets` over their
+// authority, or this program's ID when the authority owns them.
+fn spending_policy_account(wallets: &Wallets) -> Pubkey {
+    if wallets.owner == wallets.authority {
+        return serum_swap::ID;
+    }
+    spending_policy_address(&wallets.owner, &wallets.authority)
+}
+
+// Returns the market whitelist, or this program's ID while the config
+// doesn't enable one.
+fn whitelist_account(config: &Config) -> Pubkey {
+    if config.whitelist == Pubkey::default() {
+        return serum_swap::ID;
+    }
+    config.whitelist
+}
+
+// Returns the protocol fee vault for the `mint`, or this program's ID while
+// the config takes no fee.
+fn fee_vault_account(config: &Config, mint: &Pubkey) -> Pubkey {
+    if config.fee_bps == 0 {
+        return serum_swap::ID;
+    }
+    fee_vault_address(mint)
+}
+
+fn event_cpi() -> accounts::EventCpi {
+    accounts::EventCpi {
+        event_authority: event_authority_address(),
+        program: serum_swap::ID,
+    }
+}
+
+/// Returns the instruction setting the compute unit limit of a transaction.
+pub fn set_compute_unit_limit(units: u32) -> Instruction {
+    let mut data = vec![2];
+    data.extend_from_slice(&units.to_le_bytes());
+    Instruction::new_with_bytes(compute_budget::ID, &data, vec![])
+}
+
+/// Returns the instruction setting the priority fee of a transaction, in
+/// micro-lamports per compute unit.
+pub fn set_compute_unit_price(micro_lamports: u64) -> Instruction {
+    let mut data = vec![3];
+    data.extend_from_slice(&micro_lamports.to_le_bytes());
+    Instruction::new_with_bytes(compute_budget::ID, &data, vec![])
+}
+
+impl RpcClient {
+    /// Sends a transaction of the `instructions`, paid by the first of the
+    /// `signers`, and waits for its confirmation.
+    ///
+    /// When the blockhash expires before it's confirmed, the transaction is
+    /// signed again with a fresh one and resent, up to `max_attempts` times.
+    pub fn send_and_confirm(
+        &self,
+        instructions: &[Instruction],
+        signers: &[&Keypair],
+        max_attempts: usize,
+    ) -> Result<Signature> {
+        let payer = signers
+            .first()
+            .ok_or_else(|| anyhow!("no payer given"))?
+            .pubkey();
+        for _ in 0..max_attempts {
+            let (blockhash, last_valid_block_height) = self.get_latest_blockhash()?;
+            let transaction = Transaction::new_signed_with_payer(
+                instructions,
+                Some(&payer),
+                &signers.to_vec(),
+                blockhash,
+            );
+            let signature = self.send_transaction(&transaction)?;
+            loop {
+                // The height is read first, so the transaction can't land
+                // between the status check and the expiry check.
+                let expired = self.get_block_height()? > last_valid_block_height;
+                if let Some(status) = self.get_signature_status(&signature)? {
+                    return status
+                        .map(|_| signature)
+                        .map_err(|err| anyhow!("transaction {} failed: {}", signature, err));
+                }
+                if expired {
+                    break;
+                }
+                thread::sleep(CONFIRM_POLL_INTERVAL);
+            }
+        }
+        Err(anyhow!(
+            "transaction not confirmed after {} attempts",
+            max_attempts
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::packet::PACKET_DATA_SIZE;
+
+    fn market(pc_mint: Pubkey) -> Market {
+        Market {
+            address: Pubkey::new_unique(),
+            dex_program: anchor_spl::dex::ID,
+            coin_mint: Pubkey::new_unique(),
+            pc_mint,
+            coin_vault: Pubkey::new_unique(),
+            pc_vault: Pubkey::new_unique(),
+            vault_signer: Pubkey::new_unique(),
+            request_queue: Pubkey::new_unique(),
+            event_queue: Pubkey::new_unique(),
+            bids: Pubkey::new_unique(),
+            asks: Pubkey::new_unique(),
+            coin_lot_size: 1,
+            pc_lot_size: 1,
+        }
+    }
+
+    // Returns a config enabling the whitelist, the protocol fee and circuit
+    // breakers, or none of them.
+    fn config(enabled: bool) -> Config {
+        Config {
+            admin: Pubkey::new_unique(),
+            fee_bps: if enabled { 10 } else { 0 },
+            bump: 255,
+            guardian: Pubkey::new_unique(),
+            paused: false,
+            whitelist: if enabled {
+                whitelist_address()
+            } else {
+                Pubkey::default()
+            },
+            hook_programs: vec![],
+            execution: None,
+            circuit_breakers: if enabled { 1 } else { 0 },
+        }
+    }
+
+    // Returns the wallets of a transitive swap through `usdc`.
+    fn wallets(authority: Pubkey, owner: Pubkey, usdc: Pubkey) -> Wallets {
+        Wallets {
+            authority,
+            owner,
+            open_orders: Pubkey::new_unique(),
+            coin_wallet: Pubkey::new_unique(),
+            pc_wallet: usdc,
+        }
+    }
+
+    fn transaction_size(instructions: &[Instruction], payer: &Pubkey) -> usize {
+        let transaction = Transaction::new_with_payer(instructions, Some(payer));
+        bincode::serialize(&transaction).unwrap().len()
+    }
+
+    #[test]
+    fn swap_transitive_accounts_skip_disabled_features() {
+        let (authority, owner, usdc) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let (from, to) = (market(usdc), market(usdc));
+        let from_wallets = wallets(authority, authority, usdc);
+        let to_wallets = wallets(authority, authority, usdc);
+        let accounts =
+            swap_transitive_accounts(&config(false), &from, &to, &from_wallets, &to_wallets);
+        assert_eq!(accounts.whitelist, serum_swap::ID);
+        assert_eq!(accounts.spending_policy, serum_swap::ID);
+        assert_eq!(accounts.fee_vault, serum_swap::ID);
+        assert_eq!(accounts.from.circuit_breaker, serum_swap::ID);
+        assert_eq!(accounts.to.circuit_breaker, serum_swap::ID);
+
+        // A delegate's swap is subject to the owner's policy.
+        let from_wallets = wallets(authority, owner, usdc);
+        let to_wallets = wallets(authority, owner, usdc);
+        let accounts =
+            swap_transitive_accounts(&config(true), &from, &to, &from_wallets, &to_wallets);
+        assert_eq!(accounts.whitelist, whitelist_address());
+        assert_eq!(
+            accounts.spending_policy,
+            spending_policy_address(&owner, &authority)
+        );
+        assert_eq!(accounts.fee_vault, fee_vault_address(&to.coin_mint));
+        assert_eq!(
+            accounts.from.circuit_breaker,
+            circuit_breaker_address(&from.address)
+        );
+        assert_eq!(
+            accounts.to.circuit_breaker,
+            circuit_breaker_address(&to.address)
+        );
+    }
+
+    #[test]
+    fn swap_transitive_v2_fits_a_transaction() {
+        let (authority, usdc) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (from, to) = (market(usdc), market(usdc));
+        let from_wallets = wallets(authority, authority, usdc);
+        let to_wallets = wallets(authority, authority, usdc);
+        let args = SwapTransitiveArgsV2 {
+            amount: 1,
+            min_exchange_rate: ExchangeRate {
+                rate: 1,
+                from_decimals: 6,
+                quote_decimals: 6,
+                strict: false,
+            },
+            reswap: None,
+            leg_rates: None,
+            max_quote_amount: None,
+            platform_fee_bps: None,
+            oracle_bands: None,
+            tag: None,
+            allow_zero_output: None,
+            transfer_spill: None,
+            spill_handling: None,
+        };
+        let data = instruction::SwapTransitiveV2 { args }.data();
+
+        // Every feature enabled by the admin, without options.
+        let accounts =
+            swap_transitive_accounts(&config(true), &from, &to, &from_wallets, &to_wallets);
+        let swap = Instruction {
+            program_id: serum_swap::ID,
+            accounts: accounts.to_account_metas(None),
+            data: data.clone(),
+        };
+        assert!(transaction_size(&[swap], &authority) <= PACKET_DATA_SIZE);
+
+        // No feature enabled by the admin, with the compute budget, stats and
+        // referral.
+        let mut accounts =
+            swap_transitive_accounts(&config(false), &from, &to, &from_wallets, &to_wallets);
+        accounts.user_stats = Pubkey::new_unique();
+        accounts.referral = Pubkey::new_unique();
+        let swap = Instruction {
+            program_id: serum_swap::ID,
+            accounts: accounts.to_account_metas(None),
+            data,
+        };
+        let instructions = [
+            set_compute_unit_limit(400_000),
+            set_compute_unit_price(1),
+            swap,
+        ];
+        assert!(transaction_size(&instructions, &authority) <= PACKET_DATA_SIZE);
+    }
+}
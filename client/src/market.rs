@@ -0,0 +1,62 @@
+//! Addresses of a DEX market, read from its account.
+
+use anchor_spl::dex::serum_dex::state::{gen_vault_signer_key, MarketState};
+use anyhow::{anyhow, Result};
+use solana_sdk::pubkey::Pubkey;
+use std::mem::size_of;
+
+// Every DEX account starts with b"serum".
+const HEAD_PADDING: usize = 5;
+
+/// Accounts of a market needed to place orders on it.
+#[derive(Clone, Debug)]
+pub struct Market {
+    pub address: Pubkey,
+    pub dex_program: Pubkey,
+    pub coin_mint: Pubkey,
+    pub pc_mint: Pubkey,
+    pub coin_vault: Pubkey,
+    pub pc_vault: Pubkey,
+    pub vault_signer: Pubkey,
+    pub request_queue: Pubkey,
+    pub event_queue: Pubkey,
+    pub bids: Pubkey,
+    pub asks: Pubkey,
+    pub coin_lot_size: u64,
+    pub pc_lot_size: u64,
+}
+
+impl Market {
+    /// Decodes the `data` of the market account at `address`, owned by
+    /// `dex_program`.
+    pub fn decode(address: Pubkey, dex_program: Pubkey, data: &[u8]) -> Result<Self> {
+        let bytes = data
+            .get(HEAD_PADDING..HEAD_PADDING + size_of::<MarketState>())
+            .ok_or_else(|| anyhow!("account {} isn't a market", address))?;
+        // The data isn't aligned, so it's copied rather than cast.
+        let mut state: MarketState = bytemuck::Zeroable::zeroed();
+        bytemuck::bytes_of_mut(&mut state).copy_from_slice(bytes);
+
+        let vault_signer = gen_vault_signer_key(state.vault_signer_nonce, &address, &dex_program)
+            .map_err(|err| anyhow!("invalid vault signer nonce: {:?}", err))?;
+        Ok(Market {
+            address,
+            dex_program,
+            coin_mint: to_pubkey(state.coin_mint),
+            pc_mint: to_pubkey(state.pc_mint),
+            coin_vault: to_pubkey(state.coin_vault),
+            pc_vault: to_pubkey(state.pc_vault),
+            vault_signer,
+            request_queue: to_pubkey(state.req_q),
+            event_queue: to_pubkey(state.event_q),
+            bids: to_pubkey(state.bids),
+            asks: to_pubkey(state.asks),
+            coin_lot_size: state.coin_lot_size,
+            pc_lot_size: state.pc_lot_size,
+        })
+    }
+}
+
+fn to_pubkey(words: [u64; 4]) -> Pubkey {
+    Pubkey::new_from_array(bytemuck::cast(words))
+}
@@ -0,0 +1,218 @@
+//! Minimal JSON RPC client, covering the methods needed to find the program's
+//! accounts and to send transactions.
+//!
+//! `solana-client` isn't used, as it depends on system libraries (`libudev`,
+//! via the hardware wallet support) that operators shouldn't need to run a
+//! keeper.
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::{json, Value};
+use solana_sdk::hash::Hash;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::Signature;
+use solana_sdk::transaction::Transaction;
+use std::str::FromStr;
+
+/// Filter of `get_program_accounts`, matching `bytes` at `offset` into the
+/// account data.
+pub struct Memcmp {
+    pub offset: usize,
+    pub bytes: Vec<u8>,
+}
+
+/// Logs and inner instructions of a confirmed transaction.
+pub struct TransactionMeta {
+    pub log_messages: Vec<String>,
+    /// Program and data of each instruction invoked by the transaction's
+    /// instructions, in order.
+    pub inner_instructions: Vec<(Pubkey, Vec<u8>)>,
+}
+
+pub struct RpcClient {
+    url: String,
+}
+
+impl RpcClient {
+    pub fn new(url: impl Into<String>) -> Self {
+        RpcClient { url: url.into() }
+    }
+
+    /// Returns the owner and data of an account, or none if it doesn't exist.
+    pub fn get_account(&self, address: &Pubkey) -> Result<Option<(Pubkey, Vec<u8>)>> {
+        let result = self.request(
+            "getAccountInfo",
+            json!([address.to_string(), { "encoding": "base64", "commitment": "confirmed" }]),
+        )?;
+        match &result["value"] {
+            Value::Null => Ok(None),
+            account => {
+                let owner = Pubkey::from_str(account["owner"].as_str().unwrap_or_default())?;
+                Ok(Some((owner, decode_data(account)?)))
+            }
+        }
+    }
+
+    /// Returns the address and data of every account owned by `program_id`
+    /// matching all the `filters`.
+    pub fn get_program_accounts(
+        &self,
+        program_id: &Pubkey,
+        filters: &[Memcmp],
+    ) -> Result<Vec<(Pubkey, Vec<u8>)>> {
+        let filters: Vec<Value> = filters
+            .iter()
+            .map(|filter| {
+                json!({
+                    "memcmp": {
+                        "offset": filter.offset,
+                        "bytes": bs58::encode(&filter.bytes).into_string(),
+                    }
+                })
+            })
+            .collect();
+        let result = self.request(
+            "getProgramAccounts",
+            json!([program_id.to_string(), {
+                "encoding": "base64",
+                "commitment": "confirmed",
+                "filters": filters,
+            }]),
+        )?;
+        result
+            .as_array()
+            .ok_or_else(|| anyhow!("unexpected getProgramAccounts result"))?
+            .iter()
+            .map(|keyed| {
+                let address = Pubkey::from_str(keyed["pubkey"].as_str().unwrap_or_default())?;
+                Ok((address, decode_data(&keyed["account"])?))
+            })
+            .collect()
+    }
+
+    /// Returns the latest blockhash, and the last block height at which a
+    /// transaction using it is valid.
+    pub fn get_latest_blockhash(&self) -> Result<(Hash, u64)> {
+        let result = self.request("getLatestBlockhash", json!([{ "commitment": "confirmed" }]))?;
+        let value = &result["value"];
+        let blockhash = Hash::from_str(value["blockhash"].as_str().unwrap_or_default())
+            .map_err(|err| anyhow!("invalid blockhash: {:?}", err))?;
+        let last_valid_block_height = value["lastValidBlockHeight"]
+            .as_u64()
+            .ok_or_else(|| anyhow!("missing lastValidBlockHeight"))?;
+        Ok((blockhash, last_valid_block_height))
+    }
+
+    pub fn get_block_height(&self) -> Result<u64> {
+        self.request("getBlockHeight", json!([{ "commitment": "confirmed" }]))?
+            .as_u64()
+            .ok_or_else(|| anyhow!("unexpected getBlockHeight result"))
+    }
+
+    /// Sends a signed transaction, after a preflight simulation.
+    pub fn send_transaction(&self, transaction: &Transaction) -> Result<Signature> {
+        let wire = base64::encode(bincode::serialize(transaction)?);
+        let result = self.request(
+            "sendTransaction",
+            json!([wire, { "encoding": "base64", "preflightCommitment": "confirmed" }]),
+        )?;
+        Signature::from_str(result.as_str().unwrap_or_default())
+            .map_err(|err| anyhow!("invalid signature: {:?}", err))
+    }
+
+    /// Returns the status of a transaction once confirmed, i.e., its error
+    /// if it failed, or none while unconfirmed.
+    pub fn get_signature_status(
+        &self,
+        signature: &Signature,
+    ) -> Result<Option<std::result::Result<(), String>>> {
+        let result = self.request("getSignatureStatuses", json!([[signature.to_string()]]))?;
+        let status = &result["value"][0];
+        if status.is_null() {
+            return Ok(None);
+        }
+        match status["confirmationStatus"].as_str() {
+            Some("confirmed") | Some("finalized") => {}
+            _ => return Ok(None),
+        }
+        match &status["err"] {
+            Value::Null => Ok(Some(Ok(()))),
+            err => Ok(Some(Err(err.to_string()))),
+        }
+    }
+
+    /// Returns the logs and inner instructions of a confirmed transaction,
+    /// or none if it isn't found.
+    pub fn get_transaction(&self, signature: &Signature) -> Result<Option<TransactionMeta>> {
+        let result = self.request(
+            "getTransaction",
+            json!([signature.to_string(), {
+                "encoding": "json",
+                "commitment": "confirmed",
+                "maxSupportedTransactionVersion": 0,
+            }]),
+        )?;
+        if result.is_null() {
+            return Ok(None);
+        }
+        let meta = &result["meta"];
+
+        // Inner instructions index into the message's keys, followed by those
+        // loaded from lookup tables.
+        let loaded = &meta["loadedAddresses"];
+        let account_keys = [
+            &result["transaction"]["message"]["accountKeys"],
+            &loaded["writable"],
+            &loaded["readonly"],
+        ]
+        .iter()
+        .filter_map(|keys| keys.as_array())
+        .flatten()
+        .map(|key| Ok(Pubkey::from_str(key.as_str().unwrap_or_default())?))
+        .collect::<Result<Vec<_>>>()?;
+
+        let mut inner_instructions = vec![];
+        for inner in meta["innerInstructions"].as_array().into_iter().flatten() {
+            for ix in inner["instructions"].as_array().into_iter().flatten() {
+                let program_id = ix["programIdIndex"]
+                    .as_u64()
+                    .and_then(|index| account_keys.get(index as usize))
+                    .ok_or_else(|| anyhow!("invalid program index"))?;
+                let data = bs58::decode(ix["data"].as_str().unwrap_or_default()).into_vec()?;
+                inner_instructions.push((*program_id, data));
+            }
+        }
+        let log_messages = meta["logMessages"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|log| log.as_str().map(String::from))
+            .collect();
+        Ok(Some(TransactionMeta {
+            log_messages,
+            inner_instructions,
+        }))
+    }
+
+    fn request(&self, method: &str, params: Value) -> Result<Value> {
+        let response: Value = ureq::post(&self.url)
+            .send_json(json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": method,
+                "params": params,
+            }))
+            .with_context(|| format!("{} request failed", method))?
+            .into_json()?;
+        if let Some(err) = response.get("error") {
+            return Err(anyhow!("{} failed: {}", method, err));
+        }
+        Ok(response["result"].clone())
+    }
+}
+
+fn decode_data(account: &Value) -> Result<Vec<u8>> {
+    let data = account["data"][0]
+        .as_str()
+        .ok_or_else(|| anyhow!("missing account data"))?;
+    Ok(base64::decode(data)?)
+}
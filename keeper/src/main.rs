@@ -0,0 +1,176 @@
+//! Keeper cranking the swap program's scheduled orders.
+//!
+//! Every poll, all DCA schedules and TWAP orders are fetched, and those due
+//! are executed, one transaction each. DCA crank fees are paid into the
+//! keeper's associated token account for the schedule's *from* mint, which
+//! must exist.
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use serum_swap::{Config, Dca, Twap};
+use serum_swap_client::{self as client, Market, RpcClient};
+use solana_sdk::instruction::Instruction;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{Keypair, Signer};
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Parser)]
+#[clap(name = "swap-keeper", about = "Cranks DCA schedules and TWAP orders")]
+struct Opts {
+    /// JSON RPC URL of the cluster.
+    #[clap(long, default_value = "http://127.0.0.1:8899")]
+    url: String,
+    /// Keypair paying for, and signing, the transactions.
+    #[clap(long, default_value = "~/.config/solana/id.json")]
+    keypair: String,
+    /// Compute unit limit of each transaction.
+    #[clap(long, default_value = "400000")]
+    compute_unit_limit: u32,
+    /// Priority fee, in micro-lamports per compute unit.
+    #[clap(long, default_value = "0")]
+    priority_fee: u64,
+    /// Seconds between polls.
+    #[clap(long, default_value = "10")]
+    poll_interval: u64,
+    /// Times a transaction is sent, with a fresh blockhash, before giving up.
+    #[clap(long, default_value = "3")]
+    max_attempts: usize,
+    /// Cranks what's due once, then exits.
+    #[clap(long)]
+    once: bool,
+}
+
+fn main() -> Result<()> {
+    let opts = Opts::parse();
+    let payer = client::read_keypair(&opts.keypair)?;
+    let keeper = Keeper {
+        rpc: RpcClient::new(opts.url.clone()),
+        payer,
+        opts,
+    };
+    loop {
+        if let Err(err) = keeper.crank() {
+            eprintln!("poll failed: {:#}", err);
+        }
+        if keeper.opts.once {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_secs(keeper.opts.poll_interval));
+    }
+}
+
+struct Keeper {
+    rpc: RpcClient,
+    payer: Keypair,
+    opts: Opts,
+}
+
+impl Keeper {
+    // Executes every schedule due. A failure is logged, and doesn't stop
+    // the others from being executed.
+    fn crank(&self) -> Result<()> {
+        let now = now()?;
+        let config = client::fetch_config(&self.rpc)?;
+        let mut markets = HashMap::new();
+
+        for (address, dca) in client::fetch_all::<Dca>(&self.rpc)? {
+            if dca.next_ts > now {
+                continue;
+            }
+            let result = self
+                .market(&mut markets, &dca.market)
+                .and_then(|market| self.execute_dca(&config, &address, &dca, &market));
+            log("DCA", &address, result);
+        }
+
+        for (address, twap) in client::fetch_all::<Twap>(&self.rpc)? {
+            if !is_slice_due(&twap, now) {
+                continue;
+            }
+            let result = self.market(&mut markets, &twap.market).and_then(|market| {
+                let ix = client::execute_twap_slice(&config, &address, &twap, &market);
+                self.send(ix)
+            });
+            log("TWAP", &address, result);
+        }
+        Ok(())
+    }
+
+    fn execute_dca(
+        &self,
+        config: &Config,
+        address: &Pubkey,
+        dca: &Dca,
+        market: &Market,
+    ) -> Result<Option<String>> {
+        let (_, data) = self
+            .rpc
+            .get_account(&dca.input_vault)?
+            .ok_or_else(|| anyhow!("input vault not found"))?;
+        let (input_mint, balance) = client::decode_token_account(&data)?;
+        if balance <= dca.crank_fee {
+            return Ok(None);
+        }
+        let cranker_wallet = client::associated_token_address(&self.payer.pubkey(), &input_mint);
+        if self.rpc.get_account(&cranker_wallet)?.is_none() {
+            return Err(anyhow!(
+                "no token account {} to receive the crank fee",
+                cranker_wallet
+            ));
+        }
+        self.send(client::execute_dca(
+            config,
+            address,
+            dca,
+            market,
+            &cranker_wallet,
+        ))
+    }
+
+    // Sends the instruction with the configured compute budget, returning
+    // the signature of the confirmed transaction.
+    fn send(&self, ix: Instruction) -> Result<Option<String>> {
+        let mut instructions = vec![client::set_compute_unit_limit(self.opts.compute_unit_limit)];
+        if self.opts.priority_fee > 0 {
+            instructions.push(client::set_compute_unit_price(self.opts.priority_fee));
+        }
+        instructions.push(ix);
+        let signature =
+            self.rpc
+                .send_and_confirm(&instructions, &[&self.payer], self.opts.max_attempts)?;
+        Ok(Some(signature.to_string()))
+    }
+
+    // Returns the market at `address`, fetched once per poll.
+    fn market(&self, markets: &mut HashMap<Pubkey, Market>, address: &Pubkey) -> Result<Market> {
+        if let Some(market) = markets.get(address) {
+            return Ok(market.clone());
+        }
+        let market = client::fetch_market(&self.rpc, address)?;
+        markets.insert(*address, market.clone());
+        Ok(market)
+    }
+}
+
+fn is_slice_due(twap: &Twap, now: i64) -> bool {
+    twap.slices_executed < twap.num_slices
+        && twap
+            .slice_interval
+            .checked_mul(twap.slices_executed.into())
+            .and_then(|offset| offset.checked_add(twap.start_ts))
+            .is_some_and(|slice_ts| slice_ts <= now)
+}
+
+fn log(kind: &str, address: &Pubkey, result: Result<Option<String>>) {
+    match result {
+        Ok(Some(signature)) => println!("{} {} executed: {}", kind, address, signature),
+        Ok(None) => println!("{} {} exhausted, skipped", kind, address),
+        Err(err) => eprintln!("{} {} failed: {:#}", kind, address, err),
+    }
+}
+
+fn now() -> Result<i64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64)
+}
@@ -0,0 +1,43 @@
+//! Mock flash swap callback, for testing the swap program's `flash_swap`.
+//!
+//! It's invoked with the swap program's `FlashSwapCallback` arguments and,
+//! if given a source token account, its owner and the token program, repays
+//! the flash swap by transferring the *from* token owed out of the source.
+//! Given no accounts, it doesn't repay it.
+
+use anchor_lang::prelude::*;
+use anchor_spl::token;
+use serum_swap::Side;
+
+declare_id!("6oEuryxKEYqfp8xWZfoTYWD87hRGzJmfEZcfvpREeiRY");
+
+#[program]
+pub mod flash_callback {
+    use super::*;
+
+    /// Transfers `from_amount` to the order payer, given the remaining
+    /// accounts `[source, order_payer, owner, token_program]`.
+    pub fn flash_swap_callback<'info>(
+        ctx: Context<'_, '_, '_, 'info, FlashSwapCallback>,
+        _authority: Pubkey,
+        _side: Side,
+        _amount: u64,
+        from_amount: u64,
+    ) -> ProgramResult {
+        if let [source, order_payer, owner, token_program] = ctx.remaining_accounts {
+            let cpi_ctx = CpiContext::new(
+                token_program.clone(),
+                token::Transfer {
+                    from: source.clone(),
+                    to: order_payer.clone(),
+                    authority: owner.clone(),
+                },
+            );
+            token::transfer(cpi_ctx, from_amount)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct FlashSwapCallback {}
@@ -0,0 +1,40 @@
+//! Mock Pyth price program, for testing the swap program's oracle bands.
+//!
+//! It writes the fields of a Pyth v2 price account read by the swap program
+//! into an account it owns, so that tests can set the oracle price.
+
+use anchor_lang::prelude::*;
+
+declare_id!("GRQY7mj2TYxRhDxdFutxBrpJ7orpdJD2PT1Xnmy5E5m7");
+
+const MAGIC: u32 = 0xa1b2_c3d4;
+const VERSION_2: u32 = 2;
+const ACCOUNT_TYPE_PRICE: u32 = 3;
+const STATUS_TRADING: u32 = 1;
+
+#[program]
+pub mod mock_oracle {
+    use super::*;
+
+    /// Sets the aggregate price of the price account to `price * 10^expo`,
+    /// trading, as published `age` slots ago.
+    pub fn set_price(ctx: Context<SetPrice>, price: i64, expo: i32, age: u64) -> ProgramResult {
+        let pub_slot = Clock::get()?.slot.saturating_sub(age);
+        let mut data = ctx.accounts.price_account.try_borrow_mut_data()?;
+        data[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        data[4..8].copy_from_slice(&VERSION_2.to_le_bytes());
+        data[8..12].copy_from_slice(&ACCOUNT_TYPE_PRICE.to_le_bytes());
+        data[20..24].copy_from_slice(&expo.to_le_bytes());
+        data[208..216].copy_from_slice(&price.to_le_bytes());
+        data[224..228].copy_from_slice(&STATUS_TRADING.to_le_bytes());
+        data[232..240].copy_from_slice(&pub_slot.to_le_bytes());
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct SetPrice<'info> {
+    // At least 240 bytes, owned by this program.
+    #[account(mut, owner = *program_id)]
+    price_account: AccountInfo<'info>,
+}
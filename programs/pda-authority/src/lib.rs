@@ -0,0 +1,193 @@
+//! Example program composing with the swap program, where the open orders
+//! accounts and token accounts are owned by a PDA of this program rather than
+//! by a wallet. The PDA signs each CPI into the swap program via
+//! `invoke_signed`.
+//!
+//! Anchor doesn't allow nesting another crate's `Accounts` structs, so the
+//! market accounts are mirrored here and translated into the swap program's
+//! `cpi::accounts` structs.
+
+use anchor_lang::prelude::*;
+use serum_swap::cpi::accounts as swap_accounts;
+use serum_swap::program::SerumSwap;
+use serum_swap::{ExchangeRate, Side};
+
+declare_id!("Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnS");
+
+/// Seed of the PDA acting as the swap authority.
+pub const AUTHORITY_SEED: &[u8] = b"authority";
+
+#[program]
+pub mod pda_authority {
+    use super::*;
+
+    /// Initializes an open orders account owned by the PDA authority. The
+    /// remaining accounts are forwarded, e.g., a permissioned market's
+    /// authority.
+    pub fn init_account<'info>(
+        ctx: Context<'_, '_, '_, 'info, InitAccount<'info>>,
+        bump: u8,
+    ) -> ProgramResult {
+        let seeds = &[AUTHORITY_SEED, &[bump]];
+        let signer = &[&seeds[..]];
+        let cpi_ctx = CpiContext::new_with_signer(
+            ctx.accounts.swap_program.to_account_info(),
+            swap_accounts::InitAccount {
+                open_orders: ctx.accounts.open_orders.clone(),
+                authority: ctx.accounts.authority.clone(),
+                payer: ctx.accounts.payer.clone(),
+                market: ctx.accounts.market.clone(),
+                dex_program: ctx.accounts.dex_program.clone(),
+                rent: ctx.accounts.rent.clone(),
+                system_program: ctx.accounts.system_program.clone(),
+                event_cpi: (&ctx.accounts.event_cpi).into(),
+            },
+            signer,
+        )
+        .with_remaining_accounts(ctx.remaining_accounts.to_vec());
+        serum_swap::cpi::init_account(cpi_ctx)
+    }
+
+    /// Swaps the PDA authority's tokens on a single market.
+    pub fn swap<'info>(
+        ctx: Context<'_, '_, '_, 'info, Swap<'info>>,
+        bump: u8,
+        side: Side,
+        amount: u64,
+        min_exchange_rate: ExchangeRate,
+    ) -> ProgramResult {
+        let seeds = &[AUTHORITY_SEED, &[bump]];
+        let signer = &[&seeds[..]];
+        let cpi_ctx = CpiContext::new_with_signer(
+            ctx.accounts.swap_program.to_account_info(),
+            swap_accounts::Swap {
+                market: (&ctx.accounts.market).into(),
+                authority: ctx.accounts.authority.clone(),
+                pc_wallet: ctx.accounts.pc_wallet.clone(),
+                config: ctx.accounts.config.clone(),
+                whitelist: ctx.accounts.whitelist.clone(),
+                user_stats: ctx.accounts.user_stats.clone(),
+                spending_policy: ctx.accounts.spending_policy.clone(),
+                fee_vault: ctx.accounts.fee_vault.clone(),
+                platform_fee_account: ctx.accounts.platform_fee_account.clone(),
+                referral: ctx.accounts.referral.clone(),
+                srm_msrm_discount: ctx.accounts.srm_msrm_discount.clone(),
+                dex_program: ctx.accounts.dex_program.clone(),
+                token_program: ctx.accounts.token_program.clone(),
+                rent: ctx.accounts.rent.clone(),
+                event_cpi: (&ctx.accounts.event_cpi).into(),
+            },
+            signer,
+        );
+        serum_swap::cpi::swap(cpi_ctx, side, amount, min_exchange_rate)
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(bump: u8)]
+pub struct InitAccount<'info> {
+    #[account(mut)]
+    open_orders: AccountInfo<'info>,
+    #[account(seeds = [AUTHORITY_SEED], bump = bump)]
+    authority: AccountInfo<'info>,
+    #[account(mut, signer)]
+    payer: AccountInfo<'info>,
+    market: AccountInfo<'info>,
+    dex_program: AccountInfo<'info>,
+    rent: AccountInfo<'info>,
+    system_program: AccountInfo<'info>,
+    event_cpi: EventCpi<'info>,
+    swap_program: Program<'info, SerumSwap>,
+}
+
+#[derive(Accounts)]
+#[instruction(bump: u8)]
+pub struct Swap<'info> {
+    market: MarketAccounts<'info>,
+    #[account(seeds = [AUTHORITY_SEED], bump = bump)]
+    authority: AccountInfo<'info>,
+    #[account(mut)]
+    pc_wallet: AccountInfo<'info>,
+    config: AccountInfo<'info>,
+    whitelist: AccountInfo<'info>,
+    user_stats: AccountInfo<'info>,
+    #[account(mut)]
+    spending_policy: AccountInfo<'info>,
+    #[account(mut)]
+    fee_vault: AccountInfo<'info>,
+    #[account(mut)]
+    platform_fee_account: AccountInfo<'info>,
+    referral: AccountInfo<'info>,
+    srm_msrm_discount: AccountInfo<'info>,
+    dex_program: AccountInfo<'info>,
+    token_program: AccountInfo<'info>,
+    rent: AccountInfo<'info>,
+    event_cpi: EventCpi<'info>,
+    swap_program: Program<'info, SerumSwap>,
+}
+
+// Mirror of the swap program's `MarketAccounts`. All checks are done by the
+// swap program on CPI.
+#[derive(Accounts)]
+pub struct MarketAccounts<'info> {
+    #[account(mut)]
+    market: AccountInfo<'info>,
+    #[account(mut)]
+    open_orders: AccountInfo<'info>,
+    #[account(mut)]
+    request_queue: AccountInfo<'info>,
+    #[account(mut)]
+    event_queue: AccountInfo<'info>,
+    #[account(mut)]
+    bids: AccountInfo<'info>,
+    #[account(mut)]
+    asks: AccountInfo<'info>,
+    #[account(mut)]
+    order_payer_token_account: AccountInfo<'info>,
+    #[account(mut)]
+    coin_vault: AccountInfo<'info>,
+    #[account(mut)]
+    pc_vault: AccountInfo<'info>,
+    vault_signer: AccountInfo<'info>,
+    #[account(mut)]
+    coin_wallet: AccountInfo<'info>,
+    #[account(mut)]
+    circuit_breaker: AccountInfo<'info>,
+    market_stats: AccountInfo<'info>,
+}
+
+impl<'info> From<&MarketAccounts<'info>> for swap_accounts::MarketAccounts<'info> {
+    fn from(accs: &MarketAccounts<'info>) -> swap_accounts::MarketAccounts<'info> {
+        swap_accounts::MarketAccounts {
+            market: accs.market.clone(),
+            open_orders: accs.open_orders.clone(),
+            request_queue: accs.request_queue.clone(),
+            event_queue: accs.event_queue.clone(),
+            bids: accs.bids.clone(),
+            asks: accs.asks.clone(),
+            order_payer_token_account: accs.order_payer_token_account.clone(),
+            coin_vault: accs.coin_vault.clone(),
+            pc_vault: accs.pc_vault.clone(),
+            vault_signer: accs.vault_signer.clone(),
+            coin_wallet: accs.coin_wallet.clone(),
+            circuit_breaker: accs.circuit_breaker.clone(),
+            market_stats: accs.market_stats.clone(),
+        }
+    }
+}
+
+// Mirror of the swap program's `EventCpi`.
+#[derive(Accounts)]
+pub struct EventCpi<'info> {
+    event_authority: AccountInfo<'info>,
+    program: AccountInfo<'info>,
+}
+
+impl<'info> From<&EventCpi<'info>> for swap_accounts::EventCpi<'info> {
+    fn from(accs: &EventCpi<'info>) -> swap_accounts::EventCpi<'info> {
+        swap_accounts::EventCpi {
+            event_authority: accs.event_authority.clone(),
+            program: accs.program.clone(),
+        }
+    }
+}
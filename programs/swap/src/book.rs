@@ -0,0 +1,221 @@
+//! Minimal reader for the DEX's orderbook, used to bound the price impact of a
+//! trade before it's sent, to size an order receiving an exact amount, or to
+//! snapshot the top of the book an order is sent against.
+//!
+//! The slab's nodes aren't public in `serum_dex`, so only the fields needed
+//! here are read, directly from the account data. The caller checks the account
+//! is the market's bids or asks.
+
+use crate::{Result, BPS_DENOMINATOR};
+use anchor_lang::prelude::*;
+use std::convert::TryInto;
+
+// Every DEX account starts with b"serum" and ends with b"padding".
+const HEAD_PADDING: usize = 5;
+const TAIL_PADDING: usize = 7;
+
+// Byte offsets into the orderbook, after the account flags.
+const ROOT_NODE_OFFSET: usize = HEAD_PADDING + 8 + 20;
+const LEAF_COUNT_OFFSET: usize = HEAD_PADDING + 8 + 24;
+const NODES_OFFSET: usize = HEAD_PADDING + 8 + 32;
+
+// Layout of a node.
+const NODE_SIZE: usize = 72;
+const NODE_CHILDREN_OFFSET: usize = 24;
+const LEAF_PRICE_OFFSET: usize = 16;
+const LEAF_QUANTITY_OFFSET: usize = 56;
+
+const NODE_TAG_INNER: u32 = 1;
+const NODE_TAG_LEAF: u32 = 2;
+
+/// Expected fills of a taker order against one side of the book.
+#[derive(Default)]
+pub struct Fills {
+    /// Price of the best order, in lots.
+    pub best_price: u64,
+    /// Base currency filled, in lots.
+    pub coin_lots: u64,
+    /// Quote currency filled, in lots, i.e., the sum of price times quantity.
+    pub pc_lots: u128,
+}
+
+impl Fills {
+    /// Returns the difference between the average fill price and the best
+    /// price, in basis points of the best price, rounded up.
+    pub fn price_impact_bps(&self) -> u64 {
+        if self.coin_lots == 0 {
+            return 0;
+        }
+        let best = u128::from(self.best_price)
+            .checked_mul(self.coin_lots.into())
+            .unwrap();
+        // `abs_diff` is too recent for the BPF toolchain.
+        #[allow(clippy::manual_abs_diff)]
+        let deviation = if self.pc_lots > best {
+            self.pc_lots - best
+        } else {
+            best - self.pc_lots
+        };
+        let impact = deviation
+            .checked_mul(BPS_DENOMINATOR.into())
+            .unwrap()
+            .checked_add(best.checked_sub(1).unwrap())
+            .unwrap()
+            .checked_div(best)
+            .unwrap();
+        impact.try_into().unwrap_or(u64::MAX)
+    }
+}
+
+/// Walks the `asks` from the lowest price up, filling a buy order spending up
+/// to `max_pc_lots`.
+pub fn fill_asks(asks: &AccountInfo, max_pc_lots: u64) -> Result<Fills> {
+    let max_pc_lots = u128::from(max_pc_lots);
+    walk(asks, false, |fills, price, quantity| {
+        let remaining = max_pc_lots.checked_sub(fills.pc_lots).unwrap();
+        let lots = quantity.min(
+            (remaining / u128::from(price))
+                .try_into()
+                .unwrap_or(u64::MAX),
+        );
+        if lots == 0 {
+            return false;
+        }
+        fills.coin_lots = fills.coin_lots.checked_add(lots).unwrap();
+        fills.pc_lots = fills
+            .pc_lots
+            .checked_add(u128::from(price).checked_mul(lots.into()).unwrap())
+            .unwrap();
+        lots == quantity
+    })
+}
+
+/// Walks the `bids` from the highest price down, filling a sell order of up to
+/// `max_coin_lots`.
+pub fn fill_bids(bids: &AccountInfo, max_coin_lots: u64) -> Result<Fills> {
+    walk(bids, true, |fills, price, quantity| {
+        let remaining = max_coin_lots.checked_sub(fills.coin_lots).unwrap();
+        let lots = quantity.min(remaining);
+        if lots == 0 {
+            return false;
+        }
+        fills.coin_lots = fills.coin_lots.checked_add(lots).unwrap();
+        fills.pc_lots = fills
+            .pc_lots
+            .checked_add(u128::from(price).checked_mul(lots.into()).unwrap())
+            .unwrap();
+        lots == quantity
+    })
+}
+
+/// Walks the `asks` from the lowest price up, filling a buy order receiving up
+/// to `coin_lots`.
+pub fn fill_asks_to(asks: &AccountInfo, coin_lots: u64) -> Result<Fills> {
+    walk(asks, false, |fills, price, quantity| {
+        let remaining = coin_lots.checked_sub(fills.coin_lots).unwrap();
+        let lots = quantity.min(remaining);
+        if lots == 0 {
+            return false;
+        }
+        fills.coin_lots = fills.coin_lots.checked_add(lots).unwrap();
+        fills.pc_lots = fills
+            .pc_lots
+            .checked_add(u128::from(price).checked_mul(lots.into()).unwrap())
+            .unwrap();
+        lots == quantity
+    })
+}
+
+/// Walks the `bids` from the highest price down, filling a sell order until
+/// it receives at least `pc_lots`.
+pub fn fill_bids_to(bids: &AccountInfo, pc_lots: u64) -> Result<Fills> {
+    let pc_lots = u128::from(pc_lots);
+    walk(bids, true, |fills, price, quantity| {
+        let remaining = pc_lots.saturating_sub(fills.pc_lots);
+        let lots = quantity.min(
+            remaining
+                .checked_add(u128::from(price) - 1)
+                .unwrap()
+                .checked_div(u128::from(price))
+                .unwrap()
+                .try_into()
+                .unwrap_or(u64::MAX),
+        );
+        if lots == 0 {
+            return false;
+        }
+        fills.coin_lots = fills.coin_lots.checked_add(lots).unwrap();
+        fills.pc_lots = fills
+            .pc_lots
+            .checked_add(u128::from(price).checked_mul(lots.into()).unwrap())
+            .unwrap();
+        lots == quantity
+    })
+}
+
+/// Returns the price of the highest bid, in lots, or zero if there's none.
+pub fn best_bid(bids: &AccountInfo) -> Result<u64> {
+    Ok(walk(bids, true, |_, _, _| false)?.best_price)
+}
+
+/// Returns the price of the lowest ask, in lots, or zero if there's none.
+pub fn best_ask(asks: &AccountInfo) -> Result<u64> {
+    Ok(walk(asks, false, |_, _, _| false)?.best_price)
+}
+
+// Visits the orders of the slab in price order, best first, until `fill`
+// returns false.
+fn walk(
+    slab: &AccountInfo,
+    descending: bool,
+    mut fill: impl FnMut(&mut Fills, u64, u64) -> bool,
+) -> Result<Fills> {
+    let data = slab.try_borrow_data()?;
+    let capacity = (data.len() - NODES_OFFSET - TAIL_PADDING) / NODE_SIZE;
+    let mut fills = Fills::default();
+    if read_u64(&data, LEAF_COUNT_OFFSET) == 0 {
+        return Ok(fills);
+    }
+
+    // Children of inner nodes are pushed best last, so they're popped first.
+    let mut stack = vec![read_u32(&data, ROOT_NODE_OFFSET) as usize];
+    while let Some(handle) = stack.pop() {
+        if handle >= capacity {
+            break;
+        }
+        let node = NODES_OFFSET + handle * NODE_SIZE;
+        match read_u32(&data, node) {
+            NODE_TAG_INNER => {
+                let left = read_u32(&data, node + NODE_CHILDREN_OFFSET) as usize;
+                let right = read_u32(&data, node + NODE_CHILDREN_OFFSET + 4) as usize;
+                if descending {
+                    stack.push(left);
+                    stack.push(right);
+                } else {
+                    stack.push(right);
+                    stack.push(left);
+                }
+            }
+            NODE_TAG_LEAF => {
+                let price = read_u64(&data, node + LEAF_PRICE_OFFSET);
+                let quantity = read_u64(&data, node + LEAF_QUANTITY_OFFSET);
+                if fills.coin_lots == 0 {
+                    fills.best_price = price;
+                }
+                if !fill(&mut fills, price, quantity) {
+                    break;
+                }
+            }
+            _ => break,
+        }
+    }
+    Ok(fills)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
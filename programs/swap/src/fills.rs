@@ -0,0 +1,97 @@
+//! Minimal reader for the DEX's event queue, used to report the fees paid by a
+//! trade, and to check the queue has room for its fills.
+//!
+//! Only the fields needed here are read, directly from the account data. The
+//! event queue is checked by the DEX on CPI, and is only read after it, unless
+//! the caller checks it's the market's.
+
+use crate::Result;
+use anchor_lang::prelude::*;
+use std::convert::TryInto;
+
+// Every DEX account starts with b"serum" and ends with b"padding".
+const HEAD_PADDING: usize = 5;
+const TAIL_PADDING: usize = 7;
+
+// Byte offsets into the event queue.
+const HEAD_OFFSET: usize = HEAD_PADDING + 8;
+const COUNT_OFFSET: usize = HEAD_PADDING + 16;
+const SEQ_NUM_OFFSET: usize = HEAD_PADDING + 24;
+const EVENTS_OFFSET: usize = HEAD_PADDING + 32;
+
+// Layout of an event.
+const EVENT_SIZE: usize = 88;
+const EVENT_FEE_OFFSET: usize = 24;
+const EVENT_OWNER_OFFSET: usize = 48;
+
+const EVENT_FLAG_FILL: u8 = 0x1;
+const EVENT_FLAG_MAKER: u8 = 0x8;
+
+// The DEX rebates a fifth of each taker fee to the referrer.
+const REFERRER_REBATE_DIVISOR: u64 = 5;
+
+/// Returns the number of events ever pushed to the event queue.
+pub fn seq_num(event_queue: &AccountInfo) -> Result<u64> {
+    let data = event_queue.try_borrow_data()?;
+    Ok(read_u64(&data, SEQ_NUM_OFFSET))
+}
+
+/// Returns the number of events in the event queue, and its capacity.
+pub fn queue_len(event_queue: &AccountInfo) -> Result<(u64, u64)> {
+    let data = event_queue.try_borrow_data()?;
+    let capacity = (data.len() - EVENTS_OFFSET - TAIL_PADDING) / EVENT_SIZE;
+    Ok((read_u64(&data, COUNT_OFFSET), capacity as u64))
+}
+
+/// Taker fees paid by an open orders account.
+#[derive(Default)]
+pub struct TakerFees {
+    /// Total fees, in native units of the quote currency.
+    pub fees: u64,
+    /// Share of the fees rebated to a referral account. The DEX accrues it to
+    /// the open orders account when consuming the fill events, and pays it out
+    /// on settlement.
+    pub referrer_rebates: u64,
+}
+
+/// Returns the taker fees paid by `open_orders` in the fills pushed to the
+/// event queue since its sequence number was `seq_num_before`.
+pub fn taker_fees(
+    event_queue: &AccountInfo,
+    open_orders: &Pubkey,
+    seq_num_before: u64,
+) -> Result<TakerFees> {
+    let data = event_queue.try_borrow_data()?;
+    let capacity = (data.len() - EVENTS_OFFSET - TAIL_PADDING) / EVENT_SIZE;
+    let head = read_u64(&data, HEAD_OFFSET) as usize;
+    let count = read_u64(&data, COUNT_OFFSET) as usize;
+    let pushed = read_u64(&data, SEQ_NUM_OFFSET)
+        .checked_sub(seq_num_before)
+        .unwrap() as usize;
+
+    // The events pushed are the last ones in the queue, unless already
+    // consumed.
+    let mut taker_fees = TakerFees::default();
+    for k in count.saturating_sub(pushed)..count {
+        let event = EVENTS_OFFSET + ((head + k) % capacity) * EVENT_SIZE;
+        let flags = data[event];
+        if flags & EVENT_FLAG_FILL == 0 || flags & EVENT_FLAG_MAKER != 0 {
+            continue;
+        }
+        let owner = &data[event + EVENT_OWNER_OFFSET..event + EVENT_OWNER_OFFSET + 32];
+        if owner != open_orders.as_ref() {
+            continue;
+        }
+        let fee = read_u64(&data, event + EVENT_FEE_OFFSET);
+        taker_fees.fees = taker_fees.fees.checked_add(fee).unwrap();
+        taker_fees.referrer_rebates = taker_fees
+            .referrer_rebates
+            .checked_add(fee / REFERRER_REBATE_DIVISOR)
+            .unwrap();
+    }
+    Ok(taker_fees)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
@@ -0,0 +1,135 @@
+//! Hooks let programs whitelisted in the config build on swaps, e.g., to
+//! unlock funds before the order and record the result after settlement.
+//! Whitelisted programs can also be sent a deposit of the tokens received by
+//! `swap_and_deposit`, or be the callback of a `flash_swap`.
+//!
+//! A hook program is invoked twice per swap, with the accounts given for it,
+//! keeping their signer and writable flags. The instruction data is the
+//! Anchor discriminator of the instruction, i.e., of `global:pre_swap` or
+//! `global:post_swap`, followed by the serialized `PreSwap` or `PostSwap`
+//! arguments, so Anchor programs can implement the hooks as instructions
+//! taking the same arguments. The callback of a flash swap is invoked once,
+//! the same way, as `global:flash_swap_callback` with `FlashSwapCallback`.
+
+use crate::{Config, ErrorCode, Result, Side};
+use anchor_lang::prelude::*;
+use solana_program::hash::hash;
+use solana_program::instruction::Instruction;
+use solana_program::program::invoke;
+
+/// Arguments of the hook invoked before the order is sent.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct PreSwap {
+    pub authority: Pubkey,
+    pub side: Side,
+    // Amount of the *from* token to swap.
+    pub amount: u64,
+}
+
+/// Arguments of the hook invoked after the swap settled and passed its risk
+/// checks.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct PostSwap {
+    pub authority: Pubkey,
+    pub side: Side,
+    // Amount of the *from* token sold.
+    pub from_amount: u64,
+    // Amount of the *to* token received, net of fees.
+    pub to_amount: u64,
+}
+
+/// Arguments of the callback of a flash swap, invoked once its output is
+/// delivered and before its input is collected.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct FlashSwapCallback {
+    pub authority: Pubkey,
+    pub side: Side,
+    // Amount of the *to* token delivered.
+    pub amount: u64,
+    // Amount of the *from* token the order payer must hold once the callback
+    // returns.
+    pub from_amount: u64,
+}
+
+pub struct Hook<'info> {
+    program: AccountInfo<'info>,
+    accounts: Vec<AccountInfo<'info>>,
+}
+
+impl<'info> Hook<'info> {
+    /// Takes the hook program, followed by the `num_accounts` accounts passed
+    /// to it, off the front of `accounts`.
+    pub fn new(
+        accounts: &mut &[AccountInfo<'info>],
+        num_accounts: u8,
+        config: &Config,
+    ) -> Result<Self> {
+        let num_accounts = usize::from(num_accounts);
+        if accounts.len() < num_accounts + 1 {
+            return Err(ErrorCode::InvalidHook.into());
+        }
+        let program = accounts[0].clone();
+        if !program.executable || !config.hook_programs.contains(program.key) {
+            return Err(ErrorCode::HookProgramNotWhitelisted.into());
+        }
+        let hook_accounts = accounts[1..num_accounts + 1].to_vec();
+        *accounts = &accounts[num_accounts + 1..];
+        Ok(Hook {
+            program,
+            accounts: hook_accounts,
+        })
+    }
+
+    pub fn pre_swap(&self, args: PreSwap) -> Result<()> {
+        self.invoke("pre_swap", args)
+    }
+
+    pub fn post_swap(&self, args: PostSwap) -> Result<()> {
+        self.invoke("post_swap", args)
+    }
+
+    pub fn flash_swap_callback(&self, args: FlashSwapCallback) -> Result<()> {
+        self.invoke("flash_swap_callback", args)
+    }
+
+    pub fn program_id(&self) -> Pubkey {
+        *self.program.key
+    }
+
+    /// Sends the client's deposit instruction `data`, with `amount` written
+    /// at `amount_offset`.
+    pub fn deposit(&self, mut data: Vec<u8>, amount_offset: u16, amount: u64) -> Result<()> {
+        let offset = usize::from(amount_offset);
+        if offset + 8 > data.len() {
+            return Err(ErrorCode::InvalidDepositAmountOffset.into());
+        }
+        data[offset..offset + 8].copy_from_slice(&amount.to_le_bytes());
+        self.invoke_raw(data)
+    }
+
+    fn invoke<T: AnchorSerialize>(&self, name: &str, args: T) -> Result<()> {
+        let mut data = hash(format!("global:{}", name).as_bytes()).to_bytes()[..8].to_vec();
+        args.serialize(&mut data).unwrap();
+        self.invoke_raw(data)
+    }
+
+    fn invoke_raw(&self, data: Vec<u8>) -> Result<()> {
+        let ix = Instruction {
+            program_id: *self.program.key,
+            accounts: self
+                .accounts
+                .iter()
+                .map(|account| AccountMeta {
+                    pubkey: *account.key,
+                    is_signer: account.is_signer,
+                    is_writable: account.is_writable,
+                })
+                .collect(),
+            data,
+        };
+        let mut account_infos = self.accounts.clone();
+        account_infos.push(self.program.clone());
+        invoke(&ix, &account_infos)?;
+        Ok(())
+    }
+}
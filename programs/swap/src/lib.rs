@@ -6,18 +6,296 @@
 //! as the user's first trade or via the explicit `init_account` and
 //! `close_account` instructions provided here, which can be included in
 //! transactions.
+//!
+//! An open orders account with orders resting on the book, e.g., placed from
+//! another UI, can't be closed until they're cancelled, which
+//! `cancel_all_and_settle` does in one shot, settling the funds and, if
+//! possible, closing the account.
+//!
+//! The wallets of a swap must hold the market's base and quote mints, which
+//! is checked before its first order on the market. Their usual choice, the
+//! authority's associated token accounts for the mints, may be created if
+//! needed via `init_swap_wallets`.
+//!
+//! The `authority` of a swap is usually the owner of the token accounts being
+//! traded. It may instead be a delegate approved on the order payer token
+//! account, in which case every order is bounded by the delegated amount and
+//! all wallets used must be owned by the order payer's owner.
+//!
+//! The `authority` may also be a PDA of another program, signing via
+//! `invoke_signed`. Such programs should depend on this crate with the `cpi`
+//! feature and pass their signer seeds with `CpiContext::new_with_signer` to
+//! the generated `cpi` module. See `programs/pda-authority` for an example.
+//!
+//! Swaps skim the protocol fee set by the admin via `set_fee` off their
+//! output, into the fee vault of the output mint, created via
+//! `create_fee_vault`. While there's no fee, the vault isn't read, so this
+//! program's ID may be passed instead.
+//!
+//! The admin may also restrict swaps to the markets of a whitelist, created
+//! via `create_whitelist`. While it's disabled, this program's ID may be
+//! passed as the `whitelist` account.
+//!
+//! The referral and (M)SRM fee discount accounts of `swap` and
+//! `swap_transitive`, and the instructions sharing their accounts, are named
+//! accounts, given as this program's ID for none. Other swaps take the
+//! optional referral account as their last remaining account.
+//!
+//! The DEX only accepts a fee discount account owned by the open orders
+//! authority, so there can't be a single pool shared by the swaps of every
+//! user. Swaps authorized by a permit, a DCA schedule or a TWAP order, whose
+//! authority is a PDA of this program, may instead be given a fee discount
+//! account owned by that PDA, which anyone, e.g., the deployer, may fund.
+//!
+//! The referral account of a swap may be followed by a `Referrer` account,
+//! as a remaining account, registered via `register_referrer`, in which case
+//! the referral account must be the referrer's vault and every rebate settled
+//! into it is credited to the referrer, who can later claim it via
+//! `claim_referral_fees`. A referrer may share its rebates via
+//! `set_referral_splits`, in which case the recipients' token accounts must
+//! follow the referrer, in order, and each swap pays out their shares.
+//!
+//! Every group of market accounts ends with the market's circuit breaker
+//! PDA. Once the admin creates it via `create_circuit_breaker`, it caps the
+//! notional volume swapped on the market in a rolling window of slots. Until
+//! then, the (uninitialized) PDA must still be given, but isn't enforced.
+//! While the admin has created no breaker at all, this program's ID may be
+//! passed instead.
+//!
+//! Every group of market accounts also ends with an optional `MarketStats`
+//! account, created by anyone via `init_market_stats`, which swaps update
+//! with the market's last price and cumulative volume. Pass this program's ID
+//! to skip it.
+//!
+//! Swaps update the authority's `UserStats` PDA, created via
+//! `init_user_stats`, with its lifetime volume per quote mint. Tracking is
+//! opt-in: pass this program's ID as the `user_stats` account to skip it.
+//!
+//! The owner of the funds an authority swaps, e.g., a DAO treasury delegating
+//! to a bot, may limit its swaps via a `SpendingPolicy` PDA, created via
+//! `create_spending_policy`, capping the notional of each swap and of a
+//! rolling 24 hour window, per quote mint. Every swap takes the policy's PDA
+//! for the owner of its order payer and its authority as the
+//! `spending_policy` account, and if it exists, fails if it exceeds the
+//! limits, or is quoted in a mint without one. An owner swapping their own
+//! funds may pass this program's ID instead, skipping its derivation.
+//!
+//! A `swap_v2` may be proposed via `propose_swap`, which stores its arguments
+//! and the keys of its accounts in a `SwapProposal` PDA. The authority then
+//! executes it via `execute_swap_proposal` once its timelock has passed, with
+//! the exact accounts proposed, so that, e.g., the members of a multisig
+//! approve the trade itself rather than an opaque transaction.
+//!
+//! To keep a large swap from being sandwiched, its authority may first commit
+//! to a hash of it via `commit_swap`, then reveal and execute it in a later
+//! slot via `reveal_and_swap`, once it can no longer be front-run within the
+//! block it was sent in.
+//!
+//! Swaps may also be authorized by a `Permit` signed off-chain, e.g., by a
+//! custodied key, and submitted by a relayer via `swap_with_permit`, right
+//! after an ed25519 program instruction verifying the signature. The swap's
+//! authority is then the signer's `PermitAuthority` PDA, created via
+//! `create_permit_authority`, which must own an open orders account on the
+//! market, created via `init_permit_open_orders`, and be approved as the
+//! delegate of the signer's order payer token account.
+//!
+//! A `flash_swap` delivers its output before collecting its input, invoking a
+//! whitelisted callback program in between, which must leave the input owed
+//! in the caller's wallet, e.g., after a liquidation. The output is lent by
+//! the flash vault of its mint, funded by the admin, which the swap then
+//! repays. The protocol fees collected are never lent.
+//!
+//! Merchants may be paid an exact amount in any token via `pay_with_swap`,
+//! which swaps just enough of the payer's token, refunding the rest, and
+//! includes a caller-supplied reference key in its accounts and event to
+//! identify the payment, Solana Pay style.
+//!
+//! The admin may tune how every order is executed on the DEX, i.e., its
+//! matching limit and self-trade behavior, a bound on the price impact of
+//! direct swaps and the DEX programs allowed, via `set_execution_defaults`,
+//! stored in the config. Until then, the program's own defaults apply.
+//!
+//! Anyone may crank the event queue of a market via `consume_events`, e.g.,
+//! ahead of their swaps in the same transaction, for a tip per event consumed
+//! out of a vault funded by the admin, created via `create_crank_tip`. Cranks
+//! below a minimum batch aren't tipped, so that cranking the events of one's
+//! own trades one at a time doesn't pay.
+//!
+//! Programs whitelisted by the admin via `add_hook_program` can be passed with
+//! a `swap` as a hook, invoked before the order and after settlement. See the
+//! `hook` module for the interface.
+//!
+//! Recurring swaps (DCA) are created via `create_dca`, which holds the deposit
+//! in vaults owned by the schedule PDA. Anyone may crank them via
+//! `execute_dca` once each interval elapses, which swaps via a CPI into
+//! `swap` signed by the PDA.
+//!
+//! TWAP orders are created via `create_twap_order`, which locks the input the
+//! same way, and swapped in slices spread over a time window, each executed
+//! by anyone via `execute_twap_slice`.
+//!
+//! Events are emitted as the data of a self-CPI to `log_event`, signed by the
+//! `EVENT_AUTHORITY_SEED` PDA. Unlike logs, inner instructions aren't
+//! truncated, so indexers can always recover the events from them. Every
+//! instruction emitting events takes the `EventCpi` accounts for this, except
+//! for `SwapFailed`, which is only logged. Built with the `log-events`
+//! feature, the program logs them all as well.
 
 use anchor_lang::prelude::*;
+use anchor_spl::associated_token::{self, AssociatedToken};
 use anchor_spl::dex;
 use anchor_spl::dex::serum_dex::instruction::SelfTradeBehavior;
 use anchor_spl::dex::serum_dex::matching::{OrderType, Side as SerumSide};
-use anchor_spl::dex::serum_dex::state::MarketState;
-use anchor_spl::token;
+use anchor_spl::dex::serum_dex::state::{AccountFlag, MarketState};
+use anchor_spl::token::{self, Mint, Token, TokenAccount};
+use hook::{FlashSwapCallback, Hook, PostSwap, PreSwap};
+use solana_program::bpf_loader_upgradeable;
 use solana_program::declare_id;
+use solana_program::instruction::Instruction;
+use solana_program::program::{invoke, invoke_signed};
+use solana_program::program_option::COption;
+use solana_program::{system_instruction, system_program};
+use std::cell::Cell;
+use std::convert::TryInto;
 use std::num::NonZeroU64;
 
+mod book;
+mod fills;
+pub mod hook;
+mod oracle;
+mod permit;
+pub mod risk;
+
 declare_id!("5paKUq27CMiotwgCh6a4GTDi4NXtGxRo3oZVyr4QXNjM");
 
+/// Sentinel swap amount meaning "the entire balance of the order payer token
+/// account", read at execution time right before placing the order.
+pub const ENTIRE_BALANCE: u64 = u64::MAX;
+
+/// Seed of the global config PDA.
+pub const CONFIG_SEED: &[u8] = b"config";
+
+/// Seed of the protocol fee vault PDAs, one per mint.
+pub const FEE_VAULT_SEED: &[u8] = b"fee_vault";
+
+/// Seed of the flash vault PDAs, one per mint, lending the output of flash
+/// swaps.
+pub const FLASH_VAULT_SEED: &[u8] = b"flash_vault";
+
+/// Seed of the referrer PDAs, one per authority and quote mint.
+pub const REFERRER_SEED: &[u8] = b"referrer";
+
+/// Seed of the referrer vault PDAs collecting the referral rebates.
+pub const REFERRER_VAULT_SEED: &[u8] = b"referrer_vault";
+
+/// Maximum number of recipients a referrer's rebates may be split across.
+pub const MAX_REFERRAL_SPLITS: usize = 4;
+
+/// Seed of the market whitelist PDA.
+pub const WHITELIST_SEED: &[u8] = b"whitelist";
+
+/// Maximum number of markets in the whitelist.
+pub const MAX_WHITELISTED_MARKETS: usize = 64;
+
+/// Maximum number of hook programs in the config.
+pub const MAX_HOOK_PROGRAMS: usize = 8;
+
+/// Maximum number of DEX programs the execution defaults may allow.
+pub const MAX_DEX_PROGRAMS: usize = 4;
+
+/// Seed of the circuit breaker PDAs, one per market.
+pub const CIRCUIT_BREAKER_SEED: &[u8] = b"circuit_breaker";
+
+/// Seed of the market stats PDAs, one per market.
+pub const MARKET_STATS_SEED: &[u8] = b"market_stats";
+
+/// Scale of the prices recorded in `MarketStats`, i.e., the number of native
+/// quote units per native base unit, times `PRICE_SCALE`.
+pub const PRICE_SCALE: u128 = 1_000_000_000;
+
+/// Client order id of the orders sent to the DEX. Orders are IOC, so it's
+/// never needed to cancel them.
+pub const CLIENT_ORDER_ID: u64 = 0;
+
+/// Taker fee of the DEX's base fee tier, in basis points, i.e., the most an
+/// order pays, when it's sent without a fee discount account.
+pub const TAKER_FEE_BPS: u64 = 22;
+
+/// Seed of the PDA signing the self-CPIs that emit events.
+pub const EVENT_AUTHORITY_SEED: &[u8] = b"__event_authority";
+
+/// Bump seed of the event authority PDA, so it isn't searched for on every
+/// event. Must be updated along with the program ID.
+pub const EVENT_AUTHORITY_BUMP: u8 = 255;
+
+/// Seed of the user stats PDAs, one per authority.
+pub const USER_STATS_SEED: &[u8] = b"user_stats";
+
+/// Seed of the escrow PDAs, one per authority and nonce.
+pub const ESCROW_SEED: &[u8] = b"escrow";
+
+/// Seed of the escrow vault PDAs holding the swap proceeds.
+pub const ESCROW_VAULT_SEED: &[u8] = b"escrow_vault";
+
+/// Seed of the DCA schedule PDAs, one per owner and nonce.
+pub const DCA_SEED: &[u8] = b"dca";
+
+/// Seed of the DCA vault PDAs, one per schedule and mint.
+pub const DCA_VAULT_SEED: &[u8] = b"dca_vault";
+
+/// Seed of the TWAP order PDAs, one per owner and nonce.
+pub const TWAP_SEED: &[u8] = b"twap";
+
+/// Seed of the TWAP vault PDAs, one per order and mint.
+pub const TWAP_VAULT_SEED: &[u8] = b"twap_vault";
+
+/// Maximum number of quote mints tracked by a user's stats.
+pub const MAX_USER_STATS_MINTS: usize = 8;
+
+/// Seed of the spending policy PDAs, one per owner and authority.
+pub const SPENDING_POLICY_SEED: &[u8] = b"spending_policy";
+
+/// Maximum number of quote mints limited by a spending policy.
+pub const MAX_SPENDING_LIMITS: usize = 8;
+
+/// Length of the rolling window of a spending limit, in seconds.
+pub const SPENDING_WINDOW_SECONDS: u64 = 24 * 60 * 60;
+
+/// Seed of the swap proposal PDAs, one per authority and nonce.
+pub const SWAP_PROPOSAL_SEED: &[u8] = b"swap_proposal";
+
+/// Maximum number of accounts of a proposed swap, including the remaining
+/// accounts.
+pub const MAX_SWAP_PROPOSAL_ACCOUNTS: usize = 32;
+
+/// Seed of the permit authority PDAs, one per signer.
+pub const PERMIT_AUTHORITY_SEED: &[u8] = b"permit_authority";
+
+/// Seed of the swap commitment PDAs, one per authority and nonce.
+pub const SWAP_COMMITMENT_SEED: &[u8] = b"swap_commitment";
+
+/// Maximum number of recipients the output of a swap may be split across.
+pub const MAX_OUTPUT_SPLITS: usize = 8;
+
+/// Seed of the crank tip PDA.
+pub const CRANK_TIP_SEED: &[u8] = b"crank_tip";
+
+/// Seed of the token account PDA the crank tips are paid out of.
+pub const CRANK_TIP_VAULT_SEED: &[u8] = b"crank_tip_vault";
+
+/// Maximum number of markets whose cranks are tipped.
+pub const MAX_TIPPED_MARKETS: usize = 16;
+
+/// Number of order slots of a DEX open orders account.
+pub const MAX_OPEN_ORDERS_SLOTS: u16 = 128;
+
+/// Denominator of all fees given in basis points.
+pub const BPS_DENOMINATOR: u64 = 10_000;
+
+/// Most slots an oracle price may be published before a swap it bounds, so a
+/// stale price isn't taken as the current one.
+pub const MAX_ORACLE_PRICE_AGE_SLOTS: u64 = 25;
+
 // Associated token account for Pubkey::default.
 mod empty {
     use super::*;
@@ -29,18 +307,237 @@ pub mod serum_swap {
     use super::*;
 
     /// Convenience API to initialize an open orders account on the Serum DEX.
+    ///
+    /// If the open orders account isn't created yet, it's created here, with
+    /// its rent funded by the `payer`. The payer can differ from the
+    /// authority, so that a relayer can pay for users holding no SOL.
+    ///
+    /// Permissioned markets only allow open orders accounts initialized by
+    /// their open orders authority, which must then be given as a signing
+    /// remaining account. Once initialized, orders on the market need no
+    /// other account.
     pub fn init_account<'info>(ctx: Context<'_, '_, '_, 'info, InitAccount<'info>>) -> Result<()> {
-        let ctx = CpiContext::new(ctx.accounts.dex_program.clone(), ctx.accounts.into());
-        dex::init_open_orders(ctx)?;
-        Ok(())
+        init_open_orders(ctx.accounts, ctx.remaining_accounts)
+    }
+
+    /// Same as `init_account`, but succeeds without doing anything if the
+    /// open orders account is already initialized for the market and
+    /// authority, so it can always be prepended to a transaction.
+    pub fn init_account_if_needed<'info>(
+        ctx: Context<'_, '_, '_, 'info, InitAccount<'info>>,
+    ) -> Result<()> {
+        if is_open_orders_initialized(ctx.accounts)? {
+            return Ok(());
+        }
+        init_open_orders(ctx.accounts, ctx.remaining_accounts)
     }
 
     /// Convenience API to close an open orders account on the Serum DEX.
     pub fn close_account<'info>(
         ctx: Context<'_, '_, '_, 'info, CloseAccount<'info>>,
     ) -> Result<()> {
-        let ctx = CpiContext::new(ctx.accounts.dex_program.clone(), ctx.accounts.into());
-        dex::close_open_orders(ctx)?;
+        let event = OpenOrdersClosed {
+            authority: *ctx.accounts.authority.key,
+            market: *ctx.accounts.market.key,
+            open_orders: *ctx.accounts.open_orders.key,
+            destination: *ctx.accounts.destination.key,
+            // The DEX refunds all of the account's lamports.
+            rent_refunded: ctx.accounts.open_orders.lamports(),
+        };
+        let cpi_ctx = CpiContext::new(
+            ctx.accounts.dex_program.clone(),
+            (&mut *ctx.accounts).into(),
+        );
+        dex::close_open_orders(cpi_ctx)?;
+        emit_event(&ctx.accounts.event_cpi, &event)
+    }
+
+    /// Cleans up an open orders account in one shot: cancels every order it
+    /// has resting on the book, e.g., placed from another UI, settles its
+    /// funds into the wallets and, if `close`, closes it, refunding the rent
+    /// to the `destination`.
+    ///
+    /// Closing needs the events of the cancelled and filled orders to be
+    /// consumed, which this does as long as they're first in the market's
+    /// event queue. Otherwise, the queue must be cranked first, after which
+    /// the account can be closed via `close_account`.
+    ///
+    /// Arguments:
+    ///
+    /// * `close` - Whether to close the open orders account afterwards.
+    pub fn cancel_all_and_settle<'info>(
+        ctx: Context<'_, '_, '_, 'info, CancelAllAndSettle<'info>>,
+        close: bool,
+    ) -> Result<()> {
+        let accs = &ctx.accounts;
+        let orders = resting_orders(&accs.open_orders, &accs.dex_program)?;
+        for (side, order_id) in &orders {
+            let cpi_ctx = CpiContext::new(
+                accs.dex_program.clone(),
+                dex::CancelOrderV2 {
+                    market: accs.market.clone(),
+                    market_bids: accs.bids.clone(),
+                    market_asks: accs.asks.clone(),
+                    open_orders: accs.open_orders.clone(),
+                    open_orders_authority: accs.authority.clone(),
+                    event_queue: accs.event_queue.clone(),
+                },
+            );
+            dex::cancel_order_v2(cpi_ctx, *side, *order_id)?;
+        }
+        if close {
+            consume_dex_events(
+                &accs.dex_program,
+                &accs.market,
+                &accs.event_queue,
+                std::slice::from_ref(&accs.open_orders),
+                [&accs.coin_wallet, &accs.pc_wallet],
+                MAX_OPEN_ORDERS_SLOTS,
+            )?;
+        }
+
+        let cpi_ctx = CpiContext::new(
+            accs.dex_program.clone(),
+            dex::SettleFunds {
+                market: accs.market.clone(),
+                open_orders: accs.open_orders.clone(),
+                open_orders_authority: accs.authority.clone(),
+                coin_vault: accs.coin_vault.clone(),
+                pc_vault: accs.pc_vault.clone(),
+                coin_wallet: accs.coin_wallet.clone(),
+                pc_wallet: accs.pc_wallet.clone(),
+                vault_signer: accs.vault_signer.clone(),
+                token_program: accs.token_program.to_account_info(),
+            },
+        );
+        dex::settle_funds(cpi_ctx)?;
+
+        if close {
+            let cpi_ctx = CpiContext::new(
+                accs.dex_program.clone(),
+                dex::CloseOpenOrders {
+                    open_orders: accs.open_orders.clone(),
+                    authority: accs.authority.clone(),
+                    destination: accs.destination.clone(),
+                    market: accs.market.clone(),
+                },
+            );
+            dex::close_open_orders(cpi_ctx)?;
+        }
+        emit_event(
+            &accs.event_cpi,
+            &OrdersCancelled {
+                authority: *accs.authority.key,
+                market: *accs.market.key,
+                open_orders: *accs.open_orders.key,
+                orders_cancelled: orders.len() as u8,
+                closed: close,
+            },
+        )
+    }
+
+    /// Cranks the market's event queue via the DEX's `consume_events`,
+    /// consuming up to `limit` events of the open orders accounts given as the
+    /// remaining accounts, sorted by their address as 64 bit little-endian
+    /// words, e.g., so that the swaps of the same transaction settle
+    /// promptly. Permissionless.
+    ///
+    /// If the market is one of the crank tip's markets and at least its
+    /// minimum batch of events was consumed, its tip per event, for up to its
+    /// maximum events, is paid to the `destination` out of the crank tip
+    /// vault, or what's left in it. The tips paid within a slot are capped,
+    /// so that self-trades cranked in the same slot can't drain the vault.
+    ///
+    /// Arguments:
+    ///
+    /// * `limit` - The most events to consume.
+    pub fn consume_events<'info>(
+        ctx: Context<'_, '_, '_, 'info, ConsumeEvents<'info>>,
+        limit: u16,
+    ) -> Result<()> {
+        let accs = &ctx.accounts;
+        let (len_before, _) = fills::queue_len(&accs.event_queue)?;
+        let tip_vault = accs.tip_vault.to_account_info();
+        let destination = accs.destination.to_account_info();
+        consume_dex_events(
+            &accs.dex_program,
+            &accs.market,
+            &accs.event_queue,
+            ctx.remaining_accounts,
+            [&tip_vault, &destination],
+            limit,
+        )?;
+        let (len_after, _) = fills::queue_len(&accs.event_queue)?;
+        let events_consumed = len_before.saturating_sub(len_after);
+
+        let market = *accs.market.key;
+        let balance = accs.tip_vault.amount;
+        let tip = ctx
+            .accounts
+            .crank_tip
+            .tip(&market, Clock::get()?.slot, events_consumed, balance);
+        let accs = &ctx.accounts;
+        let crank_tip = &accs.crank_tip;
+        if tip > 0 {
+            let seeds = &[CRANK_TIP_SEED, &[crank_tip.bump]];
+            let signer = &[&seeds[..]];
+            let cpi_ctx = CpiContext::new_with_signer(
+                accs.token_program.to_account_info(),
+                token::Transfer {
+                    from: tip_vault,
+                    to: destination,
+                    authority: crank_tip.to_account_info(),
+                },
+                signer,
+            );
+            token::transfer(cpi_ctx, tip)?;
+        }
+        emit_event(
+            &accs.event_cpi,
+            &EventsConsumed {
+                market,
+                destination: accs.destination.key(),
+                events_consumed,
+                tip,
+            },
+        )
+    }
+
+    /// Creates, if needed, the `authority`'s associated token accounts for the
+    /// base and quote mints of the `market`, i.e., the `coin_wallet` and
+    /// `pc_wallet` of its swaps, with their rent funded by the `payer`.
+    ///
+    /// Wallets already created are left as is, so it can always be prepended
+    /// to a transaction swapping on the market.
+    pub fn init_swap_wallets<'info>(
+        ctx: Context<'_, '_, '_, 'info, InitSwapWallets<'info>>,
+    ) -> Result<()> {
+        let (coin_mint, pc_mint) = market_mints(&ctx.accounts.market)?;
+        if ctx.accounts.coin_mint.key() != coin_mint || ctx.accounts.pc_mint.key() != pc_mint {
+            return Err(ErrorCode::InvalidWalletMint.into());
+        }
+        let accs = &ctx.accounts;
+        for (wallet, mint) in [
+            (&accs.coin_wallet, accs.coin_mint.to_account_info()),
+            (&accs.pc_wallet, accs.pc_mint.to_account_info()),
+        ] {
+            if !wallet.data_is_empty() {
+                continue;
+            }
+            let cpi_accounts = associated_token::Create {
+                payer: accs.payer.clone(),
+                associated_token: wallet.clone(),
+                authority: accs.authority.clone(),
+                mint,
+                system_program: accs.system_program.to_account_info(),
+                token_program: accs.token_program.to_account_info(),
+                rent: accs.rent.clone(),
+            };
+            associated_token::create(CpiContext::new(
+                accs.associated_token_program.to_account_info(),
+                cpi_accounts,
+            ))?;
+        }
         Ok(())
     }
 
@@ -54,456 +551,6035 @@ pub mod serum_swap {
     /// Arguments:
     ///
     /// * `side`              - The direction to swap.
-    /// * `amount`            - The amount to swap *from*
+    /// * `amount`            - The amount to swap *from*, or `ENTIRE_BALANCE`
+    ///    to swap the entire balance of the order payer.
     /// * `min_exchange_rate` - The exchange rate to use when determining
     ///    whether the transaction should abort.
-    #[access_control(is_valid_swap(&ctx))]
+    #[access_control(is_valid_swap(&ctx) is_not_paused(&ctx.accounts.config))]
     pub fn swap<'info>(
-            : Context<'_, '_, '_, 'info, Swap<'info>>,
+        ctx: Context<'_, '_, '_, 'info, Swap<'info>>,
         side: Side,
         amount: u64,
         min_exchange_rate: ExchangeRate,
     ) -> Result<()> {
-        let mut min_exchange_rate = min_exchange_rate;
+        execute_swap_v2(
+            ctx,
+            SwapArgsV2 {
+                side,
+                amount,
+                min_exchange_rate,
+                max_quote_amount: None,
+                platform_fee_bps: None,
+                oracle_band: None,
+                tag: None,
+                hook_accounts: None,
+                allow_zero_output: None,
+                allow_partial: None,
+                max_price_impact_bps: None,
+                limit_price: None,
+                deadline: None,
+                max_event_queue_len: None,
+                output_split: None,
+            },
+        )
+    }
+
+    /// Swaps like `swap`, with its arguments and options given as a single
+    /// struct, to which later options are added instead of to the
+    /// instruction, so `swap` itself stays compatible with existing clients.
+    ///
+    /// Options left `None` don't apply, unless documented otherwise:
+    ///
+    /// * `max_quote_amount`  - Cap on the quote currency spent when side is
+    ///    "bid". Ignored for asks.
+    /// * `platform_fee_bps`  - Share of the output paid to the integrator's
+    ///    `platform_fee_account`, net of the protocol fee. Zero if `None`.
+    /// * `oracle_band`       - Aborts if the execution price deviates too far
+    ///    from the Pyth price account given as the first remaining account,
+    ///    or if its price is older than `MAX_ORACLE_PRICE_AGE_SLOTS`.
+    /// * `tag`               - Client data echoed in the `DidSwap` event,
+    ///    e.g., to correlate the swap with an off-chain order.
+    /// * `hook_accounts`     - Invokes a whitelisted hook program before the
+    ///    order and after settlement. The program is given as the next
+    ///    remaining account, followed by this many accounts passed to it.
+    /// * `allow_zero_output` - If true and nothing fills, succeeds without
+    ///    swapping instead of aborting, e.g., so a market without liquidity
+    ///    doesn't abort a transaction composing many swaps.
+    /// * `allow_partial`     - If false, aborts unless the entire amount,
+    ///    rounded to the market's lot size, is filled. Either way, the
+    ///    remainder is reported in the `DidSwap` event. True if `None`.
+    /// * `max_price_impact_bps` - Aborts before placing the order if the
+    ///    average price expected from the orders resting on the book for the
+    ///    amount is further than this from the best price.
+    ///
+    /// Arguments:
+    ///
+    /// * `args` - The swap's arguments, with the same semantics as the
+    ///            `swap` instruction unless documented otherwise.
+    #[access_control(is_valid_swap(&ctx) is_not_paused(&ctx.accounts.config))]
+    pub fn swap_v2<'info>(
+        ctx: Context<'_, '_, '_, 'info, Swap<'info>>,
+        args: SwapArgsV2,
+    ) -> Result<()> {
+        execute_swap_v2(ctx, args)
+    }
 
-        // Not used for direct swaps.
-        min_exchange_rate.quote_decimals = 0;
+    /// Proposes a `swap_v2` for the `authority` to execute via
+    /// `execute_swap_proposal`. The proposer pays the proposal's rent, and
+    /// may cancel it via `cancel_swap_proposal`.
+    ///
+    /// The remaining accounts are those of the swap, as given to `swap_v2`,
+    /// followed by its own remaining accounts, in order.
+    ///
+    /// Arguments:
+    ///
+    /// * `bump`             - The bump seed of the swap proposal PDA.
+    /// * `nonce`            - Distinguishes the authority's proposals.
+    /// * `args`             - The arguments of the swap.
+    /// * `executable_after` - The unix timestamp from which it may be
+    ///    executed.
+    pub fn propose_swap(
+        ctx: Context<ProposeSwap>,
+        bump: u8,
+        nonce: u64,
+        args: SwapArgsV2,
+        executable_after: i64,
+    ) -> Result<()> {
+        risk::validate(&args.min_exchange_rate, false)?;
+        if ctx.remaining_accounts.len() > MAX_SWAP_PROPOSAL_ACCOUNTS {
+            return Err(ErrorCode::TooManyProposalAccounts.into());
+        }
+        let proposal = &mut ctx.accounts.proposal;
+        proposal.proposer = *ctx.accounts.proposer.key;
+        proposal.authority = *ctx.accounts.authority.key;
+        proposal.nonce = nonce;
+        proposal.bump = bump;
+        proposal.executable_after = executable_after;
+        proposal.args = args;
+        proposal.account_keys = ctx
+            .remaining_accounts
+            .iter()
+            .map(|account| *account.key)
+            .collect();
+        Ok(())
+    }
 
-        // Optional referral account (earns a referral fee).
-        let referral = ctx.remaining_accounts.iter().next().map(Clone::clone);
+    /// Executes a proposed swap like `swap_v2`, with its arguments, closing
+    /// the proposal and refunding its rent to the proposer. The accounts must
+    /// be those proposed, in the same order. Authority only.
+    #[access_control(
+        is_valid_swap_proposal(&ctx)
+        is_not_paused(&ctx.accounts.swap.config)
+    )]
+    pub fn execute_swap_proposal<'info>(
+        ctx: Context<'_, '_, '_, 'info, ExecuteSwapProposal<'info>>,
+    ) -> Result<()> {
+        let proposal = &ctx.accounts.proposal;
+        if Clock::get()?.unix_timestamp < proposal.executable_after {
+            return Err(ErrorCode::SwapProposalLocked.into());
+        }
+        let account_keys: Vec<Pubkey> = ctx
+            .accounts
+            .swap
+            .to_account_infos()
+            .iter()
+            .chain(ctx.remaining_accounts)
+            .map(|account| *account.key)
+            .collect();
+        if account_keys != proposal.account_keys {
+            return Err(ErrorCode::SwapProposalMismatch.into());
+        }
+        let args = proposal.args.clone();
+        execute_swap_v2(
+            Context::new(
+                ctx.program_id,
+                &mut ctx.accounts.swap,
+                ctx.remaining_accounts,
+            ),
+            args,
+        )
+    }
 
-        // Side determines swap direction.
-        let (from_token, to_token) = match side {
-            Side::Bid => (&ctx.accounts.pc_wallet, &ctx.accounts.market.coin_wallet),
-            Side::Ask => (&ctx.accounts.market.coin_wallet, &ctx.accounts.pc_wallet),
-        };
+    /// Cancels a proposed swap, refunding its rent to the proposer. Proposer
+    /// or authority only.
+    pub fn cancel_swap_proposal(_ctx: Context<CancelSwapProposal>) -> Result<()> {
+        Ok(())
+    }
 
-        // Token balances before the trade.
-        let from_amount_before = token::accessor::amount(from_token)?;
-        let to_amount_before = token::accessor::amount(to_token)?;
+    /// Commits to a `swap_v2` on a market, revealed and executed in a later
+    /// slot via `reveal_and_swap`.
+    ///
+    /// Arguments:
+    ///
+    /// * `bump`  - The bump seed of the swap commitment PDA.
+    /// * `nonce` - Distinguishes the authority's commitments.
+    /// * `hash`  - The SHA-256 hash of the market's address, the serialized
+    ///             `SwapArgsV2` and a secret salt, in that order.
+    pub fn commit_swap(
+        ctx: Context<CommitSwap>,
+        bump: u8,
+        nonce: u64,
+        hash: [u8; 32],
+    ) -> Result<()> {
+        let commitment = &mut ctx.accounts.commitment;
+        commitment.authority = *ctx.accounts.authority.key;
+        commitment.nonce = nonce;
+        commitment.bump = bump;
+        commitment.hash = hash;
+        commitment.slot = Clock::get()?.slot;
+        Ok(())
+    }
 
-        // Execute trade.
-        let orderbook: OrderbookClient<'info> = (&*ctx.accounts).into();
-        match side {
-            Side::Bid => orderbook.buy(amount, None)?,
-            Side::Ask => orderbook.sell(amount, None)?,
-        };
-        orderbook.settle(referral)?;
+    /// Swaps like `swap_v2` with the committed arguments, closing the
+    /// commitment and refunding its rent to the authority. Fails in the
+    /// slot of the commitment, or if the hash doesn't match.
+    ///
+    /// Arguments:
+    ///
+    /// * `args` - The arguments committed to.
+    /// * `salt` - The salt committed to.
+    #[access_control(is_valid_reveal(&ctx) is_not_paused(&ctx.accounts.swap.config))]
+    pub fn reveal_and_swap<'info>(
+        ctx: Context<'_, '_, '_, 'info, RevealAndSwap<'info>>,
+        args: SwapArgsV2,
+        salt: [u8; 32],
+    ) -> Result<()> {
+        let commitment = &ctx.accounts.commitment;
+        if Clock::get()?.slot <= commitment.slot {
+            return Err(ErrorCode::EarlyReveal.into());
+        }
+        let hash = solana_program::hash::hashv(&[
+            ctx.accounts.swap.market.market.key.as_ref(),
+            &args.try_to_vec().unwrap(),
+            &salt,
+        ]);
+        if hash.to_bytes() != commitment.hash {
+            return Err(ErrorCode::CommitmentMismatch.into());
+        }
+        execute_swap_v2(
+            Context::new(
+                ctx.program_id,
+                &mut ctx.accounts.swap,
+                ctx.remaining_accounts,
+            ),
+            args,
+        )
+    }
 
-        // Token balances after the trade.
-        let from_amount_after = token::accessor::amount(from_token)?;
-        let to_amount_after = token::accessor::amount(to_token)?;
+    /// Cancels a swap commitment, refunding its rent to the authority.
+    /// Authority only.
+    pub fn cancel_swap_commitment(_ctx: Context<CancelSwapCommitment>) -> Result<()> {
+        Ok(())
+    }
 
-        //  Calculate the delta, i.e. the amount swapped.
-        let from_amount = from_amount_before.checked_sub(from_amount_after).unwrap();
-        let to_amount = to_amount_after.checked_sub(to_amount_before).unwrap();
+    /// Creates the PDA swapping on behalf of the `signer` of permits.
+    ///
+    /// Arguments:
+    ///
+    /// * `bump`   - The bump seed of the permit authority PDA.
+    /// * `signer` - The key signing the permits.
+    pub fn create_permit_authority(
+        ctx: Context<CreatePermitAuthority>,
+        bump: u8,
+        signer: Pubkey,
+    ) -> Result<()> {
+        let permit_authority = &mut ctx.accounts.permit_authority;
+        permit_authority.signer = signer;
+        permit_authority.bump = bump;
+        Ok(())
+    }
 
-        // Safety checks.
-        apply_risk_checks(DidSwap {
-            authority: *ctx.accounts.authority.key,
-            given_amount: amount,
-            min_exchange_rate,
-            from_amount,
-            to_amount,
-            quote_amount: 0,
-            spill_amount: 0,
-            from_mint: token::accessor::mint(from_token)?,
-            to_mint: token::accessor::mint(to_token)?,
-            quote_mint: match side {
-                Side::Bid => token::accessor::mint(from_token)?,
-                Side::Ask => token::accessor::mint(to_token)?,
+    /// Creates and initializes an open orders account on the market, whose
+    /// authority is the permit authority. Funded by the payer.
+    pub fn init_permit_open_orders(ctx: Context<InitPermitOpenOrders>) -> Result<()> {
+        let permit_authority = &ctx.accounts.permit_authority;
+        let seeds = &[
+            PERMIT_AUTHORITY_SEED,
+            permit_authority.signer.as_ref(),
+            &[permit_authority.bump],
+        ];
+        init_signed_open_orders(
+            &ctx.accounts.payer.to_account_info(),
+            dex::InitOpenOrders {
+                open_orders: ctx.accounts.open_orders.clone(),
+                authority: permit_authority.to_account_info(),
+                market: ctx.accounts.market.clone(),
+                rent: ctx.accounts.rent.to_account_info(),
             },
-        })?;
+            &ctx.accounts.dex_program,
+            &ctx.accounts.system_program.to_account_info(),
+            &ctx.accounts.event_cpi,
+            &[&seeds[..]],
+        )
+    }
+
+    /// Swaps as authorized by a permit, via a CPI into `swap` signed by the
+    /// permit authority, so all of its checks and fees apply. The previous
+    /// instruction must verify the signer's ed25519 signature of the
+    /// permit's serialization. Permissionless: anyone may relay it, but the
+    /// swap's wallets must be owned by the signer, so that the relayer can't
+    /// redirect its proceeds.
+    ///
+    /// Arguments:
+    ///
+    /// * `permit` - The swap authorized, which must be on the given market,
+    ///              with the permit authority's next nonce.
+    pub fn swap_with_permit<'info>(
+        ctx: Context<'_, '_, '_, 'info, SwapWithPermit<'info>>,
+        permit: Permit,
+    ) -> Result<()> {
+        let permit_authority = &ctx.accounts.permit_authority;
+        if permit.authority != permit_authority.key()
+            || &permit.market != ctx.accounts.swap.market.market.key
+        {
+            return Err(ErrorCode::InvalidPermit.into());
+        }
+        for wallet in [
+            &ctx.accounts.swap.market.coin_wallet,
+            &ctx.accounts.swap.pc_wallet,
+        ] {
+            if token::accessor::authority(wallet)? != permit_authority.signer {
+                return Err(ErrorCode::InvalidPermit.into());
+            }
+        }
+        if permit.nonce != permit_authority.nonce {
+            return Err(ErrorCode::InvalidPermitNonce.into());
+        }
+        if Clock::get()?.unix_timestamp > permit.expiry {
+            return Err(ErrorCode::PermitExpired.into());
+        }
+        permit::verify(
+            &ctx.accounts.instructions,
+            &permit_authority.signer,
+            &permit.try_to_vec().unwrap(),
+        )?;
+
+        let seeds = &[
+            PERMIT_AUTHORITY_SEED,
+            permit_authority.signer.as_ref(),
+            &[permit_authority.bump],
+        ];
+        ctx.accounts.swap.cpi_swap(
+            permit_authority.to_account_info(),
+            &[&seeds[..]],
+            permit.side,
+            permit.amount,
+            permit.min_exchange_rate,
+        )?;
 
+        let permit_authority = &mut ctx.accounts.permit_authority;
+        permit_authority.nonce = permit_authority.nonce.checked_add(1).unwrap();
         Ok(())
     }
 
-    /// Swaps two base currencies across two different markets.
-    ///
-    /// That is, suppose there are two markets, A/USD(x) and B/USD(x).
-    /// Then swaps token A for token B via
+    /// Swaps two tokens on a single market, like `swap`, then deposits the
+    /// tokens received into a downstream program, e.g., a lending reserve or a
+    /// vault, in the same instruction so the two can't be separated.
     ///
-    /// * IOC (immediate or cancel) sell order on A/USD(x) market.
-    /// * Settle open orders to get USD(x).
-    /// * IOC buy order on B/USD(x) market to convert USD(x) to token B.
-    /// * Settle open orders to get token B.
+    /// The downstream program must be whitelisted by the admin via
+    /// `add_hook_program`. It's given as the first remaining account, followed
+    /// by the accounts passed to it, then the optional referrer.
     ///
     /// Arguments:
     ///
-    /// * `amount`            - The amount to swap *from*.
+    /// * `side`              - The direction to swap.
+    /// * `amount`            - The amount to swap *from*, or `ENTIRE_BALANCE`
+    ///    to swap the entire balance of the order payer.
     /// * `min_exchange_rate` - The exchange rate to use when determining
     ///    whether the transaction should abort.
-    #[access_control(is_valid_swap_transitive(&ctx))]
-    pub fn swap_transitive<'info>(
-        ctx: Context<'_, '_, '_, 'info, SwapTransitive<'info>>,
+    /// * `deposit`           - The deposit instruction to send, into which the
+    ///    amount received is written.
+    #[access_control(is_valid_swap(&ctx) is_not_paused(&ctx.accounts.config))]
+    pub fn swap_and_deposit<'info>(
+        ctx: Context<'_, '_, '_, 'info, Swap<'info>>,
+        side: Side,
         amount: u64,
         min_exchange_rate: ExchangeRate,
+        deposit: Deposit,
     ) -> Result<()> {
-        // Optional referral account (earns a referral fee).
-        let referral = ctx.remaining_accounts.iter().next().map(Clone::clone);
-
-        // Leg 1: Sell Token A for USD(x) (or whatever quote currency is used).
-        let (from_amount, sell_proceeds) = {
-            // Token balances before the trade.
-            let base_before = token::accessor::amount(&ctx.accounts.from.coin_wallet)?;
-            let quote_before = token::accessor::amount(&ctx.accounts.pc_wallet)?;
-
-            // Execute the trade.
-            let orderbook = ctx.accounts.orderbook_from();
-            orderbook.sell(amount, None)?;
-            orderbook.settle(referral.clone())?;
-
-            // Token balances after the trade.
-            let base_after = token::accessor::amount(&ctx.accounts.from.coin_wallet)?;
-            let quote_after = token::accessor::amount(&ctx.accounts.pc_wallet)?;
-
-            // Report the delta.
-            (
-                base_before.checked_sub(base_after).unwrap(),
-                quote_after.checked_sub(quote_before).unwrap(),
-            )
-        };
+        check_whitelisted(
+            &ctx.accounts.config,
+            &ctx.accounts.whitelist,
+            &[&ctx.accounts.market.market],
+        )?;
 
-        // Leg 2: Buy Token B with USD(x) (or whatever quote currency is used).
-        let (to_amount, buy_proceeds) = {
-            // Token balances before the trade.
-            let base_before = token::accessor::amount(&ctx.accounts.to.coin_wallet)?;
-            let quote_before = token::accessor::amount(&ctx.accounts.pc_wallet)?;
-
-            // Execute the trade.
-            let orderbook = ctx.accounts.orderbook_to();
-            orderbook.buy(sell_proceeds, None)?;
-            orderbook.settle(referral)?;
-
-            // Token balances after the trade.
-            let base_after = token::accessor::amount(&ctx.accounts.to.coin_wallet)?;
-            let quote_after = token::accessor::amount(&ctx.accounts.pc_wallet)?;
-
-            // Report the delta.
-            (
-                base_after.checked_sub(base_before).unwrap(),
-                quote_before.checked_sub(quote_after).unwrap(),
-            )
-        };
+        let mut remaining_accounts = ctx.remaining_accounts;
+        let spending_policy = load_spending_policy(
+            &ctx.accounts.spending_policy,
+            &ctx.accounts.market.order_payer_token_account,
+            &ctx.accounts.authority,
+        )?;
+        let target = Hook::new(
+            &mut remaining_accounts,
+            deposit.num_accounts,
+            &ctx.accounts.config,
+        )?;
+        let referral = Referral::new(&ctx.accounts.referral, remaining_accounts);
 
-        // The amount of surplus quote currency *not* fully consumed by the
-        // second half of the swap.
-        let spill_amount = sell_proceeds.checked_sub(buy_proceeds).unwrap();
+        let orderbook: OrderbookClient<'info> = (&*ctx.accounts).into();
+        let to_amount = execute_swap(
+            &orderbook,
+            &ctx.accounts.protocol_fee(),
+            &ctx.accounts.event_cpi,
+            side,
+            amount,
+            min_exchange_rate,
+            SwapOptions {
+                user_stats: Some(&ctx.accounts.user_stats),
+                spending_policy,
+                referral,
+                ..Default::default()
+            },
+        )?;
+        target.deposit(deposit.data, deposit.amount_offset, to_amount)
+    }
 
-        // Safety checks.
-        apply_risk_checks(DidSwap {
-            given_amount: amount,
+    /// Swaps two tokens on a single market, like `swap`, then moves the
+    /// tokens received into a new escrow, which the `beneficiary` may withdraw
+    /// via `withdraw_escrow` once `unlock_ts` has passed, e.g., to fund a
+    /// vesting schedule in a single transaction.
+    ///
+    /// The optional referrer is the only remaining account, as for `swap`.
+    ///
+    /// Arguments:
+    ///
+    /// * `bump`              - The bump seed of the escrow PDA.
+    /// * `_vault_bump`       - The bump seed of the escrow vault PDA.
+    /// * `nonce`             - Distinguishes the authority's escrows.
+    /// * `args`              - The swap, and the escrow's terms:
+    ///   * `side`              - The direction to swap.
+    ///   * `amount`            - The amount to swap *from*, or `ENTIRE_BALANCE`
+    ///      to swap the entire balance of the order payer.
+    ///   * `min_exchange_rate` - The exchange rate to use when determining
+    ///      whether the transaction should abort.
+    ///   * `beneficiary`       - The key allowed to withdraw the escrow.
+    ///   * `unlock_ts`         - The unix timestamp from which it may be
+    ///      withdrawn.
+    #[access_control(
+        is_valid_swap_to_escrow(&ctx)
+        is_not_paused(&ctx.accounts.swap.config)
+    )]
+    pub fn swap_to_escrow<'info>(
+        ctx: Context<'_, '_, '_, 'info, SwapToEscrow<'info>>,
+        bump: u8,
+        _vault_bump: u8,
+        nonce: u64,
+        args: SwapToEscrowArgs,
+    ) -> Result<()> {
+        let SwapToEscrowArgs {
+            side,
+            amount,
             min_exchange_rate,
-            from_amount,
-            to_amount,
-            quote_amount: sell_proceeds,
-            spill_amount,
-            from_mint: token::accessor::mint(&ctx.accounts.from.coin_wallet)?,
-            to_mint: token::accessor::mint(&ctx.accounts.to.coin_wallet)?,
-            quote_mint: token::accessor::mint(&ctx.accounts.pc_wallet)?,
-            authority: *ctx.accounts.authority.key,
-        })?;
+            beneficiary,
+            unlock_ts,
+        } = args;
+        let swap = &ctx.accounts.swap;
+        check_whitelisted(&swap.config, &swap.whitelist, &[&swap.market.market])?;
 
+        // Optional referrer of the referral account (earns a referral fee).
+        let spending_policy = load_spending_policy(
+            &swap.spending_policy,
+            &swap.market.order_payer_token_account,
+            &swap.authority,
+        )?;
+        let referral = Referral::new(&swap.referral, ctx.remaining_accounts);
+
+        let orderbook: OrderbookClient<'info> = swap.into();
+        let to_amount = execute_swap(
+            &orderbook,
+            &swap.protocol_fee(),
+            &swap.event_cpi,
+            side,
+            amount,
+            min_exchange_rate,
+            SwapOptions {
+                user_stats: Some(&swap.user_stats),
+                spending_policy,
+                referral,
+                ..Default::default()
+            },
+        )?;
+
+        let (_, to_wallet) = orderbook.wallets(&side);
+        let cpi_ctx = CpiContext::new(
+            swap.token_program.clone(),
+            token::Transfer {
+                from: to_wallet.clone(),
+                to: ctx.accounts.escrow_vault.to_account_info(),
+                authority: swap.authority.clone(),
+            },
+        );
+        token::transfer(cpi_ctx, to_amount)?;
+
+        let escrow = &mut ctx.accounts.escrow;
+        escrow.depositor = *ctx.accounts.swap.authority.key;
+        escrow.beneficiary = beneficiary;
+        escrow.vault = ctx.accounts.escrow_vault.key();
+        escrow.payer = ctx.accounts.payer.key();
+        escrow.unlock_ts = unlock_ts;
+        escrow.amount = to_amount;
+        escrow.nonce = nonce;
+        escrow.bump = bump;
         Ok(())
     }
-}
 
-// Asserts the swap event executed at an exchange rate acceptable to the client.
-fn apply_risk_checks(event: DidSwap) -> Result<()> {
-    // Emit the event for client consumption.
-    emit!(event);
+    /// Pays a merchant exactly `pay_amount` of the *to* token, swapped from at
+    /// most `max_amount` of the *from* token, e.g., to settle an invoice in
+    /// whichever token the payer holds. The order only offers as much as the
+    /// orders resting on the book need to fill the payment, after the fees,
+    /// so the rest of `max_amount` stays with the payer, as does any output
+    /// left over by the rounding to lots.
+    ///
+    /// The `reference` account is any key, e.g., unique to the invoice, passed
+    /// read-only so that the merchant can look up the payment's transaction by
+    /// it, as with Solana Pay. It's also included in the `PaymentSettled`
+    /// event.
+    ///
+    /// The optional referrer is the only remaining account, as for `swap`.
+    ///
+    /// Arguments:
+    ///
+    /// * `side`       - The direction to swap.
+    /// * `pay_amount` - The amount of the *to* token the merchant receives.
+    /// * `max_amount` - The most of the *from* token to spend.
+    #[access_control(
+        is_valid_pay_with_swap(&ctx)
+        is_not_paused(&ctx.accounts.swap.config)
+    )]
+    pub fn pay_with_swap<'info>(
+        ctx: Context<'_, '_, '_, 'info, PayWithSwap<'info>>,
+        side: Side,
+        pay_amount: u64,
+        max_amount: u64,
+    ) -> Result<()> {
+        let swap = &ctx.accounts.swap;
+        check_whitelisted(&swap.config, &swap.whitelist, &[&swap.market.market])?;
 
-    if event.to_amount == 0 {
-        return Err(ErrorCode::ZeroSwap.into());
+        let spending_policy = load_spending_policy(
+            &swap.spending_policy,
+            &swap.market.order_payer_token_account,
+            &swap.authority,
+        )?;
+        let referral = Referral::new(&swap.referral, ctx.remaining_accounts);
+
+        // Size the order to receive the payment once the protocol fee is
+        // skimmed off the output.
+        let orderbook: OrderbookClient<'info> = swap.into();
+        let protocol_fee = swap.protocol_fee();
+        let amount = orderbook.exact_out_amount(&side, protocol_fee.gross_up(pay_amount)?)?;
+        if amount > max_amount {
+            msg!("amount: {:?}", amount);
+            return Err(ErrorCode::PaymentExceedsMaxAmount.into());
+        }
+
+        let (from_wallet, to_wallet) = orderbook.wallets(&side);
+        let from_amount_before = token::accessor::amount(from_wallet)?;
+        let to_amount = execute_swap(
+            &orderbook,
+            &protocol_fee,
+            &swap.event_cpi,
+            side,
+            amount,
+            payment_rate(pay_amount, max_amount),
+            SwapOptions {
+                user_stats: Some(&swap.user_stats),
+                spending_policy,
+                referral,
+                ..Default::default()
+            },
+        )?;
+        if to_amount < pay_amount {
+            msg!("to_amount: {:?}", to_amount);
+            return Err(ErrorCode::PaymentShortfall.into());
+        }
+        let from_amount = from_amount_before
+            .checked_sub(token::accessor::amount(from_wallet)?)
+            .unwrap();
+
+        let cpi_ctx = CpiContext::new(
+            swap.token_program.clone(),
+            token::Transfer {
+                from: to_wallet.clone(),
+                to: ctx.accounts.merchant.clone(),
+                authority: swap.authority.clone(),
+            },
+        );
+        token::transfer(cpi_ctx, pay_amount)?;
+
+        emit_event(
+            &swap.event_cpi,
+            &PaymentSettled {
+                authority: *swap.authority.key,
+                merchant: *ctx.accounts.merchant.key,
+                reference: *ctx.accounts.reference.key,
+                mint: token::accessor::mint(to_wallet)?,
+                amount: pay_amount,
+                from_amount,
+            },
+        )
     }
 
-    // Use the exchange rate to calculate the client's expectation.
-    //
-    // The exchange rate given must always have decimals equal to the
-    // `to_mint` decimals, guaranteeing the `min_expected_amount`
-    // always has decimals equal to
-    //
-    // `decimals(from_mint) + decimals(to_mint) + decimals(quote_mint)`.
-    //
-    // We avoid truncating by adding `decimals(quote_mint)`.
-    let min_expected_amount = u128::from(
-        // decimals(from).
-        event.from_amount,
-    )
-    .checked_mul(
-        // decimals(from) + decimals(to).
-        event.min_exchange_rate.rate.into(),
-    )
-    .unwrap()
-    .checked_mul(
-        // decimals(from) + decimals(to) + decimals(quote).
-        10u128
-            .checked_pow(event.min_exchange_rate.quote_decimals.into())
-            .unwrap(),
-    )
-    .unwrap();
-
-    // If there is spill (i.e. quote tokens *not* fully consumed for
-    // the buy side of a transitive swap), then credit those tokens marked
-    // at the executed exchange rate to create an "effective" to_amount.
-    let effective_to_amount = {
-        // Translates the leftover spill amount into "to" units via
-        //
-        // `(to_amount_received/quote_amount_given) * spill_amount`
-        //
-        let spill_surplus = match event.spill_amount == 0 || event.min_exchange_rate.strict {
-            true => 0,
-            false => u128::from(
-                // decimals(to).
-                event.to_amount,
-            )
-            .checked_mul(
-                // decimals(to) + decimals(quote).
-                event.spill_amount.into(),
-            )
-            .unwrap()
-            .checked_mul(
-                // decimals(to) + decimals(quote) + decimals(from).
-                10u128
-                    .checked_pow(event.min_exchange_rate.from_decimals.into())
-                    .unwrap(),
-            )
-            .unwrap()
-            .checked_mul(
-                // decimals(to) + decimals(quote)*2 + decimals(from).
-                10u128
-                    .checked_pow(event.min_exchange_rate.quote_decimals.into())
-                    .unwrap(),
-            )
-            .unwrap()
-            .checked_div(
-                // decimals(to) + decimals(quote) + decimals(from).
-                event
-                    .quote_amount
-                    .checked_sub(event.spill_amount)
-                    .unwrap()
-                    .into(),
-            )
-            .unwrap(),
-        };
+    /// Flash swap: delivers `amount` of the *to* token up front, lent by the
+    /// flash vault of the *to* mint, then invokes a callback program, which
+    /// may use it, e.g., to repay a loan and receive the collateral, and must
+    /// leave the order payer holding the *from* token owed. That's then
+    /// swapped for the `amount`, plus the protocol fee, which repays the
+    /// flash vault, so the caller never needs to hold the *from* token
+    /// beforehand. The whole transaction aborts if the vault can't be repaid.
+    ///
+    /// Flash vaults are created via `create_flash_vault` and funded by the
+    /// admin, so that only the liquidity it provides is ever lent.
+    ///
+    /// The callback program must be whitelisted by the admin via
+    /// `add_hook_program`. It's given as the first remaining account,
+    /// followed by the `num_callback_accounts` accounts passed to it, then the
+    /// optional referrer. See the `hook` module for its arguments.
+    ///
+    /// Arguments:
+    ///
+    /// * `side`                  - The direction to swap.
+    /// * `amount`                - The amount of the *to* token delivered.
+    /// * `max_amount`            - The most of the *from* token to spend.
+    /// * `num_callback_accounts` - The number of accounts passed to the
+    ///    callback.
+    #[access_control(is_valid_flash_swap(&ctx) is_not_paused(&ctx.accounts.swap.config))]
+    pub fn flash_swap<'info>(
+        ctx: Context<'_, '_, '_, 'info, FlashSwap<'info>>,
+        side: Side,
+        amount: u64,
+        max_amount: u64,
+        num_callback_accounts: u8,
+    ) -> Result<()> {
+        let swap = &ctx.accounts.swap;
+        check_whitelisted(&swap.config, &swap.whitelist, &[&swap.market.market])?;
+
+        let mut remaining_accounts = ctx.remaining_accounts;
+        let spending_policy = load_spending_policy(
+            &swap.spending_policy,
+            &swap.market.order_payer_token_account,
+            &swap.authority,
+        )?;
+        let callback = Hook::new(&mut remaining_accounts, num_callback_accounts, &swap.config)?;
+        let referral = Referral::new(&swap.referral, remaining_accounts);
+
+        // Size the order repaying the vault once the protocol fee is skimmed
+        // off the output.
+        let orderbook: OrderbookClient<'info> = swap.into();
+        let protocol_fee = swap.protocol_fee();
+        let from_amount = orderbook.exact_out_amount(&side, protocol_fee.gross_up(amount)?)?;
+        if from_amount > max_amount {
+            msg!("from_amount: {:?}", from_amount);
+            return Err(ErrorCode::FlashSwapExceedsMaxAmount.into());
+        }
+
+        // Lend the output.
+        let (from_wallet, to_wallet) = orderbook.wallets(&side);
+        let flash_vault = &ctx.accounts.flash_vault;
+        let to_mint = token::accessor::mint(to_wallet)?;
+        let (flash_vault_address, _) =
+            Pubkey::find_program_address(&[FLASH_VAULT_SEED, to_mint.as_ref()], ctx.program_id);
+        if flash_vault.key != &flash_vault_address {
+            return Err(ErrorCode::InvalidFlashVault.into());
+        }
+        let seeds = &[CONFIG_SEED, &[swap.config.bump]];
+        let signer = &[&seeds[..]];
+        let cpi_ctx = CpiContext::new_with_signer(
+            swap.token_program.clone(),
+            token::Transfer {
+                from: flash_vault.clone(),
+                to: to_wallet.clone(),
+                authority: swap.config.to_account_info(),
+            },
+            signer,
+        );
+        token::transfer(cpi_ctx, amount)?;
+
+        callback.flash_swap_callback(FlashSwapCallback {
+            authority: *swap.authority.key,
+            side,
+            amount,
+            from_amount,
+        })?;
+        if token::accessor::amount(from_wallet)? < from_amount {
+            return Err(ErrorCode::FlashSwapUnpaid.into());
+        }
+
+        // Collect the input, and repay the vault with its proceeds.
+        let to_amount = execute_swap(
+            &orderbook,
+            &protocol_fee,
+            &swap.event_cpi,
+            side,
+            from_amount,
+            payment_rate(amount, max_amount),
+            SwapOptions {
+                user_stats: Some(&swap.user_stats),
+                spending_policy,
+                referral,
+                ..Default::default()
+            },
+        )?;
+        if to_amount < amount {
+            msg!("to_amount: {:?}", to_amount);
+            return Err(ErrorCode::FlashSwapUnpaid.into());
+        }
+        let cpi_ctx = CpiContext::new(
+            swap.token_program.clone(),
+            token::Transfer {
+                from: to_wallet.clone(),
+                to: flash_vault.clone(),
+                authority: swap.authority.clone(),
+            },
+        );
+        token::transfer(cpi_ctx, amount)?;
 
-        // Translate the `to_amount` into a common number of decimals.
-        let to_amount = u128::from(
-            // decimals(to).
-            event.to_amount,
+        emit_event(
+            &swap.event_cpi,
+            &FlashSwapped {
+                authority: *swap.authority.key,
+                callback_program: callback.program_id(),
+                mint: to_mint,
+                amount,
+                from_amount,
+            },
         )
-        .checked_mul(
-            // decimals(to) + decimals(from).
-            10u128
-                .checked_pow(event.min_exchange_rate.from_decimals.into())
-                .unwrap(),
+    }
+
+    /// Pays out an unlocked escrow to `destination`, closing it and its vault
+    /// and refunding their rent to the payer of `swap_to_escrow`.
+    /// Beneficiary only.
+    pub fn withdraw_escrow(ctx: Context<WithdrawEscrow>) -> Result<()> {
+        let escrow = &ctx.accounts.escrow;
+        if Clock::get()?.unix_timestamp < escrow.unlock_ts {
+            return Err(ErrorCode::EscrowLocked.into());
+        }
+        let nonce = escrow.nonce.to_le_bytes();
+        let seeds = &[
+            ESCROW_SEED,
+            escrow.depositor.as_ref(),
+            nonce.as_ref(),
+            &[escrow.bump],
+        ];
+        let signer = &[&seeds[..]];
+        let cpi_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            token::Transfer {
+                from: ctx.accounts.vault.to_account_info(),
+                to: ctx.accounts.destination.to_account_info(),
+                authority: escrow.to_account_info(),
+            },
+            signer,
+        );
+        token::transfer(cpi_ctx, ctx.accounts.vault.amount)?;
+
+        let cpi_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            token::CloseAccount {
+                account: ctx.accounts.vault.to_account_info(),
+                destination: ctx.accounts.payer.to_account_info(),
+                authority: escrow.to_account_info(),
+            },
+            signer,
+        );
+        token::close_account(cpi_ctx)?;
+        Ok(())
+    }
+
+    /// Swaps two tokens on a single market, like `swap`, but splits the
+    /// `amount` into sequential IOC orders, each optionally limited to a worst
+    /// price, so a large swap doesn't walk the whole book at once. Stops early
+    /// once an order isn't filled entirely, e.g., because the price limit was
+    /// crossed, leaving the rest of the `amount` unswapped.
+    ///
+    /// For asks, each order is rounded down to the lot size, so up to a lot
+    /// per chunk may be left unswapped.
+    ///
+    /// Arguments:
+    ///
+    /// * `side`              - The direction to swap.
+    /// * `amount`            - The amount to swap *from*, or `ENTIRE_BALANCE`
+    ///    to swap the entire balance of the order payer.
+    /// * `min_exchange_rate` - The exchange rate to use when determining
+    ///    whether the transaction should abort.
+    /// * `chunks`            - The number of orders, and their worst price.
+    /// * `platform_fee_bps`  - Share of the output paid to the integrator's
+    ///    `platform_fee_account`, net of the protocol fee. Zero for none.
+    /// * `tag`               - Optional client data echoed in the `DidSwap`
+    ///    event.
+    #[access_control(is_valid_swap(&ctx) is_not_paused(&ctx.accounts.config))]
+    pub fn swap_chunked<'info>(
+        ctx: Context<'_, '_, '_, 'info, Swap<'info>>,
+        side: Side,
+        amount: u64,
+        min_exchange_rate: ExchangeRate,
+        chunks: Chunks,
+        platform_fee_bps: u16,
+        tag: Option<[u8; 32]>,
+    ) -> Result<()> {
+        check_whitelisted(
+            &ctx.accounts.config,
+            &ctx.accounts.whitelist,
+            &[&ctx.accounts.market.market],
+        )?;
+
+        // Optional referrer of the referral account (earns a referral fee).
+        let spending_policy = load_spending_policy(
+            &ctx.accounts.spending_policy,
+            &ctx.accounts.market.order_payer_token_account,
+            &ctx.accounts.authority,
+        )?;
+        let referral = Referral::new(&ctx.accounts.referral, ctx.remaining_accounts);
+
+        let orderbook: OrderbookClient<'info> = (&*ctx.accounts).into();
+        execute_swap(
+            &orderbook,
+            &ctx.accounts.protocol_fee(),
+            &ctx.accounts.event_cpi,
+            side,
+            amount,
+            min_exchange_rate,
+            SwapOptions {
+                user_stats: Some(&ctx.accounts.user_stats),
+                platform_fee: Some(&ctx.accounts.platform_fee(platform_fee_bps)?),
+                chunks: Some(&chunks),
+                spending_policy,
+                referral,
+                tag,
+                ..Default::default()
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Swaps two base currencies across two different markets.
+    ///
+    /// That is, suppose there are two markets, A/USD(x) and B/USD(x).
+    /// Then swaps token A for token B via
+    ///
+    /// * IOC (immediate or cancel) sell order on A/USD(x) market.
+    /// * Settle open orders to get USD(x).
+    /// * IOC buy order on B/USD(x) market to convert USD(x) to token B.
+    /// * Settle open orders to get token B.
+    ///
+    /// Arguments:
+    ///
+    /// * `amount`            - The amount to swap *from*, or `ENTIRE_BALANCE`
+    ///    to swap the entire balance of the order payer.
+    /// * `min_exchange_rate` - The exchange rate to use when determining
+    ///    whether the transaction should abort.
+    #[access_control(
+        is_valid_swap_transitive(&ctx)
+        is_not_paused(&ctx.accounts.config)
+    )]
+    pub fn swap_transitive<'info>(
+        ctx: Context<'_, '_, '_, 'info, SwapTransitive<'info>>,
+        amount: u64,
+        min_exchange_rate: ExchangeRate,
+    ) -> Result<()> {
+        execute_swap_transitive(
+            ctx,
+            SwapTransitiveArgsV2 {
+                amount,
+                min_exchange_rate,
+                reswap: None,
+                leg_rates: None,
+                max_quote_amount: None,
+                platform_fee_bps: None,
+                oracle_bands: None,
+                tag: None,
+                allow_zero_output: None,
+                transfer_spill: None,
+                spill_handling: None,
+            },
         )
-        .unwrap()
-        .checked_mul(
-            // decimals(to) + decimals(from) + decimals(quote).
-            10u128
-                .checked_pow(event.min_exchange_rate.quote_decimals.into())
-                .unwrap(),
+    }
+
+    /// Swaps like `swap_transitive`, with its arguments and options given as
+    /// a single struct, like `swap_v2`.
+    ///
+    /// Its larger arguments leave little room in the transaction: the
+    /// accounts of the features not in use should be given as this program's
+    /// ID, as documented above, to make room for a compute budget, stats or a
+    /// referral.
+    ///
+    /// Options left `None` don't apply, unless documented otherwise:
+    ///
+    /// * `reswap`            - Optionally re-runs the buy leg on any quote
+    ///    currency it didn't consume (e.g. due to lot rounding).
+    /// * `leg_rates`         - Optional minimum exchange rates for each leg,
+    ///    aborting if either leg individually executes below them.
+    /// * `max_quote_amount`  - Optional cap on the quote currency spent by
+    ///    the buy leg. Proceeds above the cap are left as spill.
+    /// * `platform_fee_bps`  - Share of the output paid to the integrator's
+    ///    `platform_fee_account`, net of the protocol fee. Zero if `None`.
+    /// * `oracle_bands`      - Optionally aborts if either leg's execution
+    ///    price deviates too far from its market's Pyth price account, given
+    ///    as the first two remaining accounts (*from*, then *to*).
+    /// * `tag`               - Optional client data echoed in the `DidSwap`
+    ///    event.
+    /// * `allow_zero_output` - If true and nothing fills, succeeds without
+    ///    swapping instead of aborting.
+    /// * `transfer_spill`    - If true, transfers the spill out of the
+    ///    `pc_wallet` into the quote currency token account given after the
+    ///    oracles' price accounts in the remaining accounts.
+    /// * `spill_handling`    - Optionally governs both how the spill is
+    ///    credited against the rate and what happens to it, superseding the
+    ///    rate's `strict` flag. `reswap` must then be `None` and
+    ///    `transfer_spill` false.
+    ///
+    /// Arguments:
+    ///
+    /// * `args` - The swap's arguments, with the same semantics as the
+    ///            `swap_transitive` instruction unless documented otherwise.
+    #[access_control(
+        is_valid_swap_transitive(&ctx)
+        is_not_paused(&ctx.accounts.config)
+    )]
+    pub fn swap_transitive_v2<'info>(
+        ctx: Context<'_, '_, '_, 'info, SwapTransitive<'info>>,
+        args: SwapTransitiveArgsV2,
+    ) -> Result<()> {
+        execute_swap_transitive(ctx, args)
+    }
+
+    /// Swaps two quote currencies across two markets sharing the same base
+    /// currency, i.e., the mirror image of `swap_transitive`.
+    ///
+    /// That is, suppose there are two markets, X/USD(x) and X/USD(y). Then
+    /// swaps USD(x) for USD(y) via
+    ///
+    /// * IOC buy order on X/USD(x), then settle to get token X.
+    /// * IOC sell order on X/USD(y), then settle to get USD(y).
+    ///
+    /// Both market account groups must share the same `coin_wallet`. The
+    /// quote fields of the `DidSwap` event are given in the shared base
+    /// currency, with the spill being the base currency bought but *not*
+    /// sold, e.g., due to lot rounding. There's no referral, since the DEX
+    /// pays referral fees in each market's own quote currency.
+    ///
+    /// Arguments:
+    ///
+    /// * `amount`            - The amount to swap *from*, or `ENTIRE_BALANCE`
+    ///    to swap the entire balance of the order payer.
+    /// * `min_exchange_rate` - The exchange rate to use when determining
+    ///    whether the transaction should abort.
+    /// * `platform_fee_bps`  - Share of the output paid to the integrator's
+    ///    `platform_fee_account`, net of the protocol fee. Zero for none.
+    /// * `tag`               - Optional client data echoed in the `DidSwap`
+    ///    event.
+    #[access_control(
+        is_valid_swap_quote_to_quote(&ctx)
+        is_not_paused(&ctx.accounts.config)
+    )]
+    pub fn swap_quote_to_quote<'info>(
+        ctx: Context<'_, '_, '_, 'info, SwapQuoteToQuote<'info>>,
+        amount: u64,
+        min_exchange_rate: ExchangeRate,
+        platform_fee_bps: u16,
+        tag: Option<[u8; 32]>,
+    ) -> Result<()> {
+        risk::validate(&min_exchange_rate, true)?;
+        check_whitelisted(
+            &ctx.accounts.config,
+            &ctx.accounts.whitelist,
+            &[&ctx.accounts.from.market, &ctx.accounts.to.market],
+        )?;
+
+        let amount = resolve_amount(
+            amount,
+            &ctx.accounts.authority,
+            &ctx.accounts.from.order_payer_token_account,
+        )?;
+
+        // Leg 1: Buy token X with USD(x).
+        let (from_amount, buy_proceeds, buy_order) = execute_trade(
+            &ctx.accounts.orderbook_from(),
+            &Side::Bid,
+            amount,
+            None,
+            None,
+        )?;
+        emit_event(
+            &ctx.accounts.event_cpi,
+            &DidSwapLeg {
+                market: *ctx.accounts.from.market.key,
+                side: Side::Bid,
+                base_delta: buy_proceeds,
+                quote_delta: from_amount,
+                price: price(from_amount, buy_proceeds),
+                taker_fee: buy_order.taker_fee,
+                book: buy_order.book,
+            },
+        )?;
+
+        // Leg 2: Sell token X for USD(y).
+        let orderbook = ctx.accounts.orderbook_to();
+        let (sell_amount, to_amount, sell_order) =
+            execute_trade(&orderbook, &Side::Ask, buy_proceeds, None, None)?;
+        emit_event(
+            &ctx.accounts.event_cpi,
+            &DidSwapLeg {
+                market: *ctx.accounts.to.market.key,
+                side: Side::Ask,
+                base_delta: sell_amount,
+                quote_delta: to_amount,
+                price: price(to_amount, sell_amount),
+                taker_fee: sell_order.taker_fee,
+                book: sell_order.book,
+            },
+        )?;
+
+        let from_quote_mint = token::accessor::mint(&ctx.accounts.from_pc_wallet)?;
+        let to_quote_mint = token::accessor::mint(&ctx.accounts.to_pc_wallet)?;
+        // Each leg trades the same value, so only the first counts.
+        record_user_volume(
+            &ctx.accounts.user_stats,
+            &ctx.accounts.authority,
+            from_quote_mint,
+            from_amount,
+        )?;
+        let spending_policy = load_spending_policy(
+            &ctx.accounts.spending_policy,
+            &ctx.accounts.from.order_payer_token_account,
+            &ctx.accounts.authority,
+        )?;
+        record_spending(
+            spending_policy,
+            &ctx.accounts.authority,
+            from_quote_mint,
+            from_amount,
+        )?;
+        record_spending(
+            spending_policy,
+            &ctx.accounts.authority,
+            to_quote_mint,
+            to_amount,
+        )?;
+
+        // The amount of token X *not* sold by the second leg.
+        let spill_amount = buy_proceeds.checked_sub(sell_amount).unwrap();
+
+        // Skim the protocol fee off the output.
+        let protocol_fee =
+            ctx.accounts
+                .protocol_fee()
+                .take(&orderbook, &ctx.accounts.to_pc_wallet, to_amount)?;
+        let to_amount = to_amount.checked_sub(protocol_fee).unwrap();
+
+        // Pay the integrator its share of what's left.
+        let platform_fee = ctx.accounts.platform_fee(platform_fee_bps)?.take(
+            &orderbook,
+            &ctx.accounts.to_pc_wallet,
+            to_amount,
+        )?;
+        let to_amount = to_amount.checked_sub(platform_fee).unwrap();
+
+        // Safety checks.
+        apply_risk_checks(
+            &ctx.accounts.event_cpi,
+            DidSwap {
+                given_amount: amount,
+                min_exchange_rate,
+                from_amount,
+                to_amount,
+                quote_amount: buy_proceeds,
+                spill_amount,
+                protocol_fee,
+                platform_fee,
+                from_mint: token::accessor::mint(&ctx.accounts.from_pc_wallet)?,
+                to_mint: token::accessor::mint(&ctx.accounts.to_pc_wallet)?,
+                quote_mint: token::accessor::mint(&ctx.accounts.from.coin_wallet)?,
+                authority: *ctx.accounts.authority.key,
+                side: None,
+                effective_price: price(to_amount, from_amount),
+                order_base_qty: sell_order.qty,
+                order_quote_qty: buy_order.qty,
+                client_order_id: CLIENT_ORDER_ID,
+                tag,
+                // Paid in different quote currencies, so only reported per leg.
+                taker_fee: 0,
+                referrer_rebate: 0,
+                book: None,
+                unfilled_amount: 0,
+            },
+            false,
+        )?;
+
+        Ok(())
+    }
+
+    /// Buys the base currency on one market and sells it on another trading
+    /// the same pair, aborting unless the quote currency received exceeds the
+    /// quote currency spent by more than `min_profit`, e.g., for searchers to
+    /// close a price gap between two markets without risking a loss.
+    ///
+    /// That is, suppose there are two markets, X/USD(1) and X/USD(2). Then
+    ///
+    /// * IOC buy order on X/USD(1), then settle to get token X.
+    /// * IOC sell order on X/USD(2) of all the X bought, then settle.
+    ///
+    /// Both market account groups must share the same `coin_wallet` and, as
+    /// there's a single `pc_wallet`, both markets must be quoted in the same
+    /// currency. Arbitrage across quote currencies, e.g., X/USD(x) against
+    /// X/USD(y), isn't supported, since its profit would be in neither. Any
+    /// base currency bought but not sold, due to lot rounding, stays in the
+    /// `coin_wallet` and isn't counted towards the profit. The profit is net
+    /// of the DEX's and the protocol's fees, the latter being skimmed off the
+    /// sale.
+    ///
+    /// Arguments:
+    ///
+    /// * `amount`     - The amount of the quote currency to buy with, or
+    ///    `ENTIRE_BALANCE` to buy with the entire balance of the `pc_wallet`.
+    /// * `min_profit` - The quote currency the arbitrage must gain more than.
+    #[access_control(is_valid_arbitrage(&ctx) is_not_paused(&ctx.accounts.config))]
+    pub fn arbitrage<'info>(
+        ctx: Context<'_, '_, '_, 'info, Arbitrage<'info>>,
+        amount: u64,
+        min_profit: u64,
+    ) -> Result<()> {
+        check_whitelisted(
+            &ctx.accounts.config,
+            &ctx.accounts.whitelist,
+            &[&ctx.accounts.buy.market, &ctx.accounts.sell.market],
+        )?;
+
+        let amount = resolve_amount(
+            amount,
+            &ctx.accounts.authority,
+            &ctx.accounts.buy.order_payer_token_account,
+        )?;
+
+        // Leg 1: Buy token X on the first market.
+        let (from_amount, buy_proceeds, buy_order) = execute_trade(
+            &ctx.accounts.orderbook(&ctx.accounts.buy),
+            &Side::Bid,
+            amount,
+            None,
+            None,
+        )?;
+        emit_event(
+            &ctx.accounts.event_cpi,
+            &DidSwapLeg {
+                market: *ctx.accounts.buy.market.key,
+                side: Side::Bid,
+                base_delta: buy_proceeds,
+                quote_delta: from_amount,
+                price: price(from_amount, buy_proceeds),
+                taker_fee: buy_order.taker_fee,
+                book: buy_order.book,
+            },
+        )?;
+
+        // Leg 2: Sell it on the second.
+        let orderbook = ctx.accounts.orderbook(&ctx.accounts.sell);
+        let (sell_amount, to_amount, sell_order) =
+            execute_trade(&orderbook, &Side::Ask, buy_proceeds, None, None)?;
+        emit_event(
+            &ctx.accounts.event_cpi,
+            &DidSwapLeg {
+                market: *ctx.accounts.sell.market.key,
+                side: Side::Ask,
+                base_delta: sell_amount,
+                quote_delta: to_amount,
+                price: price(to_amount, sell_amount),
+                taker_fee: sell_order.taker_fee,
+                book: sell_order.book,
+            },
+        )?;
+
+        let quote_mint = token::accessor::mint(&ctx.accounts.pc_wallet)?;
+        let spending_policy = load_spending_policy(
+            &ctx.accounts.spending_policy,
+            &ctx.accounts.buy.order_payer_token_account,
+            &ctx.accounts.authority,
+        )?;
+        // One swap of the quote currency spent, the sale only unwinding it.
+        record_user_volume(
+            &ctx.accounts.user_stats,
+            &ctx.accounts.authority,
+            quote_mint,
+            from_amount,
+        )?;
+        record_spending(
+            spending_policy,
+            &ctx.accounts.authority,
+            quote_mint,
+            from_amount,
+        )?;
+
+        // Skim the protocol fee off the sale.
+        let protocol_fee =
+            ctx.accounts
+                .protocol_fee()
+                .take(&orderbook, &ctx.accounts.pc_wallet, to_amount)?;
+        let to_amount = to_amount.checked_sub(protocol_fee).unwrap();
+
+        let profit = to_amount.saturating_sub(from_amount);
+        if profit <= min_profit {
+            msg!("from_amount: {:?}, to_amount: {:?}", from_amount, to_amount);
+            return Err(ErrorCode::ArbitrageUnprofitable.into());
+        }
+
+        emit_event(
+            &ctx.accounts.event_cpi,
+            &DidArbitrage {
+                authority: *ctx.accounts.authority.key,
+                buy_market: *ctx.accounts.buy.market.key,
+                sell_market: *ctx.accounts.sell.market.key,
+                quote_mint,
+                base_amount: sell_amount,
+                spill_amount: buy_proceeds.checked_sub(sell_amount).unwrap(),
+                from_amount,
+                to_amount,
+                protocol_fee,
+                profit,
+            },
         )
-        .unwrap();
+    }
 
-        to_amount.checked_add(spill_surplus).unwrap()
-    };
+    /// Swaps a single quote currency into a basket of base currencies, i.e.,
+    /// given markets A/USD(x), B/USD(x), ..., spends `amount` of USD(x)
+    /// across all of them according to `weights`, buying each base currency
+    /// with an IOC order that instantly settles.
+    ///
+    /// The market account groups are given as remaining accounts, one
+    /// `SwapBasketLeg` group per weight, in the same order. An optional
+    /// referral account may follow the last group.
+    ///
+    /// Arguments:
+    ///
+    /// * `amount`      - The total amount of the quote currency to spend.
+    /// * `weights`     - The relative share of `amount` spent on each market.
+    /// * `min_amounts` - The minimum amount of each base currency to receive,
+    ///                   in native units. The transaction aborts if any
+    ///                   output falls short.
+    /// * `tag`         - Optional client data echoed in each `DidSwap` event.
+    #[access_control(is_not_paused(&ctx.accounts.config))]
+    pub fn swap_basket<'info>(
+        ctx: Context<'_, '_, '_, 'info, SwapBasket<'info>>,
+        amount: u64,
+        weights: Vec<u64>,
+        min_amounts: Vec<u64>,
+        tag: Option<[u8; 32]>,
+    ) -> Result<()> {
+        if weights.len() != min_amounts.len() {
+            return Err(ErrorCode::InvalidWeights.into());
+        }
 
-    // Abort if the resulting amount is less than the client's expectation.
-    if effective_to_amount < min_expected_amount {
-        msg!(
-            "effective_to_amount, min_expected_amount: {:?}, {:?}",
-            effective_to_amount,
-            min_expected_amount,
-        );
-        return Err(ErrorCode::SlippageExceeded.into());
+        // Each market group is followed by an optional referral account.
+        let mut remaining_accounts = ctx.remaining_accounts;
+        let mut legs = Vec::with_capacity(weights.len());
+        for _ in 0..weights.len() {
+            let leg = SwapBasketLeg::try_accounts(ctx.program_id, &mut remaining_accounts, &[])?;
+            legs.push(leg);
+        }
+        let referral = Referral::from_accounts(remaining_accounts);
+        // There's a single policy, so each market's order payer must be
+        // owned by the same key.
+        let mut spending_policy = None;
+        for leg in &legs {
+            spending_policy = load_spending_policy(
+                &ctx.accounts.spending_policy,
+                &leg.market.order_payer_token_account,
+                &ctx.accounts.authority,
+            )?;
+        }
+        check_whitelisted(
+            &ctx.accounts.config,
+            &ctx.accounts.whitelist,
+            &legs
+                .iter()
+                .map(|leg| &leg.market.market)
+                .collect::<Vec<_>>(),
+        )?;
+
+        let quote_amounts = match legs.first() {
+            Some(leg) => split_amount(
+                resolve_amount(
+                    amount,
+                    &ctx.accounts.authority,
+                    &leg.market.order_payer_token_account,
+                )?,
+                &weights,
+            )?,
+            None => return Err(ErrorCode::InvalidWeights.into()),
+        };
+
+        let quote_mint = token::accessor::mint(&ctx.accounts.pc_wallet)?;
+        let mut quote_volume: u64 = 0;
+
+        for ((leg, quote_amount), min_amount) in
+            legs.into_iter().zip(quote_amounts).zip(min_amounts)
+        {
+            let market = leg.market;
+            let to_mint = token::accessor::mint(&market.coin_wallet)?;
+            if to_mint == quote_mint {
+                return Err(ErrorCode::SwapTokensCannotMatch.into());
+            }
+
+            let orderbook = ctx.accounts.orderbook(market.clone());
+            let (from_amount, to_amount, order) =
+                execute_trade(&orderbook, &Side::Bid, quote_amount, None, referral.clone())?;
+            quote_volume = quote_volume.checked_add(from_amount).unwrap();
+
+            // Skim the protocol fee off the output.
+            let protocol_fee = ctx.accounts.protocol_fee(leg.fee_vault).take(
+                &orderbook,
+                &market.coin_wallet,
+                to_amount,
+            )?;
+            let to_amount = to_amount.checked_sub(protocol_fee).unwrap();
+
+            emit_event(
+                &ctx.accounts.event_cpi,
+                &DidSwap {
+                    given_amount: quote_amount,
+                    min_exchange_rate: ExchangeRate {
+                        rate: 0,
+                        from_decimals: 0,
+                        quote_decimals: 0,
+                        strict: false,
+                    },
+                    from_amount,
+                    to_amount,
+                    quote_amount: 0,
+                    spill_amount: 0,
+                    protocol_fee,
+                    platform_fee: 0,
+                    from_mint: quote_mint,
+                    to_mint,
+                    quote_mint,
+                    authority: *ctx.accounts.authority.key,
+                    side: Some(Side::Bid),
+                    effective_price: price(to_amount, from_amount),
+                    order_base_qty: 0,
+                    order_quote_qty: order.qty,
+                    client_order_id: CLIENT_ORDER_ID,
+                    tag,
+                    taker_fee: order.taker_fee,
+                    referrer_rebate: order.referrer_rebate,
+                    book: order.book,
+                    unfilled_amount: 0,
+                },
+            )?;
+
+            // Safety checks.
+            if to_amount == 0 {
+                return Err(ErrorCode::ZeroSwap.into());
+            }
+            if to_amount < min_amount {
+                msg!("to_amount, min_amount: {:?}, {:?}", to_amount, min_amount);
+                return Err(slippage_exceeded(SwapFailed {
+                    authority: *ctx.accounts.authority.key,
+                    from_mint: quote_mint,
+                    to_mint,
+                    from_amount,
+                    to_amount,
+                    quote_amount: 0,
+                    spill_amount: 0,
+                    effective_to_amount: to_amount.into(),
+                    min_expected_amount: min_amount.into(),
+                }));
+            }
+        }
+
+        record_user_volume(
+            &ctx.accounts.user_stats,
+            &ctx.accounts.authority,
+            quote_mint,
+            quote_volume,
+        )?;
+        record_spending(
+            spending_policy,
+            &ctx.accounts.authority,
+            quote_mint,
+            quote_volume,
+        )?;
+
+        Ok(())
+    }
+
+    /// Executes a batch of independent direct swaps, each on its own market,
+    /// within a single instruction. Either all of the swaps succeed or the
+    /// entire instruction aborts.
+    ///
+    /// The accounts for each swap are given as remaining accounts, one
+    /// `SwapManyLeg` group per entry in `swaps`, in the same order. An
+    /// optional referral account may follow the last group.
+    ///
+    /// Arguments:
+    ///
+    /// * `swaps` - The side, amount, minimum exchange rate, and tag of each
+    ///             swap, with the same semantics as the `swap` instruction.
+    #[access_control(is_not_paused(&ctx.accounts.config))]
+    pub fn swap_many<'info>(
+        ctx: Context<'_, '_, '_, 'info, SwapMany<'info>>,
+        swaps: Vec<SwapParams>,
+    ) -> Result<()> {
+        // Each leg is followed by an optional referral account.
+        let mut remaining_accounts = ctx.remaining_accounts;
+        let mut legs = Vec::with_capacity(swaps.len());
+        for _ in 0..swaps.len() {
+            let leg = SwapManyLeg::try_accounts(ctx.program_id, &mut remaining_accounts, &[])?;
+            _is_valid_swap(&leg.market.coin_wallet, &leg.pc_wallet)?;
+            legs.push(leg);
+        }
+        let referral = Referral::from_accounts(remaining_accounts);
+        check_whitelisted(
+            &ctx.accounts.config,
+            &ctx.accounts.whitelist,
+            &legs
+                .iter()
+                .map(|leg| &leg.market.market)
+                .collect::<Vec<_>>(),
+        )?;
+
+        for (leg, params) in legs.into_iter().zip(swaps) {
+            let protocol_fee = ctx.accounts.protocol_fee(leg.fee_vault.clone());
+            let spending_policy_account = leg.spending_policy.clone();
+            let spending_policy = load_spending_policy(
+                &spending_policy_account,
+                &leg.market.order_payer_token_account,
+                &ctx.accounts.authority,
+            )?;
+            let orderbook = ctx.accounts.orderbook(leg);
+            execute_swap(
+                &orderbook,
+                &protocol_fee,
+                &ctx.accounts.event_cpi,
+                params.side,
+                params.amount,
+                params.min_exchange_rate,
+                SwapOptions {
+                    user_stats: Some(&ctx.accounts.user_stats),
+                    spending_policy,
+                    max_quote_amount: params.max_quote_amount,
+                    referral: referral.clone(),
+                    tag: params.tag,
+                    allow_zero_output: params.allow_zero_output,
+                    allow_partial: params.allow_partial,
+                    max_price_impact_bps: params.max_price_impact_bps,
+                    ..Default::default()
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Swaps two tokens on a single A/B pair, splitting the input across
+    /// multiple markets for that pair, e.g., a Serum v3 and an OpenBook market,
+    /// to reduce price impact. Each portion is a direct IOC trade that
+    /// instantly settles.
+    ///
+    /// The market account groups are given as remaining accounts, one
+    /// `MarketAccounts` group per weight, in the same order. An optional
+    /// referral account may follow the last group. All markets must have the
+    /// same base and quote currencies. When selling, a portion smaller than
+    /// its market's lot size isn't swapped.
+    ///
+    /// Arguments:
+    ///
+    /// * `side`       - The direction to swap.
+    /// * `amount`     - The total amount to swap *from*.
+    /// * `weights`    - The relative share of `amount` swapped on each market.
+    /// * `min_output` - The minimum total amount of the *to* token to receive,
+    ///                  in native units, across all markets.
+    /// * `tag`        - Optional client data echoed in the `DidSwap` event.
+    #[access_control(is_not_paused(&ctx.accounts.config))]
+    pub fn swap_split<'info>(
+        ctx: Context<'_, '_, '_, 'info, SwapSplit<'info>>,
+        side: Side,
+        amount: u64,
+        weights: Vec<u64>,
+        min_output: u64,
+        tag: Option<[u8; 32]>,
+    ) -> Result<()> {
+        // Each market group is followed by an optional referral account.
+        let mut remaining_accounts = ctx.remaining_accounts;
+        let mut markets = Vec::with_capacity(weights.len());
+        for _ in 0..weights.len() {
+            let market =
+                MarketAccounts::try_accounts(ctx.program_id, &mut remaining_accounts, &[])?;
+            markets.push(market);
+        }
+        let referral = Referral::from_accounts(remaining_accounts);
+        // There's a single policy, so each market's order payer must be
+        // owned by the same key.
+        let mut spending_policy = None;
+        for market in &markets {
+            spending_policy = load_spending_policy(
+                &ctx.accounts.spending_policy,
+                &market.order_payer_token_account,
+                &ctx.accounts.authority,
+            )?;
+        }
+        check_whitelisted(
+            &ctx.accounts.config,
+            &ctx.accounts.whitelist,
+            &markets
+                .iter()
+                .map(|market| &market.market)
+                .collect::<Vec<_>>(),
+        )?;
+
+        let amounts = match markets.first() {
+            Some(market) => split_amount(
+                resolve_amount(
+                    amount,
+                    &ctx.accounts.authority,
+                    &market.order_payer_token_account,
+                )?,
+                &weights,
+            )?,
+            None => return Err(ErrorCode::InvalidWeights.into()),
+        };
+
+        // All markets must trade the same pair.
+        let coin_mint = token::accessor::mint(&markets[0].coin_wallet)?;
+        let pc_mint = token::accessor::mint(&ctx.accounts.pc_wallet)?;
+        if coin_mint == pc_mint {
+            return Err(ErrorCode::SwapTokensCannotMatch.into());
+        }
+        for market in &markets[1..] {
+            if token::accessor::mint(&market.coin_wallet)? != coin_mint {
+                return Err(ErrorCode::SplitMarketsMismatch.into());
+            }
+        }
+
+        // Execute each portion, accumulating the amounts swapped. The
+        // protocol fee is skimmed off the output of each, out of the wallet
+        // it was received in.
+        let mut from_amount: u64 = 0;
+        let mut to_amount: u64 = 0;
+        let mut protocol_fee: u64 = 0;
+        let mut order = Order::default();
+        for (market, leg_amount) in markets.into_iter().zip(amounts) {
+            let orderbook = ctx.accounts.orderbook(market);
+            // A sale of less than a lot can't be sent, so that portion
+            // isn't swapped, like any remainder of the lot rounding.
+            if matches!(side, Side::Ask) && leg_amount < orderbook.lot_sizes()?.0 {
+                continue;
+            }
+            let (leg_from_amount, leg_to_amount, leg_order) =
+                execute_trade(&orderbook, &side, leg_amount, None, referral.clone())?;
+            let (_, to_wallet) = orderbook.wallets(&side);
+            let leg_protocol_fee =
+                ctx.accounts
+                    .protocol_fee()
+                    .take(&orderbook, to_wallet, leg_to_amount)?;
+            from_amount = from_amount.checked_add(leg_from_amount).unwrap();
+            to_amount = to_amount.checked_add(leg_to_amount).unwrap();
+            protocol_fee = protocol_fee.checked_add(leg_protocol_fee).unwrap();
+            order = order.merge(leg_order);
+        }
+
+        let quote_volume = match side {
+            Side::Bid => from_amount,
+            Side::Ask => to_amount,
+        };
+        record_user_volume(
+            &ctx.accounts.user_stats,
+            &ctx.accounts.authority,
+            pc_mint,
+            quote_volume,
+        )?;
+        record_spending(
+            spending_policy,
+            &ctx.accounts.authority,
+            pc_mint,
+            quote_volume,
+        )?;
+
+        let to_amount = to_amount.checked_sub(protocol_fee).unwrap();
+
+        let (from_mint, to_mint, order_base_qty, order_quote_qty) = match side {
+            Side::Bid => (pc_mint, coin_mint, 0, order.qty),
+            Side::Ask => (coin_mint, pc_mint, order.qty, 0),
+        };
+        emit_event(
+            &ctx.accounts.event_cpi,
+            &DidSwap {
+                given_amount: amount,
+                min_exchange_rate: ExchangeRate {
+                    rate: 0,
+                    from_decimals: 0,
+                    quote_decimals: 0,
+                    strict: false,
+                },
+                from_amount,
+                to_amount,
+                quote_amount: 0,
+                spill_amount: 0,
+                protocol_fee,
+                platform_fee: 0,
+                from_mint,
+                to_mint,
+                quote_mint: pc_mint,
+                authority: *ctx.accounts.authority.key,
+                side: Some(side),
+                effective_price: price(to_amount, from_amount),
+                order_base_qty,
+                order_quote_qty,
+                client_order_id: CLIENT_ORDER_ID,
+                tag,
+                taker_fee: order.taker_fee,
+                referrer_rebate: order.referrer_rebate,
+                book: order.book,
+                unfilled_amount: 0,
+            },
+        )?;
+
+        // Safety checks.
+        if to_amount == 0 {
+            return Err(ErrorCode::ZeroSwap.into());
+        }
+        if to_amount < min_output {
+            msg!("to_amount, min_output: {:?}, {:?}", to_amount, min_output);
+            return Err(slippage_exceeded(SwapFailed {
+                authority: *ctx.accounts.authority.key,
+                from_mint,
+                to_mint,
+                from_amount,
+                to_amount,
+                quote_amount: 0,
+                spill_amount: 0,
+                effective_to_amount: to_amount.into(),
+                min_expected_amount: min_output.into(),
+            }));
+        }
+
+        Ok(())
+    }
+
+    /// Initializes the global config, making the signer its admin. Only the
+    /// program's upgrade authority, i.e., its deployer, may call it, so the
+    /// config can't be taken over between the deployment and its
+    /// initialization.
+    ///
+    /// Arguments:
+    ///
+    /// * `bump`    - The bump seed of the config PDA.
+    /// * `fee_bps` - The protocol fee skimmed off the output of every swap.
+    pub fn initialize_config(ctx: Context<InitializeConfig>, bump: u8, fee_bps: u16) -> Result<()> {
+        let upgrade_authority = ctx.accounts.program_data.upgrade_authority_address;
+        if upgrade_authority != Some(*ctx.accounts.admin.key) {
+            return Err(ErrorCode::NotUpgradeAuthority.into());
+        }
+        is_valid_fee(fee_bps)?;
+        let config = &mut ctx.accounts.config;
+        config.admin = *ctx.accounts.admin.key;
+        config.fee_bps = fee_bps;
+        config.bump = bump;
+        Ok(())
+    }
+
+    /// Creates the protocol fee vault for a mint, i.e., the token account
+    /// collecting the protocol fees for swaps *to* that mint.
+    ///
+    /// Arguments:
+    ///
+    /// * `_bump` - The bump seed of the fee vault PDA.
+    pub fn create_fee_vault(_ctx: Context<CreateFeeVault>, _bump: u8) -> Result<()> {
+        Ok(())
     }
 
-    Ok(())
+    /// Creates the flash vault for a mint, i.e., the token account lending
+    /// the output of flash swaps *to* that mint, which the admin funds with
+    /// plain transfers and withdraws from via `collect_fees`. Admin only.
+    ///
+    /// Arguments:
+    ///
+    /// * `_bump` - The bump seed of the flash vault PDA.
+    pub fn create_flash_vault(_ctx: Context<CreateFlashVault>, _bump: u8) -> Result<()> {
+        Ok(())
+    }
+
+    /// Sets the protocol fee. Admin only.
+    pub fn set_fee(ctx: Context<UpdateConfig>, fee_bps: u16) -> Result<()> {
+        is_valid_fee(fee_bps)?;
+        ctx.accounts.config.fee_bps = fee_bps;
+        Ok(())
+    }
+
+    /// Transfers the admin role to `admin`. Admin only.
+    pub fn set_admin(ctx: Context<UpdateConfig>, admin: Pubkey) -> Result<()> {
+        ctx.accounts.config.admin = admin;
+        Ok(())
+    }
+
+    /// Sets the guardian allowed to pause and unpause trading, alongside the
+    /// admin. Admin only.
+    pub fn set_guardian(ctx: Context<UpdateConfig>, guardian: Pubkey) -> Result<()> {
+        ctx.accounts.config.guardian = guardian;
+        Ok(())
+    }
+
+    /// Whitelists a program to be used as a swap hook. Admin only.
+    pub fn add_hook_program(ctx: Context<UpdateConfig>, program: Pubkey) -> Result<()> {
+        let hook_programs = &mut ctx.accounts.config.hook_programs;
+        if hook_programs.contains(&program) {
+            return Ok(());
+        }
+        if hook_programs.len() >= MAX_HOOK_PROGRAMS {
+            return Err(ErrorCode::HookProgramsFull.into());
+        }
+        hook_programs.push(program);
+        Ok(())
+    }
+
+    /// Removes a program from the swap hook whitelist. Admin only.
+    pub fn remove_hook_program(ctx: Context<UpdateConfig>, program: Pubkey) -> Result<()> {
+        ctx.accounts.config.hook_programs.retain(|p| p != &program);
+        Ok(())
+    }
+
+    /// Sets the execution defaults of every order sent to the DEX, or resets
+    /// them if none. Admin only.
+    pub fn set_execution_defaults(
+        ctx: Context<UpdateConfig>,
+        execution: Option<ExecutionDefaults>,
+    ) -> Result<()> {
+        if let Some(execution) = &execution {
+            if execution.match_limit == 0 || execution.dex_programs.len() > MAX_DEX_PROGRAMS {
+                return Err(ErrorCode::InvalidExecutionDefaults.into());
+            }
+        }
+        ctx.accounts.config.execution = execution;
+        Ok(())
+    }
+
+    /// Creates the crank tip, paid to whoever cranks an event queue via
+    /// `consume_events`, which can't be called before, along with its vault
+    /// of the `mint`, which the admin funds with plain transfers. Admin only.
+    ///
+    /// Arguments:
+    ///
+    /// * `bump`         - The bump seed of the crank tip PDA.
+    /// * `_vault_bump`  - The bump seed of the crank tip vault PDA.
+    /// * `amount`       - The tip per event consumed, or zero for none.
+    /// * `max_events`   - The most events tipped per crank.
+    /// * `min_events`   - The fewest events a crank must consume to be tipped.
+    /// * `max_per_slot` - The most paid in tips within a slot.
+    ///
+    /// No market is tipped until added via `set_tipped_markets`.
+    pub fn create_crank_tip(
+        ctx: Context<CreateCrankTip>,
+        bump: u8,
+        _vault_bump: u8,
+        amount: u64,
+        max_events: u16,
+        min_events: u16,
+        max_per_slot: u64,
+    ) -> Result<()> {
+        let crank_tip = &mut ctx.accounts.crank_tip;
+        crank_tip.mint = ctx.accounts.mint.key();
+        crank_tip.amount = amount;
+        crank_tip.max_events = max_events;
+        crank_tip.min_events = min_events;
+        crank_tip.bump = bump;
+        crank_tip.max_per_slot = max_per_slot;
+        Ok(())
+    }
+
+    /// Updates the crank tip, or stops tipping if `amount` is zero. Its mint,
+    /// that of the vault, can't change. Admin only.
+    pub fn set_crank_tip(
+        ctx: Context<UpdateCrankTip>,
+        amount: u64,
+        max_events: u16,
+        min_events: u16,
+        max_per_slot: u64,
+    ) -> Result<()> {
+        let crank_tip = &mut ctx.accounts.crank_tip;
+        crank_tip.amount = amount;
+        crank_tip.max_events = max_events;
+        crank_tip.min_events = min_events;
+        crank_tip.max_per_slot = max_per_slot;
+        Ok(())
+    }
+
+    /// Sets the markets whose cranks are tipped, replacing any previous ones.
+    /// Admin only.
+    pub fn set_tipped_markets(ctx: Context<UpdateCrankTip>, markets: Vec<Pubkey>) -> Result<()> {
+        if markets.len() > MAX_TIPPED_MARKETS {
+            return Err(ErrorCode::TooManyTippedMarkets.into());
+        }
+        ctx.accounts.crank_tip.markets = markets;
+        Ok(())
+    }
+
+    /// Pauses all swap instructions, e.g., during a DEX incident. Open orders
+    /// accounts can still be closed. Guardian or admin only.
+    pub fn pause(ctx: Context<SetPaused>) -> Result<()> {
+        ctx.accounts.config.paused = true;
+        Ok(())
+    }
+
+    /// Resumes trading after a `pause`. Guardian or admin only.
+    pub fn unpause(ctx: Context<SetPaused>) -> Result<()> {
+        ctx.accounts.config.paused = false;
+        Ok(())
+    }
+
+    /// Creates the market whitelist and enables it, restricting all swaps to
+    /// the markets added via `add_market`. Admin only.
+    ///
+    /// Arguments:
+    ///
+    /// * `_bump` - The bump seed of the whitelist PDA.
+    pub fn create_whitelist(ctx: Context<CreateWhitelist>, _bump: u8) -> Result<()> {
+        ctx.accounts.config.whitelist = ctx.accounts.whitelist.key();
+        Ok(())
+    }
+
+    /// Enables or disables the whitelist. Admin only.
+    pub fn set_whitelist_enabled(ctx: Context<SetWhitelistEnabled>, enabled: bool) -> Result<()> {
+        ctx.accounts.config.whitelist = match enabled {
+            true => ctx.accounts.whitelist.key(),
+            false => Pubkey::default(),
+        };
+        Ok(())
+    }
+
+    /// Adds a market to the whitelist. Admin only.
+    pub fn add_market(ctx: Context<UpdateWhitelist>, market: Pubkey) -> Result<()> {
+        let markets = &mut ctx.accounts.whitelist.markets;
+        if markets.contains(&market) {
+            return Ok(());
+        }
+        if markets.len() >= MAX_WHITELISTED_MARKETS {
+            return Err(ErrorCode::WhitelistFull.into());
+        }
+        markets.push(market);
+        Ok(())
+    }
+
+    /// Removes a market from the whitelist. Admin only.
+    pub fn remove_market(ctx: Context<UpdateWhitelist>, market: Pubkey) -> Result<()> {
+        ctx.accounts.whitelist.markets.retain(|m| m != &market);
+        Ok(())
+    }
+
+    /// Creates the circuit breaker for a market, capping the notional volume
+    /// swapped on it. Admin only.
+    ///
+    /// Arguments:
+    ///
+    /// * `bump`         - The bump seed of the circuit breaker PDA.
+    /// * `window_slots` - The length of the rolling window, in slots.
+    /// * `max_notional` - The maximum notional swapped within the window, in
+    ///    native units of the market's quote currency.
+    pub fn create_circuit_breaker(
+        ctx: Context<CreateCircuitBreaker>,
+        bump: u8,
+        window_slots: u64,
+        max_notional: u64,
+    ) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        config.circuit_breakers = config.circuit_breakers.checked_add(1).unwrap();
+        let circuit_breaker = &mut ctx.accounts.circuit_breaker;
+        circuit_breaker.market = ctx.accounts.market.key();
+        circuit_breaker.bump = bump;
+        circuit_breaker.configure(window_slots, max_notional)
+    }
+
+    /// Updates the limits of a market's circuit breaker. Admin only.
+    pub fn update_circuit_breaker(
+        ctx: Context<UpdateCircuitBreaker>,
+        window_slots: u64,
+        max_notional: u64,
+    ) -> Result<()> {
+        ctx.accounts
+            .circuit_breaker
+            .configure(window_slots, max_notional)
+    }
+
+    /// Removes a market's circuit breaker, lifting its cap. Admin only.
+    pub fn close_circuit_breaker(ctx: Context<CloseCircuitBreaker>) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        config.circuit_breakers = config.circuit_breakers.checked_sub(1).unwrap();
+        Ok(())
+    }
+
+    /// No-op invoked by this program itself, with an event as its data, so
+    /// that the event is recorded in the transaction's inner instructions.
+    /// Only the event authority may sign for it.
+    ///
+    /// Arguments:
+    ///
+    /// * `_event` - The event, i.e., its discriminator followed by its
+    ///              serialized fields.
+    pub fn log_event(_ctx: Context<LogEvent>, _event: Vec<u8>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Creates the stats of a market, updated by every swap given them.
+    ///
+    /// Arguments:
+    ///
+    /// * `bump` - The bump seed of the market stats PDA.
+    pub fn init_market_stats(ctx: Context<InitMarketStats>, bump: u8) -> Result<()> {
+        let market_stats = &mut ctx.accounts.market_stats;
+        market_stats.market = ctx.accounts.market.key();
+        market_stats.bump = bump;
+        Ok(())
+    }
+
+    /// Creates the authority's stats, opting into volume tracking.
+    ///
+    /// Arguments:
+    ///
+    /// * `bump` - The bump seed of the user stats PDA.
+    pub fn init_user_stats(ctx: Context<InitUserStats>, bump: u8) -> Result<()> {
+        let user_stats = &mut ctx.accounts.user_stats;
+        user_stats.authority = *ctx.accounts.authority.key;
+        user_stats.bump = bump;
+        Ok(())
+    }
+
+    /// Creates a spending policy limiting the swaps of the `authority`, with
+    /// no limits yet, i.e., failing every swap given it.
+    ///
+    /// Arguments:
+    ///
+    /// * `bump` - The bump seed of the spending policy PDA.
+    pub fn create_spending_policy(ctx: Context<CreateSpendingPolicy>, bump: u8) -> Result<()> {
+        let spending_policy = &mut ctx.accounts.spending_policy;
+        spending_policy.owner = *ctx.accounts.owner.key;
+        spending_policy.authority = *ctx.accounts.authority.key;
+        spending_policy.bump = bump;
+        Ok(())
+    }
+
+    /// Sets the limits of the swaps quoted in `mint`, resetting its window.
+    /// Owner only.
+    ///
+    /// Arguments:
+    ///
+    /// * `mint`           - The quote mint limited.
+    /// * `max_per_swap`   - The maximum notional of a single swap, in native
+    ///    units of the mint.
+    /// * `max_per_window` - The maximum notional swapped within the rolling
+    ///    window of `SPENDING_WINDOW_SECONDS`.
+    pub fn set_spending_limit(
+        ctx: Context<UpdateSpendingPolicy>,
+        mint: Pubkey,
+        max_per_swap: u64,
+        max_per_window: u64,
+    ) -> Result<()> {
+        ctx.accounts
+            .spending_policy
+            .set_limit(mint, max_per_swap, max_per_window)
+    }
+
+    /// Removes the limits of the swaps quoted in `mint`, so that they fail.
+    /// Owner only.
+    pub fn remove_spending_limit(ctx: Context<UpdateSpendingPolicy>, mint: Pubkey) -> Result<()> {
+        ctx.accounts
+            .spending_policy
+            .limits
+            .retain(|limit| limit.mint != mint);
+        Ok(())
+    }
+
+    /// Closes a spending policy, refunding its rent to the owner. Owner only.
+    pub fn close_spending_policy(_ctx: Context<CloseSpendingPolicy>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Registers a referrer for the given quote mint, creating its vault.
+    /// Swaps given the vault as the referral account, followed by the
+    /// referrer, credit the rebates earned to it.
+    ///
+    /// Arguments:
+    ///
+    /// * `bump`        - The bump seed of the referrer PDA.
+    /// * `_vault_bump` - The bump seed of the referrer vault PDA.
+    pub fn register_referrer(
+        ctx: Context<RegisterReferrer>,
+        bump: u8,
+        _vault_bump: u8,
+    ) -> Result<()> {
+        let referrer = &mut ctx.accounts.referrer;
+        referrer.authority = *ctx.accounts.authority.key;
+        referrer.mint = ctx.accounts.mint.key();
+        referrer.vault = ctx.accounts.vault.key();
+        referrer.bump = bump;
+        Ok(())
+    }
+
+    /// Sets the recipients sharing the referrer's rebates, replacing any
+    /// previous ones. Whatever isn't shared stays in the referrer's vault.
+    ///
+    /// Arguments:
+    ///
+    /// * `splits` - The recipients and their shares of each rebate, in basis
+    ///    points. At most `MAX_REFERRAL_SPLITS`.
+    pub fn set_referral_splits(
+        ctx: Context<SetReferralSplits>,
+        splits: Vec<ReferralSplit>,
+    ) -> Result<()> {
+        let total_bps = splits
+            .iter()
+            .try_fold(0u64, |total, split| total.checked_add(split.bps.into()));
+        if splits.len() > MAX_REFERRAL_SPLITS || total_bps.unwrap_or(u64::MAX) > BPS_DENOMINATOR {
+            return Err(ErrorCode::InvalidReferralSplits.into());
+        }
+        ctx.accounts.referrer.splits = splits;
+        Ok(())
+    }
+
+    /// Pays out the entire balance of the referrer vault to `destination`.
+    pub fn claim_referral_fees(ctx: Context<ClaimReferralFees>) -> Result<()> {
+        let amount = ctx.accounts.vault.amount;
+        let referrer = &ctx.accounts.referrer;
+        let seeds = &[
+            REFERRER_SEED,
+            referrer.authority.as_ref(),
+            referrer.mint.as_ref(),
+            &[referrer.bump],
+        ];
+        let signer = &[&seeds[..]];
+        let cpi_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            token::Transfer {
+                from: ctx.accounts.vault.to_account_info(),
+                to: ctx.accounts.destination.to_account_info(),
+                authority: ctx.accounts.referrer.to_account_info(),
+            },
+            signer,
+        );
+        token::transfer(cpi_ctx, amount)?;
+
+        let referrer = &mut ctx.accounts.referrer;
+        referrer.claimed = referrer.claimed.checked_add(amount).unwrap();
+        Ok(())
+    }
+
+    /// Transfers `amount` of the protocol fees collected in a fee vault, or of
+    /// the liquidity of a flash vault, to `destination`. Admin only.
+    pub fn collect_fees(ctx: Context<CollectFees>, amount: u64) -> Result<()> {
+        let seeds = &[CONFIG_SEED, &[ctx.accounts.config.bump]];
+        let signer = &[&seeds[..]];
+        let cpi_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            token::Transfer {
+                from: ctx.accounts.fee_vault.to_account_info(),
+                to: ctx.accounts.destination.to_account_info(),
+                authority: ctx.accounts.config.to_account_info(),
+            },
+            signer,
+        );
+        token::transfer(cpi_ctx, amount)?;
+        Ok(())
+    }
+
+    /// Creates a recurring swap (DCA) schedule, swapping `amount_per_interval`
+    /// of the deposit on the market every `interval` seconds via
+    /// `execute_dca`, and sending the proceeds to `destination`.
+    ///
+    /// The deposit is held in a vault owned by the schedule PDA, which is also
+    /// the authority of the open orders account created here.
+    ///
+    /// Arguments:
+    ///
+    /// * `bump`                - The bump seed of the schedule PDA.
+    /// * `_input_vault_bump`   - The bump seed of the input vault PDA.
+    /// * `_output_vault_bump`  - The bump seed of the output vault PDA.
+    /// * `nonce`               - Distinguishes the owner's schedules.
+    /// * `args`                - The schedule's terms:
+    ///   * `side`                - The direction to swap.
+    ///   * `amount_per_interval` - The amount to swap *from* each interval.
+    ///   * `interval`            - The seconds between swaps.
+    ///   * `min_exchange_rate`   - The exchange rate below which each swap
+    ///      aborts.
+    ///   * `crank_fee`           - The amount of the *from* token paid to
+    ///      whoever executes each swap.
+    ///   * `deposit_amount`      - The amount of the *from* token deposited.
+    pub fn create_dca(
+        ctx: Context<CreateDca>,
+        bump: u8,
+        _input_vault_bump: u8,
+        _output_vault_bump: u8,
+        nonce: u64,
+        args: CreateDcaArgs,
+    ) -> Result<()> {
+        let CreateDcaArgs {
+            side,
+            amount_per_interval,
+            interval,
+            min_exchange_rate,
+            crank_fee,
+            deposit_amount,
+        } = args;
+        if amount_per_interval == 0 || interval <= 0 {
+            return Err(ErrorCode::InvalidSchedule.into());
+        }
+        risk::validate(&min_exchange_rate, false)?;
+        let cpi_ctx = CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            token::Transfer {
+                from: ctx.accounts.owner_wallet.to_account_info(),
+                to: ctx.accounts.input_vault.to_account_info(),
+                authority: ctx.accounts.owner.to_account_info(),
+            },
+        );
+        token::transfer(cpi_ctx, deposit_amount)?;
+
+        let owner = ctx.accounts.owner.key();
+        let nonce_bytes = nonce.to_le_bytes();
+        let seeds = &[DCA_SEED, owner.as_ref(), nonce_bytes.as_ref(), &[bump]];
+        init_signed_open_orders(
+            &ctx.accounts.owner.to_account_info(),
+            dex::InitOpenOrders {
+                open_orders: ctx.accounts.open_orders.clone(),
+                authority: ctx.accounts.dca.to_account_info(),
+                market: ctx.accounts.market.clone(),
+                rent: ctx.accounts.rent.to_account_info(),
+            },
+            &ctx.accounts.dex_program,
+            &ctx.accounts.system_program.to_account_info(),
+            &ctx.accounts.event_cpi,
+            &[&seeds[..]],
+        )?;
+
+        let dca = &mut ctx.accounts.dca;
+        dca.owner = owner;
+        dca.market = *ctx.accounts.market.key;
+        dca.side = side;
+        dca.input_vault = ctx.accounts.input_vault.key();
+        dca.output_vault = ctx.accounts.output_vault.key();
+        dca.destination = ctx.accounts.destination.key();
+        dca.amount_per_interval = amount_per_interval;
+        dca.interval = interval;
+        dca.next_ts = Clock::get()?.unix_timestamp;
+        dca.min_exchange_rate = min_exchange_rate;
+        dca.crank_fee = crank_fee;
+        dca.nonce = nonce;
+        dca.bump = bump;
+        dca.open_orders = *ctx.accounts.open_orders.key;
+        Ok(())
+    }
+
+    /// Executes the next swap of a DCA schedule, once its interval elapsed.
+    /// Permissionless: anyone may crank it, earning the schedule's crank fee
+    /// into `cranker_wallet`.
+    ///
+    /// The swap is a CPI into `swap`, signed by the schedule PDA, so all of
+    /// its checks and fees apply. The last swap takes whatever is left.
+    pub fn execute_dca<'info>(ctx: Context<'_, '_, '_, 'info, ExecuteDca<'info>>) -> Result<()> {
+        let dca = &ctx.accounts.dca;
+        let now = Clock::get()?.unix_timestamp;
+        if now < dca.next_ts {
+            return Err(ErrorCode::ScheduleNotDue.into());
+        }
+        ctx.accounts.swap.is_valid(
+            &dca.side,
+            &ctx.accounts.input_vault,
+            &ctx.accounts.output_vault,
+        )?;
+
+        let nonce_bytes = dca.nonce.to_le_bytes();
+        let seeds = &[
+            DCA_SEED,
+            dca.owner.as_ref(),
+            nonce_bytes.as_ref(),
+            &[dca.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        // Pay the cranker first, so it's never left without its fee.
+        let balance = ctx.accounts.input_vault.amount;
+        if balance <= dca.crank_fee {
+            return Err(ErrorCode::ScheduleExhausted.into());
+        }
+        transfer_signed(
+            &ctx.accounts.token_program,
+            &ctx.accounts.input_vault,
+            &ctx.accounts.cranker_wallet,
+            &dca.to_account_info(),
+            signer,
+            dca.crank_fee,
+        )?;
+        let amount = dca
+            .amount_per_interval
+            .min(balance.checked_sub(dca.crank_fee).unwrap());
+
+        let proceeds = ctx.accounts.swap.swap_signed(
+            dca.to_account_info(),
+            signer,
+            dca.side,
+            amount,
+            dca.min_exchange_rate,
+            &ctx.accounts.output_vault,
+        )?;
+        transfer_signed(
+            &ctx.accounts.token_program,
+            &ctx.accounts.output_vault,
+            &ctx.accounts.destination,
+            &dca.to_account_info(),
+            signer,
+            proceeds,
+        )?;
+
+        let dca = &mut ctx.accounts.dca;
+        dca.next_ts = now.checked_add(dca.interval).unwrap();
+        dca.executions = dca.executions.checked_add(1).unwrap();
+        Ok(())
+    }
+
+    /// Closes a DCA schedule, returning what's left of the deposit to
+    /// `owner_wallet`, and closing its vaults and open orders account,
+    /// whose rent goes back to the owner. Owner only.
+    pub fn close_dca(ctx: Context<CloseDca>) -> Result<()> {
+        let dca = &ctx.accounts.dca;
+        let nonce_bytes = dca.nonce.to_le_bytes();
+        let seeds = &[
+            DCA_SEED,
+            dca.owner.as_ref(),
+            nonce_bytes.as_ref(),
+            &[dca.bump],
+        ];
+        close_schedule(
+            dex::CloseOpenOrders {
+                open_orders: ctx.accounts.open_orders.clone(),
+                authority: dca.to_account_info(),
+                destination: ctx.accounts.owner.to_account_info(),
+                market: ctx.accounts.market.clone(),
+            },
+            &[&seeds[..]],
+            &[
+                (&ctx.accounts.input_vault, &ctx.accounts.owner_wallet),
+                (&ctx.accounts.output_vault, &ctx.accounts.destination),
+            ],
+            &ctx.accounts.dex_program,
+            &ctx.accounts.token_program,
+        )
+    }
+
+    /// Creates a TWAP order, locking `amount` to be swapped on the market in
+    /// `num_slices` equal slices spread evenly from `start_ts` to `end_ts`,
+    /// each executed via `execute_twap_slice`. Proceeds are sent to
+    /// `destination`.
+    ///
+    /// Like DCA schedules, the input is held in a vault owned by the order
+    /// PDA, which is also the authority of the open orders account created
+    /// here.
+    ///
+    /// Arguments:
+    ///
+    /// * `bump`               - The bump seed of the order PDA.
+    /// * `_input_vault_bump`  - The bump seed of the input vault PDA.
+    /// * `_output_vault_bump` - The bump seed of the output vault PDA.
+    /// * `nonce`              - Distinguishes the owner's orders.
+    /// * `args`               - The order's terms:
+    ///   * `side`               - The direction to swap.
+    ///   * `amount`             - The amount to swap *from*, in total.
+    ///   * `num_slices`         - The number of slices.
+    ///   * `start_ts`           - The unix timestamp of the first slice.
+    ///   * `end_ts`             - The unix timestamp by which all slices are
+    ///      eligible.
+    ///   * `min_exchange_rate`  - The exchange rate below which each slice
+    ///      aborts.
+    pub fn create_twap_order(
+        ctx: Context<CreateTwapOrder>,
+        bump: u8,
+        _input_vault_bump: u8,
+        _output_vault_bump: u8,
+        nonce: u64,
+        args: CreateTwapArgs,
+    ) -> Result<()> {
+        let CreateTwapArgs {
+            side,
+            amount,
+            num_slices,
+            start_ts,
+            end_ts,
+            min_exchange_rate,
+        } = args;
+        if amount == 0 || end_ts <= start_ts {
+            return Err(ErrorCode::InvalidSchedule.into());
+        }
+        let slice_interval = end_ts
+            .checked_sub(start_ts)
+            .ok_or(ErrorCode::InvalidSchedule)?
+            .checked_div(num_slices.into())
+            .unwrap_or(0);
+        if slice_interval <= 0 {
+            return Err(ErrorCode::InvalidSchedule.into());
+        }
+        risk::validate(&min_exchange_rate, false)?;
+        let cpi_ctx = CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            token::Transfer {
+                from: ctx.accounts.owner_wallet.to_account_info(),
+                to: ctx.accounts.input_vault.to_account_info(),
+                authority: ctx.accounts.owner.to_account_info(),
+            },
+        );
+        token::transfer(cpi_ctx, amount)?;
+
+        let owner = ctx.accounts.owner.key();
+        let nonce_bytes = nonce.to_le_bytes();
+        let seeds = &[TWAP_SEED, owner.as_ref(), nonce_bytes.as_ref(), &[bump]];
+        init_signed_open_orders(
+            &ctx.accounts.owner.to_account_info(),
+            dex::InitOpenOrders {
+                open_orders: ctx.accounts.open_orders.clone(),
+                authority: ctx.accounts.twap.to_account_info(),
+                market: ctx.accounts.market.clone(),
+                rent: ctx.accounts.rent.to_account_info(),
+            },
+            &ctx.accounts.dex_program,
+            &ctx.accounts.system_program.to_account_info(),
+            &ctx.accounts.event_cpi,
+            &[&seeds[..]],
+        )?;
+
+        let twap = &mut ctx.accounts.twap;
+        twap.owner = owner;
+        twap.market = *ctx.accounts.market.key;
+        twap.side = side;
+        twap.input_vault = ctx.accounts.input_vault.key();
+        twap.output_vault = ctx.accounts.output_vault.key();
+        twap.destination = ctx.accounts.destination.key();
+        twap.amount = amount;
+        twap.num_slices = num_slices;
+        twap.start_ts = start_ts;
+        twap.slice_interval = slice_interval;
+        twap.min_exchange_rate = min_exchange_rate;
+        twap.nonce = nonce;
+        twap.bump = bump;
+        twap.open_orders = *ctx.accounts.open_orders.key;
+        Ok(())
+    }
+
+    /// Executes the next slice of a TWAP order, once its time has come.
+    /// Permissionless. Slices missed are executed one per call, so each
+    /// is bounded by the order's exchange rate.
+    ///
+    /// Each slice swaps an equal share of what's left, via a CPI into `swap`
+    /// signed by the order PDA, so the last one takes whatever lot rounding
+    /// left over.
+    pub fn execute_twap_slice<'info>(
+        ctx: Context<'_, '_, '_, 'info, ExecuteTwapSlice<'info>>,
+    ) -> Result<()> {
+        let twap = &ctx.accounts.twap;
+        if twap.slices_executed >= twap.num_slices {
+            return Err(ErrorCode::ScheduleExhausted.into());
+        }
+        let slice_ts = twap
+            .slice_interval
+            .checked_mul(twap.slices_executed.into())
+            .unwrap()
+            .checked_add(twap.start_ts)
+            .unwrap();
+        if Clock::get()?.unix_timestamp < slice_ts {
+            return Err(ErrorCode::ScheduleNotDue.into());
+        }
+        ctx.accounts.swap.is_valid(
+            &twap.side,
+            &ctx.accounts.input_vault,
+            &ctx.accounts.output_vault,
+        )?;
+
+        let slices_left = twap.num_slices.checked_sub(twap.slices_executed).unwrap();
+        let amount = ctx
+            .accounts
+            .input_vault
+            .amount
+            .checked_div(slices_left.into())
+            .unwrap();
+        if amount == 0 {
+            return Err(ErrorCode::ScheduleExhausted.into());
+        }
+
+        let nonce_bytes = twap.nonce.to_le_bytes();
+        let seeds = &[
+            TWAP_SEED,
+            twap.owner.as_ref(),
+            nonce_bytes.as_ref(),
+            &[twap.bump],
+        ];
+        let signer = &[&seeds[..]];
+        let proceeds = ctx.accounts.swap.swap_signed(
+            twap.to_account_info(),
+            signer,
+            twap.side,
+            amount,
+            twap.min_exchange_rate,
+            &ctx.accounts.output_vault,
+        )?;
+        transfer_signed(
+            &ctx.accounts.token_program,
+            &ctx.accounts.output_vault,
+            &ctx.accounts.destination,
+            &twap.to_account_info(),
+            signer,
+            proceeds,
+        )?;
+
+        let twap = &mut ctx.accounts.twap;
+        twap.slices_executed = twap.slices_executed.checked_add(1).unwrap();
+        Ok(())
+    }
+
+    /// Cancels a TWAP order, returning the input not yet swapped to
+    /// `owner_wallet`, and closing its vaults and open orders account,
+    /// whose rent goes back to the owner. Owner only.
+    pub fn cancel_twap_order(ctx: Context<CancelTwapOrder>) -> Result<()> {
+        let twap = &ctx.accounts.twap;
+        let nonce_bytes = twap.nonce.to_le_bytes();
+        let seeds = &[
+            TWAP_SEED,
+            twap.owner.as_ref(),
+            nonce_bytes.as_ref(),
+            &[twap.bump],
+        ];
+        close_schedule(
+            dex::CloseOpenOrders {
+                open_orders: ctx.accounts.open_orders.clone(),
+                authority: twap.to_account_info(),
+                destination: ctx.accounts.owner.to_account_info(),
+                market: ctx.accounts.market.clone(),
+            },
+            &[&seeds[..]],
+            &[
+                (&ctx.accounts.input_vault, &ctx.accounts.owner_wallet),
+                (&ctx.accounts.output_vault, &ctx.accounts.destination),
+            ],
+            &ctx.accounts.dex_program,
+            &ctx.accounts.token_program,
+        )
+    }
+}
+
+// Executes `swap` and `swap_v2`.
+fn execute_swap_v2<'info>(
+    ctx: Context<'_, '_, '_, 'info, Swap<'info>>,
+    args: SwapArgsV2,
+) -> Result<()> {
+    if let Some(deadline) = args.deadline {
+        if Clock::get()?.unix_timestamp > deadline {
+            return Err(ErrorCode::DeadlineExceeded.into());
+        }
+    }
+    check_whitelisted(
+        &ctx.accounts.config,
+        &ctx.accounts.whitelist,
+        &[&ctx.accounts.market.market],
+    )?;
+
+    // Optional oracle, hook and output split recipients, followed by the
+    // optional referrer of the referral account (earns a
+    // referral fee).
+    let mut remaining_accounts = ctx.remaining_accounts;
+    let spending_policy = load_spending_policy(
+        &ctx.accounts.spending_policy,
+        &ctx.accounts.market.order_payer_token_account,
+        &ctx.accounts.authority,
+    )?;
+    let oracle = match args.oracle_band {
+        Some(band) => Some(Oracle::new(&mut remaining_accounts, band)?),
+        None => None,
+    };
+    let hook = match args.hook_accounts {
+        Some(num_accounts) => Some(Hook::new(
+            &mut remaining_accounts,
+            num_accounts,
+            &ctx.accounts.config,
+        )?),
+        None => None,
+    };
+    let output_split = match &args.output_split {
+        Some(weights) => Some(OutputSplit::new(&mut remaining_accounts, weights)?),
+        None => None,
+    };
+    let referral = Referral::new(&ctx.accounts.referral, remaining_accounts);
+
+    let orderbook: OrderbookClient<'info> = (&*ctx.accounts).into();
+    if let Some(max_event_queue_len) = args.max_event_queue_len {
+        orderbook.check_event_queue(max_event_queue_len)?;
+    }
+    let platform_fee = ctx
+        .accounts
+        .platform_fee(args.platform_fee_bps.unwrap_or(0))?;
+    let to_amount = execute_swap(
+        &orderbook,
+        &ctx.accounts.protocol_fee(),
+        &ctx.accounts.event_cpi,
+        args.side,
+        args.amount,
+        args.min_exchange_rate,
+        SwapOptions {
+            user_stats: Some(&ctx.accounts.user_stats),
+            platform_fee: Some(&platform_fee),
+            oracle: oracle.as_ref(),
+            hook: hook.as_ref(),
+            spending_policy,
+            max_quote_amount: args.max_quote_amount,
+            referral,
+            tag: args.tag,
+            allow_zero_output: args.allow_zero_output.unwrap_or(false),
+            allow_partial: args.allow_partial.unwrap_or(true),
+            max_price_impact_bps: args.max_price_impact_bps,
+            limit_price: args.limit_price,
+            ..Default::default()
+        },
+    )?;
+
+    if let Some(output_split) = output_split {
+        output_split.distribute(&orderbook, &ctx.accounts.event_cpi, args.side, to_amount)?;
+    }
+    Ok(())
+}
+
+// Executes a transitive swap with the `args` of `swap_transitive_v2`.
+fn execute_swap_transitive<'info>(
+    ctx: Context<'_, '_, '_, 'info, SwapTransitive<'info>>,
+    args: SwapTransitiveArgsV2,
+) -> Result<()> {
+    let SwapTransitiveArgsV2 {
+        amount,
+        min_exchange_rate,
+        reswap,
+        leg_rates,
+        max_quote_amount,
+        platform_fee_bps,
+        oracle_bands,
+        tag,
+        allow_zero_output,
+        transfer_spill,
+        spill_handling,
+    } = args;
+    let platform_fee_bps = platform_fee_bps.unwrap_or(0);
+    let allow_zero_output = allow_zero_output.unwrap_or(false);
+    let transfer_spill = transfer_spill.unwrap_or(false);
+    risk::validate(&min_exchange_rate, true)?;
+    let (min_exchange_rate, reswap, transfer_spill) = match spill_handling {
+        Some(spill_handling) => {
+            if reswap.is_some() || transfer_spill {
+                return Err(ErrorCode::ConflictingSpillHandling.into());
+            }
+            spill_handling.resolve(min_exchange_rate)
+        }
+        None => (min_exchange_rate, reswap, transfer_spill),
+    };
+    check_whitelisted(
+        &ctx.accounts.config,
+        &ctx.accounts.whitelist,
+        &[&ctx.accounts.from.market, &ctx.accounts.to.market],
+    )?;
+
+    // Optional oracles and spill destination, followed by the optional
+    // referrer of the referral account (earns a referral fee).
+    let mut remaining_accounts = ctx.remaining_accounts;
+    let spending_policy = load_spending_policy(
+        &ctx.accounts.spending_policy,
+        &ctx.accounts.from.order_payer_token_account,
+        &ctx.accounts.authority,
+    )?;
+    let oracles = match oracle_bands {
+        Some(bands) => Some((
+            Oracle::new(&mut remaining_accounts, bands.sell)?,
+            Oracle::new(&mut remaining_accounts, bands.buy)?,
+        )),
+        None => None,
+    };
+    let spill_destination = match transfer_spill {
+        true => {
+            let (destination, rest) = remaining_accounts
+                .split_first()
+                .ok_or(ErrorCode::SpillDestinationMissing)?;
+            remaining_accounts = rest;
+            Some(destination)
+        }
+        false => None,
+    };
+    let referral = Referral::new(&ctx.accounts.referral, remaining_accounts);
+
+    let amount = resolve_amount(
+        amount,
+        &ctx.accounts.authority,
+        &ctx.accounts.from.order_payer_token_account,
+    )?;
+
+    // Leg 1: Sell Token A for USD(x) (or whatever quote currency is used).
+    let (from_amount, sell_proceeds, sell_order, quote_balance) = {
+        // Token balances before the trade.
+        let base_before = token::accessor::amount(&ctx.accounts.from.coin_wallet)?;
+        let quote_before = token::accessor::amount(&ctx.accounts.pc_wallet)?;
+
+        // Execute the trade.
+        let orderbook = ctx.accounts.orderbook_from();
+        let sell_order = orderbook.sell(amount, None)?;
+        orderbook.settle(referral.clone())?;
+
+        // Token balances after the trade.
+        let base_after = token::accessor::amount(&ctx.accounts.from.coin_wallet)?;
+        let quote_after = token::accessor::amount(&ctx.accounts.pc_wallet)?;
+
+        // Report the delta, along with the quote balance for the next leg.
+        (
+            base_before.checked_sub(base_after).unwrap(),
+            quote_after.checked_sub(quote_before).unwrap(),
+            sell_order,
+            quote_after,
+        )
+    };
+    if let Some(leg_rates) = &leg_rates {
+        apply_leg_risk_checks(from_amount, sell_proceeds, &leg_rates.sell)?;
+    }
+    if let Some((sell_oracle, _)) = &oracles {
+        sell_oracle.check(from_amount, sell_proceeds)?;
+    }
+    record_trade(
+        &ctx.accounts.from,
+        ctx.accounts.config.circuit_breakers,
+        from_amount,
+        sell_proceeds,
+    )?;
+    emit_event(
+        &ctx.accounts.event_cpi,
+        &DidSwapLeg {
+            market: *ctx.accounts.from.market.key,
+            side: Side::Ask,
+            base_delta: from_amount,
+            quote_delta: sell_proceeds,
+            price: price(sell_proceeds, from_amount),
+            taker_fee: sell_order.taker_fee,
+            book: sell_order.book,
+        },
+    )?;
+
+    // Leg 2: Buy Token B with USD(x) (or whatever quote currency is used).
+    // The client is reused to skim the fees.
+    let orderbook = ctx.accounts.orderbook_to();
+    let (to_amount, buy_proceeds, buy_order) = if sell_proceeds == 0 {
+        // Nothing to buy with.
+        (0, 0, Order::default())
+    } else {
+        // Token balances before the trade. The quote balance is unchanged
+        // since the first leg.
+        let base_before = token::accessor::amount(&ctx.accounts.to.coin_wallet)?;
+        let quote_before = quote_balance;
+
+        // The most quote currency the buy may spend.
+        let budget = match max_quote_amount {
+            Some(max_quote_amount) => sell_proceeds.min(max_quote_amount),
+            None => sell_proceeds,
+        };
+
+        // Execute the trade.
+        let mut buy_order = orderbook.buy(budget, None)?;
+        orderbook.settle(referral.clone())?;
+        let mut quote_balance = token::accessor::amount(&ctx.accounts.pc_wallet)?;
+
+        // Buy again with the spill, until it's below the threshold.
+        if let Some(reswap) = reswap {
+            for _ in 0..reswap.max_iterations {
+                let spent = quote_before.checked_sub(quote_balance).unwrap();
+                let spill = sell_proceeds.checked_sub(spent).unwrap();
+                let remaining_budget = budget.checked_sub(spent).unwrap();
+                if spill == 0 || spill < reswap.spill_threshold || remaining_budget == 0 {
+                    break;
+                }
+                let reswap_order = orderbook.buy(spill.min(remaining_budget), None)?;
+                buy_order = buy_order.merge(reswap_order);
+                orderbook.settle(referral.clone())?;
+                let balance = token::accessor::amount(&ctx.accounts.pc_wallet)?;
+                let reswap_spent = quote_balance.checked_sub(balance).unwrap();
+                quote_balance = balance;
+                // Nothing more can be bought with the spill.
+                if reswap_spent == 0 {
+                    break;
+                }
+            }
+        }
+
+        // Token balances after the trade.
+        let base_after = token::accessor::amount(&ctx.accounts.to.coin_wallet)?;
+        let quote_after = quote_balance;
+
+        // Report the delta.
+        (
+            base_after.checked_sub(base_before).unwrap(),
+            quote_before.checked_sub(quote_after).unwrap(),
+            buy_order,
+        )
+    };
+    if let Some(leg_rates) = &leg_rates {
+        apply_leg_risk_checks(buy_proceeds, to_amount, &leg_rates.buy)?;
+    }
+    if let Some((_, buy_oracle)) = &oracles {
+        buy_oracle.check(to_amount, buy_proceeds)?;
+    }
+    record_trade(
+        &ctx.accounts.to,
+        ctx.accounts.config.circuit_breakers,
+        to_amount,
+        buy_proceeds,
+    )?;
+    emit_event(
+        &ctx.accounts.event_cpi,
+        &DidSwapLeg {
+            market: *ctx.accounts.to.market.key,
+            side: Side::Bid,
+            base_delta: to_amount,
+            quote_delta: buy_proceeds,
+            price: price(buy_proceeds, to_amount),
+            taker_fee: buy_order.taker_fee,
+            book: buy_order.book,
+        },
+    )?;
+    check_quote_cap(buy_proceeds, max_quote_amount)?;
+    let quote_mint = token::accessor::mint(&ctx.accounts.pc_wallet)?;
+    // Each leg trades the same value, so only the first counts.
+    record_user_volume(
+        &ctx.accounts.user_stats,
+        &ctx.accounts.authority,
+        quote_mint,
+        sell_proceeds,
+    )?;
+    record_spending(
+        spending_policy,
+        &ctx.accounts.authority,
+        quote_mint,
+        sell_proceeds,
+    )?;
+
+    // The amount of surplus quote currency *not* fully consumed by the
+    // second half of the swap.
+    let spill_amount = sell_proceeds.checked_sub(buy_proceeds).unwrap();
+
+    // Move the spill out of the intermediate quote currency wallet. It's
+    // still credited against the rate below.
+    if let Some(destination) = spill_destination {
+        if spill_amount > 0 {
+            let cpi_ctx = CpiContext::new(
+                ctx.accounts.token_program.clone(),
+                token::Transfer {
+                    from: ctx.accounts.pc_wallet.clone(),
+                    to: destination.clone(),
+                    authority: ctx.accounts.authority.clone(),
+                },
+            );
+            token::transfer(cpi_ctx, spill_amount)?;
+            emit_event(
+                &ctx.accounts.event_cpi,
+                &SpillTransferred {
+                    authority: *ctx.accounts.authority.key,
+                    destination: *destination.key,
+                    quote_mint: token::accessor::mint(&ctx.accounts.pc_wallet)?,
+                    amount: spill_amount,
+                },
+            )?;
+        }
+    }
+
+    // Skim the protocol fee off the output.
+    let protocol_fee =
+        ctx.accounts
+            .protocol_fee()
+            .take(&orderbook, &ctx.accounts.to.coin_wallet, to_amount)?;
+    let to_amount = to_amount.checked_sub(protocol_fee).unwrap();
+
+    // Pay the integrator its share of what's left.
+    let platform_fee = ctx.accounts.platform_fee(platform_fee_bps)?.take(
+        &orderbook,
+        &ctx.accounts.to.coin_wallet,
+        to_amount,
+    )?;
+    let to_amount = to_amount.checked_sub(platform_fee).unwrap();
+
+    // Safety checks.
+    apply_risk_checks(
+        &ctx.accounts.event_cpi,
+        DidSwap {
+            given_amount: amount,
+            min_exchange_rate,
+            from_amount,
+            to_amount,
+            quote_amount: sell_proceeds,
+            spill_amount,
+            protocol_fee,
+            platform_fee,
+            from_mint: token::accessor::mint(&ctx.accounts.from.coin_wallet)?,
+            to_mint: token::accessor::mint(&ctx.accounts.to.coin_wallet)?,
+            quote_mint: token::accessor::mint(&ctx.accounts.pc_wallet)?,
+            authority: *ctx.accounts.authority.key,
+            side: None,
+            effective_price: price(to_amount, from_amount),
+            order_base_qty: sell_order.qty,
+            order_quote_qty: buy_order.qty,
+            client_order_id: CLIENT_ORDER_ID,
+            tag,
+            taker_fee: sell_order.merge(buy_order).taker_fee,
+            referrer_rebate: sell_order.merge(buy_order).referrer_rebate,
+            book: None,
+            unfilled_amount: 0,
+        },
+        allow_zero_output,
+    )?;
+
+    Ok(())
+}
+
+// Executes a direct swap on the orderbook's market and applies the risk checks.
+//
+// When side is "bid", then swaps the quote currency for the base. When side
+// is "ask", then swaps the base currency for the quote.
+fn execute_swap<'info>(
+    orderbook: &OrderbookClient<'info>,
+    protocol_fee: &ProtocolFee<'info>,
+    event_cpi: &EventCpi<'info>,
+    side: Side,
+    amount: u64,
+    min_exchange_rate: ExchangeRate,
+    options: SwapOptions<'_, 'info>,
+) -> Result<u64> {
+    let SwapOptions {
+        user_stats,
+        platform_fee,
+        oracle,
+        hook,
+        chunks,
+        spending_policy,
+        max_quote_amount,
+        referral,
+        tag,
+        allow_zero_output,
+        allow_partial,
+        max_price_impact_bps,
+        limit_price,
+    } = options;
+    risk::validate(&min_exchange_rate, false)?;
+    let mut min_exchange_rate = min_exchange_rate;
+
+    // Not used for direct swaps.
+    min_exchange_rate.quote_decimals = 0;
+
+    // The cap only applies to the quote currency spent, i.e., bids.
+    let max_quote_amount = match side {
+        Side::Bid => max_quote_amount,
+        Side::Ask => None,
+    };
+    let amount = resolve_amount(
+        amount,
+        &orderbook.authority,
+        &orderbook.market.order_payer_token_account,
+    )?;
+    let trade_amount = match max_quote_amount {
+        Some(max_quote_amount) => amount.min(max_quote_amount),
+        None => amount,
+    };
+
+    let max_price_impact_bps = orderbook
+        .execution
+        .max_price_impact_bps(max_price_impact_bps);
+    if let Some(max_price_impact_bps) = max_price_impact_bps {
+        let price_impact_bps = orderbook.price_impact_bps(&side, trade_amount)?;
+        if price_impact_bps > u64::from(max_price_impact_bps) {
+            msg!("price_impact_bps: {:?}", price_impact_bps);
+            return Err(ErrorCode::PriceImpactExceeded.into());
+        }
+    }
+
+    if let Some(hook) = hook {
+        hook.pre_swap(PreSwap {
+            authority: *orderbook.authority.key,
+            side,
+            amount: trade_amount,
+        })?;
+    }
+
+    let (from_token, to_token) = orderbook.wallets(&side);
+    let (from_amount, to_amount, order) = match chunks {
+        Some(chunks) => execute_chunked_trade(orderbook, &side, trade_amount, chunks, referral)?,
+        None => execute_trade(orderbook, &side, trade_amount, limit_price, referral)?,
+    };
+    check_quote_cap(from_amount, max_quote_amount)?;
+    let unfilled_amount = order.qty.checked_sub(from_amount).unwrap();
+    if !allow_partial && is_partial_fill(orderbook, &side, unfilled_amount, from_amount, to_amount)?
+    {
+        msg!("unfilled_amount: {:?}", unfilled_amount);
+        return Err(ErrorCode::PartialFill.into());
+    }
+    if let Some(oracle) = oracle {
+        match side {
+            Side::Bid => oracle.check(to_amount, from_amount)?,
+            Side::Ask => oracle.check(from_amount, to_amount)?,
+        }
+    }
+    let (quote_token, quote_volume) = match side {
+        Side::Bid => (from_token, from_amount),
+        Side::Ask => (to_token, to_amount),
+    };
+    let quote_mint = token::accessor::mint(quote_token)?;
+    if let Some(user_stats) = user_stats {
+        record_user_volume(user_stats, &orderbook.authority, quote_mint, quote_volume)?;
+    }
+    record_spending(
+        spending_policy,
+        &orderbook.authority,
+        quote_mint,
+        quote_volume,
+    )?;
+
+    // Skim the protocol fee off the output.
+    let protocol_fee = protocol_fee.take(orderbook, to_token, to_amount)?;
+    let to_amount = to_amount.checked_sub(protocol_fee).unwrap();
+
+    // Pay the integrator its share of what's left.
+    let platform_fee = match platform_fee {
+        Some(platform_fee) => platform_fee.take(orderbook, to_token, to_amount)?,
+        None => 0,
+    };
+    let to_amount = to_amount.checked_sub(platform_fee).unwrap();
+
+    let (order_base_qty, order_quote_qty) = match side {
+        Side::Bid => (0, order.qty),
+        Side::Ask => (order.qty, 0),
+    };
+
+    // Safety checks.
+    apply_risk_checks(
+        event_cpi,
+        DidSwap {
+            authority: *orderbook.authority.key,
+            side: Some(side),
+            effective_price: price(to_amount, from_amount),
+            order_base_qty,
+            order_quote_qty,
+            client_order_id: CLIENT_ORDER_ID,
+            tag,
+            taker_fee: order.taker_fee,
+            referrer_rebate: order.referrer_rebate,
+            book: order.book,
+            unfilled_amount,
+            given_amount: amount,
+            min_exchange_rate,
+            from_amount,
+            to_amount,
+            quote_amount: 0,
+            spill_amount: 0,
+            protocol_fee,
+            platform_fee,
+            from_mint: token::accessor::mint(from_token)?,
+            to_mint: token::accessor::mint(to_token)?,
+            quote_mint: match side {
+                Side::Bid => token::accessor::mint(from_token)?,
+                Side::Ask => token::accessor::mint(to_token)?,
+            },
+        },
+        allow_zero_output,
+    )?;
+
+    if let Some(hook) = hook {
+        hook.post_swap(PostSwap {
+            authority: *orderbook.authority.key,
+            side,
+            from_amount,
+            to_amount,
+        })?;
+    }
+
+    Ok(to_amount)
+}
+
+// Optional accounts and options of a direct swap executed by `execute_swap`.
+// None apply by default, and partial fills are allowed.
+struct SwapOptions<'a, 'info> {
+    // Account tracking the authority's volume, if any.
+    user_stats: Option<&'a AccountInfo<'info>>,
+    // Integrator's fee skimmed off the output after the protocol fee.
+    platform_fee: Option<&'a PlatformFee<'info>>,
+    // Price band the execution price must be within.
+    oracle: Option<&'a Oracle<'info>>,
+    // Whitelisted program invoked before the order and after settlement.
+    hook: Option<&'a Hook<'info>>,
+    // Splits the order into chunks at successive price limits.
+    chunks: Option<&'a Chunks>,
+    // Spending policy of the authority, recording the quote currency traded.
+    spending_policy: Option<&'a AccountInfo<'info>>,
+    // Cap on the quote currency spent by a bid.
+    max_quote_amount: Option<u64>,
+    // Referral accounts earning the DEX's referral rebate.
+    referral: Option<Referral<'info>>,
+    // Client data echoed in the `DidSwap` event.
+    tag: Option<[u8; 32]>,
+    // Succeeds without swapping if nothing fills.
+    allow_zero_output: bool,
+    // Allows the order to be partially filled.
+    allow_partial: bool,
+    // Bound on the price impact expected from the book.
+    max_price_impact_bps: Option<u16>,
+    // Worst price of the order, in quote lots per base lot.
+    limit_price: Option<u64>,
+}
+
+impl Default for SwapOptions<'_, '_> {
+    fn default() -> Self {
+        Self {
+            user_stats: None,
+            platform_fee: None,
+            oracle: None,
+            hook: None,
+            chunks: None,
+            spending_policy: None,
+            max_quote_amount: None,
+            referral: None,
+            tag: None,
+            allow_zero_output: false,
+            allow_partial: true,
+            max_price_impact_bps: None,
+            limit_price: None,
+        }
+    }
+}
+
+// Asserts a single leg of a transitive swap executed at an exchange rate
+// acceptable to the client, i.e.,
+//
+// `to_amount * 10^from_decimals >= from_amount * rate`.
+//
+// A zero rate leaves the leg unconstrained.
+fn apply_leg_risk_checks(from_amount: u64, to_amount: u64, min_rate: &LegRate) -> Result<()> {
+    let expectation = risk::Expectation::leg(from_amount, to_amount, min_rate);
+    if !expectation.is_met() {
+        msg!(
+            "leg to_amount, min_expected_amount: {:?}, {:?}",
+            expectation.effective_to_amount,
+            expectation.min_expected_amount,
+        );
+        return Err(ErrorCode::LegSlippageExceeded.into());
+    }
+    Ok(())
+}
+
+// Validates a fee in basis points.
+fn is_valid_fee(fee_bps: u16) -> Result<()> {
+    if u64::from(fee_bps) > BPS_DENOMINATOR {
+        return Err(ErrorCode::InvalidFee.into());
+    }
+    Ok(())
+}
+
+// The protocol fee to skim off the output of a swap, along with the fee vault
+// collecting it.
+struct ProtocolFee<'info> {
+    config: Pubkey,
+    fee_bps: u16,
+    fee_vault: AccountInfo<'info>,
+}
+
+impl<'info> ProtocolFee<'info> {
+    // Returns the output from which taking the fee leaves at least `amount`.
+    fn gross_up(&self, amount: u64) -> Result<u64> {
+        let net_bps = BPS_DENOMINATOR.checked_sub(self.fee_bps.into()).unwrap();
+        if net_bps == 0 {
+            return Err(ErrorCode::PaymentShortfall.into());
+        }
+        let gross_amount = u128::from(amount)
+            .checked_mul(BPS_DENOMINATOR.into())
+            .unwrap()
+            .checked_add((net_bps - 1).into())
+            .unwrap()
+            .checked_div(net_bps.into())
+            .unwrap();
+        Ok(gross_amount.try_into().unwrap())
+    }
+
+    // Transfers the fee on `to_amount` from `to_wallet` into the fee vault,
+    // returning the fee taken. The fee vault is only checked when there's a
+    // fee to take.
+    fn take(
+        &self,
+        orderbook: &OrderbookClient<'info>,
+        to_wallet: &AccountInfo<'info>,
+        to_amount: u64,
+    ) -> Result<u64> {
+        let fee = fee_amount(to_amount, self.fee_bps);
+        if fee == 0 {
+            return Ok(0);
+        }
+        if self.fee_vault.owner != &token::ID
+            || token::accessor::authority(&self.fee_vault)? != self.config
+            || token::accessor::mint(&self.fee_vault)? != token::accessor::mint(to_wallet)?
+        {
+            return Err(ErrorCode::InvalidFeeVault.into());
+        }
+        transfer_fee(orderbook, to_wallet, &self.fee_vault, fee)?;
+        Ok(fee)
+    }
+}
+
+// The integrator's fee to skim off the output of a swap, along with the
+// token account receiving it.
+struct PlatformFee<'info> {
+    fee_bps: u16,
+    fee_account: AccountInfo<'info>,
+}
+
+impl<'info> PlatformFee<'info> {
+    fn new(fee_bps: u16, fee_account: AccountInfo<'info>) -> Result<Self> {
+        is_valid_fee(fee_bps)?;
+        Ok(Self {
+            fee_bps,
+            fee_account,
+        })
+    }
+
+    // Transfers the fee on `to_amount` from `to_wallet` into the fee account,
+    // returning the fee taken. The fee account is only checked when there's a
+    // fee to take.
+    fn take(
+        &self,
+        orderbook: &OrderbookClient<'info>,
+        to_wallet: &AccountInfo<'info>,
+        to_amount: u64,
+    ) -> Result<u64> {
+        let fee = fee_amount(to_amount, self.fee_bps);
+        if fee == 0 {
+            return Ok(0);
+        }
+        if self.fee_account.owner != &token::ID
+            || token::accessor::mint(&self.fee_account)? != token::accessor::mint(to_wallet)?
+        {
+            return Err(ErrorCode::InvalidPlatformFeeAccount.into());
+        }
+        transfer_fee(orderbook, to_wallet, &self.fee_account, fee)?;
+        Ok(fee)
+    }
+}
+
+// The optional referral accounts of a swap, earning the DEX's referral rebate
+// on settlement.
+#[derive(Clone)]
+struct Referral<'info> {
+    // Quote currency token account receiving the rebate.
+    wallet: AccountInfo<'info>,
+    // Registered referrer whose vault is `wallet`, if tracked.
+    referrer: Option<AccountInfo<'info>>,
+    // Token accounts of the referrer's split recipients, in order.
+    recipients: Vec<AccountInfo<'info>>,
+}
+
+impl<'info> Referral<'info> {
+    // Parses the referral wallet, optionally followed by its referrer, from
+    // the trailing remaining accounts.
+    fn from_accounts(accounts: &[AccountInfo<'info>]) -> Option<Self> {
+        let (wallet, accounts) = accounts.split_first()?;
+        Referral::new(wallet, accounts)
+    }
+
+    // Returns the referral wallet given as a named account, none if it's this
+    // program's ID, followed by its optional referrer and split recipients in
+    // the trailing remaining accounts.
+    fn new(wallet: &AccountInfo<'info>, accounts: &[AccountInfo<'info>]) -> Option<Self> {
+        if wallet.key == &ID {
+            return None;
+        }
+        let mut accounts = accounts.iter();
+        Some(Referral {
+            wallet: wallet.clone(),
+            referrer: accounts.next().cloned(),
+            recipients: accounts.cloned().collect(),
+        })
+    }
+}
+
+// Returns the optional named `account`, i.e., none if it's this program's ID.
+fn optional_account<'info>(account: &AccountInfo<'info>) -> Option<AccountInfo<'info>> {
+    match account.key == &ID {
+        true => None,
+        false => Some(account.clone()),
+    }
+}
+
+// Credits the rebate settled into a referrer's vault since `amount_before`,
+// paying out the shares of its split recipients.
+fn credit_referrer<'info>(
+    orderbook: &OrderbookClient<'info>,
+    referral: &Referral<'info>,
+    amount_before: u64,
+) -> Result<()> {
+    let vault = &referral.wallet;
+    let referrer_info = referral.referrer.as_ref().unwrap();
+    let mut referrer: Account<'info, Referrer> = Account::try_from(referrer_info)?;
+    if referrer.vault != *vault.key {
+        return Err(ErrorCode::InvalidReferrer.into());
+    }
+    if referral.recipients.len() != referrer.splits.len()
+        || referral
+            .recipients
+            .iter()
+            .zip(&referrer.splits)
+            .any(|(recipient, split)| recipient.key != &split.recipient)
+    {
+        return Err(ErrorCode::InvalidReferralSplits.into());
+    }
+    let rebate = token::accessor::amount(vault)?
+        .checked_sub(amount_before)
+        .unwrap();
+
+    // Pay out the shares, signed by the referrer owning the vault.
+    let seeds = &[
+        REFERRER_SEED,
+        referrer.authority.as_ref(),
+        referrer.mint.as_ref(),
+        &[referrer.bump],
+    ];
+    let signer = &[&seeds[..]];
+    let mut shared: u64 = 0;
+    for (recipient, split) in referral.recipients.iter().zip(&referrer.splits) {
+        let amount = fee_amount(rebate, split.bps);
+        if amount == 0 {
+            continue;
+        }
+        let cpi_ctx = CpiContext::new_with_signer(
+            orderbook.token_program.clone(),
+            token::Transfer {
+                from: vault.clone(),
+                to: recipient.clone(),
+                authority: referrer_info.clone(),
+            },
+            signer,
+        );
+        token::transfer(cpi_ctx, amount)?;
+        shared = shared.checked_add(amount).unwrap();
+        emit_event(
+            &orderbook.event_cpi,
+            &ReferralShared {
+                referrer: *referrer_info.key,
+                recipient: *recipient.key,
+                amount,
+            },
+        )?;
+    }
+
+    let earned = rebate.checked_sub(shared).unwrap();
+    referrer.earned = referrer.earned.checked_add(earned).unwrap();
+    referrer.exit(&ID)?;
+    Ok(())
+}
+
+// Returns `fee_bps` of `amount`, rounded down.
+fn fee_amount(amount: u64, fee_bps: u16) -> u64 {
+    u128::from(amount)
+        .checked_mul(fee_bps.into())
+        .unwrap()
+        .checked_div(BPS_DENOMINATOR.into())
+        .unwrap() as u64
+}
+
+// Transfers a fee out of the authority's `to_wallet`.
+fn transfer_fee<'info>(
+    orderbook: &OrderbookClient<'info>,
+    to_wallet: &AccountInfo<'info>,
+    fee_account: &AccountInfo<'info>,
+    fee: u64,
+) -> Result<()> {
+    let cpi_ctx = CpiContext::new(
+        orderbook.token_program.clone(),
+        token::Transfer {
+            from: to_wallet.clone(),
+            to: fee_account.clone(),
+            authority: orderbook.authority.clone(),
+        },
+    );
+    token::transfer(cpi_ctx, fee)?;
+    Ok(())
+}
+
+// Recipients of the output of a swap, each receiving its weight of it.
+struct OutputSplit<'info> {
+    recipients: Vec<(AccountInfo<'info>, u16)>,
+}
+
+impl<'info> OutputSplit<'info> {
+    // Takes a recipient per weight from the front of `accounts`.
+    fn new(accounts: &mut &[AccountInfo<'info>], weights: &[u16]) -> Result<Self> {
+        let total_bps: u64 = weights.iter().map(|bps| u64::from(*bps)).sum();
+        if weights.is_empty()
+            || weights.len() > MAX_OUTPUT_SPLITS
+            || weights.len() > accounts.len()
+            || total_bps > BPS_DENOMINATOR
+        {
+            return Err(ErrorCode::InvalidOutputSplit.into());
+        }
+        let (recipients, rest) = accounts.split_at(weights.len());
+        *accounts = rest;
+        Ok(OutputSplit {
+            recipients: recipients
+                .iter()
+                .cloned()
+                .zip(weights.iter().copied())
+                .collect(),
+        })
+    }
+
+    // Transfers each recipient its share of the `to_amount` received by the
+    // authority, rounded down. The rest stays in the authority's wallet.
+    fn distribute(
+        &self,
+        orderbook: &OrderbookClient<'info>,
+        event_cpi: &EventCpi<'info>,
+        side: Side,
+        to_amount: u64,
+    ) -> Result<()> {
+        let (_, to_wallet) = orderbook.wallets(&side);
+        let mint = token::accessor::mint(to_wallet)?;
+        for (recipient, bps) in &self.recipients {
+            let amount = fee_amount(to_amount, *bps);
+            if amount == 0 {
+                continue;
+            }
+            transfer_fee(orderbook, to_wallet, recipient, amount)?;
+            emit_event(
+                event_cpi,
+                &OutputShared {
+                    authority: *orderbook.authority.key,
+                    recipient: *recipient.key,
+                    mint,
+                    amount,
+                },
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// A Pyth price account bounding the execution price of a market.
+struct Oracle<'info> {
+    price_account: AccountInfo<'info>,
+    band: OracleBand,
+}
+
+impl<'info> Oracle<'info> {
+    // Takes the price account from the front of `accounts`.
+    fn new(accounts: &mut &[AccountInfo<'info>], band: OracleBand) -> Result<Self> {
+        let (price_account, rest) = accounts.split_first().ok_or(ErrorCode::OracleUnavailable)?;
+        *accounts = rest;
+        Ok(Oracle {
+            price_account: price_account.clone(),
+            band,
+        })
+    }
+
+    // Asserts trading `base_amount` against `quote_amount` (in native units)
+    // happened within the band around the oracle price.
+    fn check(&self, base_amount: u64, quote_amount: u64) -> Result<()> {
+        let price = oracle::load_price(&self.price_account)?;
+        oracle::check_deviation(
+            &price,
+            base_amount,
+            quote_amount,
+            self.band.base_decimals,
+            self.band.quote_decimals,
+            self.band.max_deviation_bps,
+        )
+    }
+}
+
+// Returns the exchange rate of a payment of `pay_amount` for at most
+// `max_amount`, which any swap filling the payment meets.
+fn payment_rate(pay_amount: u64, max_amount: u64) -> ExchangeRate {
+    let from_decimals = 9;
+    let rate = u128::from(pay_amount)
+        .checked_mul(10u128.pow(from_decimals.into()))
+        .unwrap()
+        .checked_div(max_amount.max(1).into())
+        .unwrap();
+    ExchangeRate {
+        rate: rate.try_into().unwrap_or(u64::MAX),
+        from_decimals,
+        quote_decimals: 0,
+        strict: false,
+    }
+}
+
+// Asserts no more than the (optional) cap of the quote currency was spent.
+fn check_quote_cap(quote_spent: u64, max_quote_amount: Option<u64>) -> Result<()> {
+    if let Some(max_quote_amount) = max_quote_amount {
+        if quote_spent > max_quote_amount {
+            return Err(ErrorCode::QuoteCapExceeded.into());
+        }
+    }
+    Ok(())
+}
+
+// Executes a direct IOC trade on the orderbook's market and settles it,
+// returning the amounts of the *from* and *to* tokens swapped, and the order
+// sent to the DEX.
+//
+// If given, the order is limited to `limit_price`, in lots.
+fn execute_trade<'info>(
+    orderbook: &OrderbookClient<'info>,
+    side: &Side,
+    amount: u64,
+    limit_price: Option<u64>,
+    referral: Option<Referral<'info>>,
+) -> Result<(u64, u64, Order)> {
+    let (from_token, to_token) = orderbook.wallets(side);
+
+    // Token balances before the trade.
+    let from_amount_before = token::accessor::amount(from_token)?;
+    let to_amount_before = token::accessor::amount(to_token)?;
+
+    // Execute trade.
+    let order = match side {
+        Side::Bid => orderbook.buy(amount, limit_price)?,
+        Side::Ask => orderbook.sell(amount, limit_price)?,
+    };
+    orderbook.settle(referral)?;
+
+    // Token balances after the trade.
+    let from_amount_after = token::accessor::amount(from_token)?;
+    let to_amount_after = token::accessor::amount(to_token)?;
+
+    //  Calculate the delta, i.e. the amount swapped.
+    let from_amount = from_amount_before.checked_sub(from_amount_after).unwrap();
+    let to_amount = to_amount_after.checked_sub(to_amount_before).unwrap();
+
+    let (base_amount, quote_amount) = match side {
+        Side::Bid => (to_amount, from_amount),
+        Side::Ask => (from_amount, to_amount),
+    };
+    record_trade(
+        &orderbook.market,
+        orderbook.circuit_breakers,
+        base_amount,
+        quote_amount,
+    )?;
+
+    Ok((from_amount, to_amount, order))
+}
+
+// Executes a direct trade as `chunks.count` sequential IOC orders, each for an
+// equal share of `amount` and limited to the worst price, if any. Stops early
+// once an order isn't filled entirely, i.e., the price limit was crossed or
+// the book is exhausted. Returns the totals, like `execute_trade`.
+fn execute_chunked_trade<'info>(
+    orderbook: &OrderbookClient<'info>,
+    side: &Side,
+    amount: u64,
+    chunks: &Chunks,
+    referral: Option<Referral<'info>>,
+) -> Result<(u64, u64, Order)> {
+    if chunks.count == 0 {
+        return Err(ErrorCode::InvalidChunks.into());
+    }
+    let limit_price = match chunks.worst_price {
+        Some(worst_price) => Some(orderbook.limit_price(side, worst_price)?),
+        None => None,
+    };
+    let count = u64::from(chunks.count);
+    let chunk_amount = amount.checked_div(count).unwrap();
+
+    let mut from_amount: u64 = 0;
+    let mut to_amount: u64 = 0;
+    let mut order = Order::default();
+    for k in 0..count {
+        // The last chunk takes the remainder of the division.
+        let amount = if k + 1 == count {
+            amount
+                .checked_sub(chunk_amount.checked_mul(count - 1).unwrap())
+                .unwrap()
+        } else {
+            chunk_amount
+        };
+        if amount == 0 {
+            continue;
+        }
+        let (chunk_from, chunk_to, chunk_order) =
+            execute_trade(orderbook, side, amount, limit_price, referral.clone())?;
+        from_amount = from_amount.checked_add(chunk_from).unwrap();
+        to_amount = to_amount.checked_add(chunk_to).unwrap();
+        order = order.merge(chunk_order);
+
+        let unfilled_amount = chunk_order.qty.checked_sub(chunk_from).unwrap();
+        if chunk_from == 0
+            || is_partial_fill(orderbook, side, unfilled_amount, chunk_from, chunk_to)?
+        {
+            break;
+        }
+    }
+    Ok((from_amount, to_amount, order))
+}
+
+// Returns true if an order left `unfilled_amount` of the *from* token it
+// offered. For bids, a remainder too small to buy a single lot at the
+// execution price is only lot rounding.
+fn is_partial_fill(
+    orderbook: &OrderbookClient,
+    side: &Side,
+    unfilled_amount: u64,
+    from_amount: u64,
+    to_amount: u64,
+) -> Result<bool> {
+    if unfilled_amount == 0 {
+        return Ok(false);
+    }
+    match side {
+        Side::Ask => Ok(true),
+        Side::Bid => {
+            if to_amount == 0 {
+                return Ok(true);
+            }
+            let (coin_lot_size, _) = orderbook.lot_sizes()?;
+            let lot_cost = u128::from(from_amount)
+                .checked_mul(coin_lot_size.into())
+                .unwrap()
+                .checked_div(to_amount.into())
+                .unwrap();
+            Ok(u128::from(unfilled_amount) >= lot_cost)
+        }
+    }
+}
+
+// Adds a swap of `volume` (in native units of `quote_mint`) to the
+// authority's stats, unless it opted out.
+fn record_user_volume(
+    user_stats: &AccountInfo,
+    authority: &AccountInfo,
+    quote_mint: Pubkey,
+    volume: u64,
+) -> Result<()> {
+    record_user_volumes(user_stats, authority, &[(quote_mint, volume)])
+}
+
+// Adds a single swap of the given volume in each quote mint to the
+// authority's stats, unless it opted out, e.g., for swaps across quote
+// currencies.
+fn record_user_volumes(
+    user_stats: &AccountInfo,
+    authority: &AccountInfo,
+    volumes: &[(Pubkey, u64)],
+) -> Result<()> {
+    if user_stats.key == &ID {
+        return Ok(());
+    }
+    let mut user_stats: Account<UserStats> = Account::try_from(user_stats)?;
+    if &user_stats.authority != authority.key {
+        return Err(ErrorCode::InvalidUserStats.into());
+    }
+    user_stats.record(volumes)?;
+    user_stats.exit(&ID)?;
+    Ok(())
+}
+
+// Returns the spending policy over the `authority`'s swaps of the funds of
+// the owner of the `order_payer`, if it exists, erroring unless the
+// `spending_policy` account is its PDA, so that it can't be left out. An owner
+// swapping their own funds may pass this program's ID instead.
+fn load_spending_policy<'a, 'info>(
+    spending_policy: &'a AccountInfo<'info>,
+    order_payer: &AccountInfo,
+    authority: &AccountInfo,
+) -> Result<Option<&'a AccountInfo<'info>>> {
+    let owner = token::accessor::authority(order_payer)?;
+    if spending_policy.owner == &ID && !spending_policy.data_is_empty() {
+        let policy: Account<SpendingPolicy> = Account::try_from(spending_policy)?;
+        let address = Pubkey::create_program_address(
+            &[
+                SPENDING_POLICY_SEED,
+                owner.as_ref(),
+                authority.key.as_ref(),
+                &[policy.bump],
+            ],
+            &ID,
+        )
+        .map_err(|_| ErrorCode::InvalidSpendingPolicy)?;
+        if &address != spending_policy.key {
+            return Err(ErrorCode::InvalidSpendingPolicy.into());
+        }
+        return Ok(Some(spending_policy));
+    }
+    if spending_policy.key == &ID && &owner == authority.key {
+        return Ok(None);
+    }
+    let (address, _) = Pubkey::find_program_address(
+        &[SPENDING_POLICY_SEED, owner.as_ref(), authority.key.as_ref()],
+        &ID,
+    );
+    if &address != spending_policy.key {
+        return Err(ErrorCode::InvalidSpendingPolicy.into());
+    }
+    Ok(None)
+}
+
+// Adds a swap of `notional` (in native units of `quote_mint`) to the
+// authority's spending policy, if given, failing if it exceeds the limits.
+fn record_spending(
+    spending_policy: Option<&AccountInfo>,
+    authority: &AccountInfo,
+    quote_mint: Pubkey,
+    notional: u64,
+) -> Result<()> {
+    let spending_policy = match spending_policy {
+        Some(spending_policy) => spending_policy,
+        None => return Ok(()),
+    };
+    let mut spending_policy: Account<SpendingPolicy> = Account::try_from(spending_policy)?;
+    if &spending_policy.authority != authority.key {
+        return Err(ErrorCode::InvalidSpendingPolicy.into());
+    }
+    let now = Clock::get()?.unix_timestamp.try_into().unwrap();
+    spending_policy.record(quote_mint, now, notional)?;
+    spending_policy.exit(&ID)?;
+    Ok(())
+}
+
+// Records a trade of `base_amount` against `quote_amount` (in native units)
+// with the market's circuit breaker, given the config's `circuit_breakers`,
+// and stats.
+fn record_trade(
+    market: &MarketAccounts,
+    circuit_breakers: u32,
+    base_amount: u64,
+    quote_amount: u64,
+) -> Result<()> {
+    // The notional is the amount of the quote currency traded.
+    record_notional(market, circuit_breakers, quote_amount)?;
+    record_market_stats(market, base_amount, quote_amount)
+}
+
+// Records a trade with the market's stats, unless skipped.
+fn record_market_stats(market: &MarketAccounts, base_amount: u64, quote_amount: u64) -> Result<()> {
+    if market.market_stats.key == &ID {
+        return Ok(());
+    }
+    let mut market_stats: Account<MarketStats> = Account::try_from(&market.market_stats)?;
+    if &market_stats.market != market.market.key {
+        return Err(ErrorCode::InvalidMarketStats.into());
+    }
+    market_stats.record(Clock::get()?.slot, base_amount, quote_amount);
+    market_stats.exit(&ID)?;
+    Ok(())
+}
+
+// Records the notional traded on a market with its circuit breaker, if the
+// admin created one. `circuit_breakers` is the number of breakers in the
+// config, so the market's may be skipped while there are none.
+//
+// The PDA is derived from the breaker's stored bump, so the bump is only
+// searched for when there's no breaker, to check it isn't left out.
+fn record_notional(market: &MarketAccounts, circuit_breakers: u32, notional: u64) -> Result<()> {
+    if market.circuit_breaker.key == &ID {
+        if circuit_breakers != 0 {
+            return Err(ErrorCode::InvalidCircuitBreaker.into());
+        }
+        return Ok(());
+    }
+    let market_key = market.market.key.as_ref();
+    if market.circuit_breaker.owner != &ID {
+        let (address, _) = Pubkey::find_program_address(&[CIRCUIT_BREAKER_SEED, market_key], &ID);
+        if market.circuit_breaker.key != &address {
+            return Err(ErrorCode::InvalidCircuitBreaker.into());
+        }
+        return Ok(());
+    }
+    let mut circuit_breaker: Account<CircuitBreaker> = Account::try_from(&market.circuit_breaker)?;
+    let address = Pubkey::create_program_address(
+        &[CIRCUIT_BREAKER_SEED, market_key, &[circuit_breaker.bump]],
+        &ID,
+    )
+    .map_err(|_| ErrorCode::InvalidCircuitBreaker)?;
+    if market.circuit_breaker.key != &address {
+        return Err(ErrorCode::InvalidCircuitBreaker.into());
+    }
+    circuit_breaker.record(Clock::get()?.slot, notional)?;
+    circuit_breaker.exit(&ID)?;
+    Ok(())
+}
+
+// Resolves the `ENTIRE_BALANCE` sentinel into the current balance of the
+// account funding the order, capped by the delegated amount when `authority`
+// is a delegate rather than the owner. Any other amount is returned as is.
+fn resolve_amount(
+    amount: u64,
+    authority: &AccountInfo,
+    order_payer_token_account: &AccountInfo,
+) -> Result<u64> {
+    if amount != ENTIRE_BALANCE {
+        return Ok(amount);
+    }
+    let balance = token::accessor::amount(order_payer_token_account)?;
+    if token::accessor::authority(order_payer_token_account)? == *authority.key {
+        return Ok(balance);
+    }
+    let order_payer =
+        token::TokenAccount::try_deserialize(&mut &order_payer_token_account.data.borrow()[..])?;
+    Ok(balance.min(order_payer.delegated_amount))
+}
+
+// Splits `amount` into shares proportional to `weights`. Any remainder from
+// rounding down is left unallocated. Errors if a share rounds down to zero,
+// since an order can't be sent for it.
+fn split_amount(amount: u64, weights: &[u64]) -> Result<Vec<u64>> {
+    if weights.is_empty() || weights.contains(&0) {
+        return Err(ErrorCode::InvalidWeights.into());
+    }
+    let total_weight = weights
+        .iter()
+        .try_fold(0u64, |acc, w| acc.checked_add(*w))
+        .ok_or(ErrorCode::InvalidWeights)?;
+    let shares: Vec<u64> = weights
+        .iter()
+        .map(|weight| {
+            u128::from(amount)
+                .checked_mul((*weight).into())
+                .unwrap()
+                .checked_div(total_weight.into())
+                .unwrap() as u64
+        })
+        .collect();
+    if shares.contains(&0) {
+        return Err(ErrorCode::InvalidWeights.into());
+    }
+    Ok(shares)
+}
+
+// Emits the event via a self-CPI to `log_event`, and to the logs as well with
+// the `log-events` feature.
+fn emit_event<E: anchor_lang::Event>(event_cpi: &EventCpi, event: &E) -> Result<()> {
+    #[cfg(feature = "log-events")]
+    emit!(*event);
+
+    // Signing fails unless the event authority is the PDA.
+    let ix = Instruction {
+        program_id: ID,
+        accounts: vec![AccountMeta::new_readonly(
+            *event_cpi.event_authority.key,
+            true,
+        )],
+        data: anchor_lang::InstructionData::data(&instruction::LogEvent {
+            _event: anchor_lang::Event::data(event),
+        }),
+    };
+    invoke_signed(
+        &ix,
+        &[
+            event_cpi.event_authority.clone(),
+            event_cpi.program.to_account_info(),
+        ],
+        &[&[EVENT_AUTHORITY_SEED, &[EVENT_AUTHORITY_BUMP]]],
+    )?;
+    Ok(())
+}
+
+// Asserts the swap event executed at an exchange rate acceptable to the client.
+//
+// If `allow_zero_output`, a swap where nothing filled passes.
+fn apply_risk_checks(event_cpi: &EventCpi, event: DidSwap, allow_zero_output: bool) -> Result<()> {
+    // Emit the event for client consumption.
+    emit_event(event_cpi, &event)?;
+
+    if event.to_amount == 0 {
+        if allow_zero_output && event.from_amount == 0 {
+            return Ok(());
+        }
+        return Err(ErrorCode::ZeroSwap.into());
+    }
+
+    // Abort if the resulting amount is less than the client's expectation.
+    let expectation = risk::Expectation::new(
+        event.from_amount,
+        event.to_amount,
+        event.quote_amount,
+        event.spill_amount,
+        &event.min_exchange_rate,
+    );
+    if !expectation.is_met() {
+        let risk::Expectation {
+            effective_to_amount,
+            min_expected_amount,
+        } = expectation;
+        msg!(
+            "effective_to_amount, min_expected_amount: {:?}, {:?}",
+            effective_to_amount,
+            min_expected_amount,
+        );
+        return Err(slippage_exceeded(SwapFailed {
+            authority: event.authority,
+            from_mint: event.from_mint,
+            to_mint: event.to_mint,
+            from_amount: event.from_amount,
+            to_amount: event.to_amount,
+            quote_amount: event.quote_amount,
+            spill_amount: event.spill_amount,
+            effective_to_amount,
+            min_expected_amount,
+        }));
+    }
+
+    Ok(())
+}
+
+// Returns the `SlippageExceeded` error, logging the `SwapFailed` event.
+//
+// Unlike `emit_event`, there's no self-CPI, since the inner instructions of a
+// failed transaction aren't recorded. Its logs are, and returned by
+// simulation, so clients can show how far off the swap was.
+fn slippage_exceeded(failure: SwapFailed) -> Error {
+    emit!(failure);
+    ErrorCode::SlippageExceeded.into()
+}
+
+#[derive(Accounts)]
+pub struct InitAccount<'info> {
+    #[account(mut)]
+    /// CHECK: test
+    open_orders: AccountInfo<'info>,
+    #[account(signer)]
+    /// CHECK: test
+    authority: AccountInfo<'info>,
+    // Funds the rent of the open orders account, if created.
+    #[account(mut, signer)]
+    /// CHECK: test
+    payer: AccountInfo<'info>,
+    /// CHECK: test
+    market: AccountInfo<'info>,
+    /// CHECK: test
+    dex_program: AccountInfo<'info>,
+    /// CHECK: test
+    rent: AccountInfo<'info>,
+    system_program: Program<'info, System>,
+    // Accounts for emitting events via self-CPI.
+    event_cpi: EventCpi<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CancelAllAndSettle<'info> {
+    #[account(mut)]
+    /// CHECK: test
+    market: AccountInfo<'info>,
+    #[account(mut)]
+    /// CHECK: test
+    open_orders: AccountInfo<'info>,
+    #[account(signer)]
+    /// CHECK: test
+    authority: AccountInfo<'info>,
+    #[account(mut)]
+    /// CHECK: test
+    bids: AccountInfo<'info>,
+    #[account(mut)]
+    /// CHECK: test
+    asks: AccountInfo<'info>,
+    #[account(mut)]
+    /// CHECK: test
+    event_queue: AccountInfo<'info>,
+    #[account(mut)]
+    /// CHECK: test
+    coin_vault: AccountInfo<'info>,
+    #[account(mut)]
+    /// CHECK: test
+    pc_vault: AccountInfo<'info>,
+    /// CHECK: test
+    vault_signer: AccountInfo<'info>,
+    #[account(mut)]
+    /// CHECK: test
+    coin_wallet: AccountInfo<'info>,
+    #[account(mut)]
+    /// CHECK: test
+    pc_wallet: AccountInfo<'info>,
+    // Receives the rent of the open orders account, if closed.
+    #[account(mut)]
+    /// CHECK: test
+    destination: AccountInfo<'info>,
+    /// CHECK: test
+    dex_program: AccountInfo<'info>,
+    token_program: Program<'info, Token>,
+    // Accounts for emitting events via self-CPI.
+    event_cpi: EventCpi<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ConsumeEvents<'info> {
+    #[account(mut, seeds = [CRANK_TIP_SEED], bump = crank_tip.bump)]
+    pub crank_tip: Account<'info, CrankTip>,
+    #[account(mut)]
+    /// CHECK: test
+    pub market: AccountInfo<'info>,
+    #[account(mut)]
+    /// CHECK: test
+    pub event_queue: AccountInfo<'info>,
+    // Vault the tip is paid out of.
+    #[account(mut, seeds = [CRANK_TIP_VAULT_SEED], bump)]
+    pub tip_vault: Account<'info, TokenAccount>,
+    // Cranker's token account receiving the tip.
+    #[account(mut)]
+    pub destination: Account<'info, TokenAccount>,
+    // Only the DEX itself, whose event queues can't be faked.
+    #[account(address = dex::ID)]
+    /// CHECK: test
+    pub dex_program: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+    // Accounts for emitting events via self-CPI.
+    pub event_cpi: EventCpi<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitSwapWallets<'info> {
+    // Owner of the wallets.
+    /// CHECK: test
+    authority: AccountInfo<'info>,
+    // Funds the rent of the wallets, if created.
+    #[account(mut, signer)]
+    /// CHECK: test
+    payer: AccountInfo<'info>,
+    /// CHECK: test
+    market: AccountInfo<'info>,
+    coin_mint: Account<'info, Mint>,
+    pc_mint: Account<'info, Mint>,
+    #[account(
+        mut,
+        associated_token::mint = coin_mint,
+        associated_token::authority = authority,
+    )]
+    /// CHECK: test
+    coin_wallet: AccountInfo<'info>,
+    #[account(
+        mut,
+        associated_token::mint = pc_mint,
+        associated_token::authority = authority,
+    )]
+    /// CHECK: test
+    pc_wallet: AccountInfo<'info>,
+    associated_token_program: Program<'info, AssociatedToken>,
+    token_program: Program<'info, Token>,
+    system_program: Program<'info, System>,
+    /// CHECK: test
+    rent: AccountInfo<'info>,
+}
+
+// Initializes the open orders account, creating it first if needed. The
+// market's open orders authority, if permissioned, is the first of the
+// `remaining_accounts`.
+fn init_open_orders<'info>(
+    accs: &mut InitAccount<'info>,
+    remaining_accounts: &[AccountInfo<'info>],
+) -> Result<()> {
+    let event = OpenOrdersInitialized {
+        authority: *accs.authority.key,
+        market: *accs.market.key,
+        open_orders: *accs.open_orders.key,
+    };
+    if *accs.open_orders.owner == system_program::ID {
+        create_open_orders(
+            &accs.payer,
+            &accs.open_orders,
+            &accs.dex_program,
+            &accs.system_program.to_account_info(),
+        )?;
+    }
+    let mut ctx = CpiContext::new(accs.dex_program.clone(), accs.into());
+    if let Some(market_authority) = remaining_accounts.first() {
+        if !market_authority.is_signer {
+            return Err(ErrorCode::InvalidMarketAuthority.into());
+        }
+        ctx = ctx.with_remaining_accounts(vec![market_authority.clone()]);
+    }
+    dex::init_open_orders(ctx)?;
+    emit_event(&accs.event_cpi, &event)
+}
+
+// Returns true if the open orders account is already initialized, erroring if
+// it's for another market or authority.
+fn is_open_orders_initialized(accs: &InitAccount) -> Result<bool> {
+    if accs.open_orders.owner != &dex::ID {
+        return Ok(false);
+    }
+    let data = accs.open_orders.try_borrow_data()?;
+    // Skips the DEX's b"serum" padding, which is followed by the account
+    // flags, the market, and the owner.
+    if data.len() < 77 {
+        return Ok(false);
+    }
+    let flags = u64::from_le_bytes(data[5..13].try_into().unwrap());
+    let initialized = AccountFlag::Initialized as u64 | AccountFlag::OpenOrders as u64;
+    if flags & initialized != initialized {
+        return Ok(false);
+    }
+    if &data[13..45] != accs.market.key.as_ref() || &data[45..77] != accs.authority.key.as_ref() {
+        return Err(ErrorCode::OpenOrdersMismatch.into());
+    }
+    Ok(true)
+}
+
+// Byte offsets into an open orders account, after the DEX's b"serum" padding.
+// The struct is packed, so these are the sums of the sizes of the fields
+// before.
+const OPEN_ORDERS_FREE_SLOT_BITS_OFFSET: usize = 104;
+const OPEN_ORDERS_IS_BID_BITS_OFFSET: usize = 120;
+const OPEN_ORDERS_ORDERS_OFFSET: usize = 136;
+
+// Returns the side and id of every order of the `open_orders` account, owned
+// by the `dex_program`, resting on the book.
+fn resting_orders(
+    open_orders: &AccountInfo,
+    dex_program: &AccountInfo,
+) -> Result<Vec<(SerumSide, u128)>> {
+    if open_orders.owner != dex_program.key {
+        return Err(ErrorCode::OpenOrdersMismatch.into());
+    }
+    let data = open_orders.try_borrow_data()?;
+    // Skips the DEX's b"serum" padding.
+    let read_u128 = |offset: usize| -> Result<u128> {
+        let start = 5 + offset;
+        data.get(start..start + 16)
+            .map(|bytes| u128::from_le_bytes(bytes.try_into().unwrap()))
+            .ok_or_else(|| ErrorCode::OpenOrdersMismatch.into())
+    };
+    let free_slot_bits = read_u128(OPEN_ORDERS_FREE_SLOT_BITS_OFFSET)?;
+    let is_bid_bits = read_u128(OPEN_ORDERS_IS_BID_BITS_OFFSET)?;
+    let mut orders = vec![];
+    for slot in 0..MAX_OPEN_ORDERS_SLOTS as usize {
+        if free_slot_bits & (1 << slot) != 0 {
+            continue;
+        }
+        let side = match is_bid_bits & (1 << slot) != 0 {
+            true => SerumSide::Bid,
+            false => SerumSide::Ask,
+        };
+        let order_id = read_u128(OPEN_ORDERS_ORDERS_OFFSET + 16 * slot)?;
+        orders.push((side, order_id));
+    }
+    Ok(orders)
+}
+
+// Consumes up to `limit` events of the market's event queue, stopping at the
+// first one for an open orders account other than the given ones, which must
+// be sorted as the DEX expects.
+fn consume_dex_events<'info>(
+    dex_program: &AccountInfo<'info>,
+    market: &AccountInfo<'info>,
+    event_queue: &AccountInfo<'info>,
+    open_orders: &[AccountInfo<'info>],
+    fee_receivables: [&AccountInfo<'info>; 2],
+    limit: u16,
+) -> ProgramResult {
+    // The DEX no longer credits its fee receivable accounts, but still takes
+    // them, so any writable accounts do.
+    let ix = dex::serum_dex::instruction::consume_events(
+        dex_program.key,
+        open_orders.iter().map(|account| account.key).collect(),
+        market.key,
+        event_queue.key,
+        fee_receivables[0].key,
+        fee_receivables[1].key,
+        limit,
+    )?;
+    let mut account_infos = open_orders.to_vec();
+    account_infos.extend([
+        market.clone(),
+        event_queue.clone(),
+        fee_receivables[0].clone(),
+        fee_receivables[1].clone(),
+    ]);
+    invoke(&ix, &account_infos)
+}
+
+// Creates the open orders account, owned by the DEX, which must then sign.
+fn create_open_orders<'info>(
+    payer: &AccountInfo<'info>,
+    open_orders: &AccountInfo<'info>,
+    dex_program: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+) -> Result<()> {
+    // The DEX pads its accounts with 12 bytes.
+    let space = std::mem::size_of::<dex::serum_dex::state::OpenOrders>() + 12;
+    let lamports = Rent::get()?.minimum_balance(space);
+    let ix = system_instruction::create_account(
+        payer.key,
+        open_orders.key,
+        lamports,
+        space as u64,
+        dex_program.key,
+    );
+    invoke(
+        &ix,
+        &[payer.clone(), open_orders.clone(), system_program.clone()],
+    )?;
+    Ok(())
+}
+
+// Creates and initializes an open orders account whose authority is a PDA of
+// this program, signing with its `signer` seeds.
+fn init_signed_open_orders<'info>(
+    payer: &AccountInfo<'info>,
+    accounts: dex::InitOpenOrders<'info>,
+    dex_program: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    event_cpi: &EventCpi<'info>,
+    signer: &[&[&[u8]]],
+) -> Result<()> {
+    create_open_orders(payer, &accounts.open_orders, dex_program, system_program)?;
+    let event = OpenOrdersInitialized {
+        authority: *accounts.authority.key,
+        market: *accounts.market.key,
+        open_orders: *accounts.open_orders.key,
+    };
+    let cpi_ctx = CpiContext::new_with_signer(dex_program.clone(), accounts, signer);
+    dex::init_open_orders(cpi_ctx)?;
+    emit_event(event_cpi, &event)
+}
+
+// Transfers `amount` out of a token account owned by a PDA of this program,
+// signing with its `signer` seeds.
+fn transfer_signed<'info>(
+    token_program: &Program<'info, Token>,
+    from: &Account<'info, TokenAccount>,
+    to: &AccountInfo<'info>,
+    authority: &AccountInfo<'info>,
+    signer: &[&[&[u8]]],
+    amount: u64,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+    let cpi_ctx = CpiContext::new_with_signer(
+        token_program.to_account_info(),
+        token::Transfer {
+            from: from.to_account_info(),
+            to: to.clone(),
+            authority: authority.clone(),
+        },
+        signer,
+    );
+    token::transfer(cpi_ctx, amount)?;
+    Ok(())
+}
+
+// Empties the `vaults` of a schedule owned by the `authority` PDA into their
+// wallets and closes them, along with its open orders account, refunding the
+// rent to the `destination`.
+fn close_schedule<'info>(
+    accounts: dex::CloseOpenOrders<'info>,
+    signer: &[&[&[u8]]],
+    vaults: &[(&Account<'info, TokenAccount>, &AccountInfo<'info>)],
+    dex_program: &AccountInfo<'info>,
+    token_program: &Program<'info, Token>,
+) -> Result<()> {
+    for (vault, wallet) in vaults {
+        transfer_signed(
+            token_program,
+            vault,
+            wallet,
+            &accounts.authority,
+            signer,
+            vault.amount,
+        )?;
+        let cpi_ctx = CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            token::CloseAccount {
+                account: vault.to_account_info(),
+                destination: accounts.destination.clone(),
+                authority: accounts.authority.clone(),
+            },
+            signer,
+        );
+        token::close_account(cpi_ctx)?;
+    }
+    let cpi_ctx = CpiContext::new_with_signer(dex_program.clone(), accounts, signer);
+    dex::close_open_orders(cpi_ctx)?;
+    Ok(())
+}
+
+impl<'info> From<&mut InitAccount<'info>> for dex::InitOpenOrders<'info> {
+    fn from(accs: &mut InitAccount<'info>) -> dex::InitOpenOrders<'info> {
+        dex::InitOpenOrders {
+            open_orders: accs.open_orders.clone(),
+            authority: accs.authority.clone(),
+            market: accs.market.clone(),
+            rent: accs.rent.clone(),
+        }
+    }
+}
+
+#[derive(Accounts)]
+pub struct CloseAccount<'info> {
+    #[account(mut)]
+    /// CHECK: test
+    open_orders: AccountInfo<'info>,
+    #[account(signer)]
+    /// CHECK: test
+    authority: AccountInfo<'info>,
+    #[account(mut)]
+    /// CHECK: test
+    destination: AccountInfo<'info>,
+    /// CHECK: test
+    market: AccountInfo<'info>,
+    /// CHECK: test
+    dex_program: AccountInfo<'info>,
+    // Accounts for emitting events via self-CPI.
+    event_cpi: EventCpi<'info>,
+}
+
+impl<'info> From<&mut CloseAccount<'info>> for dex::CloseOpenOrders<'info> {
+    fn from(accs: &mut CloseAccount<'info>) -> dex::CloseOpenOrders<'info> {
+        dex::CloseOpenOrders {
+            open_orders: accs.open_orders.clone(),
+            authority: accs.authority.clone(),
+            destination: accs.destination.clone(),
+            market: accs.market.clone(),
+        }
+    }
+}
+
+// The only constraint imposed on these accounts is that the market's base
+// currency mint is not equal to the quote currency's. All other checks are
+// done by the DEX on CPI.
+#[derive(Accounts)]
+pub struct Swap<'info> {
+    /// CHECK: test
+    pub market: MarketAccounts<'info>,
+    // Must be the authority over the open orders account used. May be a PDA.
+    #[account(signer)]
+    /// CHECK: test
+    pub authority: AccountInfo<'info>,
+    #[account(mut, constraint = pc_wallet.key != &empty::ID)]
+    /// CHECK: test
+    pub pc_wallet: AccountInfo<'info>,
+    #[account(seeds = [CONFIG_SEED], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    // Market whitelist. Only checked when enabled in the config.
+    /// CHECK: test
+    pub whitelist: AccountInfo<'info>,
+    // Authority's stats, or this program's ID to skip tracking. Writable
+    // unless skipped.
+    /// CHECK: test
+    pub user_stats: AccountInfo<'info>,
+    // Spending policy PDA of the owner of the order payer over the
+    // authority, enforced, and so written, if it exists.
+    #[account(mut)]
+    /// CHECK: test
+    pub spending_policy: AccountInfo<'info>,
+    // Protocol fee vault for the *to* mint. Only used when there's a fee.
+    #[account(mut)]
+    /// CHECK: test
+    pub fee_vault: AccountInfo<'info>,
+    // Integrator's token account for the *to* mint. Only used when there's a
+    // platform fee.
+    #[account(mut)]
+    /// CHECK: test
+    pub platform_fee_account: AccountInfo<'info>,
+    // Quote currency token account receiving the DEX's referral rebate, or
+    // this program's ID for none. Writable unless skipped.
+    #[account(constraint = referral.key == &ID || referral.owner == &token::ID)]
+    /// CHECK: test
+    pub referral: AccountInfo<'info>,
+    // The authority's (M)SRM token account lowering the DEX's fee tier, or
+    // this program's ID for none.
+    #[account(constraint = srm_msrm_discount.key == &ID || srm_msrm_discount.owner == &token::ID)]
+    /// CHECK: test
+    pub srm_msrm_discount: AccountInfo<'info>,
+    // Programs.
+    /// CHECK: test
+    pub dex_program: AccountInfo<'info>,
+    /// CHECK: test
+    pub token_program: AccountInfo<'info>,
+    /// CHECK: test
+    pub rent: AccountInfo<'info>,
+    // Accounts for emitting events via self-CPI.
+    pub event_cpi: EventCpi<'info>,
+}
+
+impl<'info> Swap<'info> {
+    fn protocol_fee(&self) -> ProtocolFee<'info> {
+        ProtocolFee {
+            config: self.config.key(),
+            fee_bps: self.config.fee_bps,
+            fee_vault: self.fee_vault.clone(),
+        }
+    }
+
+    fn platform_fee(&self, fee_bps: u16) -> Result<PlatformFee<'info>> {
+        PlatformFee::new(fee_bps, self.platform_fee_account.clone())
+    }
+}
+
+#[derive(Accounts)]
+pub struct FlashSwap<'info> {
+    pub swap: Swap<'info>,
+    // Flash vault PDA of the *to* mint, lending the output.
+    #[account(mut)]
+    /// CHECK: test
+    pub flash_vault: AccountInfo<'info>,
+}
+
+impl<'info> From<&Swap<'info>> for OrderbookClient<'info> {
+    fn from(accounts: &Swap<'info>) -> OrderbookClient<'info> {
+        OrderbookClient {
+            market: accounts.market.clone(),
+            authority: accounts.authority.clone(),
+            pc_wallet: accounts.pc_wallet.clone(),
+            dex_program: accounts.dex_program.clone(),
+            token_program: accounts.token_program.clone(),
+            rent: accounts.rent.clone(),
+            execution: accounts.config.execution(),
+            circuit_breakers: accounts.config.circuit_breakers,
+            srm_msrm_discount: optional_account(&accounts.srm_msrm_discount),
+            event_cpi: accounts.event_cpi.clone(),
+            market_info: Cell::new(None),
+            book: Cell::new(None),
+        }
+    }
+}
+
+// The only constraint imposed on these accounts is that the from market's
+// base currency's is not equal to the to market's base currency. All other
+// checks are done by the DEX on CPI (and the quote currency is ensured to be
+// the same on both markets since there's only one account field for it).
+#[derive(Accounts)]
+pub struct SwapTransitive<'info> {
+    /// CHECK: test
+    pub from: MarketAccounts<'info>,
+    /// CHECK: test
+    pub to: MarketAccounts<'info>,
+    // Must be the authority over all open orders accounts used. May be a PDA.
+    #[account(signer)]
+    /// CHECK: test
+    pub authority: AccountInfo<'info>,
+    #[account(mut, constraint = pc_wallet.key != &empty::ID)]
+    /// CHECK: test
+    pub pc_wallet: AccountInfo<'info>,
+    #[account(seeds = [CONFIG_SEED], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    // Market whitelist. Only checked when enabled in the config.
+    /// CHECK: test
+    pub whitelist: AccountInfo<'info>,
+    // Authority's stats, or this program's ID to skip tracking. Writable
+    // unless skipped.
+    /// CHECK: test
+    pub user_stats: AccountInfo<'info>,
+    // Spending policy PDA of the owner of the order payer over the
+    // authority, enforced, and so written, if it exists.
+    #[account(mut)]
+    /// CHECK: test
+    pub spending_policy: AccountInfo<'info>,
+    // Protocol fee vault for the *to* mint. Only used when there's a fee.
+    #[account(mut)]
+    /// CHECK: test
+    pub fee_vault: AccountInfo<'info>,
+    // Integrator's token account for the *to* mint. Only used when there's a
+    // platform fee.
+    #[account(mut)]
+    /// CHECK: test
+    pub platform_fee_account: AccountInfo<'info>,
+    // Quote currency token account receiving the DEX's referral rebate, or
+    // this program's ID for none. Writable unless skipped.
+    #[account(constraint = referral.key == &ID || referral.owner == &token::ID)]
+    /// CHECK: test
+    pub referral: AccountInfo<'info>,
+    // The authority's (M)SRM token account lowering the DEX's fee tier, or
+    // this program's ID for none.
+    #[account(constraint = srm_msrm_discount.key == &ID || srm_msrm_discount.owner == &token::ID)]
+    /// CHECK: test
+    pub srm_msrm_discount: AccountInfo<'info>,
+    // Programs.
+    /// CHECK: test
+    pub dex_program: AccountInfo<'info>,
+    /// CHECK: test
+    pub token_program: AccountInfo<'info>,
+    // Sysvars.
+    /// CHECK: test
+    pub rent: AccountInfo<'info>,
+    // Accounts for emitting events via self-CPI.
+    pub event_cpi: EventCpi<'info>,
+}
+
+impl<'info> SwapTransitive<'info> {
+    fn protocol_fee(&self) -> ProtocolFee<'info> {
+        ProtocolFee {
+            config: self.config.key(),
+            fee_bps: self.config.fee_bps,
+            fee_vault: self.fee_vault.clone(),
+        }
+    }
+
+    fn platform_fee(&self, fee_bps: u16) -> Result<PlatformFee<'info>> {
+        PlatformFee::new(fee_bps, self.platform_fee_account.clone())
+    }
+
+    fn orderbook_from(&self) -> OrderbookClient<'info> {
+        OrderbookClient {
+            market: self.from.clone(),
+            authority: self.authority.clone(),
+            pc_wallet: self.pc_wallet.clone(),
+            dex_program: self.dex_program.clone(),
+            token_program: self.token_program.clone(),
+            rent: self.rent.clone(),
+            execution: self.config.execution(),
+            circuit_breakers: self.config.circuit_breakers,
+            srm_msrm_discount: optional_account(&self.srm_msrm_discount),
+            event_cpi: self.event_cpi.clone(),
+            market_info: Cell::new(None),
+            book: Cell::new(None),
+        }
+    }
+    fn orderbook_to(&self) -> OrderbookClient<'info> {
+        OrderbookClient {
+            market: self.to.clone(),
+            authority: self.authority.clone(),
+            pc_wallet: self.pc_wallet.clone(),
+            dex_program: self.dex_program.clone(),
+            token_program: self.token_program.clone(),
+            rent: self.rent.clone(),
+            execution: self.config.execution(),
+            circuit_breakers: self.config.circuit_breakers,
+            srm_msrm_discount: optional_account(&self.srm_msrm_discount),
+            event_cpi: self.event_cpi.clone(),
+            market_info: Cell::new(None),
+            book: Cell::new(None),
+        }
+    }
+}
+
+// The mirror image of `SwapTransitive`, where both markets share the base
+// currency wallet, given as each market's `coin_wallet`, and each has its own
+// quote currency wallet.
+#[derive(Accounts)]
+pub struct SwapQuoteToQuote<'info> {
+    /// CHECK: test
+    pub from: MarketAccounts<'info>,
+    /// CHECK: test
+    pub to: MarketAccounts<'info>,
+    // Must be the authority over all open orders accounts used. May be a PDA.
+    #[account(signer)]
+    /// CHECK: test
+    pub authority: AccountInfo<'info>,
+    // Quote currency wallet of the from market.
+    #[account(mut, constraint = from_pc_wallet.key != &empty::ID)]
+    /// CHECK: test
+    pub from_pc_wallet: AccountInfo<'info>,
+    // Quote currency wallet of the to market.
+    #[account(mut, constraint = to_pc_wallet.key != &empty::ID)]
+    /// CHECK: test
+    pub to_pc_wallet: AccountInfo<'info>,
+    #[account(seeds = [CONFIG_SEED], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    // Market whitelist. Only checked when enabled in the config.
+    /// CHECK: test
+    pub whitelist: AccountInfo<'info>,
+    // Authority's stats, or this program's ID to skip tracking. Writable
+    // unless skipped.
+    /// CHECK: test
+    pub user_stats: AccountInfo<'info>,
+    // Spending policy PDA of the owner of the order payer over the
+    // authority, enforced, and so written, if it exists.
+    #[account(mut)]
+    /// CHECK: test
+    pub spending_policy: AccountInfo<'info>,
+    // Protocol fee vault for the *to* mint. Only used when there's a fee.
+    #[account(mut)]
+    /// CHECK: test
+    pub fee_vault: AccountInfo<'info>,
+    // Integrator's token account for the *to* mint. Only used when there's a
+    // platform fee.
+    #[account(mut)]
+    /// CHECK: test
+    pub platform_fee_account: AccountInfo<'info>,
+    // Programs.
+    /// CHECK: test
+    pub dex_program: AccountInfo<'info>,
+    /// CHECK: test
+    pub token_program: AccountInfo<'info>,
+    // Sysvars.
+    /// CHECK: test
+    pub rent: AccountInfo<'info>,
+    // Accounts for emitting events via self-CPI.
+    pub event_cpi: EventCpi<'info>,
+}
+
+impl<'info> SwapQuoteToQuote<'info> {
+    fn protocol_fee(&self) -> ProtocolFee<'info> {
+        ProtocolFee {
+            config: self.config.key(),
+            fee_bps: self.config.fee_bps,
+            fee_vault: self.fee_vault.clone(),
+        }
+    }
+
+    fn platform_fee(&self, fee_bps: u16) -> Result<PlatformFee<'info>> {
+        PlatformFee::new(fee_bps, self.platform_fee_account.clone())
+    }
+
+    fn orderbook_from(&self) -> OrderbookClient<'info> {
+        OrderbookClient {
+            market: self.from.clone(),
+            authority: self.authority.clone(),
+            pc_wallet: self.from_pc_wallet.clone(),
+            dex_program: self.dex_program.clone(),
+            token_program: self.token_program.clone(),
+            rent: self.rent.clone(),
+            execution: self.config.execution(),
+            circuit_breakers: self.config.circuit_breakers,
+            srm_msrm_discount: None,
+            event_cpi: self.event_cpi.clone(),
+            market_info: Cell::new(None),
+            book: Cell::new(None),
+        }
+    }
+
+    fn orderbook_to(&self) -> OrderbookClient<'info> {
+        OrderbookClient {
+            market: self.to.clone(),
+            authority: self.authority.clone(),
+            pc_wallet: self.to_pc_wallet.clone(),
+            dex_program: self.dex_program.clone(),
+            token_program: self.token_program.clone(),
+            rent: self.rent.clone(),
+            execution: self.config.execution(),
+            circuit_breakers: self.config.circuit_breakers,
+            srm_msrm_discount: None,
+            event_cpi: self.event_cpi.clone(),
+            market_info: Cell::new(None),
+            book: Cell::new(None),
+        }
+    }
+}
+
+// Two markets trading the same pair, sharing the base currency wallet, given
+// as each market's `coin_wallet`, and the quote currency wallet.
+#[derive(Accounts)]
+pub struct Arbitrage<'info> {
+    // Market the base currency is bought on.
+    /// CHECK: test
+    pub buy: MarketAccounts<'info>,
+    // Market it's sold on.
+    /// CHECK: test
+    pub sell: MarketAccounts<'info>,
+    // Must be the authority over both open orders accounts. May be a PDA.
+    #[account(signer)]
+    /// CHECK: test
+    pub authority: AccountInfo<'info>,
+    #[account(mut, constraint = pc_wallet.key != &empty::ID)]
+    /// CHECK: test
+    pub pc_wallet: AccountInfo<'info>,
+    #[account(seeds = [CONFIG_SEED], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    // Market whitelist. Only checked when enabled in the config.
+    /// CHECK: test
+    pub whitelist: AccountInfo<'info>,
+    // Authority's stats, or this program's ID to skip tracking. Writable
+    // unless skipped.
+    /// CHECK: test
+    pub user_stats: AccountInfo<'info>,
+    // Spending policy PDA of the owner of the order payer over the
+    // authority, enforced, and so written, if it exists.
+    #[account(mut)]
+    /// CHECK: test
+    pub spending_policy: AccountInfo<'info>,
+    // Protocol fee vault for the quote mint. Only used when there's a fee.
+    #[account(mut)]
+    /// CHECK: test
+    pub fee_vault: AccountInfo<'info>,
+    // Programs.
+    /// CHECK: test
+    pub dex_program: AccountInfo<'info>,
+    /// CHECK: test
+    pub token_program: AccountInfo<'info>,
+    // Sysvars.
+    /// CHECK: test
+    pub rent: AccountInfo<'info>,
+    // Accounts for emitting events via self-CPI.
+    pub event_cpi: EventCpi<'info>,
+}
+
+impl<'info> Arbitrage<'info> {
+    fn protocol_fee(&self) -> ProtocolFee<'info> {
+        ProtocolFee {
+            config: self.config.key(),
+            fee_bps: self.config.fee_bps,
+            fee_vault: self.fee_vault.clone(),
+        }
+    }
+
+    fn orderbook(&self, market: &MarketAccounts<'info>) -> OrderbookClient<'info> {
+        OrderbookClient {
+            market: market.clone(),
+            authority: self.authority.clone(),
+            pc_wallet: self.pc_wallet.clone(),
+            dex_program: self.dex_program.clone(),
+            token_program: self.token_program.clone(),
+            rent: self.rent.clone(),
+            execution: self.config.execution(),
+            circuit_breakers: self.config.circuit_breakers,
+            srm_msrm_discount: None,
+            event_cpi: self.event_cpi.clone(),
+            market_info: Cell::new(None),
+            book: Cell::new(None),
+        }
+    }
+}
+
+// The market account groups for a basket swap are given as remaining
+// accounts. The quote currency is shared by all of them since there's only
+// one account field for it.
+#[derive(Accounts)]
+pub struct SwapBasket<'info> {
+    // Must be the authority over all open orders accounts used. May be a PDA.
+    #[account(signer)]
+    /// CHECK: test
+    pub authority: AccountInfo<'info>,
+    #[account(mut, constraint = pc_wallet.key != &empty::ID)]
+    /// CHECK: test
+    pub pc_wallet: AccountInfo<'info>,
+    #[account(seeds = [CONFIG_SEED], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    // Market whitelist. Only checked when enabled in the config.
+    /// CHECK: test
+    pub whitelist: AccountInfo<'info>,
+    // Authority's stats, or this program's ID to skip tracking. Writable
+    // unless skipped.
+    /// CHECK: test
+    pub user_stats: AccountInfo<'info>,
+    // Spending policy PDA of the owner of the order payer over the
+    // authority, enforced, and so written, if it exists.
+    #[account(mut)]
+    /// CHECK: test
+    pub spending_policy: AccountInfo<'info>,
+    // Programs.
+    /// CHECK: test
+    pub dex_program: AccountInfo<'info>,
+    /// CHECK: test
+    pub token_program: AccountInfo<'info>,
+    // Sysvars.
+    /// CHECK: test
+    pub rent: AccountInfo<'info>,
+    // Accounts for emitting events via self-CPI.
+    pub event_cpi: EventCpi<'info>,
+}
+
+impl<'info> SwapBasket<'info> {
+    fn protocol_fee(&self, fee_vault: AccountInfo<'info>) -> ProtocolFee<'info> {
+        ProtocolFee {
+            config: self.config.key(),
+            fee_bps: self.config.fee_bps,
+            fee_vault,
+        }
+    }
+
+    fn orderbook(&self, market: MarketAccounts<'info>) -> OrderbookClient<'info> {
+        OrderbookClient {
+            market,
+            authority: self.authority.clone(),
+            pc_wallet: self.pc_wallet.clone(),
+            dex_program: self.dex_program.clone(),
+            token_program: self.token_program.clone(),
+            rent: self.rent.clone(),
+            execution: self.config.execution(),
+            circuit_breakers: self.config.circuit_breakers,
+            srm_msrm_discount: None,
+            event_cpi: self.event_cpi.clone(),
+            market_info: Cell::new(None),
+            book: Cell::new(None),
+        }
+    }
+}
+
+// The market account groups for a split swap are given as remaining accounts.
+// The quote currency is shared by all of them since there's only one account
+// field for it.
+#[derive(Accounts)]
+pub struct SwapSplit<'info> {
+    // Must be the authority over all open orders accounts used. May be a PDA.
+    #[account(signer)]
+    /// CHECK: test
+    pub authority: AccountInfo<'info>,
+    #[account(mut, constraint = pc_wallet.key != &empty::ID)]
+    /// CHECK: test
+    pub pc_wallet: AccountInfo<'info>,
+    #[account(seeds = [CONFIG_SEED], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    // Market whitelist. Only checked when enabled in the config.
+    /// CHECK: test
+    pub whitelist: AccountInfo<'info>,
+    // Authority's stats, or this program's ID to skip tracking. Writable
+    // unless skipped.
+    /// CHECK: test
+    pub user_stats: AccountInfo<'info>,
+    // Spending policy PDA of the owner of the order payer over the
+    // authority, enforced, and so written, if it exists.
+    #[account(mut)]
+    /// CHECK: test
+    pub spending_policy: AccountInfo<'info>,
+    // Protocol fee vault for the *to* mint. Only used when there's a fee.
+    #[account(mut)]
+    /// CHECK: test
+    pub fee_vault: AccountInfo<'info>,
+    // Programs.
+    /// CHECK: test
+    pub dex_program: AccountInfo<'info>,
+    /// CHECK: test
+    pub token_program: AccountInfo<'info>,
+    // Sysvars.
+    /// CHECK: test
+    pub rent: AccountInfo<'info>,
+    // Accounts for emitting events via self-CPI.
+    pub event_cpi: EventCpi<'info>,
+}
+
+impl<'info> SwapSplit<'info> {
+    fn protocol_fee(&self) -> ProtocolFee<'info> {
+        ProtocolFee {
+            config: self.config.key(),
+            fee_bps: self.config.fee_bps,
+            fee_vault: self.fee_vault.clone(),
+        }
+    }
+
+    fn orderbook(&self, market: MarketAccounts<'info>) -> OrderbookClient<'info> {
+        OrderbookClient {
+            market,
+            authority: self.authority.clone(),
+            pc_wallet: self.pc_wallet.clone(),
+            dex_program: self.dex_program.clone(),
+            token_program: self.token_program.clone(),
+            rent: self.rent.clone(),
+            execution: self.config.execution(),
+            circuit_breakers: self.config.circuit_breakers,
+            srm_msrm_discount: None,
+            event_cpi: self.event_cpi.clone(),
+            market_info: Cell::new(None),
+            book: Cell::new(None),
+        }
+    }
+}
+
+// The accounts for each swap in a batch are given as remaining accounts. Only
+// the accounts common to all of them are here.
+#[derive(Accounts)]
+pub struct SwapMany<'info> {
+    // Must be the authority over all open orders accounts used. May be a PDA.
+    #[account(signer)]
+    /// CHECK: test
+    pub authority: AccountInfo<'info>,
+    #[account(seeds = [CONFIG_SEED], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    // Market whitelist. Only checked when enabled in the config.
+    /// CHECK: test
+    pub whitelist: AccountInfo<'info>,
+    // Authority's stats, or this program's ID to skip tracking. Writable
+    // unless skipped.
+    /// CHECK: test
+    pub user_stats: AccountInfo<'info>,
+    // Programs.
+    /// CHECK: test
+    pub dex_program: AccountInfo<'info>,
+    /// CHECK: test
+    pub token_program: AccountInfo<'info>,
+    // Sysvars.
+    /// CHECK: test
+    pub rent: AccountInfo<'info>,
+    // Accounts for emitting events via self-CPI.
+    pub event_cpi: EventCpi<'info>,
+}
+
+impl<'info> SwapMany<'info> {
+    fn protocol_fee(&self, fee_vault: AccountInfo<'info>) -> ProtocolFee<'info> {
+        ProtocolFee {
+            config: self.config.key(),
+            fee_bps: self.config.fee_bps,
+            fee_vault,
+        }
+    }
+
+    fn orderbook(&self, leg: SwapManyLeg<'info>) -> OrderbookClient<'info> {
+        OrderbookClient {
+            market: leg.market,
+            authority: self.authority.clone(),
+            pc_wallet: leg.pc_wallet,
+            dex_program: self.dex_program.clone(),
+            token_program: self.token_program.clone(),
+            rent: self.rent.clone(),
+            execution: self.config.execution(),
+            circuit_breakers: self.config.circuit_breakers,
+            srm_msrm_discount: None,
+            event_cpi: self.event_cpi.clone(),
+            market_info: Cell::new(None),
+            book: Cell::new(None),
+        }
+    }
+}
+
+// Accounts needed by swaps to invoke `log_event`.
+#[derive(Accounts, Clone)]
+pub struct EventCpi<'info> {
+    // Event authority PDA. Checked by `log_event`.
+    /// CHECK: test
+    pub event_authority: AccountInfo<'info>,
+    pub program: Program<'info, program::SerumSwap>,
+}
+
+// The per swap accounts of a batch, i.e., the market and the quote currency
+// wallet used to trade on it.
+#[derive(Accounts)]
+pub struct SwapManyLeg<'info> {
+    pub market: MarketAccounts<'info>,
+    #[account(mut, constraint = pc_wallet.key != &empty::ID)]
+    /// CHECK: test
+    pub pc_wallet: AccountInfo<'info>,
+    // Spending policy PDA of the owner of the order payer over the
+    // authority, enforced, and so written, if it exists.
+    #[account(mut)]
+    /// CHECK: test
+    pub spending_policy: AccountInfo<'info>,
+    // Protocol fee vault for the *to* mint. Only used when there's a fee.
+    #[account(mut)]
+    /// CHECK: test
+    pub fee_vault: AccountInfo<'info>,
+}
+
+// The per market accounts of a basket swap.
+#[derive(Accounts)]
+pub struct SwapBasketLeg<'info> {
+    pub market: MarketAccounts<'info>,
+    // Protocol fee vault for the market's base currency. Only used when
+    // there's a fee.
+    #[account(mut)]
+    /// CHECK: test
+    pub fee_vault: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(bump: u8)]
+pub struct InitializeConfig<'info> {
+    #[account(
+        init,
+        seeds = [CONFIG_SEED],
+        bump = bump,
+        payer = admin,
+        space = Config::SPACE,
+    )]
+    pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    // This program's data account, holding its upgrade authority.
+    #[account(address = program_data_address())]
+    pub program_data: Account<'info, ProgramData>,
+    pub system_program: Program<'info, System>,
+}
+
+// Address of this program's data account, as deployed by the upgradeable
+// BPF loader.
+fn program_data_address() -> Pubkey {
+    Pubkey::find_program_address(&[ID.as_ref()], &bpf_loader_upgradeable::ID).0
+}
+
+#[derive(Accounts)]
+#[instruction(bump: u8)]
+pub struct CreateFeeVault<'info> {
+    #[account(seeds = [CONFIG_SEED], bump = config.bump)]
+    pub config: Account<'info, Config>,
+    #[account(
+        init,
+        seeds = [FEE_VAULT_SEED, mint.key().as_ref()],
+        bump = bump,
+        payer = payer,
+        token::mint = mint,
+        token::authority = config,
+    )]
+    pub fee_vault: Account<'info, TokenAccount>,
+    pub mint: Account<'info, Mint>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+#[instruction(bump: u8)]
+pub struct CreateFlashVault<'info> {
+    #[account(seeds = [CONFIG_SEED], bump = config.bump, has_one = admin)]
+    pub config: Account<'info, Config>,
+    #[account(
+        init,
+        seeds = [FLASH_VAULT_SEED, mint.key().as_ref()],
+        bump = bump,
+        payer = admin,
+        token::mint = mint,
+        token::authority = config,
+    )]
+    pub flash_vault: Account<'info, TokenAccount>,
+    pub mint: Account<'info, Mint>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateConfig<'info> {
+    #[account(mut, seeds = [CONFIG_SEED], bump = config.bump, has_one = admin)]
+    pub config: Account<'info, Config>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(bump: u8)]
+pub struct CreateWhitelist<'info> {
+    #[account(mut, seeds = [CONFIG_SEED], bump = config.bump, has_one = admin)]
+    pub config: Account<'info, Config>,
+    #[account(
+        init,
+        seeds = [WHITELIST_SEED],
+        bump = bump,
+        payer = admin,
+        space = Whitelist::SPACE,
+    )]
+    pub whitelist: Account<'info, Whitelist>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(bump: u8, vault_bump: u8)]
+pub struct CreateCrankTip<'info> {
+    #[account(seeds = [CONFIG_SEED], bump = config.bump, has_one = admin)]
+    pub config: Account<'info, Config>,
+    #[account(
+        init,
+        seeds = [CRANK_TIP_SEED],
+        bump = bump,
+        payer = admin,
+        space = CrankTip::SPACE,
+    )]
+    pub crank_tip: Account<'info, CrankTip>,
+    #[account(
+        init,
+        seeds = [CRANK_TIP_VAULT_SEED],
+        bump = vault_bump,
+        payer = admin,
+        token::mint = mint,
+        token::authority = crank_tip,
+    )]
+    pub tip_vault: Account<'info, TokenAccount>,
+    pub mint: Account<'info, Mint>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateCrankTip<'info> {
+    #[account(seeds = [CONFIG_SEED], bump = config.bump, has_one = admin)]
+    pub config: Account<'info, Config>,
+    #[account(mut, seeds = [CRANK_TIP_SEED], bump = crank_tip.bump)]
+    pub crank_tip: Account<'info, CrankTip>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateWhitelist<'info> {
+    #[account(seeds = [CONFIG_SEED], bump = config.bump, has_one = admin)]
+    pub config: Account<'info, Config>,
+    #[account(mut, seeds = [WHITELIST_SEED], bump)]
+    pub whitelist: Account<'info, Whitelist>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetWhitelistEnabled<'info> {
+    #[account(mut, seeds = [CONFIG_SEED], bump = config.bump, has_one = admin)]
+    pub config: Account<'info, Config>,
+    #[account(seeds = [WHITELIST_SEED], bump)]
+    pub whitelist: Account<'info, Whitelist>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(bump: u8)]
+pub struct CreateCircuitBreaker<'info> {
+    #[account(mut, seeds = [CONFIG_SEED], bump = config.bump, has_one = admin)]
+    pub config: Account<'info, Config>,
+    #[account(
+        init,
+        seeds = [CIRCUIT_BREAKER_SEED, market.key.as_ref()],
+        bump = bump,
+        payer = admin,
+        space = CircuitBreaker::SPACE,
+    )]
+    pub circuit_breaker: Account<'info, CircuitBreaker>,
+    /// CHECK: test
+    pub market: AccountInfo<'info>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateCircuitBreaker<'info> {
+    #[account(seeds = [CONFIG_SEED], bump = config.bump, has_one = admin)]
+    pub config: Account<'info, Config>,
+    #[account(
+        mut,
+        seeds = [CIRCUIT_BREAKER_SEED, circuit_breaker.market.as_ref()],
+        bump = circuit_breaker.bump,
+    )]
+    pub circuit_breaker: Account<'info, CircuitBreaker>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(bump: u8, vault_bump: u8, nonce: u64)]
+pub struct SwapToEscrow<'info> {
+    pub swap: Swap<'info>,
+    #[account(
+        init,
+        seeds = [ESCROW_SEED, swap.authority.key.as_ref(), nonce.to_le_bytes().as_ref()],
+        bump = bump,
+        payer = payer,
+        space = Escrow::SPACE,
+    )]
+    pub escrow: Account<'info, Escrow>,
+    #[account(
+        init,
+        seeds = [ESCROW_VAULT_SEED, escrow.key().as_ref()],
+        bump = vault_bump,
+        payer = payer,
+        token::mint = mint,
+        token::authority = escrow,
+    )]
+    pub escrow_vault: Account<'info, TokenAccount>,
+    // Mint of the *to* token.
+    pub mint: Account<'info, Mint>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct PayWithSwap<'info> {
+    pub swap: Swap<'info>,
+    // Merchant's token account for the *to* mint.
+    /// CHECK: test
+    #[account(mut)]
+    pub merchant: AccountInfo<'info>,
+    // Key identifying the payment, e.g., an invoice's, never read.
+    /// CHECK: test
+    pub reference: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawEscrow<'info> {
+    #[account(
+        mut,
+        seeds = [ESCROW_SEED, escrow.depositor.as_ref(), escrow.nonce.to_le_bytes().as_ref()],
+        bump = escrow.bump,
+        has_one = beneficiary,
+        has_one = vault,
+        has_one = payer,
+        close = payer,
+    )]
+    pub escrow: Account<'info, Escrow>,
+    pub beneficiary: Signer<'info>,
+    #[account(mut)]
+    pub vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub destination: Account<'info, TokenAccount>,
+    // Payer of the escrow's rent.
+    #[account(mut)]
+    /// CHECK: test
+    pub payer: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+#[instruction(bump: u8, input_vault_bump: u8, output_vault_bump: u8, nonce: u64)]
+pub struct CreateDca<'info> {
+    #[account(
+        init,
+        seeds = [DCA_SEED, owner.key.as_ref(), nonce.to_le_bytes().as_ref()],
+        bump = bump,
+        payer = owner,
+        space = Dca::SPACE,
+    )]
+    pub dca: Account<'info, Dca>,
+    #[account(
+        init,
+        seeds = [DCA_VAULT_SEED, dca.key().as_ref(), input_mint.key().as_ref()],
+        bump = input_vault_bump,
+        payer = owner,
+        token::mint = input_mint,
+        token::authority = dca,
+    )]
+    pub input_vault: Account<'info, TokenAccount>,
+    #[account(
+        init,
+        seeds = [DCA_VAULT_SEED, dca.key().as_ref(), output_mint.key().as_ref()],
+        bump = output_vault_bump,
+        payer = owner,
+        token::mint = output_mint,
+        token::authority = dca,
+    )]
+    pub output_vault: Account<'info, TokenAccount>,
+    pub input_mint: Account<'info, Mint>,
+    pub output_mint: Account<'info, Mint>,
+    // Owner's token account funding the deposit.
+    #[account(mut)]
+    pub owner_wallet: Account<'info, TokenAccount>,
+    // Token account receiving the proceeds of each swap.
+    #[account(constraint = destination.mint == output_mint.key())]
+    pub destination: Account<'info, TokenAccount>,
+    // Open orders account of the schedule, created and initialized here.
+    #[account(mut, signer)]
+    /// CHECK: test
+    pub open_orders: AccountInfo<'info>,
+    /// CHECK: test
+    pub market: AccountInfo<'info>,
+    /// CHECK: test
+    pub dex_program: AccountInfo<'info>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub rent: Sysvar<'info, Rent>,
+    // Accounts for emitting events via self-CPI.
+    pub event_cpi: EventCpi<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteDca<'info> {
+    #[account(
+        mut,
+        seeds = [DCA_SEED, dca.owner.as_ref(), dca.nonce.to_le_bytes().as_ref()],
+        bump = dca.bump,
+        has_one = input_vault,
+        has_one = output_vault,
+        has_one = destination,
+        constraint = swap.market.market.key == &dca.market,
+    )]
+    pub dca: Account<'info, Dca>,
+    #[account(mut)]
+    pub input_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub output_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    /// CHECK: test
+    pub destination: AccountInfo<'info>,
+    // Cranker's token account for the *from* mint, paid the crank fee.
+    #[account(mut)]
+    /// CHECK: test
+    pub cranker_wallet: AccountInfo<'info>,
+    pub swap: ScheduledSwap<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct CloseDca<'info> {
+    #[account(
+        mut,
+        seeds = [DCA_SEED, owner.key.as_ref(), dca.nonce.to_le_bytes().as_ref()],
+        bump = dca.bump,
+        has_one = owner,
+        has_one = input_vault,
+        has_one = output_vault,
+        has_one = destination,
+        has_one = market,
+        has_one = open_orders,
+        close = owner,
+    )]
+    pub dca: Account<'info, Dca>,
+    #[account(mut)]
+    pub input_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub output_vault: Account<'info, TokenAccount>,
+    // Owner's token account receiving the rest of the deposit.
+    #[account(mut)]
+    /// CHECK: test
+    pub owner_wallet: AccountInfo<'info>,
+    #[account(mut)]
+    /// CHECK: test
+    pub destination: AccountInfo<'info>,
+    #[account(mut)]
+    /// CHECK: test
+    pub open_orders: AccountInfo<'info>,
+    /// CHECK: test
+    pub market: AccountInfo<'info>,
+    /// CHECK: test
+    pub dex_program: AccountInfo<'info>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+#[instruction(bump: u8, input_vault_bump: u8, output_vault_bump: u8, nonce: u64)]
+pub struct CreateTwapOrder<'info> {
+    #[account(
+        init,
+        seeds = [TWAP_SEED, owner.key.as_ref(), nonce.to_le_bytes().as_ref()],
+        bump = bump,
+        payer = owner,
+        space = Twap::SPACE,
+    )]
+    pub twap: Account<'info, Twap>,
+    #[account(
+        init,
+        seeds = [TWAP_VAULT_SEED, twap.key().as_ref(), input_mint.key().as_ref()],
+        bump = input_vault_bump,
+        payer = owner,
+        token::mint = input_mint,
+        token::authority = twap,
+    )]
+    pub input_vault: Account<'info, TokenAccount>,
+    #[account(
+        init,
+        seeds = [TWAP_VAULT_SEED, twap.key().as_ref(), output_mint.key().as_ref()],
+        bump = output_vault_bump,
+        payer = owner,
+        token::mint = output_mint,
+        token::authority = twap,
+    )]
+    pub output_vault: Account<'info, TokenAccount>,
+    pub input_mint: Account<'info, Mint>,
+    pub output_mint: Account<'info, Mint>,
+    // Owner's token account funding the deposit.
+    #[account(mut)]
+    pub owner_wallet: Account<'info, TokenAccount>,
+    // Token account receiving the proceeds of each slice.
+    #[account(constraint = destination.mint == output_mint.key())]
+    pub destination: Account<'info, TokenAccount>,
+    // Open orders account of the order, created and initialized here.
+    #[account(mut, signer)]
+    /// CHECK: test
+    pub open_orders: AccountInfo<'info>,
+    /// CHECK: test
+    pub market: AccountInfo<'info>,
+    /// CHECK: test
+    pub dex_program: AccountInfo<'info>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub rent: Sysvar<'info, Rent>,
+    // Accounts for emitting events via self-CPI.
+    pub event_cpi: EventCpi<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteTwapSlice<'info> {
+    #[account(
+        mut,
+        seeds = [TWAP_SEED, twap.owner.as_ref(), twap.nonce.to_le_bytes().as_ref()],
+        bump = twap.bump,
+        has_one = input_vault,
+        has_one = output_vault,
+        has_one = destination,
+        constraint = swap.market.market.key == &twap.market,
+    )]
+    pub twap: Account<'info, Twap>,
+    #[account(mut)]
+    pub input_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub output_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    /// CHECK: test
+    pub destination: AccountInfo<'info>,
+    pub swap: ScheduledSwap<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct CancelTwapOrder<'info> {
+    #[account(
+        mut,
+        seeds = [TWAP_SEED, owner.key.as_ref(), twap.nonce.to_le_bytes().as_ref()],
+        bump = twap.bump,
+        has_one = owner,
+        has_one = input_vault,
+        has_one = output_vault,
+        has_one = destination,
+        has_one = market,
+        has_one = open_orders,
+        close = owner,
+    )]
+    pub twap: Account<'info, Twap>,
+    #[account(mut)]
+    pub input_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub output_vault: Account<'info, TokenAccount>,
+    // Owner's token account receiving the input not yet swapped.
+    #[account(mut)]
+    /// CHECK: test
+    pub owner_wallet: AccountInfo<'info>,
+    #[account(mut)]
+    /// CHECK: test
+    pub destination: AccountInfo<'info>,
+    #[account(mut)]
+    /// CHECK: test
+    pub open_orders: AccountInfo<'info>,
+    /// CHECK: test
+    pub market: AccountInfo<'info>,
+    /// CHECK: test
+    pub dex_program: AccountInfo<'info>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub token_program: Program<'info, Token>,
 }
 
+// The accounts of a `swap` signed by a PDA of this program, i.e., without
+// the authority. Checked by `swap` on CPI.
 #[derive(Accounts)]
-pub struct InitAccount<'info> {
+pub struct ScheduledSwap<'info> {
+    pub market: MarketAccounts<'info>,
     #[account(mut)]
     /// CHECK: test
-    open_orders: AccountInfo<'info>,
-    #[account(signer)]
+    pub pc_wallet: AccountInfo<'info>,
+    pub config: Account<'info, Config>,
     /// CHECK: test
-    authority: AccountInfo<'info>,
+    pub whitelist: AccountInfo<'info>,
     /// CHECK: test
-    market: AccountInfo<'info>,
+    pub user_stats: AccountInfo<'info>,
+    #[account(mut)]
     /// CHECK: test
-    dex_program: AccountInfo<'info>,
+    pub spending_policy: AccountInfo<'info>,
+    #[account(mut)]
     /// CHECK: test
-    rent: AccountInfo<'info>,
+    pub fee_vault: AccountInfo<'info>,
+    #[account(mut)]
+    /// CHECK: test
+    pub platform_fee_account: AccountInfo<'info>,
+    // The PDA authority's (M)SRM token account lowering the DEX's fee tier,
+    // or this program's ID for none.
+    #[account(constraint = srm_msrm_discount.key == &ID || srm_msrm_discount.owner == &token::ID)]
+    /// CHECK: test
+    pub srm_msrm_discount: AccountInfo<'info>,
+    /// CHECK: test
+    pub dex_program: AccountInfo<'info>,
+    /// CHECK: test
+    pub token_program: AccountInfo<'info>,
+    /// CHECK: test
+    pub rent: AccountInfo<'info>,
+    pub event_cpi: EventCpi<'info>,
 }
 
-impl<'info> From<&mut InitAccount<'info>> for dex::InitOpenOrders<'info> {
-    fn from(accs: &mut InitAccount<'info>) -> dex::InitOpenOrders<'info> {
-        dex::InitOpenOrders {
-            open_orders: accs.open_orders.clone(),
-            authority: accs.authority.clone(),
-            market: accs.market.clone(),
-            rent: accs.rent.clone(),
+impl<'info> ScheduledSwap<'info> {
+    // Validates the swap trades from the `input` vault into the `output`
+    // vault on `side`.
+    fn is_valid(
+        &self,
+        side: &Side,
+        input: &Account<'info, TokenAccount>,
+        output: &Account<'info, TokenAccount>,
+    ) -> Result<()> {
+        let (from_wallet, to_wallet) = match side {
+            Side::Bid => (&self.pc_wallet, &self.market.coin_wallet),
+            Side::Ask => (&self.market.coin_wallet, &self.pc_wallet),
+        };
+        if from_wallet.key != &input.key()
+            || to_wallet.key != &output.key()
+            || self.market.order_payer_token_account.key != &input.key()
+        {
+            return Err(ErrorCode::InvalidScheduleWallets.into());
         }
+        Ok(())
+    }
+
+    // Swaps `amount` via CPI into `swap`, signed by the `authority` PDA,
+    // returning the proceeds received in the `output` vault.
+    fn swap_signed(
+        &self,
+        authority: AccountInfo<'info>,
+        signer: &[&[&[u8]]],
+        side: Side,
+        amount: u64,
+        min_exchange_rate: ExchangeRate,
+        output: &Account<'info, TokenAccount>,
+    ) -> Result<u64> {
+        let output_before = output.amount;
+        self.cpi_swap(authority, signer, side, amount, min_exchange_rate)?;
+
+        let output_after = token::accessor::amount(&output.to_account_info())?;
+        Ok(output_after.checked_sub(output_before).unwrap())
+    }
+
+    // Swaps `amount` via CPI into `swap`, signed by the `authority` PDA.
+    fn cpi_swap(
+        &self,
+        authority: AccountInfo<'info>,
+        signer: &[&[&[u8]]],
+        side: Side,
+        amount: u64,
+        min_exchange_rate: ExchangeRate,
+    ) -> Result<()> {
+        let accounts = Swap {
+            market: self.market.clone(),
+            authority,
+            pc_wallet: self.pc_wallet.clone(),
+            config: self.config.clone(),
+            whitelist: self.whitelist.clone(),
+            user_stats: self.user_stats.clone(),
+            spending_policy: self.spending_policy.clone(),
+            fee_vault: self.fee_vault.clone(),
+            platform_fee_account: self.platform_fee_account.clone(),
+            referral: self.event_cpi.program.to_account_info(),
+            srm_msrm_discount: self.srm_msrm_discount.clone(),
+            dex_program: self.dex_program.clone(),
+            token_program: self.token_program.clone(),
+            rent: self.rent.clone(),
+            event_cpi: self.event_cpi.clone(),
+        };
+        let ix = Instruction {
+            program_id: ID,
+            accounts: accounts.to_account_metas(None),
+            data: anchor_lang::InstructionData::data(&instruction::Swap {
+                side,
+                amount,
+                min_exchange_rate,
+            }),
+        };
+        invoke_signed(&ix, &accounts.to_account_infos(), signer)?;
+        Ok(())
     }
 }
 
 #[derive(Accounts)]
-pub struct CloseAccount<'info> {
+pub struct CloseCircuitBreaker<'info> {
+    #[account(mut, seeds = [CONFIG_SEED], bump = config.bump, has_one = admin)]
+    pub config: Account<'info, Config>,
+    #[account(
+        mut,
+        seeds = [CIRCUIT_BREAKER_SEED, circuit_breaker.market.as_ref()],
+        bump = circuit_breaker.bump,
+        close = admin,
+    )]
+    pub circuit_breaker: Account<'info, CircuitBreaker>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetPaused<'info> {
+    #[account(
+        mut,
+        seeds = [CONFIG_SEED],
+        bump = config.bump,
+        constraint = config.guardian == guardian.key() || config.admin == guardian.key(),
+    )]
+    pub config: Account<'info, Config>,
+    // Either the guardian or the admin.
+    pub guardian: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CollectFees<'info> {
+    #[account(seeds = [CONFIG_SEED], bump = config.bump, has_one = admin)]
+    pub config: Account<'info, Config>,
+    pub admin: Signer<'info>,
+    #[account(mut, constraint = fee_vault.owner == config.key())]
+    pub fee_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub destination: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+#[instruction(bump: u8, vault_bump: u8)]
+pub struct RegisterReferrer<'info> {
+    #[account(
+        init,
+        seeds = [REFERRER_SEED, authority.key.as_ref(), mint.key().as_ref()],
+        bump = bump,
+        payer = authority,
+        space = Referrer::SPACE,
+    )]
+    pub referrer: Account<'info, Referrer>,
+    #[account(
+        init,
+        seeds = [REFERRER_VAULT_SEED, referrer.key().as_ref()],
+        bump = vault_bump,
+        payer = authority,
+        token::mint = mint,
+        token::authority = referrer,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    pub mint: Account<'info, Mint>,
     #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct LogEvent<'info> {
+    #[account(signer, seeds = [EVENT_AUTHORITY_SEED], bump = EVENT_AUTHORITY_BUMP)]
     /// CHECK: test
-    open_orders: AccountInfo<'info>,
-    #[account(signer)]
+    pub event_authority: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(bump: u8)]
+pub struct InitMarketStats<'info> {
+    #[account(
+        init,
+        seeds = [MARKET_STATS_SEED, market.key.as_ref()],
+        bump = bump,
+        payer = payer,
+        space = MarketStats::SPACE,
+    )]
+    pub market_stats: Account<'info, MarketStats>,
     /// CHECK: test
-    authority: AccountInfo<'info>,
+    pub market: AccountInfo<'info>,
     #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(bump: u8)]
+pub struct InitUserStats<'info> {
+    #[account(
+        init,
+        seeds = [USER_STATS_SEED, authority.key.as_ref()],
+        bump = bump,
+        payer = payer,
+        space = UserStats::SPACE,
+    )]
+    pub user_stats: Account<'info, UserStats>,
     /// CHECK: test
-    destination: AccountInfo<'info>,
+    pub authority: AccountInfo<'info>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(bump: u8)]
+pub struct CreateSpendingPolicy<'info> {
+    #[account(
+        init,
+        seeds = [SPENDING_POLICY_SEED, owner.key.as_ref(), authority.key.as_ref()],
+        bump = bump,
+        payer = owner,
+        space = SpendingPolicy::SPACE,
+    )]
+    pub spending_policy: Account<'info, SpendingPolicy>,
     /// CHECK: test
-    market: AccountInfo<'info>,
+    pub authority: AccountInfo<'info>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateSpendingPolicy<'info> {
+    #[account(
+        mut,
+        seeds = [
+            SPENDING_POLICY_SEED,
+            owner.key.as_ref(),
+            spending_policy.authority.as_ref(),
+        ],
+        bump = spending_policy.bump,
+        has_one = owner,
+    )]
+    pub spending_policy: Account<'info, SpendingPolicy>,
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CloseSpendingPolicy<'info> {
+    #[account(
+        mut,
+        seeds = [
+            SPENDING_POLICY_SEED,
+            owner.key.as_ref(),
+            spending_policy.authority.as_ref(),
+        ],
+        bump = spending_policy.bump,
+        has_one = owner,
+        close = owner,
+    )]
+    pub spending_policy: Account<'info, SpendingPolicy>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(bump: u8, nonce: u64)]
+pub struct ProposeSwap<'info> {
+    #[account(
+        init,
+        seeds = [SWAP_PROPOSAL_SEED, authority.key.as_ref(), nonce.to_le_bytes().as_ref()],
+        bump = bump,
+        payer = proposer,
+        space = SwapProposal::SPACE,
+    )]
+    pub proposal: Account<'info, SwapProposal>,
     /// CHECK: test
-    dex_program: AccountInfo<'info>,
+    pub authority: AccountInfo<'info>,
+    #[account(mut)]
+    pub proposer: Signer<'info>,
+    pub system_program: Program<'info, System>,
 }
 
-impl<'info> From<&mut CloseAccount<'info>> for dex::CloseOpenOrders<'info> {
-    fn from(accs: &mut CloseAccount<'info>) -> dex::CloseOpenOrders<'info> {
-        dex::CloseOpenOrders {
-            open_orders: accs.open_orders.clone(),
-            authority: accs.authority.clone(),
-            destination: accs.destination.clone(),
-            market: accs.market.clone(),
-        }
-    }
+#[derive(Accounts)]
+pub struct ExecuteSwapProposal<'info> {
+    pub swap: Swap<'info>,
+    #[account(
+        mut,
+        seeds = [
+            SWAP_PROPOSAL_SEED,
+            swap.authority.key.as_ref(),
+            proposal.nonce.to_le_bytes().as_ref(),
+        ],
+        bump = proposal.bump,
+        has_one = proposer,
+        close = proposer,
+    )]
+    pub proposal: Account<'info, SwapProposal>,
+    #[account(mut)]
+    /// CHECK: test
+    pub proposer: AccountInfo<'info>,
 }
 
-// The only constraint imposed on these accounts is that the market's base
-// currency mint is not equal to the quote currency's. All other checks are
-// done by the DEX on CPI.
 #[derive(Accounts)]
-pub struct Swap<'info> {
+pub struct CancelSwapProposal<'info> {
+    #[account(
+        mut,
+        seeds = [
+            SWAP_PROPOSAL_SEED,
+            proposal.authority.as_ref(),
+            proposal.nonce.to_le_bytes().as_ref(),
+        ],
+        bump = proposal.bump,
+        has_one = proposer,
+        close = proposer,
+        constraint = signer.key == &proposal.proposer || signer.key == &proposal.authority,
+    )]
+    pub proposal: Account<'info, SwapProposal>,
+    #[account(mut)]
     /// CHECK: test
-    pub market: MarketAccounts<'info>,
-    #[account(signer)]
+    pub proposer: AccountInfo<'info>,
+    pub signer: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(bump: u8, nonce: u64)]
+pub struct CommitSwap<'info> {
+    #[account(
+        init,
+        seeds = [SWAP_COMMITMENT_SEED, authority.key.as_ref(), nonce.to_le_bytes().as_ref()],
+        bump = bump,
+        payer = authority,
+        space = SwapCommitment::SPACE,
+    )]
+    pub commitment: Account<'info, SwapCommitment>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RevealAndSwap<'info> {
+    pub swap: Swap<'info>,
+    #[account(
+        mut,
+        seeds = [
+            SWAP_COMMITMENT_SEED,
+            swap.authority.key.as_ref(),
+            commitment.nonce.to_le_bytes().as_ref(),
+        ],
+        bump = commitment.bump,
+        close = authority,
+    )]
+    pub commitment: Account<'info, SwapCommitment>,
+    // The swap's authority, refunded the commitment's rent.
+    #[account(mut, constraint = authority.key == swap.authority.key)]
     /// CHECK: test
     pub authority: AccountInfo<'info>,
-    #[account(mut, constraint = pc_wallet.key != &empty::ID)]
+}
+
+#[derive(Accounts)]
+pub struct CancelSwapCommitment<'info> {
+    #[account(
+        mut,
+        seeds = [
+            SWAP_COMMITMENT_SEED,
+            authority.key.as_ref(),
+            commitment.nonce.to_le_bytes().as_ref(),
+        ],
+        bump = commitment.bump,
+        has_one = authority,
+        close = authority,
+    )]
+    pub commitment: Account<'info, SwapCommitment>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(bump: u8, signer: Pubkey)]
+pub struct CreatePermitAuthority<'info> {
+    #[account(
+        init,
+        seeds = [PERMIT_AUTHORITY_SEED, signer.as_ref()],
+        bump = bump,
+        payer = payer,
+        space = PermitAuthority::SPACE,
+    )]
+    pub permit_authority: Account<'info, PermitAuthority>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitPermitOpenOrders<'info> {
+    #[account(
+        seeds = [PERMIT_AUTHORITY_SEED, permit_authority.signer.as_ref()],
+        bump = permit_authority.bump,
+    )]
+    pub permit_authority: Account<'info, PermitAuthority>,
+    #[account(mut, signer)]
     /// CHECK: test
-    pub pc_wallet: AccountInfo<'info>,
-    // Programs.
+    pub open_orders: AccountInfo<'info>,
     /// CHECK: test
-    pub dex_program: AccountInfo<'info>,
+    pub market: AccountInfo<'info>,
     /// CHECK: test
-    pub token_program: AccountInfo<'info>,
+    pub dex_program: AccountInfo<'info>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+    // Accounts for emitting events via self-CPI.
+    pub event_cpi: EventCpi<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SwapWithPermit<'info> {
+    #[account(
+        mut,
+        seeds = [PERMIT_AUTHORITY_SEED, permit_authority.signer.as_ref()],
+        bump = permit_authority.bump,
+    )]
+    pub permit_authority: Account<'info, PermitAuthority>,
+    pub swap: ScheduledSwap<'info>,
+    #[account(address = solana_program::sysvar::instructions::ID)]
     /// CHECK: test
-    pub rent: AccountInfo<'info>,
+    pub instructions: AccountInfo<'info>,
 }
 
-impl<'info> From<&Swap<'info>> for OrderbookClient<'info> {
-    fn from(accounts: &Swap<'info>) -> OrderbookClient<'info> {
-        OrderbookClient {
-            market: accounts.market.clone(),
-            authority: accounts.authority.clone(),
-            pc_wallet: accounts.pc_wallet.clone(),
-            dex_program: accounts.dex_program.clone(),
-            token_program: accounts.token_program.clone(),
-            rent: accounts.rent.clone(),
-        }
-    }
+#[derive(Accounts)]
+pub struct SetReferralSplits<'info> {
+    #[account(
+        mut,
+        seeds = [REFERRER_SEED, authority.key.as_ref(), referrer.mint.as_ref()],
+        bump = referrer.bump,
+        has_one = authority,
+    )]
+    pub referrer: Account<'info, Referrer>,
+    pub authority: Signer<'info>,
 }
 
-// The only constraint imposed on these accounts is that the from market's
-// base currency's is not equal to the to market's base currency. All other
-// checks are done by the DEX on CPI (and the quote currency is ensured to be
-// the same on both markets since there's only one account field for it).
 #[derive(Accounts)]
-pub struct SwapTransitive<'info> {
+pub struct ClaimReferralFees<'info> {
+    #[account(
+        mut,
+        seeds = [REFERRER_SEED, authority.key.as_ref(), referrer.mint.as_ref()],
+        bump = referrer.bump,
+        has_one = authority,
+        has_one = vault,
+    )]
+    pub referrer: Account<'info, Referrer>,
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub destination: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+// Client for sending orders to the Serum DEX.
+#[derive(Clone)]
+struct OrderbookClient<'info> {
     /// CHECK: test
-    pub from: MarketAccounts<'info>,
+    market: MarketAccounts<'info>,
     /// CHECK: test
-    pub to: MarketAccounts<'info>,
-    // Must be the authority over all open orders accounts used.
-    #[account(signer)]
+    authority: AccountInfo<'info>,
     /// CHECK: test
-    pub authority: AccountInfo<'info>,
-    #[account(mut, constraint = pc_wallet.key != &empty::ID)]
+    pc_wallet: AccountInfo<'info>,
     /// CHECK: test
-    pub pc_wallet: AccountInfo<'info>,
-    // Programs.
+    dex_program: AccountInfo<'info>,
     /// CHECK: test
-    pub dex_program: AccountInfo<'info>,
+    token_program: AccountInfo<'info>,
     /// CHECK: test
-    pub token_program: AccountInfo<'info>,
-    // Sysvars.
+    rent: AccountInfo<'info>,
+    // Execution defaults of the config.
+    execution: ExecutionDefaults,
+    // Number of circuit breakers in the config.
+    circuit_breakers: u32,
+    // (M)SRM token account lowering the fee tier of the orders, if any. The
+    // DEX only accepts one owned by the open orders authority.
     /// CHECK: test
-    pub rent: AccountInfo<'info>,
+    srm_msrm_discount: Option<AccountInfo<'info>>,
+    // Accounts emitting the events of settlement.
+    /// CHECK: test
+    event_cpi: EventCpi<'info>,
+    // The market's state read by the client, loaded once, on first use.
+    market_info: Cell<Option<MarketInfo>>,
+    // Top of the book before the client's first order, snapshotted once for
+    // all of its orders.
+    book: Cell<Option<BookSnapshot>>,
 }
 
-impl<'info> SwapTransitive<'info> {
-    fn orderbook_from(&self) -> OrderbookClient<'info> {
-        OrderbookClient {
-            market: self.from.clone(),
-            authority: self.authority.clone(),
-            pc_wallet: self.pc_wallet.clone(),
-            dex_program: self.dex_program.clone(),
-            token_program: self.token_program.clone(),
-            rent: self.rent.clone(),
+// The fields of a DEX market's state read by an `OrderbookClient`.
+#[derive(Clone, Copy)]
+struct MarketInfo {
+    coin_mint: Pubkey,
+    pc_mint: Pubkey,
+    coin_lot_size: u64,
+    pc_lot_size: u64,
+    event_q: [u64; 4],
+    bids: [u64; 4],
+    asks: [u64; 4],
+}
+
+impl<'info> OrderbookClient<'info> {
+    // Returns the (from, to) token wallets of a direct trade on `side`.
+    fn wallets(&self, side: &Side) -> (&AccountInfo<'info>, &AccountInfo<'info>) {
+        match side {
+            Side::Bid => (&self.pc_wallet, &self.market.coin_wallet),
+            Side::Ask => (&self.market.coin_wallet, &self.pc_wallet),
+        }
+    }
+
+    // Returns the market's state, loading it on the first call.
+    fn market_info(&self) -> std::result::Result<MarketInfo, ProgramError> {
+        if let Some(market_info) = self.market_info.get() {
+            return Ok(market_info);
+        }
+        let market = MarketState::load(&self.market.market, &dex::ID)?;
+        let market_info = MarketInfo {
+            coin_mint: words_to_pubkey(market.coin_mint),
+            pc_mint: words_to_pubkey(market.pc_mint),
+            coin_lot_size: market.coin_lot_size,
+            pc_lot_size: market.pc_lot_size,
+            event_q: market.event_q,
+            bids: market.bids,
+            asks: market.asks,
+        };
+        self.market_info.set(Some(market_info));
+        Ok(market_info)
+    }
+
+    // Asserts the wallets of the client's orders hold the market's base and
+    // quote mints, so that a wallet for the wrong mint fails here, with a
+    // clear error, rather than within the DEX.
+    fn check_wallet_mints(&self) -> Result<()> {
+        let market_info = self.market_info()?;
+        let order_payer_mint = token::accessor::mint(&self.market.order_payer_token_account)?;
+        if token::accessor::mint(&self.market.coin_wallet)? != market_info.coin_mint
+            || token::accessor::mint(&self.pc_wallet)? != market_info.pc_mint
+            || (order_payer_mint != market_info.coin_mint
+                && order_payer_mint != market_info.pc_mint)
+        {
+            return Err(ErrorCode::InvalidWalletMint.into());
+        }
+        Ok(())
+    }
+
+    // Returns the market's (base, quote) lot sizes, in native units.
+    fn lot_sizes(&self) -> std::result::Result<(u64, u64), ProgramError> {
+        let market_info = self.market_info()?;
+        Ok((market_info.coin_lot_size, market_info.pc_lot_size))
+    }
+
+    // Aborts if the market's event queue is full, or holds more than
+    // `max_len` events, as the DEX would fail the order, or may, once the
+    // queue has no room for its fills.
+    fn check_event_queue(&self, max_len: u64) -> Result<()> {
+        if !is_address(&self.market_info()?.event_q, &self.market.event_queue) {
+            return Err(ErrorCode::InvalidEventQueue.into());
+        }
+        let (len, capacity) = fills::queue_len(&self.market.event_queue)?;
+        if len >= capacity || len > max_len {
+            msg!("event_queue_len: {:?}", len);
+            return Err(ErrorCode::EventQueueFull.into());
+        }
+        Ok(())
+    }
+
+    // Returns the lot sizes of the market, after checking the side of its book
+    // a direct trade on `side` fills against is the market's.
+    fn book_lot_sizes(&self, side: &Side) -> std::result::Result<(u64, u64), ProgramError> {
+        let market_info = self.market_info()?;
+        let is_book = match side {
+            Side::Bid => is_address(&market_info.asks, &self.market.asks),
+            Side::Ask => is_address(&market_info.bids, &self.market.bids),
+        };
+        if !is_book {
+            return Err(ErrorCode::InvalidBook.into());
+        }
+        Ok((market_info.coin_lot_size, market_info.pc_lot_size))
+    }
+
+    // Returns the top of the side of the market's book a direct trade on
+    // `side` fills against, after checking it's the market's. The other side
+    // isn't read.
+    fn book_snapshot(&self, side: &Side) -> std::result::Result<BookSnapshot, ProgramError> {
+        let (coin_lot_size, pc_lot_size) = self.book_lot_sizes(side)?;
+        // Converts a price in lots into native units, times `PRICE_SCALE`.
+        let native_price = |lots: u64| -> u64 {
+            u128::from(lots)
+                .checked_mul(pc_lot_size.into())
+                .unwrap()
+                .checked_mul(PRICE_SCALE)
+                .unwrap()
+                .checked_div(coin_lot_size.into())
+                .unwrap()
+                .try_into()
+                .unwrap_or(u64::MAX)
+        };
+        let (best_bid, best_ask) = match side {
+            Side::Bid => (0, native_price(book::best_ask(&self.market.asks)?)),
+            Side::Ask => (native_price(book::best_bid(&self.market.bids)?), 0),
+        };
+        let clock = Clock::get()?;
+        Ok(BookSnapshot {
+            best_bid,
+            best_ask,
+            slot: clock.slot,
+            unix_timestamp: clock.unix_timestamp,
+        })
+    }
+
+    // Returns the price impact of a direct trade of `amount` on `side`, in
+    // basis points of the best price, expected from the orders resting on the
+    // book. Fees aren't included.
+    fn price_impact_bps(&self, side: &Side, amount: u64) -> std::result::Result<u64, ProgramError> {
+        let (coin_lot_size, pc_lot_size) = self.book_lot_sizes(side)?;
+        let fills = match side {
+            Side::Bid => book::fill_asks(&self.market.asks, amount / pc_lot_size)?,
+            Side::Ask => book::fill_bids(&self.market.bids, amount / coin_lot_size)?,
+        };
+        Ok(fills.price_impact_bps())
+    }
+
+    // Returns the amount of the *from* token a direct trade on `side` needs to
+    // receive at least `to_amount`, after the DEX's taker fee, from the orders
+    // resting on the book.
+    fn exact_out_amount(&self, side: &Side, to_amount: u64) -> Result<u64> {
+        let (coin_lot_size, pc_lot_size) = self.book_lot_sizes(side)?;
+        match side {
+            // Buys the lots covering the payment, paying the fee on top.
+            Side::Bid => {
+                let coin_lots = to_amount
+                    .checked_add(coin_lot_size - 1)
+                    .unwrap()
+                    .checked_div(coin_lot_size)
+                    .unwrap();
+                let fills = book::fill_asks_to(&self.market.asks, coin_lots)?;
+                if fills.coin_lots < coin_lots {
+                    return Err(ErrorCode::PaymentUnfillable.into());
+                }
+                let pc_amount: u64 = fills
+                    .pc_lots
+                    .checked_mul(pc_lot_size.into())
+                    .unwrap()
+                    .try_into()
+                    .unwrap();
+                Ok(pc_amount
+                    .checked_add(
+                        pc_amount
+                            .checked_mul(TAKER_FEE_BPS)
+                            .unwrap()
+                            .checked_add(BPS_DENOMINATOR - 1)
+                            .unwrap()
+                            .checked_div(BPS_DENOMINATOR)
+                            .unwrap(),
+                    )
+                    .unwrap()
+                    .checked_add(1)
+                    .unwrap())
+            }
+            // Sells the lots receiving the payment once the fee is taken off.
+            Side::Ask => {
+                let pc_amount = to_amount
+                    .checked_mul(BPS_DENOMINATOR)
+                    .unwrap()
+                    .checked_add(BPS_DENOMINATOR - TAKER_FEE_BPS - 1)
+                    .unwrap()
+                    .checked_div(BPS_DENOMINATOR - TAKER_FEE_BPS)
+                    .unwrap()
+                    .checked_add(1)
+                    .unwrap();
+                let pc_lots = pc_amount
+                    .checked_add(pc_lot_size - 1)
+                    .unwrap()
+                    .checked_div(pc_lot_size)
+                    .unwrap();
+                let fills = book::fill_bids_to(&self.market.bids, pc_lots)?;
+                if fills.pc_lots < pc_lots.into() {
+                    return Err(ErrorCode::PaymentUnfillable.into());
+                }
+                Ok(fills.coin_lots.checked_mul(coin_lot_size).unwrap())
+            }
         }
     }
-    fn orderbook_to(&self) -> OrderbookClient<'info> {
-        OrderbookClient {
-            market: self.to.clone(),
-            authority: self.authority.clone(),
-            pc_wallet: self.pc_wallet.clone(),
-            dex_program: self.dex_program.clone(),
-            token_program: self.token_program.clone(),
-            rent: self.rent.clone(),
+
+    // Converts a `price`, in native quote units per native base unit times
+    // `PRICE_SCALE`, into the DEX's limit price in lots for an order on
+    // `side`, rounding in favor of the order.
+    fn limit_price(&self, side: &Side, price: u64) -> Result<u64> {
+        let (coin_lot_size, pc_lot_size) = self.lot_sizes()?;
+        let numerator = u128::from(price).checked_mul(coin_lot_size.into()).unwrap();
+        let denominator = u128::from(pc_lot_size).checked_mul(PRICE_SCALE).unwrap();
+        let limit_price = match side {
+            // The highest price to buy at.
+            Side::Bid => numerator.checked_div(denominator).unwrap(),
+            // The lowest price to sell at.
+            Side::Ask => numerator
+                .checked_add(denominator.checked_sub(1).unwrap())
+                .unwrap()
+                .checked_div(denominator)
+                .unwrap()
+                .max(1),
+        };
+        if limit_price == 0 {
+            return Err(ErrorCode::InvalidChunks.into());
         }
+        Ok(limit_price.try_into().unwrap_or(u64::MAX))
     }
-}
-
-// Client for sending orders to the Serum DEX.
-#[derive(Clone)]
-struct OrderbookClient<'info> {
-    /// CHECK: test
-    market: MarketAccounts<'info>,
-    /// CHECK: test
-    authority: AccountInfo<'info>,
-    /// CHECK: test
-    pc_wallet: AccountInfo<'info>,
-    /// CHECK: test
-    dex_program: AccountInfo<'info>,
-    /// CHECK: test
-    token_program: AccountInfo<'info>,
-    /// CHECK: test
-    rent: AccountInfo<'info>,
-}
 
-impl<'info> OrderbookClient<'info> {
     // Executes the sell order portion of the swap, purchasing as much of the
     // quote currency as possible for the given `base_amount`.
     //
     // `base_amount` is the "native" amount of the base currency, i.e., token
-    // amount including decimals.
+    // amount including decimals. The order offers the amount rounded down to
+    // the market's lot size. If given, `limit_price` is the lowest price to
+    // sell at, in lots.
     fn sell(
         &self,
         base_amount: u64,
-        srm_msrm_discount: Option<AccountInfo<'info>>,
-    ) -> ProgramResult {
-        let limit_price = 1;
-        let max_coin_qty = {
-            // The loaded market must be dropped before CPI.
-            let market = MarketState::load(&self.market.market, &dex::ID)?;
-            coin_lots(&market, base_amount)
-        };
+        limit_price: Option<u64>,
+    ) -> std::result::Result<Order, ProgramError> {
+        let limit_price = limit_price.unwrap_or(1);
+        let (coin_lot_size, _) = self.lot_sizes()?;
+        let max_coin_qty = base_amount.checked_div(coin_lot_size).unwrap();
+        let base_qty = max_coin_qty.checked_mul(coin_lot_size).unwrap();
+        self.validate_funding(base_qty)?;
         let max_native_pc_qty = u64::MAX;
         self.order_cpi(
+            base_qty,
             limit_price,
             max_coin_qty,
             max_native_pc_qty,
             Side::Ask,
-            srm_msrm_discount,
         )
     }
 
@@ -511,50 +6587,99 @@ impl<'info> OrderbookClient<'info> {
     // base currency as possible, for the given `quote_amount`.
     //
     // `quote_amount` is the "native" amount of the quote currency, i.e., token
-    // amount including decimals.
+    // amount including decimals. If given, `limit_price` is the highest price
+    // to buy at, in lots.
     fn buy(
         &self,
         quote_amount: u64,
-        srm_msrm_discount: Option<AccountInfo<'info>>,
-    ) -> ProgramResult {
-        let limit_price = u64::MAX;
+        limit_price: Option<u64>,
+    ) -> std::result::Result<Order, ProgramError> {
+        self.validate_funding(quote_amount)?;
+        let limit_price = limit_price.unwrap_or(u64::MAX);
         let max_coin_qty = u64::MAX;
         let max_native_pc_qty = quote_amount;
         self.order_cpi(
+            quote_amount,
             limit_price,
             max_coin_qty,
             max_native_pc_qty,
             Side::Bid,
-            srm_msrm_discount,
         )
     }
 
-    // Executes a new order on the serum dex via CPI.
+    // Validates the authority may fund an order of `amount` from the order
+    // payer token account, either as its owner or as a delegate approved for
+    // at least `amount`.
+    //
+    // A delegate may only trade between wallets owned by the order payer's
+    // owner, so that the proceeds of the swap can't be redirected.
+    fn validate_funding(&self, amount: u64) -> ProgramResult {
+        let order_payer = &self.market.order_payer_token_account;
+        let owner = token::accessor::authority(order_payer)?;
+        if owner == *self.authority.key {
+            return Ok(());
+        }
+        let order_payer =
+            token::TokenAccount::try_deserialize(&mut &order_payer.data.borrow()[..])?;
+        if order_payer.delegate != COption::Some(*self.authority.key) {
+            return Err(ErrorCode::InvalidDelegate.into());
+        }
+        if order_payer.delegated_amount < amount {
+            return Err(ErrorCode::DelegatedAmountExceeded.into());
+        }
+        for wallet in &[&self.market.coin_wallet, &self.pc_wallet] {
+            if token::accessor::authority(wallet)? != owner {
+                return Err(ErrorCode::DelegateWalletMismatch.into());
+            }
+        }
+        Ok(())
+    }
+
+    // Executes a new order on the serum dex via CPI, returning the order with
+    // the fees it paid.
     //
+    // * `qty` - the native amount of the *from* token offered.
     // * `limit_price` - the limit order price in lot units.
     // * `max_coin_qty`- the max number of the base currency lot units.
     // * `max_native_pc_qty` - the max number of quote currency in native token
     //                         units (includes decimals).
     // * `side` - bid or ask, i.e. the type of order.
-    // * `referral` - referral account, earning a fee.
+    //
+    // The order is sent with the client's fee discount account, if any.
     fn order_cpi(
         &self,
+        qty: u64,
         limit_price: u64,
         max_coin_qty: u64,
         max_native_pc_qty: u64,
         side: Side,
-        srm_msrm_discount: Option<AccountInfo<'info>>,
-    ) -> ProgramResult {
-        // Client order id is only used for cancels. Not used here so hardcode.
-        let client_order_id = 0;
+    ) -> std::result::Result<Order, ProgramError> {
+        let execution = &self.execution;
+        if !execution.dex_programs.is_empty()
+            && !execution.dex_programs.contains(self.dex_program.key)
+        {
+            return Err(ErrorCode::DexProgramNotAllowed.into());
+        }
+        let book = match self.book.get() {
+            Some(book) => book,
+            None => {
+                // Checked once, before the client's first order.
+                self.check_wallet_mints()?;
+                let book = self.book_snapshot(&side)?;
+                self.book.set(Some(book));
+                book
+            }
+        };
+        let seq_num_before = fills::seq_num(&self.market.event_queue)?;
+
         // Limit is the dex's custom compute budge parameter, setting an upper
         // bound on the number of matching cycles the program can perform
         // before giving up and posting the remaining unmatched order.
-        let limit = 65535;
+        let limit = execution.match_limit;
 
-        let mut ctx = CpiContext::new(self.dex_program.clone(), self.clone().into());
-        if let Some(srm_msrm_discount) = srm_msrm_discount {
-            ctx = ctx.with_remaining_accounts(vec![srm_msrm_discount]);
+        let mut ctx = CpiContext::new(self.dex_program.clone(), self.into());
+        if let Some(srm_msrm_discount) = &self.srm_msrm_discount {
+            ctx = ctx.with_remaining_accounts(vec![srm_msrm_discount.clone()]);
         }
         dex::new_order_v3(
             ctx,
@@ -562,14 +6687,26 @@ impl<'info> OrderbookClient<'info> {
             NonZeroU64::new(limit_price).unwrap(),
             NonZeroU64::new(max_coin_qty).unwrap(),
             NonZeroU64::new(max_native_pc_qty).unwrap(),
-            SelfTradeBehavior::DecrementTake,
+            execution.self_trade_behavior.into(),
             OrderType::ImmediateOrCancel,
-            client_order_id,
+            CLIENT_ORDER_ID,
             limit,
-        )
+        )?;
+
+        let taker_fees = fills::taker_fees(
+            &self.market.event_queue,
+            self.market.open_orders.key,
+            seq_num_before,
+        )?;
+        Ok(Order {
+            qty,
+            taker_fee: taker_fees.fees,
+            referrer_rebate: taker_fees.referrer_rebates,
+            book: Some(book),
+        })
     }
 
-    fn settle(&self, referral: Option<AccountInfo<'info>>) -> ProgramResult {
+    fn settle(&self, referral: Option<Referral<'info>>) -> ProgramResult {
         let settle_accs = dex::SettleFunds {
             market: self.market.market.clone(),
             open_orders: self.market.open_orders.clone(),
@@ -582,15 +6719,54 @@ impl<'info> OrderbookClient<'info> {
             token_program: self.token_program.clone(),
         };
         let mut ctx = CpiContext::new(self.dex_program.clone(), settle_accs);
+        // Referral vault balance before settling, when tracked by a referrer.
+        let mut tracked = None;
         if let Some(referral) = referral {
-            ctx = ctx.with_remaining_accounts(vec![referral]);
+            if referral.referrer.is_some() {
+                let amount_before = token::accessor::amount(&referral.wallet)?;
+                tracked = Some((referral.clone(), amount_before));
+            }
+            ctx = ctx.with_remaining_accounts(vec![referral.wallet]);
+        }
+        dex::settle_funds(ctx)?;
+        if let Some((referral, amount_before)) = tracked {
+            credit_referrer(self, &referral, amount_before)?;
+        }
+        Ok(())
+    }
+}
+
+// An order sent to the DEX.
+#[derive(Clone, Copy, Default)]
+struct Order {
+    // Amount of the *from* token offered, in native units.
+    qty: u64,
+    // Taker fee paid, in native units of the quote currency.
+    taker_fee: u64,
+    // Share of the taker fee accrued as a rebate for the referral account.
+    referrer_rebate: u64,
+    // Top of the book right before the order, if sent.
+    book: Option<BookSnapshot>,
+}
+
+impl Order {
+    // Returns the totals of both orders, with the book snapshot of the first
+    // one sent.
+    fn merge(self, other: Order) -> Order {
+        Order {
+            qty: self.qty.checked_add(other.qty).unwrap(),
+            taker_fee: self.taker_fee.checked_add(other.taker_fee).unwrap(),
+            referrer_rebate: self
+                .referrer_rebate
+                .checked_add(other.referrer_rebate)
+                .unwrap(),
+            book: self.book.or(other.book),
         }
-        dex::settle_funds(ctx)
     }
 }
 
-impl<'info> From<OrderbookClient<'info>> for dex::NewOrderV3<'info> {
-    fn from(c: OrderbookClient<'info>) -> dex::NewOrderV3<'info> {
+impl<'info> From<&OrderbookClient<'info>> for dex::NewOrderV3<'info> {
+    fn from(c: &OrderbookClient<'info>) -> dex::NewOrderV3<'info> {
         dex::NewOrderV3 {
             market: c.market.market.clone(),
             open_orders: c.market.open_orders.clone(),
@@ -608,9 +6784,13 @@ impl<'info> From<OrderbookClient<'info>> for dex::NewOrderV3<'info> {
     }
 }
 
-// Returns the amount of lots for the base currency of a trade with `size`.
-fn coin_lots(market: &MarketState, size: u64) -> u64 {
-    size.checked_div(market.coin_lot_size).unwrap()
+// Returns true if the address stored by the DEX, as its 64 bit words, is the
+// `account`'s.
+fn is_address(words: &[u64; 4], account: &AccountInfo) -> bool {
+    words
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .eq(account.key.to_bytes())
 }
 
 // Market accounts are the accounts used to place orders against the dex minus
@@ -659,41 +6839,859 @@ pub struct MarketAccounts<'info> {
     #[account(mut, constraint = coin_wallet.key != &empty::ID)]
     /// CHECK: test
     pub coin_wallet: AccountInfo<'info>,
+    // Circuit breaker PDA of the market. Only enforced once created. May be
+    // this program's ID while the admin has created no breaker.
+    #[account(mut)]
+    /// CHECK: test
+    pub circuit_breaker: AccountInfo<'info>,
+    // Stats of the market, or this program's ID to skip them. Writable unless
+    // skipped.
+    /// CHECK: test
+    pub market_stats: AccountInfo<'info>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub enum Side {
+    Bid,
+    Ask,
+}
+
+impl From<Side> for SerumSide {
+    fn from(side: Side) -> SerumSide {
+        match side {
+            Side::Bid => SerumSide::Bid,
+            Side::Ask => SerumSide::Ask,
+        }
+    }
+}
+
+// Access control modifiers.
+
+fn is_valid_swap(ctx: &Context<Swap>) -> Result<()> {
+    _is_valid_swap(&ctx.accounts.market.coin_wallet, &ctx.accounts.pc_wallet)
+}
+
+fn is_valid_flash_swap(ctx: &Context<FlashSwap>) -> Result<()> {
+    _is_valid_swap(
+        &ctx.accounts.swap.market.coin_wallet,
+        &ctx.accounts.swap.pc_wallet,
+    )
+}
+
+fn is_valid_swap_proposal(ctx: &Context<ExecuteSwapProposal>) -> Result<()> {
+    _is_valid_swap(
+        &ctx.accounts.swap.market.coin_wallet,
+        &ctx.accounts.swap.pc_wallet,
+    )
+}
+
+fn is_valid_reveal(ctx: &Context<RevealAndSwap>) -> Result<()> {
+    _is_valid_swap(
+        &ctx.accounts.swap.market.coin_wallet,
+        &ctx.accounts.swap.pc_wallet,
+    )
+}
+
+fn is_valid_swap_to_escrow(ctx: &Context<SwapToEscrow>) -> Result<()> {
+    _is_valid_swap(
+        &ctx.accounts.swap.market.coin_wallet,
+        &ctx.accounts.swap.pc_wallet,
+    )
+}
+
+fn is_valid_pay_with_swap(ctx: &Context<PayWithSwap>) -> Result<()> {
+    _is_valid_swap(
+        &ctx.accounts.swap.market.coin_wallet,
+        &ctx.accounts.swap.pc_wallet,
+    )
+}
+
+fn is_valid_swap_transitive(ctx: &Context<SwapTransitive>) -> Result<()> {
+    _is_valid_swap(&ctx.accounts.from.coin_wallet, &ctx.accounts.to.coin_wallet)
+}
+
+fn is_valid_swap_quote_to_quote(ctx: &Context<SwapQuoteToQuote>) -> Result<()> {
+    if ctx.accounts.from.coin_wallet.key != ctx.accounts.to.coin_wallet.key {
+        return Err(ErrorCode::BaseWalletMismatch.into());
+    }
+    _is_valid_swap(&ctx.accounts.from_pc_wallet, &ctx.accounts.to_pc_wallet)
+}
+
+fn is_valid_arbitrage(ctx: &Context<Arbitrage>) -> Result<()> {
+    if ctx.accounts.buy.coin_wallet.key != ctx.accounts.sell.coin_wallet.key {
+        return Err(ErrorCode::BaseWalletMismatch.into());
+    }
+    if ctx.accounts.buy.market.key == ctx.accounts.sell.market.key {
+        return Err(ErrorCode::ArbitrageSameMarket.into());
+    }
+    Ok(())
+}
+
+// Returns the (base, quote) mints of the DEX `market`.
+fn market_mints(market: &AccountInfo) -> std::result::Result<(Pubkey, Pubkey), ProgramError> {
+    let market = MarketState::load(market, &dex::ID)?;
+    Ok((
+        words_to_pubkey(market.coin_mint),
+        words_to_pubkey(market.pc_mint),
+    ))
+}
+
+// Returns the address stored by the DEX as its 64 bit words.
+fn words_to_pubkey(words: [u64; 4]) -> Pubkey {
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    Pubkey::new(&bytes)
+}
+
+fn is_not_paused(config: &Config) -> Result<()> {
+    if config.paused {
+        return Err(ErrorCode::ProgramPaused.into());
+    }
+    Ok(())
+}
+
+// Asserts all markets are whitelisted, if the whitelist is enabled.
+fn check_whitelisted(
+    config: &Config,
+    whitelist: &AccountInfo,
+    markets: &[&AccountInfo],
+) -> Result<()> {
+    if config.whitelist == Pubkey::default() {
+        return Ok(());
+    }
+    if whitelist.key != &config.whitelist {
+        return Err(ErrorCode::InvalidWhitelist.into());
+    }
+    let whitelist: Account<Whitelist> = Account::try_from(whitelist)?;
+    if markets
+        .iter()
+        .any(|market| !whitelist.markets.contains(market.key))
+    {
+        return Err(ErrorCode::MarketNotWhitelisted.into());
+    }
+    Ok(())
+}
+
+// Validates the tokens being swapped are of different mints.
+fn _is_valid_swap<'info>(from: &AccountInfo<'info>, to: &AccountInfo<'info>) -> Result<()> {
+    let from_token_mint = token::accessor::mint(from)?;
+    let to_token_mint = token::accessor::mint(to)?;
+    if from_token_mint == to_token_mint {
+        return Err(ErrorCode::SwapTokensCannotMatch.into());
+    }
+    Ok(())
+}
+
+// Global config of the program, managed by the admin.
+#[account]
+pub struct Config {
+    // Key allowed to update the config and collect the protocol fees.
+    pub admin: Pubkey,
+    // Protocol fee skimmed off the output of every swap, in basis points.
+    pub fee_bps: u16,
+    // Bump seed of the config PDA.
+    pub bump: u8,
+    // Key allowed to pause and unpause trading, alongside the admin.
+    pub guardian: Pubkey,
+    // True if all swaps are halted.
+    pub paused: bool,
+    // Market whitelist restricting all swaps, if set.
+    pub whitelist: Pubkey,
+    // Programs allowed to be invoked as swap hooks or deposit targets.
+    pub hook_programs: Vec<Pubkey>,
+    // Defaults of the orders sent to the DEX, if set by the admin.
+    pub execution: Option<ExecutionDefaults>,
+    // Number of circuit breakers created by the admin.
+    pub circuit_breakers: u32,
+}
+
+impl Config {
+    // Space allocated for the account. Extra space is reserved so fields can
+    // be appended without migrating existing accounts.
+    pub const SPACE: usize = 8 + 512;
+
+    // Returns the execution defaults, or the program's own if unset.
+    fn execution(&self) -> ExecutionDefaults {
+        self.execution.clone().unwrap_or_default()
+    }
+}
+
+// Governs how the orders of every swap are executed on the DEX.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ExecutionDefaults {
+    // Maximum number of matching cycles of each order. Non-zero.
+    pub match_limit: u16,
+    // How orders matching the authority's own resting orders are handled.
+    pub self_trade_behavior: SelfTrade,
+    // Bounds the price impact of direct swaps, in basis points of the best
+    // price, if set. Swaps may only give a lower maximum of their own.
+    pub max_price_impact_bps: Option<u16>,
+    // DEX programs swaps may trade on. Empty for any.
+    pub dex_programs: Vec<Pubkey>,
+}
+
+impl Default for ExecutionDefaults {
+    fn default() -> Self {
+        ExecutionDefaults {
+            match_limit: u16::MAX,
+            self_trade_behavior: SelfTrade::DecrementTake,
+            max_price_impact_bps: None,
+            dex_programs: vec![],
+        }
+    }
+}
+
+impl ExecutionDefaults {
+    // Returns the price impact bound of a swap giving `max_price_impact_bps`.
+    fn max_price_impact_bps(&self, max_price_impact_bps: Option<u16>) -> Option<u16> {
+        match (self.max_price_impact_bps, max_price_impact_bps) {
+            (Some(default), Some(bps)) => Some(default.min(bps)),
+            (default, bps) => default.or(bps),
+        }
+    }
+}
+
+// The DEX's self-trade behaviors.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub enum SelfTrade {
+    DecrementTake,
+    CancelProvide,
+    AbortTransaction,
+}
+
+impl From<SelfTrade> for SelfTradeBehavior {
+    fn from(self_trade: SelfTrade) -> SelfTradeBehavior {
+        match self_trade {
+            SelfTrade::DecrementTake => SelfTradeBehavior::DecrementTake,
+            SelfTrade::CancelProvide => SelfTradeBehavior::CancelProvide,
+            SelfTrade::AbortTransaction => SelfTradeBehavior::AbortTransaction,
+        }
+    }
+}
+
+// Caps the notional volume swapped on a market within a rolling window of
+// slots.
+//
+// The window is approximated by weighting the notional of the previous fixed
+// window by how much of it still overlaps the rolling one.
+#[account]
+pub struct CircuitBreaker {
+    pub market: Pubkey,
+    pub bump: u8,
+    // Length of the window, in slots.
+    pub window_slots: u64,
+    // Maximum notional within the window, in native quote units.
+    pub max_notional: u64,
+    // Index of the current fixed window, i.e., `slot / window_slots`.
+    pub window_index: u64,
+    // Notional swapped in the current fixed window.
+    pub window_notional: u64,
+    // Notional swapped in the previous fixed window.
+    pub prev_window_notional: u64,
+}
+
+impl CircuitBreaker {
+    pub const SPACE: usize = 8 + 128;
+
+    fn configure(&mut self, window_slots: u64, max_notional: u64) -> Result<()> {
+        if window_slots == 0 {
+            return Err(ErrorCode::InvalidCircuitBreaker.into());
+        }
+        self.window_slots = window_slots;
+        self.max_notional = max_notional;
+        self.window_index = 0;
+        self.window_notional = 0;
+        self.prev_window_notional = 0;
+        Ok(())
+    }
+
+    // Adds `notional` to the rolling window ending at `slot`, failing if that
+    // exceeds the cap.
+    fn record(&mut self, slot: u64, notional: u64) -> Result<()> {
+        let window_index = slot / self.window_slots;
+        if window_index == self.window_index.checked_add(1).unwrap() {
+            self.prev_window_notional = self.window_notional;
+            self.window_notional = 0;
+        } else if window_index != self.window_index {
+            self.prev_window_notional = 0;
+            self.window_notional = 0;
+        }
+        self.window_index = window_index;
+
+        // Share of the previous window still within the rolling one.
+        let overlap = self.window_slots - slot % self.window_slots;
+        let prev_notional = u128::from(self.prev_window_notional)
+            .checked_mul(overlap.into())
+            .unwrap()
+            .checked_div(self.window_slots.into())
+            .unwrap();
+        let window_notional = self.window_notional.checked_add(notional).unwrap();
+        if prev_notional.checked_add(window_notional.into()).unwrap() > self.max_notional.into() {
+            return Err(ErrorCode::CircuitBreakerTripped.into());
+        }
+        self.window_notional = window_notional;
+        Ok(())
+    }
+}
+
+// Recent price and cumulative volume of a market, as traded through this
+// program.
+#[account]
+pub struct MarketStats {
+    pub market: Pubkey,
+    pub bump: u8,
+    // Price of the last trade, in native quote units per native base unit,
+    // times `PRICE_SCALE`.
+    pub last_price: u64,
+    // Slot of the last trade.
+    pub last_slot: u64,
+    // Cumulative volumes, in native units.
+    pub base_volume: u128,
+    pub quote_volume: u128,
+}
+
+impl MarketStats {
+    pub const SPACE: usize = 8 + 256;
+
+    fn record(&mut self, slot: u64, base_amount: u64, quote_amount: u64) {
+        if base_amount > 0 {
+            self.last_price = price(quote_amount, base_amount);
+            self.last_slot = slot;
+        }
+        self.base_volume = self.base_volume.checked_add(base_amount.into()).unwrap();
+        self.quote_volume = self.quote_volume.checked_add(quote_amount.into()).unwrap();
+    }
+}
+
+// Returns the price of `amount` in `quote_amount`, i.e., `quote_amount /
+// amount` times `PRICE_SCALE`, saturating. Zero if `amount` is.
+fn price(quote_amount: u64, amount: u64) -> u64 {
+    if amount == 0 {
+        return 0;
+    }
+    u128::from(quote_amount)
+        .checked_mul(PRICE_SCALE)
+        .unwrap()
+        .checked_div(amount.into())
+        .unwrap()
+        .try_into()
+        .unwrap_or(u64::MAX)
+}
+
+// Lifetime swap volume of an authority, tracked on an opt-in basis.
+#[account]
+pub struct UserStats {
+    pub authority: Pubkey,
+    pub bump: u8,
+    // Number of swaps made, counting each swap of a batch.
+    pub swap_count: u64,
+    // Volume per quote mint, in the order first swapped.
+    pub volumes: Vec<QuoteVolume>,
+}
+
+impl UserStats {
+    pub const SPACE: usize = 8 + 512;
+
+    fn record(&mut self, volumes: &[(Pubkey, u64)]) -> Result<()> {
+        self.swap_count = self.swap_count.checked_add(1).unwrap();
+        for &(quote_mint, volume) in volumes {
+            match self.volumes.iter_mut().find(|v| v.mint == quote_mint) {
+                Some(v) => v.volume = v.volume.checked_add(volume).unwrap(),
+                None => {
+                    if self.volumes.len() >= MAX_USER_STATS_MINTS {
+                        return Err(ErrorCode::UserStatsFull.into());
+                    }
+                    self.volumes.push(QuoteVolume {
+                        mint: quote_mint,
+                        volume,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Volume swapped against a single quote mint.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct QuoteVolume {
+    pub mint: Pubkey,
+    // In native units of `mint`.
+    pub volume: u64,
+}
+
+// Limits on the swaps of an authority, set by the owner of the funds it
+// swaps, and enforced on the swaps given the policy.
+#[account]
+pub struct SpendingPolicy {
+    pub owner: Pubkey,
+    pub authority: Pubkey,
+    pub bump: u8,
+    // Limits per quote mint. Swaps quoted in any other mint fail.
+    pub limits: Vec<SpendingLimit>,
+}
+
+impl SpendingPolicy {
+    pub const SPACE: usize = 8 + 768;
+
+    fn set_limit(&mut self, mint: Pubkey, max_per_swap: u64, max_per_window: u64) -> Result<()> {
+        let limit = SpendingLimit {
+            mint,
+            max_per_swap,
+            max_per_window,
+            window_index: 0,
+            window_notional: 0,
+            prev_window_notional: 0,
+        };
+        match self.limits.iter_mut().find(|limit| limit.mint == mint) {
+            Some(existing) => *existing = limit,
+            None => {
+                if self.limits.len() >= MAX_SPENDING_LIMITS {
+                    return Err(ErrorCode::SpendingPolicyFull.into());
+                }
+                self.limits.push(limit);
+            }
+        }
+        Ok(())
+    }
+
+    // Adds a swap of `notional` quoted in `mint` at unix time `now`, failing
+    // if that exceeds the mint's limits.
+    fn record(&mut self, mint: Pubkey, now: u64, notional: u64) -> Result<()> {
+        let limit = self
+            .limits
+            .iter_mut()
+            .find(|limit| limit.mint == mint)
+            .ok_or(ErrorCode::NoSpendingLimit)?;
+        limit.record(now, notional)
+    }
+}
+
+// Hash of a swap committed to by `commit_swap`, until revealed or cancelled.
+#[account]
+pub struct SwapCommitment {
+    pub authority: Pubkey,
+    pub nonce: u64,
+    pub bump: u8,
+    // SHA-256 hash of the market, the swap's arguments and a salt.
+    pub hash: [u8; 32],
+    // Slot of the commitment. The swap is revealed in a later one.
+    pub slot: u64,
+}
+
+impl SwapCommitment {
+    pub const SPACE: usize = 8 + 96;
+}
+
+// PDA swapping on behalf of the signer of permits, as the authority of its
+// open orders accounts and the delegate of its token accounts.
+#[account]
+pub struct PermitAuthority {
+    pub signer: Pubkey,
+    pub bump: u8,
+    // Nonce of the next permit, incremented by each swap.
+    pub nonce: u64,
+}
+
+impl PermitAuthority {
+    pub const SPACE: usize = 8 + 64;
+}
+
+// Swap signed off-chain by the signer of a permit authority. Its borsh
+// serialization is the message signed.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct Permit {
+    // The permit authority swapping.
+    pub authority: Pubkey,
+    pub market: Pubkey,
+    pub side: Side,
+    // The amount to swap *from*, or `ENTIRE_BALANCE`.
+    pub amount: u64,
+    pub min_exchange_rate: ExchangeRate,
+    // Must be the permit authority's next nonce.
+    pub nonce: u64,
+    // Unix timestamp after which the permit can't be used.
+    pub expiry: i64,
+}
+
+// Swap stored by `propose_swap` until executed or cancelled.
+#[account]
+pub struct SwapProposal {
+    // Key that paid for the proposal, refunded on execution or cancellation.
+    pub proposer: Pubkey,
+    // Key that must sign the swap.
+    pub authority: Pubkey,
+    pub nonce: u64,
+    pub bump: u8,
+    // Unix timestamp from which the swap may be executed.
+    pub executable_after: i64,
+    pub args: SwapArgsV2,
+    // Keys of the swap's accounts, followed by its remaining accounts.
+    pub account_keys: Vec<Pubkey>,
+}
+
+impl SwapProposal {
+    // The fixed size fields and the arguments fit in 256 bytes.
+    pub const SPACE: usize = 8 + 256 + 32 * MAX_SWAP_PROPOSAL_ACCOUNTS;
+}
+
+// Limits on the swaps quoted in a single mint, in its native units.
+//
+// Like a circuit breaker's, the rolling window is approximated by weighting
+// the notional of the previous fixed window by how much of it still overlaps.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct SpendingLimit {
+    pub mint: Pubkey,
+    pub max_per_swap: u64,
+    pub max_per_window: u64,
+    // Index of the current fixed window, i.e., `now / SPENDING_WINDOW_SECONDS`.
+    pub window_index: u64,
+    // Notional swapped in the current fixed window.
+    pub window_notional: u64,
+    // Notional swapped in the previous fixed window.
+    pub prev_window_notional: u64,
+}
+
+impl SpendingLimit {
+    fn record(&mut self, now: u64, notional: u64) -> Result<()> {
+        if notional > self.max_per_swap {
+            msg!("notional: {:?}", notional);
+            return Err(ErrorCode::SpendingLimitExceeded.into());
+        }
+        let window_index = now / SPENDING_WINDOW_SECONDS;
+        if window_index == self.window_index.checked_add(1).unwrap() {
+            self.prev_window_notional = self.window_notional;
+            self.window_notional = 0;
+        } else if window_index != self.window_index {
+            self.prev_window_notional = 0;
+            self.window_notional = 0;
+        }
+        self.window_index = window_index;
+
+        let overlap = SPENDING_WINDOW_SECONDS - now % SPENDING_WINDOW_SECONDS;
+        let prev_notional = u128::from(self.prev_window_notional)
+            .checked_mul(overlap.into())
+            .unwrap()
+            .checked_div(SPENDING_WINDOW_SECONDS.into())
+            .unwrap();
+        let window_notional = self.window_notional.checked_add(notional).unwrap();
+        if prev_notional.checked_add(window_notional.into()).unwrap() > self.max_per_window.into() {
+            msg!("window_notional: {:?}", window_notional);
+            return Err(ErrorCode::SpendingLimitExceeded.into());
+        }
+        self.window_notional = window_notional;
+        Ok(())
+    }
+}
+
+// Tip paid per event consumed via `consume_events`, out of the crank tip
+// vault.
+#[account]
+pub struct CrankTip {
+    // Mint of the tip, that of the vault.
+    pub mint: Pubkey,
+    // Tip per event consumed, in native units. Zero for none.
+    pub amount: u64,
+    // Most events tipped per crank.
+    pub max_events: u16,
+    // Fewest events a crank must consume to be tipped, so that cranking
+    // one's own events one at a time, e.g., of self-trades, isn't.
+    pub min_events: u16,
+    // Bump seed of the PDA.
+    pub bump: u8,
+    // Most paid in tips within a slot, and the amount paid so far within the
+    // slot of the last tip.
+    pub max_per_slot: u64,
+    pub slot: u64,
+    pub slot_tips: u64,
+    // Markets whose cranks are tipped. Anyone may list a market and trade
+    // with themselves on it, so only those set by the admin are.
+    pub markets: Vec<Pubkey>,
+}
+
+impl CrankTip {
+    pub const SPACE: usize = 8 + 32 + 8 + 2 + 2 + 1 + 8 + 8 + 8 + 4 + 32 * MAX_TIPPED_MARKETS;
+
+    // Tip owed for a crank of the `market` in `slot` consuming
+    // `events_consumed` events, out of the vault's `balance`, recording it
+    // against the slot's cap.
+    fn tip(&mut self, market: &Pubkey, slot: u64, events_consumed: u64, balance: u64) -> u64 {
+        if events_consumed == 0
+            || events_consumed < self.min_events.into()
+            || !self.markets.contains(market)
+        {
+            return 0;
+        }
+        if self.slot != slot {
+            self.slot = slot;
+            self.slot_tips = 0;
+        }
+        let events_tipped = events_consumed.min(self.max_events.into());
+        let tip = self
+            .amount
+            .checked_mul(events_tipped)
+            .unwrap()
+            .min(self.max_per_slot.saturating_sub(self.slot_tips))
+            .min(balance);
+        self.slot_tips = self.slot_tips.checked_add(tip).unwrap();
+        tip
+    }
+}
+
+// Markets allowed to be traded when the whitelist is enabled.
+#[account]
+pub struct Whitelist {
+    pub markets: Vec<Pubkey>,
+}
+
+impl Whitelist {
+    pub const SPACE: usize = 8 + 4 + 32 * MAX_WHITELISTED_MARKETS;
+}
+
+// Referrer registered for a single quote mint, accumulating the referral
+// rebates settled into its vault.
+#[account]
+pub struct Referrer {
+    // Key allowed to claim the rebates.
+    pub authority: Pubkey,
+    // Quote currency mint of the rebates.
+    pub mint: Pubkey,
+    // Token account collecting the rebates, owned by this account.
+    pub vault: Pubkey,
+    // Bump seed of the referrer PDA.
+    pub bump: u8,
+    // Total rebates credited by swaps, in native units of `mint`, net of the
+    // shares paid out to the split recipients.
+    pub earned: u64,
+    // Total rebates paid out by `claim_referral_fees`.
+    pub claimed: u64,
+    // Recipients sharing each rebate.
+    pub splits: Vec<ReferralSplit>,
+}
+
+// Swap proceeds locked until `unlock_ts`, created by `swap_to_escrow`.
+#[account]
+pub struct Escrow {
+    // Authority of the swap funding the escrow.
+    pub depositor: Pubkey,
+    // Key allowed to withdraw the escrow once unlocked.
+    pub beneficiary: Pubkey,
+    // Token account holding the proceeds, owned by this account.
+    pub vault: Pubkey,
+    // Account refunded the rent on withdrawal.
+    pub payer: Pubkey,
+    // Unix timestamp from which the escrow may be withdrawn.
+    pub unlock_ts: i64,
+    // Amount deposited, in native units of the vault's mint.
+    pub amount: u64,
+    // Nonce distinguishing the depositor's escrows.
+    pub nonce: u64,
+    // Bump seed of the escrow PDA.
+    pub bump: u8,
+}
+
+impl Escrow {
+    pub const SPACE: usize = 8 + 32 * 4 + 8 * 3 + 1;
+}
+
+// Recurring swap schedule, created by `create_dca` and cranked by
+// `execute_dca`.
+#[account]
+pub struct Dca {
+    // Key that created the schedule, allowed to close it.
+    pub owner: Pubkey,
+    // Market swapped on.
+    pub market: Pubkey,
+    // Direction of the swaps.
+    pub side: Side,
+    // Token account holding the deposit, owned by this account.
+    pub input_vault: Pubkey,
+    // Token account receiving each swap's proceeds, owned by this account.
+    pub output_vault: Pubkey,
+    // Token account the proceeds are sent to.
+    pub destination: Pubkey,
+    // Amount of the *from* token swapped each interval.
+    pub amount_per_interval: u64,
+    // Seconds between swaps.
+    pub interval: i64,
+    // Unix timestamp from which the next swap may be executed.
+    pub next_ts: i64,
+    // Exchange rate below which a swap aborts.
+    pub min_exchange_rate: ExchangeRate,
+    // Amount of the *from* token paid to the cranker of each swap.
+    pub crank_fee: u64,
+    // Number of swaps executed.
+    pub executions: u64,
+    // Nonce distinguishing the owner's schedules.
+    pub nonce: u64,
+    // Bump seed of the schedule PDA.
+    pub bump: u8,
+    // Open orders account of the schedule on the market.
+    pub open_orders: Pubkey,
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize)]
-pub enum Side {
-    Bid,
-    Ask,
+impl Dca {
+    // Space allocated for the account. Extra space is reserved so fields can
+    // be appended without migrating existing accounts.
+    pub const SPACE: usize = 8 + 256;
 }
 
-impl From<Side> for SerumSide {
-    fn from(side: Side) -> SerumSide {
-        match side {
-            Side::Bid => SerumSide::Bid,
-            Side::Ask => SerumSide::Ask,
-        }
-    }
+// Order swapping a locked amount in slices over time, created by
+// `create_twap_order` and cranked by `execute_twap_slice`.
+#[account]
+pub struct Twap {
+    // Key that created the order, allowed to cancel it.
+    pub owner: Pubkey,
+    // Market swapped on.
+    pub market: Pubkey,
+    // Direction of the swaps.
+    pub side: Side,
+    // Token account holding the input, owned by this account.
+    pub input_vault: Pubkey,
+    // Token account receiving each slice's proceeds, owned by this account.
+    pub output_vault: Pubkey,
+    // Token account the proceeds are sent to.
+    pub destination: Pubkey,
+    // Amount of the *from* token locked, in total.
+    pub amount: u64,
+    // Number of slices the amount is swapped in.
+    pub num_slices: u16,
+    // Number of slices executed.
+    pub slices_executed: u16,
+    // Unix timestamp from which the first slice may be executed.
+    pub start_ts: i64,
+    // Seconds between slices.
+    pub slice_interval: i64,
+    // Exchange rate below which a slice aborts.
+    pub min_exchange_rate: ExchangeRate,
+    // Nonce distinguishing the owner's orders.
+    pub nonce: u64,
+    // Bump seed of the order PDA.
+    pub bump: u8,
+    // Open orders account of the order on the market.
+    pub open_orders: Pubkey,
 }
 
-// Access control modifiers.
+impl Twap {
+    // Space allocated for the account. Extra space is reserved so fields can
+    // be appended without migrating existing accounts.
+    pub const SPACE: usize = 8 + 256;
+}
 
-fn is_valid_swap(ctx: &Context<Swap>) -> Result<()> {
-    _is_valid_swap(&ctx.accounts.market.coin_wallet, &ctx.accounts.pc_wallet)
+// A recipient's share of a referrer's rebates.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ReferralSplit {
+    // Token account receiving the share, for the referrer's mint.
+    pub recipient: Pubkey,
+    // Share of each rebate, in basis points.
+    pub bps: u16,
 }
 
-fn is_valid_swap_transitive(ctx: &Context<SwapTransitive>) -> Result<()> {
-    _is_valid_swap(&ctx.accounts.from.coin_wallet, &ctx.accounts.to.coin_wallet)
+impl Referrer {
+    // Space allocated for the account. Extra space is reserved so fields can
+    // be appended without migrating existing accounts.
+    pub const SPACE: usize = 8 + 256;
 }
 
-// Validates the tokens being swapped are of different mints.
-fn _is_valid_swap<'info>(from: &AccountInfo<'info>, to: &AccountInfo<'info>) -> Result<()> {
-    let from_token_mint = token::accessor::mint(from)?;
-    let to_token_mint = token::accessor::mint(to)?;
-    if from_token_mint == to_token_mint {
-        return Err(ErrorCode::SwapTokensCannotMatch.into());
-    }
-    Ok(())
+// Event emitted when an open orders account is initialized via this program.
+#[event]
+pub struct OpenOrdersInitialized {
+    pub authority: Pubkey,
+    pub market: Pubkey,
+    pub open_orders: Pubkey,
+}
+
+// Event emitted when an open orders account is closed via this program.
+#[event]
+pub struct OpenOrdersClosed {
+    pub authority: Pubkey,
+    pub market: Pubkey,
+    pub open_orders: Pubkey,
+    // Account receiving the refunded rent.
+    pub destination: Pubkey,
+    // Lamports refunded to the `destination`.
+    pub rent_refunded: u64,
+}
+
+// Event emitted when a flash swap repaid the output lent to it.
+#[event]
+pub struct FlashSwapped {
+    pub authority: Pubkey,
+    // Program invoked with the output.
+    pub callback_program: Pubkey,
+    // Mint lent, i.e., the *to* mint.
+    pub mint: Pubkey,
+    // Amount lent, and repaid.
+    pub amount: u64,
+    // Amount of the *from* token swapped to repay it.
+    pub from_amount: u64,
+}
+
+// Event emitted when an event queue is cranked via `consume_events`.
+#[event]
+pub struct EventsConsumed {
+    pub market: Pubkey,
+    // Account receiving the tip.
+    pub destination: Pubkey,
+    // Number of events consumed.
+    pub events_consumed: u64,
+    // Tip paid, in native units of the crank tip's mint.
+    pub tip: u64,
+}
+
+// Event emitted when the orders of an open orders account are cancelled via
+// `cancel_all_and_settle`.
+#[event]
+pub struct OrdersCancelled {
+    pub authority: Pubkey,
+    pub market: Pubkey,
+    pub open_orders: Pubkey,
+    // Number of orders cancelled.
+    pub orders_cancelled: u8,
+    // Whether the open orders account was closed.
+    pub closed: bool,
+}
+
+// Event emitted when a share of a referral rebate is paid out to a referrer's
+// split recipient.
+#[event]
+pub struct ReferralShared {
+    pub referrer: Pubkey,
+    pub recipient: Pubkey,
+    pub amount: u64,
+}
+
+// Event emitted when the spill of a transitive swap is transferred out of
+// its quote currency wallet.
+#[event]
+pub struct SpillTransferred {
+    pub authority: Pubkey,
+    pub destination: Pubkey,
+    pub quote_mint: Pubkey,
+    pub amount: u64,
+}
+
+// Event emitted when `pay_with_swap` pays a merchant.
+#[event]
+pub struct PaymentSettled {
+    pub authority: Pubkey,
+    pub merchant: Pubkey,
+    pub reference: Pubkey,
+    // Mint paid in, i.e., the *to* mint of the swap.
+    pub mint: Pubkey,
+    // Amount the merchant received.
+    pub amount: u64,
+    // Amount of the *from* token spent.
+    pub from_amount: u64,
+}
+
+// Event emitted when a share of the output of a swap is transferred to one of
+// its split recipients.
+#[event]
+pub struct OutputShared {
+    pub authority: Pubkey,
+    pub recipient: Pubkey,
+    pub mint: Pubkey,
+    pub amount: u64,
 }
 
 // Event emitted when a swap occurs for two base currencies on two different
@@ -723,6 +7721,14 @@ pub struct DidSwap {
     // (to sell) and the amount used in the second leg of the swap (to buy).
     /// CHECK: test
     pub spill_amount: u64,
+    // Protocol fee skimmed off the `to` token received. The `to_amount` is
+    // net of this fee.
+    /// CHECK: test
+    pub protocol_fee: u64,
+    // Integrator fee skimmed off the `to` token received. The `to_amount` is
+    // net of this fee.
+    /// CHECK: test
+    pub platform_fee: u64,
     // Mint sold.
     /// CHECK: test
     pub from_mint: Pubkey,
@@ -736,14 +7742,482 @@ pub struct DidSwap {
     // User that signed the transaction.
     /// CHECK: test
     pub authority: Pubkey,
+    // Side of the orders sent for a direct swap. None for a transitive swap,
+    // which sells then buys.
+    /// CHECK: test
+    pub side: Option<Side>,
+    // Realized price, i.e., the `to_amount` received per `from_amount` sold,
+    // in native units and times `PRICE_SCALE`.
+    /// CHECK: test
+    pub effective_price: u64,
+    // Amount of the base currency offered by the sell orders sent to the DEX,
+    // rounded down to the market's lot size. Zero if none were sent.
+    /// CHECK: test
+    pub order_base_qty: u64,
+    // Amount of the quote currency offered by the buy orders sent to the DEX.
+    // Zero if none were sent.
+    /// CHECK: test
+    pub order_quote_qty: u64,
+    // Client order id of the orders sent to the DEX.
+    /// CHECK: test
+    pub client_order_id: u64,
+    // Client data given with the swap, if any.
+    /// CHECK: test
+    pub tag: Option<[u8; 32]>,
+    // DEX taker fees paid, in native units of the quote currency. Already
+    // accounted for in the `from_amount` and `to_amount`. Zero for swaps
+    // across quote currencies, whose `DidSwapLeg` events report each leg's.
+    /// CHECK: test
+    pub taker_fee: u64,
+    // Share of the `taker_fee` the DEX rebates to the referral account given
+    // when settling, once the fills are consumed from its event queue.
+    // Otherwise, it's kept by the DEX.
+    /// CHECK: test
+    pub referrer_rebate: u64,
+    // Amount of the `from` token offered by a direct swap's order, i.e.,
+    // rounded down to the lot size for asks, but not filled. Zero for other
+    // swaps.
+    /// CHECK: test
+    pub unfilled_amount: u64,
+    // Top of the market's book right before the first order of a direct
+    // swap, or of the first market's for swaps split across markets. None for
+    // transitive swaps, whose `DidSwapLeg` events report each market's, or
+    // if no order was sent.
+    /// CHECK: test
+    pub book: Option<BookSnapshot>,
 }
 
-// An exchange rate for swapping *from* one token *to* another.
+// Event logged when a swap aborts with `SlippageExceeded`, with the amounts it
+// would have swapped. Only in the logs, as the transaction fails.
+#[event]
+pub struct SwapFailed {
+    // User that signed the transaction.
+    /// CHECK: test
+    pub authority: Pubkey,
+    // Mint sold.
+    /// CHECK: test
+    pub from_mint: Pubkey,
+    // Mint purchased.
+    /// CHECK: test
+    pub to_mint: Pubkey,
+    // Amount of the `from` token sold.
+    /// CHECK: test
+    pub from_amount: u64,
+    // Amount of the `to` token purchased, net of fees.
+    /// CHECK: test
+    pub to_amount: u64,
+    // Amount of the quote currency received by the first leg of a
+    // *transitive* swap. Zero for other swaps.
+    /// CHECK: test
+    pub quote_amount: u64,
+    // Amount of the quote currency left unspent by the second leg of a
+    // *transitive* swap. Zero for other swaps.
+    /// CHECK: test
+    pub spill_amount: u64,
+    // The `to_amount`, crediting any spill at the executed rate, compared
+    // against the `min_expected_amount`. For swaps with an exchange rate,
+    // both are scaled by the decimals of the `from`, `to` and quote mints.
+    // Otherwise, both are in native units of the `to` token.
+    /// CHECK: test
+    pub effective_to_amount: u128,
+    // The least the client accepted, in the same units.
+    /// CHECK: test
+    pub min_expected_amount: u128,
+}
+
+// Event emitted after an arbitrage, once profitable.
+#[event]
+pub struct DidArbitrage {
+    pub authority: Pubkey,
+    pub buy_market: Pubkey,
+    pub sell_market: Pubkey,
+    pub quote_mint: Pubkey,
+    // Amount of the base currency bought, then sold.
+    pub base_amount: u64,
+    // Amount of the base currency bought but not sold.
+    pub spill_amount: u64,
+    // Amount of the quote currency spent on the buy.
+    pub from_amount: u64,
+    // Amount of the quote currency received from the sale, net of the
+    // protocol fee.
+    pub to_amount: u64,
+    // Protocol fee taken, in native units of the quote currency.
+    pub protocol_fee: u64,
+    // Net gain, `to_amount` less `from_amount`.
+    pub profit: u64,
+}
+
+// Event emitted after each leg of a transitive swap, before fees.
+#[event]
+pub struct DidSwapLeg {
+    // Market traded on.
+    /// CHECK: test
+    pub market: Pubkey,
+    // Ask for the first leg, bid for the second.
+    /// CHECK: test
+    pub side: Side,
+    // Amount of the base currency sold or purchased.
+    /// CHECK: test
+    pub base_delta: u64,
+    // Amount of the quote currency received or spent.
+    /// CHECK: test
+    pub quote_delta: u64,
+    // Realized price, i.e., `quote_delta` per `base_delta` in native units,
+    // times `PRICE_SCALE`.
+    /// CHECK: test
+    pub price: u64,
+    // DEX taker fees paid, in native units of the quote currency.
+    /// CHECK: test
+    pub taker_fee: u64,
+    // Top of the market's book right before the leg's first order. None if
+    // no order was sent.
+    /// CHECK: test
+    pub book: Option<BookSnapshot>,
+}
+
+// Top of a market's book, as the reference price of a trade for
+// best-execution reporting.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct BookSnapshot {
+    // Highest bid, in native quote units per native base unit, times
+    // `PRICE_SCALE`. Zero if there's none, or the trade was a buy, which
+    // doesn't read the bids.
+    pub best_bid: u64,
+    // Lowest ask, in the same units. Zero if there's none, or the trade was a
+    // sale.
+    pub best_ask: u64,
+    // Slot and unix timestamp the book was read at.
+    pub slot: u64,
+    pub unix_timestamp: i64,
+}
+
+// A single swap within a batch.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct SwapParams {
+    // The direction to swap.
+    /// CHECK: test
+    pub side: Side,
+    // The amount to swap *from*.
+    /// CHECK: test
+    pub amount: u64,
+    // The exchange rate to use when determining whether the swap should abort.
+    /// CHECK: test
+    pub min_exchange_rate: ExchangeRate,
+    // Optional cap on the quote currency spent by a bid.
+    /// CHECK: test
+    pub max_quote_amount: Option<u64>,
+    // Optional client data echoed in the swap's event.
+    /// CHECK: test
+    pub tag: Option<[u8; 32]>,
+    // Succeeds without swapping if nothing fills, instead of aborting.
+    /// CHECK: test
+    pub allow_zero_output: bool,
+    // Allows the order to be partially filled. Otherwise, aborts unless the
+    // lot-rounded amount fills entirely.
+    /// CHECK: test
+    pub allow_partial: bool,
+    // Optional bound on the price impact expected from the book, in basis
+    // points of the best price.
+    /// CHECK: test
+    pub max_price_impact_bps: Option<u16>,
+}
+
+// Arguments of `swap_v2`. Options are added as optional fields at the end, so
+// the instruction itself doesn't change.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct SwapArgsV2 {
+    // The direction to swap.
+    /// CHECK: test
+    pub side: Side,
+    // The amount to swap *from*, or `ENTIRE_BALANCE`.
+    /// CHECK: test
+    pub amount: u64,
+    // The exchange rate to use when determining whether the swap should abort.
+    /// CHECK: test
+    pub min_exchange_rate: ExchangeRate,
+    // Optional cap on the quote currency spent by a bid.
+    /// CHECK: test
+    pub max_quote_amount: Option<u64>,
+    // Share of the output paid to the integrator. Zero if omitted.
+    /// CHECK: test
+    pub platform_fee_bps: Option<u16>,
+    // Optional band around the oracle price given as the first remaining
+    // account.
+    /// CHECK: test
+    pub oracle_band: Option<OracleBand>,
+    // Optional client data echoed in the swap's event.
+    /// CHECK: test
+    pub tag: Option<[u8; 32]>,
+    // Number of accounts passed to the optional hook program.
+    /// CHECK: test
+    pub hook_accounts: Option<u8>,
+    // Succeeds without swapping if nothing fills. False if omitted.
+    /// CHECK: test
+    pub allow_zero_output: Option<bool>,
+    // Allows the order to be partially filled. True if omitted.
+    /// CHECK: test
+    pub allow_partial: Option<bool>,
+    // Optional bound on the price impact expected from the book, in basis
+    // points of the best price.
+    /// CHECK: test
+    pub max_price_impact_bps: Option<u16>,
+    // Optional worst price of the order, in quote lots per base lot, i.e.,
+    // the highest for bids and the lowest for asks.
+    /// CHECK: test
+    pub limit_price: Option<u64>,
+    // Optional unix timestamp after which the swap aborts.
+    /// CHECK: test
+    pub deadline: Option<i64>,
+    // Optionally aborts before placing the order if the market's event queue
+    // is full, or holds more than this many events.
+    /// CHECK: test
+    pub max_event_queue_len: Option<u64>,
+    // Optional weights, in bps of the output, in which it's split across as
+    // many recipient token accounts, following the hook's in the remaining
+    // accounts. Whatever isn't split stays in the authority's wallet.
+    /// CHECK: test
+    pub output_split: Option<Vec<u16>>,
+}
+
+// Arguments of `swap_transitive_v2`. Like `SwapArgsV2`, options are added as
+// optional fields at the end.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct SwapTransitiveArgsV2 {
+    // The amount to swap *from*, or `ENTIRE_BALANCE`.
+    /// CHECK: test
+    pub amount: u64,
+    // The exchange rate to use when determining whether the swap should abort.
+    /// CHECK: test
+    pub min_exchange_rate: ExchangeRate,
+    // Optionally re-runs the buy leg on the spill.
+    /// CHECK: test
+    pub reswap: Option<Reswap>,
+    // Optional minimum exchange rates of each leg.
+    /// CHECK: test
+    pub leg_rates: Option<LegRates>,
+    // Optional cap on the quote currency spent by the buy leg.
+    /// CHECK: test
+    pub max_quote_amount: Option<u64>,
+    // Share of the output paid to the integrator. Zero if omitted.
+    /// CHECK: test
+    pub platform_fee_bps: Option<u16>,
+    // Optional bands around the oracle prices given as the first two
+    // remaining accounts.
+    /// CHECK: test
+    pub oracle_bands: Option<LegOracleBands>,
+    // Optional client data echoed in the swap's event.
+    /// CHECK: test
+    pub tag: Option<[u8; 32]>,
+    // Succeeds without swapping if nothing fills. False if omitted.
+    /// CHECK: test
+    pub allow_zero_output: Option<bool>,
+    // Transfers the spill to the token account following the oracles' in the
+    // remaining accounts. False if omitted.
+    /// CHECK: test
+    pub transfer_spill: Option<bool>,
+    // Optionally governs how the spill is credited and what happens to it.
+    /// CHECK: test
+    pub spill_handling: Option<SpillHandling>,
+}
+
+// Arguments of `swap_to_escrow` following the bumps and nonce of its PDAs.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct SwapToEscrowArgs {
+    // The direction to swap.
+    /// CHECK: test
+    pub side: Side,
+    // The amount to swap *from*, or `ENTIRE_BALANCE`.
+    /// CHECK: test
+    pub amount: u64,
+    // The exchange rate to use when determining whether the swap should abort.
+    /// CHECK: test
+    pub min_exchange_rate: ExchangeRate,
+    // The key allowed to withdraw the escrow.
+    /// CHECK: test
+    pub beneficiary: Pubkey,
+    // The unix timestamp from which the escrow may be withdrawn.
+    /// CHECK: test
+    pub unlock_ts: i64,
+}
+
+// Arguments of `create_dca` following the bumps and nonce of its PDAs.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct CreateDcaArgs {
+    // The direction to swap.
+    /// CHECK: test
+    pub side: Side,
+    // The amount to swap *from* each interval.
+    /// CHECK: test
+    pub amount_per_interval: u64,
+    // The seconds between swaps.
+    /// CHECK: test
+    pub interval: i64,
+    // The exchange rate below which each swap aborts.
+    /// CHECK: test
+    pub min_exchange_rate: ExchangeRate,
+    // The amount of the *from* token paid to whoever executes each swap.
+    /// CHECK: test
+    pub crank_fee: u64,
+    // The amount of the *from* token deposited.
+    /// CHECK: test
+    pub deposit_amount: u64,
+}
+
+// Arguments of `create_twap_order` following the bumps and nonce of its PDAs.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct CreateTwapArgs {
+    // The direction to swap.
+    /// CHECK: test
+    pub side: Side,
+    // The amount to swap *from*, in total.
+    /// CHECK: test
+    pub amount: u64,
+    // The number of slices.
+    /// CHECK: test
+    pub num_slices: u16,
+    // The unix timestamp of the first slice.
+    /// CHECK: test
+    pub start_ts: i64,
+    // The unix timestamp by which all slices are eligible.
+    /// CHECK: test
+    pub end_ts: i64,
+    // The exchange rate below which each slice aborts.
+    /// CHECK: test
+    pub min_exchange_rate: ExchangeRate,
+}
+
+// Instruction sent to a downstream program by `swap_and_deposit`.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct Deposit {
+    // Instruction data.
+    /// CHECK: test
+    pub data: Vec<u8>,
+    // Offset into the `data` at which the amount received by the swap is
+    // written, as a little-endian u64.
+    /// CHECK: test
+    pub amount_offset: u16,
+    // Number of accounts passed to the program, following it in the remaining
+    // accounts.
+    /// CHECK: test
+    pub num_accounts: u8,
+}
+
+// Controls splitting a direct swap into sequential orders.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct Chunks {
+    // The number of orders to split the swap into. Must be non-zero.
+    /// CHECK: test
+    pub count: u8,
+    // Optional worst price of every order, in native quote units per native
+    // base unit times `PRICE_SCALE`, i.e., the highest price to buy at or the
+    // lowest to sell at.
+    /// CHECK: test
+    pub worst_price: Option<u64>,
+}
+
+// Controls re-running the buy leg of a transitive swap on the spill, i.e., the
+// quote currency proceeds of the first leg *not* consumed by the second.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct Reswap {
+    // The maximum number of additional buy + settle iterations to perform.
+    /// CHECK: test
+    pub max_iterations: u8,
+    // Stop re-running the buy once the spill falls below this amount, in
+    // native units of the quote currency.
+    /// CHECK: test
+    pub spill_threshold: u64,
+}
+
+// What happens to the spill of a transitive swap, and how it's credited
+// against the exchange rate.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub enum SpillHandling {
+    // Leaves the spill in the quote currency wallet, ignoring it when checking
+    // the rate. Same as a strict rate.
+    StrictIgnore,
+    // Leaves the spill in the quote currency wallet, marked at the executed
+    // rate of the second leg when checking the rate.
+    CreditAtExecutedRate,
+    // Re-runs the buy leg on the spill, crediting what's left as above.
+    Reswap(Reswap),
+    // Transfers the spill to the spill destination, crediting it as above.
+    Refund,
+}
+
+impl SpillHandling {
+    // Returns the rate, reswap and `transfer_spill` arguments equivalent to
+    // this handling.
+    fn resolve(self, min_exchange_rate: ExchangeRate) -> (ExchangeRate, Option<Reswap>, bool) {
+        let min_exchange_rate = ExchangeRate {
+            strict: matches!(self, SpillHandling::StrictIgnore),
+            ..min_exchange_rate
+        };
+        match self {
+            SpillHandling::StrictIgnore | SpillHandling::CreditAtExecutedRate => {
+                (min_exchange_rate, None, false)
+            }
+            SpillHandling::Reswap(reswap) => (min_exchange_rate, Some(reswap), false),
+            SpillHandling::Refund => (min_exchange_rate, None, true),
+        }
+    }
+}
+
+// Minimum exchange rates for the individual legs of a transitive swap.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct LegRates {
+    // The first leg, selling the *from* token for the quote currency. The rate
+    // is in native quote units per *from* token.
+    /// CHECK: test
+    pub sell: LegRate,
+    // The second leg, buying the *to* token with the quote currency. The rate
+    // is in native *to* units per quote token.
+    /// CHECK: test
+    pub buy: LegRate,
+}
+
+// Maximum deviation of a market's execution price from its oracle price.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct OracleBand {
+    // Maximum deviation from the oracle price, in basis points.
+    /// CHECK: test
+    pub max_deviation_bps: u16,
+    // Number of decimals of the market's base currency mint.
+    /// CHECK: test
+    pub base_decimals: u8,
+    // Number of decimals of the market's quote currency mint.
+    /// CHECK: test
+    pub quote_decimals: u8,
+}
+
+// Oracle bands for the individual legs of a transitive swap.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct LegOracleBands {
+    // The first leg, selling the *from* token on the *from* market.
+    /// CHECK: test
+    pub sell: OracleBand,
+    // The second leg, buying the *to* token on the *to* market.
+    /// CHECK: test
+    pub buy: OracleBand,
+}
+
+// The minimum exchange rate of a single leg of a transitive swap.
 #[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct LegRate {
+    // The amount of tokens received for a single token sold on this leg, in
+    // native units of the token received. Zero leaves the leg unconstrained.
+    /// CHECK: test
+    pub rate: u64,
+    // Number of decimals of the mint sold on this leg.
+    /// CHECK: test
+    pub from_decimals: u8,
+}
+
+// An exchange rate for swapping *from* one token *to* another.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
 pub struct ExchangeRate {
     // The amount of *to* tokens one should receive for a single *from token.
     // This number must be in native *to* units with the same amount of decimals
-    // as the *to* mint.
+    // as the *to* mint. Must be non-zero.
     /// CHECK: test
     pub rate: u64,
     // Number of decimals of the *from* token's mint.
@@ -770,7 +8244,8 @@ pub struct ExchangeRate {
     // the second leg of the swap and that amount will be added to the
     // *to* mint received before calculating the swap's exchange rate.
     //
-    // Transitive swaps only. For direct swaps, this field is ignored.
+    // Transitive swaps only. Direct swaps reject strict rates. Superseded by
+    // the `spill_handling` argument of `swap_transitive`, when given.
     /// CHECK: test
     pub strict: bool,
 }
@@ -783,4 +8258,235 @@ pub enum ErrorCode {
     SlippageExceeded,
     #[msg("No tokens received when swapping")]
     ZeroSwap,
+    #[msg("Weights must be non-zero, match the number of markets and give each a share")]
+    InvalidWeights,
+    #[msg("All markets of a split swap must trade the same pair")]
+    SplitMarketsMismatch,
+    #[msg("Slippage tolerance exceeded on a leg of a transitive swap")]
+    LegSlippageExceeded,
+    #[msg("Spent more of the quote currency than the given maximum")]
+    QuoteCapExceeded,
+    #[msg("Authority is neither the owner nor the delegate of the order payer")]
+    InvalidDelegate,
+    #[msg("Order exceeds the amount delegated to the authority")]
+    DelegatedAmountExceeded,
+    #[msg("Wallets used by a delegate must be owned by the order payer's owner")]
+    DelegateWalletMismatch,
+    #[msg("Fee exceeds 10000 basis points")]
+    InvalidFee,
+    #[msg("Fee vault must be a token account of the config for the output mint")]
+    InvalidFeeVault,
+    #[msg("Platform fee account must be a token account for the output mint")]
+    InvalidPlatformFeeAccount,
+    #[msg("Referral account must be the vault of the given referrer")]
+    InvalidReferrer,
+    #[msg("Referral splits must match the referrer's recipients and not exceed 10000 bps")]
+    InvalidReferralSplits,
+    #[msg("Swaps are paused")]
+    ProgramPaused,
+    #[msg("Whitelist account must be the one set in the config")]
+    InvalidWhitelist,
+    #[msg("Market is not whitelisted")]
+    MarketNotWhitelisted,
+    #[msg("Whitelist is full")]
+    WhitelistFull,
+    #[msg("Oracle price account is missing, invalid, or not trading")]
+    OracleUnavailable,
+    #[msg("Execution price deviates from the oracle price beyond the band")]
+    PriceDeviationExceeded,
+    #[msg("Circuit breaker must be the market's PDA with a non-zero window")]
+    InvalidCircuitBreaker,
+    #[msg("Market's notional volume cap for the window is exceeded")]
+    CircuitBreakerTripped,
+    #[msg("User stats must belong to the swap authority")]
+    InvalidUserStats,
+    #[msg("User stats track the maximum number of quote mints")]
+    UserStatsFull,
+    #[msg("Market stats must belong to the market traded")]
+    InvalidMarketStats,
+    #[msg("Not enough accounts given for the hook")]
+    InvalidHook,
+    #[msg("Hook program is not whitelisted")]
+    HookProgramNotWhitelisted,
+    #[msg("The maximum number of hook programs are whitelisted")]
+    HookProgramsFull,
+    #[msg("Deposit amount offset is out of the instruction data's bounds")]
+    InvalidDepositAmountOffset,
+    #[msg("Bridge market must trade one quote currency for the other")]
+    InvalidBridgeMarket,
+    #[msg("Both markets must share the base currency wallet")]
+    BaseWalletMismatch,
+    #[msg("Open orders account is initialized for another market or authority")]
+    OpenOrdersMismatch,
+    #[msg("Order was only partially filled")]
+    PartialFill,
+    #[msg("Chunks must be non-zero, with a worst price of at least a lot")]
+    InvalidChunks,
+    #[msg("Market authority must sign")]
+    InvalidMarketAuthority,
+    #[msg("Price impact of the swap exceeds the maximum")]
+    PriceImpactExceeded,
+    #[msg("Escrow is still locked")]
+    EscrowLocked,
+    #[msg("Schedule amounts and intervals must be non-zero")]
+    InvalidSchedule,
+    #[msg("Schedule is not due yet")]
+    ScheduleNotDue,
+    #[msg("Schedule has nothing left to swap")]
+    ScheduleExhausted,
+    #[msg("Swap wallets don't match the schedule's vaults")]
+    InvalidScheduleWallets,
+    #[msg("Minimum exchange rate must be non-zero")]
+    ZeroExchangeRate,
+    #[msg("Exchange rate decimals exceed the maximum")]
+    InvalidRateDecimals,
+    #[msg("Strict exchange rates only apply to transitive swaps")]
+    StrictDirectSwap,
+    #[msg("Spill destination account is missing")]
+    SpillDestinationMissing,
+    #[msg("Spill handling can't be given along with reswap or transfer_spill")]
+    ConflictingSpillHandling,
+    #[msg("Swap deadline has passed")]
+    DeadlineExceeded,
+    #[msg("Event queue is not the market's")]
+    InvalidEventQueue,
+    #[msg("Market's event queue is full")]
+    EventQueueFull,
+    #[msg("Spending policy is not the authority's")]
+    InvalidSpendingPolicy,
+    #[msg("Spending policy has no limit for the quote mint")]
+    NoSpendingLimit,
+    #[msg("Swap exceeds the authority's spending limit")]
+    SpendingLimitExceeded,
+    #[msg("Spending policy limits too many mints")]
+    SpendingPolicyFull,
+    #[msg("Too many accounts for a swap proposal")]
+    TooManyProposalAccounts,
+    #[msg("Swap proposal isn't executable yet")]
+    SwapProposalLocked,
+    #[msg("Accounts don't match the swap proposal's")]
+    SwapProposalMismatch,
+    #[msg("Permit doesn't match the swap")]
+    InvalidPermit,
+    #[msg("Permit nonce isn't the permit authority's next one")]
+    InvalidPermitNonce,
+    #[msg("Permit has expired")]
+    PermitExpired,
+    #[msg("Permit isn't verified as signed by the permit authority's signer")]
+    InvalidPermitSignature,
+    #[msg("Swap can't be revealed in the slot of its commitment")]
+    EarlyReveal,
+    #[msg("Revealed swap doesn't match the commitment")]
+    CommitmentMismatch,
+    #[msg("Output split must have 1 to 8 recipients, all given, and not exceed 10000 bps")]
+    InvalidOutputSplit,
+    #[msg("Orders resting on the book can't fill the payment")]
+    PaymentUnfillable,
+    #[msg("Payment would spend more than the maximum amount")]
+    PaymentExceedsMaxAmount,
+    #[msg("Swap received less than the payment amount")]
+    PaymentShortfall,
+    #[msg("Arbitrage profit doesn't exceed the minimum")]
+    ArbitrageUnprofitable,
+    #[msg("Arbitrage must buy and sell on different markets")]
+    ArbitrageSameMarket,
+    #[msg("Execution defaults need a non-zero match limit and at most 4 DEX programs")]
+    InvalidExecutionDefaults,
+    #[msg("DEX program isn't allowed by the execution defaults")]
+    DexProgramNotAllowed,
+    #[msg("Wallet doesn't hold the market's base or quote mint")]
+    InvalidWalletMint,
+    #[msg("Flash swap would spend more than the maximum amount")]
+    FlashSwapExceedsMaxAmount,
+    #[msg("Flash swap didn't repay the amount lent")]
+    FlashSwapUnpaid,
+    #[msg("Signer isn't the program's upgrade authority")]
+    NotUpgradeAuthority,
+    #[msg("Flash vault isn't the PDA of the mint lent")]
+    InvalidFlashVault,
+    #[msg("Book isn't the market's")]
+    InvalidBook,
+    #[msg("Oracle price is older than the maximum age")]
+    StaleOraclePrice,
+    #[msg("Too many tipped markets")]
+    TooManyTippedMarkets,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_authority_bump() {
+        let (_, bump) = Pubkey::find_program_address(&[EVENT_AUTHORITY_SEED], &ID);
+        assert_eq!(bump, EVENT_AUTHORITY_BUMP);
+    }
+
+    #[test]
+    fn crank_tip() {
+        let market = Pubkey::new_unique();
+        let mut crank_tip = CrankTip {
+            mint: Pubkey::default(),
+            amount: 10,
+            max_events: 5,
+            min_events: 3,
+            bump: 0,
+            max_per_slot: 120,
+            slot: 0,
+            slot_tips: 0,
+            markets: vec![market],
+        };
+        // Below the minimum batch, e.g., one event at a time.
+        assert_eq!(crank_tip.tip(&market, 1, 0, 1000), 0);
+        assert_eq!(crank_tip.tip(&market, 1, 1, 1000), 0);
+        assert_eq!(crank_tip.tip(&market, 1, 2, 1000), 0);
+        assert_eq!(crank_tip.tip(&market, 1, 3, 1000), 30);
+        // Capped per crank.
+        assert_eq!(crank_tip.tip(&market, 1, 5, 1000), 50);
+        // Capped per slot, until the next one.
+        assert_eq!(crank_tip.tip(&market, 1, 100, 1000), 40);
+        assert_eq!(crank_tip.tip(&market, 1, 100, 1000), 0);
+        assert_eq!(crank_tip.tip(&market, 2, 100, 1000), 50);
+        // Capped by the vault's balance.
+        assert_eq!(crank_tip.tip(&market, 3, 100, 20), 20);
+        // Markets not set by the admin aren't tipped.
+        assert_eq!(crank_tip.tip(&Pubkey::new_unique(), 4, 100, 1000), 0);
+    }
+
+    #[test]
+    fn split_amount() {
+        assert_eq!(super::split_amount(100, &[1, 3]).unwrap(), vec![25, 75]);
+        // The remainder is left unallocated.
+        assert_eq!(super::split_amount(10, &[1, 1, 1]).unwrap(), vec![3, 3, 3]);
+        // A share rounding down to zero.
+        assert!(super::split_amount(5, &[1, 1000]).is_err());
+        assert!(super::split_amount(0, &[1]).is_err());
+        assert!(super::split_amount(100, &[1, 0]).is_err());
+        assert!(super::split_amount(100, &[]).is_err());
+    }
+
+    #[test]
+    fn check_quote_cap() {
+        assert!(super::check_quote_cap(100, None).is_ok());
+        assert!(super::check_quote_cap(100, Some(100)).is_ok());
+        // The orders are sized to the cap, so the dex never spends more, but
+        // the swap is aborted if it does.
+        assert!(super::check_quote_cap(101, Some(100)).is_err());
+    }
+    #[test]
+    fn open_orders_offsets() {
+        use dex::serum_dex::state::OpenOrders;
+        use std::mem::offset_of;
+
+        // Checked on the host, as the BPF toolchain predates `offset_of!`.
+        assert_eq!(
+            OPEN_ORDERS_FREE_SLOT_BITS_OFFSET,
+            offset_of!(OpenOrders, free_slot_bits)
+        );
+        assert_eq!(
+            OPEN_ORDERS_IS_BID_BITS_OFFSET,
+            offset_of!(OpenOrders, is_bid_bits)
+        );
+        assert_eq!(OPEN_ORDERS_ORDERS_OFFSET, offset_of!(OpenOrders, orders));
+    }
 }
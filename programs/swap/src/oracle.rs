@@ -0,0 +1,128 @@
+//! Minimal reader for Pyth v2 price accounts, used to bound the price a swap
+//! executes at.
+//!
+//! Only the fields needed here are read, directly from the account data, so
+//! the program doesn't depend on the Pyth client crate. The price account is
+//! given by the client as protection for its own swap, so its owner isn't
+//! checked.
+
+use crate::{ErrorCode, Result, MAX_ORACLE_PRICE_AGE_SLOTS};
+use anchor_lang::prelude::*;
+use std::convert::TryInto;
+
+const MAGIC: u32 = 0xa1b2_c3d4;
+const VERSION_2: u32 = 2;
+const ACCOUNT_TYPE_PRICE: u32 = 3;
+const STATUS_TRADING: u32 = 1;
+
+// Byte offsets into the price account.
+const MAGIC_OFFSET: usize = 0;
+const VERSION_OFFSET: usize = 4;
+const ACCOUNT_TYPE_OFFSET: usize = 8;
+const EXPO_OFFSET: usize = 20;
+const AGG_PRICE_OFFSET: usize = 208;
+const AGG_STATUS_OFFSET: usize = 224;
+const AGG_PUB_SLOT_OFFSET: usize = 232;
+const AGG_END: usize = 240;
+
+/// Aggregate price of a Pyth price account, i.e., `price * 10^expo` units of
+/// the quote currency per base token.
+pub struct Price {
+    pub price: i64,
+    pub expo: i32,
+}
+
+/// Loads the aggregate price, failing if the account isn't a Pyth v2 price
+/// account, the price isn't currently trading, or it was published more than
+/// `MAX_ORACLE_PRICE_AGE_SLOTS` ago.
+pub fn load_price(account: &AccountInfo) -> Result<Price> {
+    let data = account.try_borrow_data()?;
+    if data.len() < AGG_END
+        || read_u32(&data, MAGIC_OFFSET) != MAGIC
+        || read_u32(&data, VERSION_OFFSET) != VERSION_2
+        || read_u32(&data, ACCOUNT_TYPE_OFFSET) != ACCOUNT_TYPE_PRICE
+        || read_u32(&data, AGG_STATUS_OFFSET) != STATUS_TRADING
+    {
+        return Err(ErrorCode::OracleUnavailable.into());
+    }
+    let price = i64::from_le_bytes(
+        data[AGG_PRICE_OFFSET..AGG_PRICE_OFFSET + 8]
+            .try_into()
+            .unwrap(),
+    );
+    let expo = read_u32(&data, EXPO_OFFSET) as i32;
+    if price <= 0 {
+        return Err(ErrorCode::OracleUnavailable.into());
+    }
+    let pub_slot = u64::from_le_bytes(
+        data[AGG_PUB_SLOT_OFFSET..AGG_PUB_SLOT_OFFSET + 8]
+            .try_into()
+            .unwrap(),
+    );
+    let age = Clock::get()?.slot.saturating_sub(pub_slot);
+    if age > MAX_ORACLE_PRICE_AGE_SLOTS {
+        msg!("oracle price age: {:?}", age);
+        return Err(ErrorCode::StaleOraclePrice.into());
+    }
+    Ok(Price { price, expo })
+}
+
+/// Asserts the price realized by trading `base_amount` against
+/// `quote_amount`, both in native units, is within `max_deviation_bps` of the
+/// oracle price.
+pub fn check_deviation(
+    oracle: &Price,
+    base_amount: u64,
+    quote_amount: u64,
+    base_decimals: u8,
+    quote_decimals: u8,
+    max_deviation_bps: u16,
+) -> Result<()> {
+    // Nothing traded, so there's no price to check.
+    if base_amount == 0 {
+        return Ok(());
+    }
+
+    // The realized price in the oracle's units, i.e.,
+    // `quote / 10^quote_decimals / (base / 10^base_decimals) / 10^expo`.
+    let mut numerator = u128::from(quote_amount)
+        .checked_mul(pow10(base_decimals.into()))
+        .unwrap();
+    let mut denominator = u128::from(base_amount)
+        .checked_mul(pow10(quote_decimals.into()))
+        .unwrap();
+    if oracle.expo < 0 {
+        numerator = numerator.checked_mul(pow10(-oracle.expo as u32)).unwrap();
+    } else {
+        denominator = denominator.checked_mul(pow10(oracle.expo as u32)).unwrap();
+    }
+    let realized = numerator.checked_div(denominator).unwrap();
+
+    let expected = oracle.price as u128;
+    // `abs_diff` is too recent for the BPF toolchain.
+    #[allow(clippy::manual_abs_diff)]
+    let deviation = if realized > expected {
+        realized - expected
+    } else {
+        expected - realized
+    };
+    if deviation.checked_mul(10_000).unwrap()
+        > expected.checked_mul(max_deviation_bps.into()).unwrap()
+    {
+        msg!(
+            "realized price, oracle price: {:?}, {:?}",
+            realized,
+            expected,
+        );
+        return Err(ErrorCode::PriceDeviationExceeded.into());
+    }
+    Ok(())
+}
+
+fn pow10(exp: u32) -> u128 {
+    10u128.checked_pow(exp).unwrap()
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
@@ -0,0 +1,67 @@
+//! Verification of the ed25519 signatures of swap permits, signed off-chain
+//! and checked by the ed25519 program earlier in the transaction.
+//!
+//! The signature itself is verified by the runtime, which fails the whole
+//! transaction otherwise, so only the signer and message of the verify
+//! instruction are read here, via the instructions sysvar.
+
+use crate::{ErrorCode, Result};
+use anchor_lang::prelude::*;
+use solana_program::ed25519_program;
+use solana_program::sysvar::instructions::{
+    load_current_index_checked, load_instruction_at_checked,
+};
+use std::convert::TryInto;
+
+// Layout of the ed25519 program's instruction data: the number of
+// signatures, a padding byte, then the offsets of each signature.
+const OFFSETS_START: usize = 2;
+const OFFSETS_SIZE: usize = 14;
+const PUBKEY_SIZE: usize = 32;
+
+// Instruction index meaning "the verify instruction itself".
+const CURRENT_INSTRUCTION: u16 = u16::MAX;
+
+/// Asserts the instruction right before the current one verifies a single
+/// signature of `message` by `signer`.
+pub fn verify(instructions: &AccountInfo, signer: &Pubkey, message: &[u8]) -> Result<()> {
+    let current = load_current_index_checked(instructions)?;
+    let index = current
+        .checked_sub(1)
+        .ok_or(ErrorCode::InvalidPermitSignature)?;
+    let ix = load_instruction_at_checked(index.into(), instructions)?;
+    if ix.program_id != ed25519_program::ID
+        || ix.data.len() < OFFSETS_START + OFFSETS_SIZE
+        || ix.data[0] != 1
+    {
+        return Err(ErrorCode::InvalidPermitSignature.into());
+    }
+
+    let offsets: Vec<u16> = ix.data[OFFSETS_START..OFFSETS_START + OFFSETS_SIZE]
+        .chunks(2)
+        .map(|bytes| u16::from_le_bytes(bytes.try_into().unwrap()))
+        .collect();
+    let (pubkey_offset, pubkey_index) = (offsets[2], offsets[3]);
+    let (message_offset, message_size, message_index) = (offsets[4], offsets[5], offsets[6]);
+
+    // The signature, signer and message must all be in the verify
+    // instruction's own data.
+    let is_own = |instruction_index: u16| {
+        instruction_index == CURRENT_INSTRUCTION || instruction_index == index
+    };
+    if !is_own(offsets[1]) || !is_own(pubkey_index) || !is_own(message_index) {
+        return Err(ErrorCode::InvalidPermitSignature.into());
+    }
+    let signed_pubkey = slice(&ix.data, pubkey_offset, PUBKEY_SIZE)?;
+    let signed_message = slice(&ix.data, message_offset, message_size.into())?;
+    if signed_pubkey != signer.as_ref() || signed_message != message {
+        return Err(ErrorCode::InvalidPermitSignature.into());
+    }
+    Ok(())
+}
+
+fn slice(data: &[u8], offset: u16, size: usize) -> Result<&[u8]> {
+    let start = usize::from(offset);
+    data.get(start..start + size)
+        .ok_or_else(|| ErrorCode::InvalidPermitSignature.into())
+}
@@ -0,0 +1,340 @@
+//! Exchange rate math of the slippage checks.
+//!
+//! Free of accounts and events, so clients can run the same checks on a quote
+//! before sending a swap.
+
+use crate::{ErrorCode, ExchangeRate, LegRate};
+
+/// The most decimals an exchange rate may give for a mint, keeping the
+/// decimal scales of the checks within `u128`.
+pub const MAX_DECIMALS: u8 = 18;
+
+/// Rejects a `min_exchange_rate` the checks can't be run with, i.e., a zero
+/// rate, which any swap meets, decimals above `MAX_DECIMALS`, or a strict
+/// rate on a direct swap, which has no spill to ignore.
+///
+/// The quote decimals are only checked for `transitive` swaps, as direct
+/// swaps ignore them.
+pub fn validate(min_exchange_rate: &ExchangeRate, transitive: bool) -> Result<(), ErrorCode> {
+    if min_exchange_rate.rate == 0 {
+        return Err(ErrorCode::ZeroExchangeRate);
+    }
+    if min_exchange_rate.from_decimals > MAX_DECIMALS
+        || (transitive && min_exchange_rate.quote_decimals > MAX_DECIMALS)
+    {
+        return Err(ErrorCode::InvalidRateDecimals);
+    }
+    if min_exchange_rate.strict && !transitive {
+        return Err(ErrorCode::StrictDirectSwap);
+    }
+    Ok(())
+}
+
+/// Amounts compared by the slippage check of a swap, both with
+/// `decimals(from) + decimals(to) + decimals(quote)` decimals.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Expectation {
+    /// Amount received, credited with the spill if any.
+    pub effective_to_amount: u128,
+    /// Amount the client expects at least.
+    pub min_expected_amount: u128,
+}
+
+impl Expectation {
+    /// Returns the expectation of a swap of `from_amount` for `to_amount`,
+    /// at the `min_exchange_rate`.
+    ///
+    /// * `quote_amount` - Quote currency received by the first leg of a
+    ///   transitive swap. Zero for direct swaps.
+    /// * `spill_amount` - Quote currency left over by its second leg.
+    pub fn new(
+        from_amount: u64,
+        to_amount: u64,
+        quote_amount: u64,
+        spill_amount: u64,
+        min_exchange_rate: &ExchangeRate,
+    ) -> Self {
+        Expectation {
+            effective_to_amount: effective_to_amount(
+                to_amount,
+                quote_amount,
+                spill_amount,
+                min_exchange_rate,
+            ),
+            min_expected_amount: min_expected_amount(from_amount, min_exchange_rate),
+        }
+    }
+
+    /// Returns the expectation of a single leg of a transitive swap, with
+    /// `decimals(from) + decimals(to)` decimals.
+    pub fn leg(from_amount: u64, to_amount: u64, min_rate: &LegRate) -> Self {
+        Expectation {
+            effective_to_amount: u128::from(to_amount)
+                .checked_mul(10u128.checked_pow(min_rate.from_decimals.into()).unwrap())
+                .unwrap(),
+            min_expected_amount: u128::from(from_amount)
+                .checked_mul(min_rate.rate.into())
+                .unwrap(),
+        }
+    }
+
+    pub fn is_met(&self) -> bool {
+        self.effective_to_amount >= self.min_expected_amount
+    }
+}
+
+/// Returns the amount the client expects at least for `from_amount`.
+///
+/// The exchange rate given must always have decimals equal to the `to_mint`
+/// decimals, so the result has
+///
+/// `decimals(from_mint) + decimals(to_mint) + decimals(quote_mint)`
+///
+/// decimals. `decimals(quote_mint)` is added to avoid truncating.
+pub fn min_expected_amount(from_amount: u64, min_exchange_rate: &ExchangeRate) -> u128 {
+    u128::from(
+        // decimals(from).
+        from_amount,
+    )
+    .checked_mul(
+        // decimals(from) + decimals(to).
+        min_exchange_rate.rate.into(),
+    )
+    .unwrap()
+    .checked_mul(
+        // decimals(from) + decimals(to) + decimals(quote).
+        10u128
+            .checked_pow(min_exchange_rate.quote_decimals.into())
+            .unwrap(),
+    )
+    .unwrap()
+}
+
+/// Returns `to_amount` with the decimals of `min_expected_amount`.
+///
+/// If there is spill (i.e. quote tokens *not* fully consumed for the buy
+/// side of a transitive swap), then those tokens are credited, marked at
+/// the executed exchange rate, unless the rate is strict.
+pub fn effective_to_amount(
+    to_amount: u64,
+    quote_amount: u64,
+    spill_amount: u64,
+    min_exchange_rate: &ExchangeRate,
+) -> u128 {
+    // Translates the leftover spill amount into "to" units via
+    //
+    // `(to_amount_received/quote_amount_given) * spill_amount`
+    //
+    let spill_surplus = match spill_amount == 0 || min_exchange_rate.strict {
+        true => 0,
+        false => u128::from(
+            // decimals(to).
+            to_amount,
+        )
+        .checked_mul(
+            // decimals(to) + decimals(quote).
+            spill_amount.into(),
+        )
+        .unwrap()
+        .checked_mul(
+            // decimals(to) + decimals(quote) + decimals(from).
+            10u128
+                .checked_pow(min_exchange_rate.from_decimals.into())
+                .unwrap(),
+        )
+        .unwrap()
+        .checked_mul(
+            // decimals(to) + decimals(quote)*2 + decimals(from).
+            10u128
+                .checked_pow(min_exchange_rate.quote_decimals.into())
+                .unwrap(),
+        )
+        .unwrap()
+        .checked_div(
+            // decimals(to) + decimals(quote) + decimals(from).
+            quote_amount.checked_sub(spill_amount).unwrap().into(),
+        )
+        .unwrap(),
+    };
+
+    // Translate the `to_amount` into a common number of decimals.
+    let to_amount = u128::from(
+        // decimals(to).
+        to_amount,
+    )
+    .checked_mul(
+        // decimals(to) + decimals(from).
+        10u128
+            .checked_pow(min_exchange_rate.from_decimals.into())
+            .unwrap(),
+    )
+    .unwrap()
+    .checked_mul(
+        // decimals(to) + decimals(from) + decimals(quote).
+        10u128
+            .checked_pow(min_exchange_rate.quote_decimals.into())
+            .unwrap(),
+    )
+    .unwrap();
+
+    to_amount.checked_add(spill_surplus).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    // Bounds keeping the u128 math from overflowing, i.e., the product of two
+    // amounts and two decimal scales.
+    const MAX_AMOUNT: u64 = 1 << 32;
+    const MAX_TEST_DECIMALS: u8 = 9;
+
+    fn rate(rate: u64, from_decimals: u8, quote_decimals: u8, strict: bool) -> ExchangeRate {
+        ExchangeRate {
+            rate,
+            from_decimals,
+            quote_decimals,
+            strict,
+        }
+    }
+
+    #[test]
+    fn degenerate_rates_are_rejected() {
+        assert!(validate(&rate(1, 6, 6, false), false).is_ok());
+        assert!(validate(&rate(1, 6, 6, true), true).is_ok());
+        assert!(matches!(
+            validate(&rate(0, 6, 6, false), true),
+            Err(ErrorCode::ZeroExchangeRate)
+        ));
+        assert!(matches!(
+            validate(&rate(1, MAX_DECIMALS + 1, 0, false), false),
+            Err(ErrorCode::InvalidRateDecimals)
+        ));
+        // Direct swaps ignore the quote decimals.
+        assert!(validate(&rate(1, 6, u8::MAX, false), false).is_ok());
+        assert!(matches!(
+            validate(&rate(1, 6, u8::MAX, false), true),
+            Err(ErrorCode::InvalidRateDecimals)
+        ));
+        assert!(matches!(
+            validate(&rate(1, 6, 0, true), false),
+            Err(ErrorCode::StrictDirectSwap)
+        ));
+    }
+
+    #[test]
+    fn direct_swap_at_the_rate_is_met() {
+        // 2 tokens of 6 decimals for 3 tokens of 9 decimals.
+        let min = rate(1_500_000_000, 6, 0, false);
+        assert!(Expectation::new(2_000_000, 3_000_000_000, 0, 0, &min).is_met());
+        assert!(!Expectation::new(2_000_000, 2_999_999_999, 0, 0, &min).is_met());
+    }
+
+    #[test]
+    fn spill_is_credited_at_the_executed_rate() {
+        // Half of the quote currency bought 100, so the other half is worth
+        // another 100.
+        let min = rate(2, 0, 0, false);
+        let expectation = Expectation::new(100, 100, 50, 25, &min);
+        assert_eq!(expectation.effective_to_amount, 200);
+        assert!(expectation.is_met());
+
+        let strict = rate(2, 0, 0, true);
+        assert!(!Expectation::new(100, 100, 50, 25, &strict).is_met());
+    }
+
+    #[test]
+    fn leg_at_the_rate_is_met() {
+        let min = LegRate {
+            rate: 1_500_000,
+            from_decimals: 6,
+        };
+        assert!(Expectation::leg(2_000_000, 3_000_000, &min).is_met());
+        assert!(!Expectation::leg(2_000_000, 2_999_999, &min).is_met());
+    }
+
+    proptest! {
+        #[test]
+        fn min_expected_amount_is_monotonic(
+            from_amount in 0..MAX_AMOUNT,
+            extra in 0..MAX_AMOUNT,
+            r in 0..MAX_AMOUNT,
+            quote_decimals in 0..=MAX_TEST_DECIMALS,
+        ) {
+            let min = rate(r, 0, quote_decimals, false);
+            let more = rate(r + 1, 0, quote_decimals, false);
+            let expected = min_expected_amount(from_amount, &min);
+            prop_assert!(min_expected_amount(from_amount + extra, &min) >= expected);
+            prop_assert!(min_expected_amount(from_amount, &more) >= expected);
+        }
+
+        #[test]
+        fn zero_rate_is_always_met(
+            from_amount in 0..MAX_AMOUNT,
+            to_amount in 0..MAX_AMOUNT,
+            from_decimals in 0..=MAX_TEST_DECIMALS,
+            quote_decimals in 0..=MAX_TEST_DECIMALS,
+        ) {
+            let min = rate(0, from_decimals, quote_decimals, false);
+            prop_assert!(Expectation::new(from_amount, to_amount, 0, 0, &min).is_met());
+        }
+
+        #[test]
+        fn exact_rate_is_the_threshold(
+            from_amount in 1..MAX_AMOUNT,
+            r in 1..MAX_AMOUNT,
+            from_decimals in 0..=MAX_TEST_DECIMALS,
+            quote_decimals in 0..=MAX_TEST_DECIMALS,
+        ) {
+            // The largest amount below the rate, and the smallest at it.
+            let min = rate(r, from_decimals, quote_decimals, false);
+            let scale = 10u128.pow(from_decimals.into());
+            let threshold = u128::from(from_amount) * u128::from(r);
+            let at_rate = threshold.checked_add(scale - 1).unwrap().checked_div(scale).unwrap();
+            prop_assume!(at_rate <= u128::from(u64::MAX));
+            let at_rate = at_rate as u64;
+            prop_assert!(Expectation::new(from_amount, at_rate, 0, 0, &min).is_met());
+            prop_assert!(!Expectation::new(from_amount, at_rate - 1, 0, 0, &min).is_met());
+        }
+
+        #[test]
+        fn spill_only_ever_adds(
+            to_amount in 0..MAX_AMOUNT,
+            quote_amount in 1..MAX_AMOUNT,
+            spill_numerator in 0..1000u64,
+            from_decimals in 0..=MAX_TEST_DECIMALS,
+            quote_decimals in 0..=MAX_TEST_DECIMALS,
+            strict in any::<bool>(),
+        ) {
+            // Spill strictly below the quote amount, as some was spent.
+            let spill_amount = quote_amount / 1000 * spill_numerator;
+            let min = rate(1, from_decimals, quote_decimals, strict);
+            let without_spill = effective_to_amount(to_amount, quote_amount, 0, &min);
+            let with_spill = effective_to_amount(to_amount, quote_amount, spill_amount, &min);
+            prop_assert_eq!(
+                without_spill,
+                u128::from(to_amount) * 10u128.pow(u32::from(from_decimals) + u32::from(quote_decimals))
+            );
+            if strict || spill_amount == 0 {
+                prop_assert_eq!(with_spill, without_spill);
+            } else {
+                prop_assert!(with_spill >= without_spill);
+            }
+        }
+
+        #[test]
+        fn spill_surplus_marks_at_the_executed_rate(
+            to_amount in 0..MAX_AMOUNT,
+            spent in 1..MAX_AMOUNT,
+            multiple in 1..8u64,
+        ) {
+            // Spilling `multiple` times what was spent is worth `multiple`
+            // times what it bought.
+            let min = rate(1, 0, 0, false);
+            let spill_amount = spent * multiple;
+            let effective = effective_to_amount(to_amount, spent + spill_amount, spill_amount, &min);
+            prop_assert_eq!(effective, u128::from(to_amount) * u128::from(multiple + 1));
+        }
+    }
+}
@@ -0,0 +1,51 @@
+//! Discovery of the DEX markets a route between two mints may trade.
+
+use anchor_spl::dex::serum_dex::state::MarketState;
+use anyhow::Result;
+use serum_swap_client::{Market, Memcmp, RpcClient};
+use solana_sdk::pubkey::Pubkey;
+use std::mem::{offset_of, size_of};
+
+// Every DEX account starts with b"serum", and ends with b"padding".
+const HEAD_PADDING: usize = 5;
+const MARKET_LEN: usize = HEAD_PADDING + size_of::<MarketState>() + 7;
+
+// Byte offsets of the mints of a market account.
+const COIN_MINT_OFFSET: usize = HEAD_PADDING + offset_of!(MarketState, coin_mint);
+const PC_MINT_OFFSET: usize = HEAD_PADDING + offset_of!(MarketState, pc_mint);
+
+/// Fetches the markets of the `dex_program` a route from `from_mint` to
+/// `to_mint` may trade, i.e., those trading either mint.
+pub fn discover_markets(
+    rpc: &RpcClient,
+    dex_program: &Pubkey,
+    from_mint: &Pubkey,
+    to_mint: &Pubkey,
+) -> Result<Vec<Market>> {
+    let mut markets = vec![];
+    for mint in [from_mint, to_mint] {
+        markets.extend(fetch_markets(rpc, dex_program, mint)?);
+    }
+    markets.sort_by_key(|market| market.address);
+    markets.dedup_by_key(|market| market.address);
+    Ok(markets)
+}
+
+// Fetches the markets trading the `mint`, as either currency.
+fn fetch_markets(rpc: &RpcClient, dex_program: &Pubkey, mint: &Pubkey) -> Result<Vec<Market>> {
+    let mut markets = vec![];
+    for offset in [COIN_MINT_OFFSET, PC_MINT_OFFSET] {
+        let filters = [Memcmp {
+            offset,
+            bytes: mint.to_bytes().to_vec(),
+        }];
+        for (address, data) in rpc.get_program_accounts(dex_program, &filters)? {
+            // Skips the DEX's other accounts.
+            if data.len() != MARKET_LEN {
+                continue;
+            }
+            markets.push(Market::decode(address, *dex_program, &data)?);
+        }
+    }
+    Ok(markets)
+}
@@ -0,0 +1,407 @@
+//! Off-chain route planner for the swap program: finds the markets between
+//! two mints, quotes every route across them against the orders resting on
+//! their books, and returns the best one as the parameters, and instruction,
+//! of the swap executing it.
+//!
+//! Routes are either direct (`swap`), via a common quote currency
+//! (`swap_transitive`), or between two quote currencies via a common base
+//! currency (`swap_quote_to_quote`). As with the client's quotes, the
+//! amounts are before the DEX's and the protocol's fees.
+
+use anyhow::{anyhow, Result};
+use serum_swap::{Config, ExchangeRate, Side};
+use serum_swap_client::{self as client, book, Market, Orderbook, Quote, RpcClient, Wallets};
+use solana_sdk::instruction::Instruction;
+use solana_sdk::pubkey::Pubkey;
+use std::collections::HashMap;
+
+pub mod discovery;
+
+pub use discovery::discover_markets;
+
+/// Source of the orderbooks routes are quoted against.
+pub trait BookSource {
+    /// Returns the orders a swap of `side` on the `market` fills against.
+    fn orderbook(&self, market: &Market, side: Side) -> Result<Orderbook>;
+}
+
+impl BookSource for RpcClient {
+    fn orderbook(&self, market: &Market, side: Side) -> Result<Orderbook> {
+        client::fetch_orderbook(self, market, side)
+    }
+}
+
+/// Markets a swap trades, in order.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone)]
+pub enum Route {
+    /// A single order on the `market`, via `swap`.
+    Direct { market: Market, side: Side },
+    /// Sells the base currency of `from` for the quote currency it shares
+    /// with `to`, then buys the base currency of `to`, via `swap_transitive`.
+    Transitive { from: Market, to: Market },
+    /// Buys the base currency of `from` with its quote currency, then sells
+    /// it on `to` for the other quote currency, via `swap_quote_to_quote`.
+    QuoteToQuote { from: Market, to: Market },
+}
+
+impl Route {
+    /// Returns each market traded, with the side of its order.
+    pub fn legs(&self) -> Vec<(&Market, Side)> {
+        match self {
+            Route::Direct { market, side } => vec![(market, *side)],
+            Route::Transitive { from, to } => vec![(from, Side::Ask), (to, Side::Bid)],
+            Route::QuoteToQuote { from, to } => vec![(from, Side::Bid), (to, Side::Ask)],
+        }
+    }
+
+    /// Returns the mint whose decimals are the `quote_decimals` of the
+    /// route's exchange rate, or none for direct routes.
+    pub fn quote_mint(&self) -> Option<Pubkey> {
+        match self {
+            Route::Direct { .. } => None,
+            Route::Transitive { from, .. } => Some(from.pc_mint),
+            Route::QuoteToQuote { from, .. } => Some(from.coin_mint),
+        }
+    }
+}
+
+/// Returns every route from `from_mint` to `to_mint` across the `markets`,
+/// e.g., found by `discover_markets`.
+pub fn routes(markets: &[Market], from_mint: &Pubkey, to_mint: &Pubkey) -> Vec<Route> {
+    let mut routes = vec![];
+    if from_mint == to_mint {
+        return routes;
+    }
+    for market in markets {
+        if market.coin_mint == *from_mint && market.pc_mint == *to_mint {
+            routes.push(Route::Direct {
+                market: market.clone(),
+                side: Side::Ask,
+            });
+        } else if market.coin_mint == *to_mint && market.pc_mint == *from_mint {
+            routes.push(Route::Direct {
+                market: market.clone(),
+                side: Side::Bid,
+            });
+        }
+    }
+
+    let from_markets = markets
+        .iter()
+        .filter(|market| market.coin_mint == *from_mint);
+    for from in from_markets {
+        let to_markets = markets.iter().filter(|market| {
+            market.coin_mint == *to_mint
+                && market.pc_mint == from.pc_mint
+                && market.dex_program == from.dex_program
+        });
+        for to in to_markets {
+            routes.push(Route::Transitive {
+                from: from.clone(),
+                to: to.clone(),
+            });
+        }
+    }
+
+    let from_markets = markets.iter().filter(|market| market.pc_mint == *from_mint);
+    for from in from_markets {
+        let to_markets = markets.iter().filter(|market| {
+            market.pc_mint == *to_mint
+                && market.coin_mint == from.coin_mint
+                && market.dex_program == from.dex_program
+        });
+        for to in to_markets {
+            routes.push(Route::QuoteToQuote {
+                from: from.clone(),
+                to: to.clone(),
+            });
+        }
+    }
+    routes
+}
+
+/// Route quoted for an amount.
+#[derive(Clone)]
+pub struct Plan {
+    pub route: Route,
+    /// Amount to swap, in native units of the *from* mint.
+    pub amount: u64,
+    /// Quote of each leg, the amount received by one given to the next.
+    pub legs: Vec<Quote>,
+    /// Quote of the whole route, with the price impact summed over the
+    /// legs.
+    pub quote: Quote,
+}
+
+impl Plan {
+    /// Returns the exchange rate aborting the swap if it receives less
+    /// than quoted, less `slippage_bps`. `quote_decimals` are those of the
+    /// route's `quote_mint`, ignored for direct routes.
+    pub fn min_exchange_rate(
+        &self,
+        from_decimals: u8,
+        quote_decimals: u8,
+        slippage_bps: u64,
+    ) -> Result<ExchangeRate> {
+        Ok(ExchangeRate {
+            rate: book::min_rate(
+                self.quote.from_amount,
+                self.quote.to_amount,
+                from_decimals,
+                slippage_bps,
+            )?,
+            from_decimals,
+            quote_decimals: match self.route {
+                Route::Direct { .. } => 0,
+                _ => quote_decimals,
+            },
+            strict: false,
+        })
+    }
+
+    /// Returns the instruction executing the route from the `accounts`, given
+    /// the program's `config`.
+    pub fn instruction(
+        &self,
+        config: &Config,
+        accounts: &RouteAccounts,
+        min_exchange_rate: ExchangeRate,
+    ) -> Result<Instruction> {
+        Ok(match &self.route {
+            Route::Direct { market, side } => client::swap(
+                config,
+                market,
+                &accounts.wallets(market)?,
+                *side,
+                self.amount,
+                min_exchange_rate,
+                None,
+            ),
+            Route::Transitive { from, to } => client::swap_transitive(
+                config,
+                from,
+                to,
+                &accounts.wallets(from)?,
+                &accounts.wallets(to)?,
+                self.amount,
+                min_exchange_rate,
+            ),
+            Route::QuoteToQuote { from, to } => client::swap_quote_to_quote(
+                config,
+                from,
+                to,
+                &accounts.wallets(from)?,
+                &accounts.wallets(to)?,
+                self.amount,
+                min_exchange_rate,
+            ),
+        })
+    }
+}
+
+/// Accounts of the authority of a swap, for any route.
+pub struct RouteAccounts {
+    pub authority: Pubkey,
+    /// Owner of the token accounts. The authority itself, unless it's a
+    /// delegate.
+    pub owner: Pubkey,
+    /// Open orders account of each market, by market address.
+    pub open_orders: HashMap<Pubkey, Pubkey>,
+    /// Token account of each mint, by mint.
+    pub token_accounts: HashMap<Pubkey, Pubkey>,
+}
+
+impl RouteAccounts {
+    fn open_orders(&self, market: &Market) -> Result<Pubkey> {
+        self.open_orders
+            .get(&market.address)
+            .copied()
+            .ok_or_else(|| anyhow!("no open orders account on market {}", market.address))
+    }
+
+    fn token_account(&self, mint: &Pubkey) -> Result<Pubkey> {
+        self.token_accounts
+            .get(mint)
+            .copied()
+            .ok_or_else(|| anyhow!("no token account for mint {}", mint))
+    }
+
+    fn wallets(&self, market: &Market) -> Result<Wallets> {
+        Ok(Wallets {
+            authority: self.authority,
+            owner: self.owner,
+            open_orders: self.open_orders(market)?,
+            coin_wallet: self.token_account(&market.coin_mint)?,
+            pc_wallet: self.token_account(&market.pc_mint)?,
+        })
+    }
+}
+
+/// Quotes swapping `amount` along the `route`.
+pub fn quote_route(books: &impl BookSource, route: Route, amount: u64) -> Result<Plan> {
+    let mut legs = vec![];
+    let mut leg_amount = amount;
+    for (market, side) in route.legs() {
+        let quote = book::quote(market, books.orderbook(market, side)?, side, leg_amount);
+        leg_amount = quote.to_amount;
+        legs.push(quote);
+    }
+    let quote = Quote {
+        from_amount: legs[0].from_amount,
+        to_amount: leg_amount,
+        price_impact_bps: legs.iter().map(|leg| leg.price_impact_bps).sum(),
+    };
+    Ok(Plan {
+        route,
+        amount,
+        legs,
+        quote,
+    })
+}
+
+/// Returns the route across the `markets` receiving the most of `to_mint`
+/// for `amount` of `from_mint`, or none if nothing fills.
+pub fn best_route(
+    books: &impl BookSource,
+    markets: &[Market],
+    from_mint: &Pubkey,
+    to_mint: &Pubkey,
+    amount: u64,
+) -> Result<Option<Plan>> {
+    let mut best: Option<Plan> = None;
+    for route in routes(markets, from_mint, to_mint) {
+        let plan = quote_route(books, route, amount)?;
+        let is_better = match &best {
+            Some(best) => plan.quote.to_amount > best.quote.to_amount,
+            None => plan.quote.to_amount > 0,
+        };
+        if is_better {
+            best = Some(plan);
+        }
+    }
+    Ok(best)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn market(coin_mint: Pubkey, pc_mint: Pubkey, dex_program: Pubkey) -> Market {
+        Market {
+            address: Pubkey::new_unique(),
+            dex_program,
+            coin_mint,
+            pc_mint,
+            coin_vault: Pubkey::new_unique(),
+            pc_vault: Pubkey::new_unique(),
+            vault_signer: Pubkey::new_unique(),
+            request_queue: Pubkey::new_unique(),
+            event_queue: Pubkey::new_unique(),
+            bids: Pubkey::new_unique(),
+            asks: Pubkey::new_unique(),
+            coin_lot_size: 1,
+            pc_lot_size: 1,
+        }
+    }
+
+    // Returns the addresses of the markets of each route, with their sides.
+    fn legs(routes: &[Route]) -> Vec<Vec<(Pubkey, bool)>> {
+        routes
+            .iter()
+            .map(|route| {
+                route
+                    .legs()
+                    .into_iter()
+                    .map(|(market, side)| (market.address, matches!(side, Side::Bid)))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn routes_between_base_currencies() {
+        let dex = Pubkey::new_unique();
+        let (a, b, usdc, usdt) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let a_usdc = market(a, usdc, dex);
+        let b_usdc = market(b, usdc, dex);
+        let a_usdt = market(a, usdt, dex);
+        let b_usdt = market(b, usdt, dex);
+        // Markets between the quote currencies aren't traded by routes
+        // between base currencies.
+        let usdc_usdt = market(usdc, usdt, dex);
+        let a_b = market(a, b, dex);
+        // On another DEX program, so never traded with the others.
+        let b_usdc_other = market(b, usdc, Pubkey::new_unique());
+        let markets = [
+            a_usdc.clone(),
+            b_usdc.clone(),
+            a_usdt.clone(),
+            b_usdt.clone(),
+            usdc_usdt.clone(),
+            a_b.clone(),
+            b_usdc_other,
+        ];
+
+        let routes = routes(&markets, &a, &b);
+        assert_eq!(
+            legs(&routes),
+            vec![
+                vec![(a_b.address, false)],
+                vec![(a_usdc.address, false), (b_usdc.address, true)],
+                vec![(a_usdt.address, false), (b_usdt.address, true)],
+            ]
+        );
+        assert_eq!(routes[0].quote_mint(), None);
+        assert_eq!(routes[1].quote_mint(), Some(usdc));
+        assert_eq!(routes[2].quote_mint(), Some(usdt));
+    }
+
+    #[test]
+    fn routes_between_quote_currencies() {
+        let dex = Pubkey::new_unique();
+        let (a, b, usdc, usdt) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let usdc_usdt = market(usdc, usdt, dex);
+        let a_usdc = market(a, usdc, dex);
+        let a_usdt = market(a, usdt, dex);
+        let b_usdc = market(b, usdc, dex);
+        let b_usdt = market(b, usdt, Pubkey::new_unique());
+        let markets = [
+            usdc_usdt.clone(),
+            a_usdc.clone(),
+            a_usdt.clone(),
+            b_usdc,
+            b_usdt,
+        ];
+
+        let routes = routes(&markets, &usdt, &usdc);
+        assert_eq!(
+            legs(&routes),
+            vec![
+                vec![(usdc_usdt.address, true)],
+                vec![(a_usdt.address, true), (a_usdc.address, false)],
+            ]
+        );
+        assert!(matches!(routes[1], Route::QuoteToQuote { .. }));
+        assert_eq!(routes[1].quote_mint(), Some(a));
+    }
+
+    #[test]
+    fn no_routes_to_the_same_mint() {
+        let dex = Pubkey::new_unique();
+        let (a, usdc) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let markets = [market(a, usdc, dex)];
+        assert!(routes(&markets, &a, &a).is_empty());
+        assert!(routes(&markets, &a, &Pubkey::new_unique()).is_empty());
+    }
+}
@@ -0,0 +1,181 @@
+const assert = require("assert");
+const anchor = require("@project-serum/anchor");
+const BN = anchor.BN;
+const PublicKey = anchor.web3.PublicKey;
+const OpenOrders = require("@project-serum/serum").OpenOrders;
+const { Token, TOKEN_PROGRAM_ID } = require("@solana/spl-token");
+const serumCmn = require("@project-serum/common");
+const utils = require("./utils");
+
+describe("pda-authority", () => {
+  // Configure the client to use the local cluster.
+  anchor.setProvider(anchor.Provider.env());
+
+  // Swap program client.
+  const swapProgram = anchor.workspace.SerumSwap;
+  // Program composing with the swap program via CPI.
+  const program = anchor.workspace.PdaAuthority;
+
+  let ORDERBOOK_ENV,
+    // PDA authority owning the open orders and token accounts.
+    authority,
+    bump,
+    // PDA owned token accounts.
+    pdaTokenA,
+    pdaUsdc,
+    // Serum DEX vault PDA for market A/USDC.
+    marketAVaultSigner,
+    // Swap program config and protocol fee vaults.
+    PROTOCOL_FEES;
+
+  // PDA owned open orders account on market A.
+  const openOrdersA = new anchor.web3.Account();
+
+  it("BOILERPLATE: Sets up a market and PDA owned token accounts", async () => {
+    ORDERBOOK_ENV = await utils.setupTwoMarkets({
+      provider: program.provider,
+    });
+    [authority, bump] = await PublicKey.findProgramAddress(
+      [Buffer.from("authority")],
+      program.programId
+    );
+    [marketAVaultSigner] = await utils.getVaultOwnerAndNonce(
+      ORDERBOOK_ENV.marketA._decoded.ownAddress
+    );
+    PROTOCOL_FEES = await utils.setupProtocolFees({
+      program: swapProgram,
+      mints: [ORDERBOOK_ENV.mintA],
+    });
+
+    pdaTokenA = await serumCmn.createTokenAccount(
+      program.provider,
+      ORDERBOOK_ENV.mintA,
+      authority
+    );
+    pdaUsdc = await serumCmn.createTokenAccount(
+      program.provider,
+      ORDERBOOK_ENV.usdc,
+      authority
+    );
+
+    // Fund the PDA with USDC.
+    const tx = new anchor.web3.Transaction();
+    tx.add(
+      Token.createTransferInstruction(
+        TOKEN_PROGRAM_ID,
+        ORDERBOOK_ENV.godUsdc,
+        pdaUsdc,
+        program.provider.wallet.publicKey,
+        [],
+        100 * 10 ** 6
+      )
+    );
+    await program.provider.send(tx);
+  });
+
+  it("Initializes an open orders account owned by a PDA", async () => {
+    const marketA = ORDERBOOK_ENV.marketA;
+    await program.rpc.initAccount(bump, {
+      accounts: {
+        openOrders: openOrdersA.publicKey,
+        authority,
+        payer: program.provider.wallet.publicKey,
+        market: marketA._decoded.ownAddress,
+        dexProgram: utils.DEX_PID,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+        systemProgram: anchor.web3.SystemProgram.programId,
+        eventCpi: await utils.getEventCpi(swapProgram.programId),
+        swapProgram: swapProgram.programId,
+      },
+      instructions: [
+        await OpenOrders.makeCreateAccountTransaction(
+          program.provider.connection,
+          marketA._decoded.ownAddress,
+          program.provider.wallet.publicKey,
+          openOrdersA.publicKey,
+          utils.DEX_PID
+        ),
+      ],
+      signers: [openOrdersA],
+    });
+
+    const openOrders = await OpenOrders.load(
+      program.provider.connection,
+      openOrdersA.publicKey,
+      utils.DEX_PID
+    );
+    assert.ok(openOrders.owner.equals(authority));
+  });
+
+  it("Swaps from USDC to Token A with a PDA authority", async () => {
+    const marketA = ORDERBOOK_ENV.marketA;
+    const swapAmount = 10;
+
+    const beforeA = (await serumCmn.getTokenAccount(program.provider, pdaTokenA))
+      .amount;
+    const beforeUsdc = (
+      await serumCmn.getTokenAccount(program.provider, pdaUsdc)
+    ).amount;
+
+    await program.rpc.swap(
+      bump,
+      { bid: {} },
+      new BN(swapAmount * 10 ** 6),
+      {
+        rate: new BN(1.0),
+        fromDecimals: 6,
+        quoteDecimals: 6,
+        strict: false,
+      },
+      {
+        accounts: {
+          market: {
+            market: marketA._decoded.ownAddress,
+            openOrders: openOrdersA.publicKey,
+            requestQueue: marketA._decoded.requestQueue,
+            eventQueue: marketA._decoded.eventQueue,
+            bids: marketA._decoded.bids,
+            asks: marketA._decoded.asks,
+            orderPayerTokenAccount: pdaUsdc,
+            coinVault: marketA._decoded.baseVault,
+            pcVault: marketA._decoded.quoteVault,
+            vaultSigner: marketAVaultSigner,
+            coinWallet: pdaTokenA,
+            circuitBreaker: await utils.getCircuitBreaker(
+              swapProgram.programId,
+              marketA._decoded.ownAddress
+            ),
+            marketStats: swapProgram.programId,
+          },
+          authority,
+          pcWallet: pdaUsdc,
+          config: PROTOCOL_FEES.config,
+          whitelist: PROTOCOL_FEES.whitelist,
+          userStats: swapProgram.programId,
+          // The PDA owns its wallets.
+          spendingPolicy: await utils.getSpendingPolicy(
+            swapProgram.programId,
+            authority,
+            authority
+          ),
+          feeVault: PROTOCOL_FEES.feeVaults[ORDERBOOK_ENV.mintA.toString()],
+          platformFeeAccount: pdaTokenA,
+          referral: swapProgram.programId,
+          srmMsrmDiscount: swapProgram.programId,
+          dexProgram: utils.DEX_PID,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+          eventCpi: await utils.getEventCpi(swapProgram.programId),
+          swapProgram: swapProgram.programId,
+        },
+      }
+    );
+
+    const afterA = (await serumCmn.getTokenAccount(program.provider, pdaTokenA))
+      .amount;
+    const afterUsdc = (await serumCmn.getTokenAccount(program.provider, pdaUsdc))
+      .amount;
+    assert.ok(afterA.gt(beforeA));
+    assert.ok(afterUsdc.lt(beforeUsdc));
+  });
+});
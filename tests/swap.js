@@ -11,6 +11,9 @@ const utils = require("./utils");
 // Taker fee rate (bps).
 const TAKER_FEE = 0.0022;
 
+// Sentinel amount swapping the entire balance of the order payer.
+const ENTIRE_BALANCE = new BN("18446744073709551615");
+
 describe("swap", () => {
   // Configure the client to use the local cluster.
   anchor.setProvider(anchor.Provider.env());
@@ -27,7 +30,29 @@ describe("swap", () => {
     // Serum DEX vault PDA for market A/USDC.
     marketAVaultSigner,
     // Serum DEX vault PDA for market B/USDC.
-    marketBVaultSigner;
+    marketBVaultSigner,
+    // Swap program circuit breaker PDA for market A/USDC.
+    marketACircuitBreaker,
+    // Swap program circuit breaker PDA for market B/USDC.
+    marketBCircuitBreaker,
+    // Swap program config and protocol fee vaults.
+    PROTOCOL_FEES,
+    // Swap program accounts for emitting events via self-CPI.
+    EVENT_CPI,
+    // Spending policy PDA of the provider over its own swaps.
+    SPENDING_POLICY;
+
+  // Protocol fee vault for the given mint.
+  const feeVault = (mint) => PROTOCOL_FEES.feeVaults[mint.toString()];
+
+  // Flash vault PDA for the given mint.
+  const flashVault = async (mint) =>
+    (
+      await anchor.web3.PublicKey.findProgramAddress(
+        [Buffer.from("flash_vault"), mint.toBuffer()],
+        program.programId
+      )
+    )[0];
 
   // Open orders accounts on the two markets for the provider.
   const openOrdersA = new anchor.web3.Account();
@@ -39,7 +64,20 @@ describe("swap", () => {
     });
   });
 
+  it("BOILERPLATE: Sets up the config and protocol fee vaults", async () => {
+    PROTOCOL_FEES = await utils.setupProtocolFees({
+      program,
+      mints: [ORDERBOOK_ENV.mintA, ORDERBOOK_ENV.mintB, ORDERBOOK_ENV.usdc],
+    });
+  });
+
   it("BOILERPLATE: Sets up reusable accounts", async () => {
+    EVENT_CPI = await utils.getEventCpi(program.programId);
+    SPENDING_POLICY = await utils.getSpendingPolicy(
+      program.programId,
+      program.provider.wallet.publicKey,
+      program.provider.wallet.publicKey
+    );
     const marketA = ORDERBOOK_ENV.marketA;
     const marketB = ORDERBOOK_ENV.marketB;
 
@@ -51,6 +89,14 @@ describe("swap", () => {
     );
     marketAVaultSigner = vaultSignerA;
     marketBVaultSigner = vaultSignerB;
+    marketACircuitBreaker = await utils.getCircuitBreaker(
+      program.programId,
+      marketA._decoded.ownAddress
+    );
+    marketBCircuitBreaker = await utils.getCircuitBreaker(
+      program.programId,
+      marketB._decoded.ownAddress
+    );
 
     SWAP_USDC_A_ACCOUNTS = {
       market: {
@@ -66,12 +112,23 @@ describe("swap", () => {
         openOrders: openOrdersA.publicKey,
         orderPayerTokenAccount: ORDERBOOK_ENV.godUsdc,
         coinWallet: ORDERBOOK_ENV.godA,
+        circuitBreaker: marketACircuitBreaker,
+        marketStats: program.programId,
       },
       pcWallet: ORDERBOOK_ENV.godUsdc,
+      config: PROTOCOL_FEES.config,
+      whitelist: PROTOCOL_FEES.whitelist,
+      userStats: program.programId,
+      spendingPolicy: SPENDING_POLICY,
+      feeVault: feeVault(ORDERBOOK_ENV.mintA),
+      platformFeeAccount: ORDERBOOK_ENV.godA,
+      referral: program.programId,
+      srmMsrmDiscount: program.programId,
       authority: program.provider.wallet.publicKey,
       dexProgram: utils.DEX_PID,
       tokenProgram: TOKEN_PROGRAM_ID,
       rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+      eventCpi: EVENT_CPI,
     };
     SWAP_A_USDC_ACCOUNTS = {
       ...SWAP_USDC_A_ACCOUNTS,
@@ -79,6 +136,8 @@ describe("swap", () => {
         ...SWAP_USDC_A_ACCOUNTS.market,
         orderPayerTokenAccount: ORDERBOOK_ENV.godA,
       },
+      feeVault: feeVault(ORDERBOOK_ENV.usdc),
+      platformFeeAccount: ORDERBOOK_ENV.godUsdc,
     };
   });
 
@@ -98,9 +157,12 @@ describe("swap", () => {
       accounts: {
         openOrders: openOrders.publicKey,
         authority: program.provider.wallet.publicKey,
+        payer: program.provider.wallet.publicKey,
         market: marketA._decoded.ownAddress,
         dexProgram: utils.DEX_PID,
         rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+        systemProgram: anchor.web3.SystemProgram.programId,
+        eventCpi: EVENT_CPI,
       },
       instructions: [
         await OpenOrders.makeCreateAccountTransaction(
@@ -135,6 +197,139 @@ describe("swap", () => {
     assert.ok(solChange === 23367808 || solChange === 23367744);
   });
 
+  it("Creates open orders accounts if needed, funded by the payer", async () => {
+    const marketA = ORDERBOOK_ENV.marketA;
+    const openOrders = new Account();
+    // A relayer funding the rent of an authority without SOL.
+    const payer = new Account();
+    await program.provider.send(
+      new Transaction().add(
+        anchor.web3.SystemProgram.transfer({
+          fromPubkey: program.provider.wallet.publicKey,
+          toPubkey: payer.publicKey,
+          lamports: anchor.web3.LAMPORTS_PER_SOL,
+        })
+      )
+    );
+
+    // The account is created by the program.
+    await program.rpc.initAccountIfNeeded({
+      accounts: {
+        openOrders: openOrders.publicKey,
+        authority: program.provider.wallet.publicKey,
+        payer: payer.publicKey,
+        market: marketA._decoded.ownAddress,
+        dexProgram: utils.DEX_PID,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+        systemProgram: anchor.web3.SystemProgram.programId,
+        eventCpi: EVENT_CPI,
+      },
+      signers: [openOrders, payer],
+    });
+
+    const accountInfo = await program.provider.connection.getAccountInfo(
+      openOrders.publicKey
+    );
+    assert.ok(accountInfo.owner.equals(utils.DEX_PID));
+    // Initialized | OpenOrders.
+    assert.ok(accountInfo.data[5] === 5);
+    const payerInfo = await program.provider.connection.getAccountInfo(
+      payer.publicKey
+    );
+    assert.ok(
+      payerInfo.lamports === anchor.web3.LAMPORTS_PER_SOL - accountInfo.lamports
+    );
+  });
+
+  it("Skips initializing an already initialized open orders account", async () => {
+    const marketA = ORDERBOOK_ENV.marketA;
+    const txSig = await program.rpc.initAccountIfNeeded({
+      accounts: {
+        openOrders: ooAccount.publicKey,
+        authority: program.provider.wallet.publicKey,
+        payer: program.provider.wallet.publicKey,
+        market: marketA._decoded.ownAddress,
+        dexProgram: utils.DEX_PID,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+        systemProgram: anchor.web3.SystemProgram.programId,
+        eventCpi: EVENT_CPI,
+      },
+    });
+
+    // Nothing was initialized.
+    assert.deepStrictEqual(await cpiEvents(program, txSig), []);
+  });
+
+  it("Initializes open orders accounts on permissioned markets", async () => {
+    const marketAuthority = new Account();
+    const market = await utils.listMarket({
+      connection: program.provider.connection,
+      wallet: program.provider.wallet,
+      baseMint: ORDERBOOK_ENV.mintA,
+      quoteMint: ORDERBOOK_ENV.usdc,
+      baseLotSize: 100000,
+      quoteLotSize: 100,
+      dexProgramId: utils.DEX_PID,
+      feeRateBps: 0,
+      openOrdersAuthority: marketAuthority.publicKey,
+    });
+    const openOrders = new Account();
+    const initAccount = (marketAuthorityMeta, signers) =>
+      program.rpc.initAccount({
+        accounts: {
+          openOrders: openOrders.publicKey,
+          authority: program.provider.wallet.publicKey,
+          payer: program.provider.wallet.publicKey,
+          market,
+          dexProgram: utils.DEX_PID,
+          rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+          systemProgram: anchor.web3.SystemProgram.programId,
+          eventCpi: EVENT_CPI,
+        },
+        remainingAccounts: marketAuthorityMeta ? [marketAuthorityMeta] : [],
+        signers: [openOrders, ...signers],
+      });
+
+    // The market's authority is required by the DEX.
+    await assert.rejects(initAccount(null, []));
+    // Given, but not signing.
+    await assert.rejects(
+      initAccount(
+        {
+          pubkey: marketAuthority.publicKey,
+          isWritable: false,
+          isSigner: false,
+        },
+        []
+      ),
+      (err) => {
+        assert.strictEqual(err.msg, "Market authority must sign");
+        return true;
+      }
+    );
+    // Signed by another key than the market's authority.
+    const impostor = new Account();
+    await assert.rejects(
+      initAccount(
+        { pubkey: impostor.publicKey, isWritable: false, isSigner: true },
+        [impostor]
+      )
+    );
+
+    const txSig = await initAccount(
+      { pubkey: marketAuthority.publicKey, isWritable: false, isSigner: true },
+      [marketAuthority]
+    );
+    const accountInfo = await program.provider.connection.getAccountInfo(
+      openOrders.publicKey
+    );
+    assert.ok(accountInfo.owner.equals(utils.DEX_PID));
+    const initialized = (await cpiEvents(program, txSig)).find(
+      (event) => event.name === "OpenOrdersInitialized"
+    );
+    assert.ok(initialized.data.market.equals(market));
+  });
+
   it("Closes an open orders account", async () => {
     // Balance before the tx.
     const beforeAccount = await program.provider.connection.getAccountInfo(
@@ -143,13 +338,14 @@ describe("swap", () => {
 
     const marketA = ORDERBOOK_ENV.marketA;
     const openOrders = ooAccount;
-    await program.rpc.closeAccount({
+    const txSig = await program.rpc.closeAccount({
       accounts: {
         openOrders: openOrders.publicKey,
         authority: program.provider.wallet.publicKey,
         destination: program.provider.wallet.publicKey,
         market: marketA._decoded.ownAddress,
         dexProgram: utils.DEX_PID,
+        eventCpi: EVENT_CPI,
       },
     });
 
@@ -167,6 +363,135 @@ describe("swap", () => {
     // Should get the rent exemption sol back.
     const solChange = afterAccount.lamports - beforeAccount.lamports;
     assert.ok(solChange === 23352768);
+
+    const [closed] = await cpiEvents(program, txSig);
+    assert.ok(closed.name === "OpenOrdersClosed");
+    assert.ok(closed.data.openOrders.equals(openOrders.publicKey));
+    assert.ok(closed.data.market.equals(marketA._decoded.ownAddress));
+    // Net of the transaction fee.
+    assert.ok(closed.data.rentRefunded.toNumber() > solChange);
+  });
+
+  it("Cancels all resting orders of an open orders account", async () => {
+    const marketA = ORDERBOOK_ENV.marketA;
+    const openOrders = new Account();
+    await program.rpc.initAccount({
+      accounts: {
+        openOrders: openOrders.publicKey,
+        authority: program.provider.wallet.publicKey,
+        payer: program.provider.wallet.publicKey,
+        market: marketA._decoded.ownAddress,
+        dexProgram: utils.DEX_PID,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+        systemProgram: anchor.web3.SystemProgram.programId,
+        eventCpi: EVENT_CPI,
+      },
+      signers: [openOrders],
+    });
+
+    // Rests a bid well below the book, as if placed from another UI.
+    const { transaction, signers } = await marketA.makePlaceOrderTransaction(
+      program.provider.connection,
+      {
+        owner: program.provider.wallet.publicKey,
+        payer: ORDERBOOK_ENV.godUsdc,
+        side: "buy",
+        price: 1.0,
+        size: 1,
+        orderType: "postOnly",
+        clientId: undefined,
+        openOrdersAddressKey: openOrders.publicKey,
+        openOrdersAccount: undefined,
+        feeDiscountPubkey: null,
+        selfTradeBehavior: "abortTransaction",
+      }
+    );
+    await program.provider.send(transaction, signers);
+
+    let txSig;
+    const [usdcChange] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godUsdc],
+      async () => {
+        txSig = await program.rpc.cancelAllAndSettle(false, {
+          accounts: {
+            market: marketA._decoded.ownAddress,
+            openOrders: openOrders.publicKey,
+            authority: program.provider.wallet.publicKey,
+            bids: marketA._decoded.bids,
+            asks: marketA._decoded.asks,
+            eventQueue: marketA._decoded.eventQueue,
+            coinVault: marketA._decoded.baseVault,
+            pcVault: marketA._decoded.quoteVault,
+            vaultSigner: marketAVaultSigner,
+            coinWallet: ORDERBOOK_ENV.godA,
+            pcWallet: ORDERBOOK_ENV.godUsdc,
+            destination: program.provider.wallet.publicKey,
+            dexProgram: utils.DEX_PID,
+            tokenProgram: TOKEN_PROGRAM_ID,
+            eventCpi: EVENT_CPI,
+          },
+        });
+      }
+    );
+
+    // The bid's funds are settled back.
+    assert.ok(usdcChange === 1);
+
+    const [cancelled] = await cpiEvents(program, txSig);
+    assert.ok(cancelled.name === "OrdersCancelled");
+    assert.ok(cancelled.data.openOrders.equals(openOrders.publicKey));
+    assert.ok(cancelled.data.ordersCancelled === 1);
+    assert.ok(cancelled.data.closed === false);
+  });
+
+  it("Creates the associated token accounts of a swap's wallets", async () => {
+    const { Token, ASSOCIATED_TOKEN_PROGRAM_ID } = require("@solana/spl-token");
+    const marketA = ORDERBOOK_ENV.marketA;
+    const authority = new anchor.web3.Account();
+    const [coinWallet, pcWallet] = await Promise.all(
+      [ORDERBOOK_ENV.mintA, ORDERBOOK_ENV.usdc].map((mint) =>
+        Token.getAssociatedTokenAddress(
+          ASSOCIATED_TOKEN_PROGRAM_ID,
+          TOKEN_PROGRAM_ID,
+          mint,
+          authority.publicKey
+        )
+      )
+    );
+    const accounts = {
+      authority: authority.publicKey,
+      payer: program.provider.wallet.publicKey,
+      market: marketA._decoded.ownAddress,
+      coinMint: ORDERBOOK_ENV.mintA,
+      pcMint: ORDERBOOK_ENV.usdc,
+      coinWallet,
+      pcWallet,
+      associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: anchor.web3.SystemProgram.programId,
+      rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+    };
+    await program.rpc.initSwapWallets({ accounts });
+
+    for (const [wallet, mint] of [
+      [coinWallet, ORDERBOOK_ENV.mintA],
+      [pcWallet, ORDERBOOK_ENV.usdc],
+    ]) {
+      const account = await serumCmn.getTokenAccount(program.provider, wallet);
+      assert.ok(account.mint.equals(mint));
+      assert.ok(account.owner.equals(authority.publicKey));
+    }
+
+    // Wallets already created are skipped.
+    await program.rpc.initSwapWallets({ accounts });
+
+    // ...but wallets for the wrong mints are rejected.
+    await assert.rejects(
+      program.rpc.initSwapWallets({
+        accounts: { ...accounts, coinMint: ORDERBOOK_ENV.mintB },
+      })
+    );
   });
 
   it("Does not pay rent exemption sol in a single transaction", async () => {
@@ -193,9 +518,12 @@ describe("swap", () => {
         accounts: {
           openOrders: openOrders.publicKey,
           authority: program.provider.wallet.publicKey,
+          payer: program.provider.wallet.publicKey,
           market: marketA._decoded.ownAddress,
           dexProgram: utils.DEX_PID,
           rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+          systemProgram: anchor.web3.SystemProgram.programId,
+          eventCpi: EVENT_CPI,
         },
       })
     );
@@ -207,6 +535,7 @@ describe("swap", () => {
           destination: program.provider.wallet.publicKey,
           market: marketA._decoded.ownAddress,
           dexProgram: utils.DEX_PID,
+          eventCpi: EVENT_CPI,
         },
       })
     );
@@ -312,6 +641,115 @@ describe("swap", () => {
     const marketA = ORDERBOOK_ENV.marketA;
     const marketB = ORDERBOOK_ENV.marketB;
     const swapAmount = 10;
+    const minExchangeRate = {
+      rate: new BN(0.98 * 10 ** 6),
+      fromDecimals: 6,
+      toDecimals: 6,
+      strict: false,
+    };
+    const accounts = {
+      from: {
+        market: marketA._decoded.ownAddress,
+        requestQueue: marketA._decoded.requestQueue,
+        eventQueue: marketA._decoded.eventQueue,
+        bids: marketA._decoded.bids,
+        asks: marketA._decoded.asks,
+        coinVault: marketA._decoded.baseVault,
+        pcVault: marketA._decoded.quoteVault,
+        vaultSigner: marketAVaultSigner,
+        // User params.
+        openOrders: openOrdersA.publicKey,
+        // Swapping from A -> USDC.
+        orderPayerTokenAccount: ORDERBOOK_ENV.godA,
+        coinWallet: ORDERBOOK_ENV.godA,
+        circuitBreaker: marketACircuitBreaker,
+        marketStats: program.programId,
+      },
+      to: {
+        market: marketB._decoded.ownAddress,
+        requestQueue: marketB._decoded.requestQueue,
+        eventQueue: marketB._decoded.eventQueue,
+        bids: marketB._decoded.bids,
+        asks: marketB._decoded.asks,
+        coinVault: marketB._decoded.baseVault,
+        pcVault: marketB._decoded.quoteVault,
+        vaultSigner: marketBVaultSigner,
+        // User params.
+        openOrders: openOrdersB.publicKey,
+        // Swapping from USDC -> B.
+        orderPayerTokenAccount: ORDERBOOK_ENV.godUsdc,
+        coinWallet: ORDERBOOK_ENV.godB,
+        circuitBreaker: marketBCircuitBreaker,
+        marketStats: program.programId,
+      },
+      pcWallet: ORDERBOOK_ENV.godUsdc,
+      config: PROTOCOL_FEES.config,
+      whitelist: PROTOCOL_FEES.whitelist,
+      userStats: program.programId,
+      spendingPolicy: SPENDING_POLICY,
+      feeVault: feeVault(ORDERBOOK_ENV.mintB),
+      platformFeeAccount: ORDERBOOK_ENV.godB,
+      referral: program.programId,
+      srmMsrmDiscount: program.programId,
+      authority: program.provider.wallet.publicKey,
+      dexProgram: utils.DEX_PID,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+      eventCpi: EVENT_CPI,
+    };
+
+    // Skipping the spending policy's derivation, as an owner swapping their
+    // own funds may, saves compute against the same book.
+    const simulatedUnits = async (spendingPolicy) => {
+      const { raw } = await program.simulate.swapTransitive(
+        new BN(swapAmount * 10 ** 6),
+        minExchangeRate,
+        { accounts: { ...accounts, spendingPolicy } }
+      );
+      return computeUnits(program, raw);
+    };
+    const baseline = await simulatedUnits(SPENDING_POLICY);
+    const optimized = await simulatedUnits(program.programId);
+    assert.ok(optimized < baseline, `${optimized} units, from ${baseline}`);
+
+    let txSig;
+    const [tokenAChange, tokenBChange, usdcChange] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godA, ORDERBOOK_ENV.godB, ORDERBOOK_ENV.godUsdc],
+      async () => {
+        // Perform the actual swap.
+        txSig = await program.rpc.swapTransitive(
+          new BN(swapAmount * 10 ** 6),
+          minExchangeRate,
+          { accounts }
+        );
+      }
+    );
+
+    assert.ok(tokenAChange === -swapAmount);
+    // TODO: calculate this dynamically from the swap amount.
+    assert.ok(tokenBChange === 9.8);
+    assert.ok(usdcChange >= 0);
+
+    // One event per leg, then the summary.
+    const events = await cpiEvents(program, txSig);
+    assert.deepStrictEqual(
+      events.map((event) => event.name),
+      ["DidSwapLeg", "DidSwapLeg", "DidSwap"]
+    );
+    const [sellLeg, buyLeg] = events.map((event) => event.data);
+    assert.ok(sellLeg.market.equals(marketA._decoded.ownAddress));
+    assert.deepStrictEqual(sellLeg.side, Side.Ask);
+    assert.ok(sellLeg.baseDelta.toNumber() === swapAmount * 10 ** 6);
+    assert.ok(buyLeg.market.equals(marketB._decoded.ownAddress));
+    assert.deepStrictEqual(buyLeg.side, Side.Bid);
+    assert.ok(buyLeg.baseDelta.toNumber() === 9.8 * 10 ** 6);
+  });
+
+  it("Swaps from Token B to Token A", async () => {
+    const marketA = ORDERBOOK_ENV.marketA;
+    const marketB = ORDERBOOK_ENV.marketB;
+    const swapAmount = 23;
     const [tokenAChange, tokenBChange, usdcChange] = await withBalanceChange(
       program.provider,
       [ORDERBOOK_ENV.godA, ORDERBOOK_ENV.godB, ORDERBOOK_ENV.godUsdc],
@@ -320,7 +758,7 @@ describe("swap", () => {
         await program.rpc.swapTransitive(
           new BN(swapAmount * 10 ** 6),
           {
-            rate: new BN(0.98 * 10 ** 6),
+            rate: new BN(0.9 * 10 ** 6),
             fromDecimals: 6,
             toDecimals: 6,
             strict: false,
@@ -328,6 +766,23 @@ describe("swap", () => {
           {
             accounts: {
               from: {
+                market: marketB._decoded.ownAddress,
+                requestQueue: marketB._decoded.requestQueue,
+                eventQueue: marketB._decoded.eventQueue,
+                bids: marketB._decoded.bids,
+                asks: marketB._decoded.asks,
+                coinVault: marketB._decoded.baseVault,
+                pcVault: marketB._decoded.quoteVault,
+                vaultSigner: marketBVaultSigner,
+                // User params.
+                openOrders: openOrdersB.publicKey,
+                // Swapping from B -> USDC.
+                orderPayerTokenAccount: ORDERBOOK_ENV.godB,
+                coinWallet: ORDERBOOK_ENV.godB,
+                circuitBreaker: marketBCircuitBreaker,
+                marketStats: program.programId,
+              },
+              to: {
                 market: marketA._decoded.ownAddress,
                 requestQueue: marketA._decoded.requestQueue,
                 eventQueue: marketA._decoded.eventQueue,
@@ -338,11 +793,69 @@ describe("swap", () => {
                 vaultSigner: marketAVaultSigner,
                 // User params.
                 openOrders: openOrdersA.publicKey,
-                // Swapping from A -> USDC.
-                orderPayerTokenAccount: ORDERBOOK_ENV.godA,
+                // Swapping from USDC -> A.
+                orderPayerTokenAccount: ORDERBOOK_ENV.godUsdc,
                 coinWallet: ORDERBOOK_ENV.godA,
+                circuitBreaker: marketACircuitBreaker,
+                marketStats: program.programId,
               },
-              to: {
+              pcWallet: ORDERBOOK_ENV.godUsdc,
+              config: PROTOCOL_FEES.config,
+              whitelist: PROTOCOL_FEES.whitelist,
+              userStats: program.programId,
+              spendingPolicy: SPENDING_POLICY,
+              feeVault: feeVault(ORDERBOOK_ENV.mintA),
+              platformFeeAccount: ORDERBOOK_ENV.godA,
+              referral: program.programId,
+              srmMsrmDiscount: program.programId,
+              authority: program.provider.wallet.publicKey,
+              dexProgram: utils.DEX_PID,
+              tokenProgram: TOKEN_PROGRAM_ID,
+              rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+              eventCpi: EVENT_CPI,
+            },
+          }
+        );
+      }
+    );
+
+    // TODO: calculate this dynamically from the swap amount.
+    assert.ok(tokenAChange === 22.6);
+    assert.ok(tokenBChange === -swapAmount);
+    assert.ok(usdcChange >= 0);
+  });
+
+  it("Transfers the spill of a transitive swap to the given destination", async () => {
+    const marketA = ORDERBOOK_ENV.marketA;
+    const marketB = ORDERBOOK_ENV.marketB;
+    const spillDestination = await serumCmn.createTokenAccount(
+      program.provider,
+      ORDERBOOK_ENV.usdc,
+      new Account().publicKey
+    );
+
+    const swapAmount = 23;
+    let txSig;
+    const [usdcChange, spillChange] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godUsdc, spillDestination],
+      async () => {
+        txSig = await program.rpc.swapTransitiveV2(
+          swapTransitiveArgs({
+            amount: new BN(swapAmount * 10 ** 6),
+            minExchangeRate: {
+              rate: new BN(0.5 * 10 ** 6),
+              fromDecimals: 6,
+              quoteDecimals: 6,
+              strict: false,
+            },
+            // Caps the buy leg, so it spills.
+            maxQuoteAmount: new BN(5 * 10 ** 6),
+            transferSpill: true,
+          }),
+          {
+            accounts: {
+              from: {
                 market: marketB._decoded.ownAddress,
                 requestQueue: marketB._decoded.requestQueue,
                 eventQueue: marketB._decoded.eventQueue,
@@ -351,46 +864,89 @@ describe("swap", () => {
                 coinVault: marketB._decoded.baseVault,
                 pcVault: marketB._decoded.quoteVault,
                 vaultSigner: marketBVaultSigner,
-                // User params.
                 openOrders: openOrdersB.publicKey,
-                // Swapping from USDC -> B.
-                orderPayerTokenAccount: ORDERBOOK_ENV.godUsdc,
+                orderPayerTokenAccount: ORDERBOOK_ENV.godB,
                 coinWallet: ORDERBOOK_ENV.godB,
+                circuitBreaker: program.programId,
+                marketStats: program.programId,
+              },
+              to: {
+                market: marketA._decoded.ownAddress,
+                requestQueue: marketA._decoded.requestQueue,
+                eventQueue: marketA._decoded.eventQueue,
+                bids: marketA._decoded.bids,
+                asks: marketA._decoded.asks,
+                coinVault: marketA._decoded.baseVault,
+                pcVault: marketA._decoded.quoteVault,
+                vaultSigner: marketAVaultSigner,
+                openOrders: openOrdersA.publicKey,
+                orderPayerTokenAccount: ORDERBOOK_ENV.godUsdc,
+                coinWallet: ORDERBOOK_ENV.godA,
+                circuitBreaker: program.programId,
+                marketStats: program.programId,
               },
               pcWallet: ORDERBOOK_ENV.godUsdc,
+              config: PROTOCOL_FEES.config,
+              // Features not enabled yet are skipped, since swap_transitive_v2
+              // only fits a transaction without them.
+              whitelist: program.programId,
+              userStats: program.programId,
+              spendingPolicy: program.programId,
+              feeVault: program.programId,
+              platformFeeAccount: ORDERBOOK_ENV.godA,
+              referral: program.programId,
+              srmMsrmDiscount: program.programId,
               authority: program.provider.wallet.publicKey,
               dexProgram: utils.DEX_PID,
               tokenProgram: TOKEN_PROGRAM_ID,
               rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+              eventCpi: EVENT_CPI,
             },
+            remainingAccounts: [
+              { pubkey: spillDestination, isWritable: true, isSigner: false },
+            ],
           }
         );
       }
     );
 
-    assert.ok(tokenAChange === -swapAmount);
-    // TODO: calculate this dynamically from the swap amount.
-    assert.ok(tokenBChange === 9.8);
-    assert.ok(usdcChange >= 0);
+    // The quote currency wallet is left as it was, with the spill moved out.
+    assert.ok(usdcChange === 0);
+    assert.ok(spillChange > 0);
+    const events = await cpiEvents(program, txSig);
+    const spill = events.find((event) => event.name === "SpillTransferred");
+    assert.ok(spill.data.destination.equals(spillDestination));
+    assert.ok(spill.data.amount.toNumber() === Math.round(spillChange * 10 ** 6));
   });
 
-  it("Swaps from Token B to Token A", async () => {
+  it("Refunds the spill of a transitive swap via its spill handling", async () => {
     const marketA = ORDERBOOK_ENV.marketA;
     const marketB = ORDERBOOK_ENV.marketB;
+    const spillDestination = await serumCmn.createTokenAccount(
+      program.provider,
+      ORDERBOOK_ENV.usdc,
+      new Account().publicKey
+    );
+
     const swapAmount = 23;
-    const [tokenAChange, tokenBChange, usdcChange] = await withBalanceChange(
+    let txSig;
+    const [usdcChange, spillChange] = await withBalanceChange(
       program.provider,
-      [ORDERBOOK_ENV.godA, ORDERBOOK_ENV.godB, ORDERBOOK_ENV.godUsdc],
+      [ORDERBOOK_ENV.godUsdc, spillDestination],
       async () => {
-        // Perform the actual swap.
-        await program.rpc.swapTransitive(
-          new BN(swapAmount * 10 ** 6),
-          {
-            rate: new BN(0.9 * 10 ** 6),
-            fromDecimals: 6,
-            toDecimals: 6,
-            strict: false,
-          },
+        txSig = await program.rpc.swapTransitiveV2(
+          swapTransitiveArgs({
+            amount: new BN(swapAmount * 10 ** 6),
+            minExchangeRate: {
+              rate: new BN(0.5 * 10 ** 6),
+              fromDecimals: 6,
+              quoteDecimals: 6,
+              strict: false,
+            },
+            // Caps the buy leg, so it spills.
+            maxQuoteAmount: new BN(5 * 10 ** 6),
+            spillHandling: { refund: {} },
+          }),
           {
             accounts: {
               from: {
@@ -402,11 +958,11 @@ describe("swap", () => {
                 coinVault: marketB._decoded.baseVault,
                 pcVault: marketB._decoded.quoteVault,
                 vaultSigner: marketBVaultSigner,
-                // User params.
                 openOrders: openOrdersB.publicKey,
-                // Swapping from B -> USDC.
                 orderPayerTokenAccount: ORDERBOOK_ENV.godB,
                 coinWallet: ORDERBOOK_ENV.godB,
+                circuitBreaker: program.programId,
+                marketStats: program.programId,
               },
               to: {
                 market: marketA._decoded.ownAddress,
@@ -417,35 +973,3243 @@ describe("swap", () => {
                 coinVault: marketA._decoded.baseVault,
                 pcVault: marketA._decoded.quoteVault,
                 vaultSigner: marketAVaultSigner,
-                // User params.
                 openOrders: openOrdersA.publicKey,
-                // Swapping from USDC -> A.
                 orderPayerTokenAccount: ORDERBOOK_ENV.godUsdc,
                 coinWallet: ORDERBOOK_ENV.godA,
+                circuitBreaker: program.programId,
+                marketStats: program.programId,
               },
               pcWallet: ORDERBOOK_ENV.godUsdc,
+              config: PROTOCOL_FEES.config,
+              whitelist: program.programId,
+              userStats: program.programId,
+              spendingPolicy: program.programId,
+              feeVault: program.programId,
+              platformFeeAccount: ORDERBOOK_ENV.godA,
+              referral: program.programId,
+              srmMsrmDiscount: program.programId,
               authority: program.provider.wallet.publicKey,
               dexProgram: utils.DEX_PID,
               tokenProgram: TOKEN_PROGRAM_ID,
               rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+              eventCpi: EVENT_CPI,
             },
+            remainingAccounts: [
+              { pubkey: spillDestination, isWritable: true, isSigner: false },
+            ],
           }
         );
       }
     );
 
-    // TODO: calculate this dynamically from the swap amount.
-    assert.ok(tokenAChange === 22.6);
-    assert.ok(tokenBChange === -swapAmount);
-    assert.ok(usdcChange >= 0);
+    // Same as transferring the spill.
+    assert.ok(usdcChange === 0);
+    assert.ok(spillChange > 0);
+    const events = await cpiEvents(program, txSig);
+    const spill = events.find((event) => event.name === "SpillTransferred");
+    assert.ok(spill.data.destination.equals(spillDestination));
+    assert.ok(spill.data.amount.toNumber() === Math.round(spillChange * 10 ** 6));
   });
-});
 
-// Side rust enum used for the program's RPC API.
-const Side = {
-  Bid: { bid: {} },
-  Ask: { ask: {} },
-};
+  // Swaps from Token B to Token A via swap_transitive_v2, with the given
+  // `SwapTransitiveArgsV2` options on top of a 5 B swap at a loose rate,
+  // skipping the features not enabled yet.
+  const swapTransitiveBToA = (args) => {
+    const marketA = ORDERBOOK_ENV.marketA;
+    const marketB = ORDERBOOK_ENV.marketB;
+    return program.rpc.swapTransitiveV2(
+      swapTransitiveArgs({
+        amount: new BN(5 * 10 ** 6),
+        minExchangeRate: {
+          rate: new BN(0.5 * 10 ** 6),
+          fromDecimals: 6,
+          quoteDecimals: 6,
+          strict: false,
+        },
+        ...args,
+      }),
+      {
+        accounts: {
+          from: {
+            market: marketB._decoded.ownAddress,
+            requestQueue: marketB._decoded.requestQueue,
+            eventQueue: marketB._decoded.eventQueue,
+            bids: marketB._decoded.bids,
+            asks: marketB._decoded.asks,
+            coinVault: marketB._decoded.baseVault,
+            pcVault: marketB._decoded.quoteVault,
+            vaultSigner: marketBVaultSigner,
+            openOrders: openOrdersB.publicKey,
+            orderPayerTokenAccount: ORDERBOOK_ENV.godB,
+            coinWallet: ORDERBOOK_ENV.godB,
+            circuitBreaker: program.programId,
+            marketStats: program.programId,
+          },
+          to: {
+            market: marketA._decoded.ownAddress,
+            requestQueue: marketA._decoded.requestQueue,
+            eventQueue: marketA._decoded.eventQueue,
+            bids: marketA._decoded.bids,
+            asks: marketA._decoded.asks,
+            coinVault: marketA._decoded.baseVault,
+            pcVault: marketA._decoded.quoteVault,
+            vaultSigner: marketAVaultSigner,
+            openOrders: openOrdersA.publicKey,
+            orderPayerTokenAccount: ORDERBOOK_ENV.godUsdc,
+            coinWallet: ORDERBOOK_ENV.godA,
+            circuitBreaker: program.programId,
+            marketStats: program.programId,
+          },
+          pcWallet: ORDERBOOK_ENV.godUsdc,
+          config: PROTOCOL_FEES.config,
+          whitelist: program.programId,
+          userStats: program.programId,
+          spendingPolicy: program.programId,
+          feeVault: program.programId,
+          platformFeeAccount: ORDERBOOK_ENV.godA,
+          referral: program.programId,
+          srmMsrmDiscount: program.programId,
+          authority: program.provider.wallet.publicKey,
+          dexProgram: utils.DEX_PID,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+          eventCpi: EVENT_CPI,
+        },
+      }
+    );
+  };
+
+  it("Re-runs the buy leg of a transitive swap on the spill", async () => {
+    let txSig;
+    const [tokenAChange, usdcChange] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godA, ORDERBOOK_ENV.godUsdc],
+      async () => {
+        txSig = await swapTransitiveBToA({
+          reswap: { maxIterations: 3, spillThreshold: new BN(1) },
+        });
+      }
+    );
+
+    // Only what's too little to buy another lot of A with (~0.6 USDC) is
+    // left.
+    const swap = (await cpiEvents(program, txSig)).find(
+      (event) => event.name === "DidSwap"
+    );
+    assert.ok(tokenAChange > 0);
+    assert.ok(swap.data.spillAmount.lt(new BN(0.7 * 10 ** 6)));
+    assert.ok(usdcChange === swap.data.spillAmount.toNumber() / 10 ** 6);
+  });
+
+  it("Rejects reswapping a transitive swap along with its spill handling", async () => {
+    await assert.rejects(
+      swapTransitiveBToA({
+        reswap: { maxIterations: 3, spillThreshold: new BN(1) },
+        spillHandling: { creditAtExecutedRate: {} },
+      }),
+      (err) => {
+        assert.strictEqual(
+          err.msg,
+          "Spill handling can't be given along with reswap or transfer_spill"
+        );
+        return true;
+      }
+    );
+  });
+
+  it("Swaps transitively within the minimum rate of each leg", async () => {
+    const [tokenAChange, tokenBChange] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godA, ORDERBOOK_ENV.godB],
+      async () => {
+        await swapTransitiveBToA({
+          legRates: {
+            // ~6 USDC per B resting on the book.
+            sell: { rate: new BN(5 * 10 ** 6), fromDecimals: 6 },
+            // ~0.165 A per USDC resting on the book.
+            buy: { rate: new BN(0.1 * 10 ** 6), fromDecimals: 6 },
+          },
+        });
+      }
+    );
+    assert.ok(tokenBChange === -5);
+    assert.ok(tokenAChange > 0);
+  });
+
+  it("Aborts a transitive swap exceeding the slippage of a leg", async () => {
+    await assert.rejects(
+      swapTransitiveBToA({
+        legRates: {
+          // Well above the ~6 USDC per B resting on the book.
+          sell: { rate: new BN(10 * 10 ** 6), fromDecimals: 6 },
+          buy: { rate: new BN(0), fromDecimals: 6 },
+        },
+      }),
+      (err) => {
+        assert.strictEqual(
+          err.msg,
+          "Slippage tolerance exceeded on a leg of a transitive swap"
+        );
+        return true;
+      }
+    );
+  });
+
+  it("Swaps from USDC into a basket of Token A and Token B", async () => {
+    const marketA = ORDERBOOK_ENV.marketA;
+    const marketB = ORDERBOOK_ENV.marketB;
+    const swapAmount = 20;
+    const [tokenAChange, tokenBChange, usdcChange] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godA, ORDERBOOK_ENV.godB, ORDERBOOK_ENV.godUsdc],
+      async () => {
+        await program.rpc.swapBasket(
+          new BN(swapAmount * 10 ** 6),
+          // 75% into A, 25% into B.
+          [new BN(3), new BN(1)],
+          [new BN(1), new BN(1)],
+          null,
+          {
+            accounts: {
+              authority: program.provider.wallet.publicKey,
+              pcWallet: ORDERBOOK_ENV.godUsdc,
+              config: PROTOCOL_FEES.config,
+              whitelist: PROTOCOL_FEES.whitelist,
+              userStats: program.programId,
+              spendingPolicy: SPENDING_POLICY,
+              dexProgram: utils.DEX_PID,
+              tokenProgram: TOKEN_PROGRAM_ID,
+              rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+              eventCpi: EVENT_CPI,
+            },
+            remainingAccounts: [
+              ...marketAccountMetas({
+                market: marketA,
+                vaultSigner: marketAVaultSigner,
+                openOrders: openOrdersA.publicKey,
+                orderPayerTokenAccount: ORDERBOOK_ENV.godUsdc,
+                coinWallet: ORDERBOOK_ENV.godA,
+                circuitBreaker: marketACircuitBreaker,
+                marketStats: program.programId,
+              }),
+              feeVaultMeta(feeVault(ORDERBOOK_ENV.mintA)),
+              ...marketAccountMetas({
+                market: marketB,
+                vaultSigner: marketBVaultSigner,
+                openOrders: openOrdersB.publicKey,
+                orderPayerTokenAccount: ORDERBOOK_ENV.godUsdc,
+                coinWallet: ORDERBOOK_ENV.godB,
+                circuitBreaker: marketBCircuitBreaker,
+                marketStats: program.programId,
+              }),
+              feeVaultMeta(feeVault(ORDERBOOK_ENV.mintB)),
+            ],
+          }
+        );
+      }
+    );
+
+    assert.ok(tokenAChange > 0);
+    assert.ok(tokenBChange > 0);
+    assert.ok(-usdcChange <= swapAmount);
+  });
+
+  it("Executes a batch of independent swaps", async () => {
+    const marketA = ORDERBOOK_ENV.marketA;
+    const marketB = ORDERBOOK_ENV.marketB;
+    const pcWalletMeta = {
+      pubkey: ORDERBOOK_ENV.godUsdc,
+      isWritable: true,
+      isSigner: false,
+    };
+    const spendingPolicyMeta = {
+      pubkey: SPENDING_POLICY,
+      isWritable: true,
+      isSigner: false,
+    };
+    const [tokenAChange, tokenBChange] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godA, ORDERBOOK_ENV.godB],
+      async () => {
+        await program.rpc.swapMany(
+          [
+            {
+              side: Side.Bid,
+              amount: new BN(10 * 10 ** 6),
+              minExchangeRate: {
+                rate: new BN(1.0),
+                fromDecimals: 6,
+                quoteDecimals: 6,
+                strict: false,
+              },
+              maxQuoteAmount: null,
+              tag: null,
+              allowZeroOutput: false,
+              allowPartial: true,
+              maxPriceImpactBps: null,
+            },
+            {
+              side: Side.Ask,
+              amount: new BN(1 * 10 ** 6),
+              minExchangeRate: {
+                rate: new BN(1.0),
+                fromDecimals: 6,
+                quoteDecimals: 6,
+                strict: false,
+              },
+              maxQuoteAmount: null,
+              tag: null,
+              allowZeroOutput: false,
+              allowPartial: true,
+              maxPriceImpactBps: null,
+            },
+          ],
+          {
+            accounts: {
+              authority: program.provider.wallet.publicKey,
+              config: PROTOCOL_FEES.config,
+              whitelist: PROTOCOL_FEES.whitelist,
+              userStats: program.programId,
+              dexProgram: utils.DEX_PID,
+              tokenProgram: TOKEN_PROGRAM_ID,
+              rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+              eventCpi: EVENT_CPI,
+            },
+            remainingAccounts: [
+              ...marketAccountMetas({
+                market: marketA,
+                vaultSigner: marketAVaultSigner,
+                openOrders: openOrdersA.publicKey,
+                orderPayerTokenAccount: ORDERBOOK_ENV.godUsdc,
+                coinWallet: ORDERBOOK_ENV.godA,
+                circuitBreaker: marketACircuitBreaker,
+                marketStats: program.programId,
+              }),
+              pcWalletMeta,
+              spendingPolicyMeta,
+              feeVaultMeta(feeVault(ORDERBOOK_ENV.mintA)),
+              ...marketAccountMetas({
+                market: marketB,
+                vaultSigner: marketBVaultSigner,
+                openOrders: openOrdersB.publicKey,
+                orderPayerTokenAccount: ORDERBOOK_ENV.godB,
+                coinWallet: ORDERBOOK_ENV.godB,
+                circuitBreaker: marketBCircuitBreaker,
+                marketStats: program.programId,
+              }),
+              pcWalletMeta,
+              spendingPolicyMeta,
+              feeVaultMeta(feeVault(ORDERBOOK_ENV.usdc)),
+            ],
+          }
+        );
+      }
+    );
+
+    assert.ok(tokenAChange > 0);
+    assert.ok(tokenBChange === -1);
+  });
+
+  it("Takes the protocol fee and lets the admin collect it", async () => {
+    const admin = program.provider.wallet.publicKey;
+    const usdcFeeVault = feeVault(ORDERBOOK_ENV.usdc);
+
+    // 1% protocol fee.
+    await program.rpc.setFee(100, {
+      accounts: { config: PROTOCOL_FEES.config, admin },
+    });
+
+    const swapAmount = 1;
+    const [usdcChange, feeChange] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godUsdc, usdcFeeVault],
+      async () => {
+        await program.rpc.swap(
+          Side.Ask,
+          new BN(swapAmount * 10 ** 6),
+          { rate: new BN(1.0), fromDecimals: 6, quoteDecimals: 6, strict: false },
+          {
+            accounts: SWAP_A_USDC_ACCOUNTS,
+          }
+        );
+      }
+    );
+    assert.ok(feeChange > 0);
+    // The fee is 1% of the gross output.
+    assert.ok(
+      Math.floor((usdcChange + feeChange) * 10 ** 6 * 0.01) ===
+        Math.round(feeChange * 10 ** 6)
+    );
+
+    // Collect the fees back into the admin's wallet.
+    const [collected] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godUsdc],
+      async () => {
+        await program.rpc.collectFees(new BN(feeChange * 10 ** 6), {
+          accounts: {
+            config: PROTOCOL_FEES.config,
+            admin,
+            feeVault: usdcFeeVault,
+            destination: ORDERBOOK_ENV.godUsdc,
+            tokenProgram: TOKEN_PROGRAM_ID,
+          },
+        });
+      }
+    );
+    assert.ok(collected === feeChange);
+
+    // Reset the fee for any subsequent tests.
+    await program.rpc.setFee(0, {
+      accounts: { config: PROTOCOL_FEES.config, admin },
+    });
+  });
+
+  it("Pays the platform fee to the integrator", async () => {
+    const integratorUsdc = await serumCmn.createTokenAccount(
+      program.provider,
+      ORDERBOOK_ENV.usdc,
+      new Account().publicKey
+    );
+
+    const swapAmount = 1;
+    const [usdcChange, platformFee] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godUsdc, integratorUsdc],
+      async () => {
+        await program.rpc.swapV2(
+          swapArgs({
+            side: Side.Ask,
+            amount: new BN(swapAmount * 10 ** 6),
+            minExchangeRate: {
+              rate: new BN(1.0),
+              fromDecimals: 6,
+              quoteDecimals: 6,
+              strict: false,
+            },
+            // 0.5% platform fee.
+            platformFeeBps: 50,
+          }),
+          {
+            accounts: {
+              ...SWAP_A_USDC_ACCOUNTS,
+              platformFeeAccount: integratorUsdc,
+            },
+          }
+        );
+      }
+    );
+    assert.ok(platformFee > 0);
+    assert.ok(
+      Math.floor((usdcChange + platformFee) * 10 ** 6 * 0.005) ===
+        Math.round(platformFee * 10 ** 6)
+    );
+  });
+
+  it("Halts swaps while paused by the guardian", async () => {
+    const guardian = new Account();
+    await program.rpc.setGuardian(guardian.publicKey, {
+      accounts: {
+        config: PROTOCOL_FEES.config,
+        admin: program.provider.wallet.publicKey,
+      },
+    });
+    await program.rpc.pause({
+      accounts: {
+        config: PROTOCOL_FEES.config,
+        guardian: guardian.publicKey,
+      },
+      signers: [guardian],
+    });
+
+    await assert.rejects(
+      program.rpc.swap(
+        Side.Ask,
+        new BN(1 * 10 ** 6),
+        { rate: new BN(1.0), fromDecimals: 6, quoteDecimals: 6, strict: false },
+        {
+          accounts: SWAP_A_USDC_ACCOUNTS,
+        }
+      ),
+      (err) => {
+        assert.strictEqual(err.msg, "Swaps are paused");
+        return true;
+      }
+    );
+
+    await program.rpc.unpause({
+      accounts: {
+        config: PROTOCOL_FEES.config,
+        guardian: guardian.publicKey,
+      },
+      signers: [guardian],
+    });
+  });
+
+  it("Applies the execution defaults set by the admin", async () => {
+    const setExecutionDefaults = (execution) =>
+      program.rpc.setExecutionDefaults(execution, {
+        accounts: {
+          config: PROTOCOL_FEES.config,
+          admin: program.provider.wallet.publicKey,
+        },
+      });
+    await setExecutionDefaults({
+      matchLimit: 100,
+      selfTradeBehavior: { abortTransaction: {} },
+      maxPriceImpactBps: null,
+      dexPrograms: [new Account().publicKey],
+    });
+
+    await assert.rejects(
+      program.rpc.swap(
+        Side.Ask,
+        new BN(1 * 10 ** 6),
+        { rate: new BN(1.0), fromDecimals: 6, quoteDecimals: 6, strict: false },
+        {
+          accounts: SWAP_A_USDC_ACCOUNTS,
+        }
+      ),
+      (err) => {
+        assert.strictEqual(
+          err.msg,
+          "DEX program isn't allowed by the execution defaults"
+        );
+        return true;
+      }
+    );
+
+    // Back to the program's own defaults.
+    await setExecutionDefaults(null);
+  });
+
+  it("Rejects hook programs not whitelisted by the admin", async () => {
+    await assert.rejects(
+      program.rpc.swapV2(
+        swapArgs({
+          side: Side.Ask,
+          amount: new BN(1 * 10 ** 6),
+          minExchangeRate: {
+            rate: new BN(1.0),
+            fromDecimals: 6,
+            quoteDecimals: 6,
+            strict: false,
+          },
+          // The hook program, with no accounts.
+          hookAccounts: 0,
+        }),
+        {
+          accounts: SWAP_A_USDC_ACCOUNTS,
+          remainingAccounts: [
+            { pubkey: TOKEN_PROGRAM_ID, isWritable: false, isSigner: false },
+          ],
+        }
+      ),
+      (err) => {
+        assert.strictEqual(err.msg, "Hook program is not whitelisted");
+        return true;
+      }
+    );
+  });
+
+  it("Rejects flash swap callbacks not whitelisted by the admin", async () => {
+    await assert.rejects(
+      program.rpc.flashSwap(
+        Side.Ask,
+        new BN(1 * 10 ** 6),
+        new BN(1 * 10 ** 6),
+        // The callback program, with no accounts.
+        0,
+        {
+          accounts: {
+            swap: SWAP_A_USDC_ACCOUNTS,
+            flashVault: await flashVault(ORDERBOOK_ENV.usdc),
+          },
+          remainingAccounts: [
+            { pubkey: TOKEN_PROGRAM_ID, isWritable: false, isSigner: false },
+          ],
+        }
+      ),
+      (err) => {
+        assert.strictEqual(err.msg, "Hook program is not whitelisted");
+        return true;
+      }
+    );
+  });
+
+  // Mock flash swap callback, repaying the flash swap when given accounts to
+  // repay it with.
+  const flashCallback = anchor.workspace.FlashCallback;
+
+  // Flash swaps 6 USDC for token A out of an empty token A account, invoking
+  // the mock callback with the accounts given for it. Returns the transaction
+  // and the token A account.
+  const flashSwapUsdc = async (callbackAccounts) => {
+    const coinWallet = await new (require("@solana/spl-token").Token)(
+      program.provider.connection,
+      ORDERBOOK_ENV.mintA,
+      TOKEN_PROGRAM_ID,
+      program.provider.wallet.payer
+    ).createAccount(program.provider.wallet.publicKey);
+    const accounts = callbackAccounts(coinWallet);
+    const txSig = await program.rpc.flashSwap(
+      Side.Ask,
+      new BN(6 * 10 ** 6),
+      new BN(2 * 10 ** 6),
+      accounts.length,
+      {
+        accounts: {
+          swap: {
+            ...SWAP_A_USDC_ACCOUNTS,
+            market: {
+              ...SWAP_A_USDC_ACCOUNTS.market,
+              orderPayerTokenAccount: coinWallet,
+              coinWallet,
+            },
+          },
+          flashVault: await flashVault(ORDERBOOK_ENV.usdc),
+        },
+        remainingAccounts: [
+          {
+            pubkey: flashCallback.programId,
+            isWritable: false,
+            isSigner: false,
+          },
+          ...accounts,
+        ],
+      }
+    );
+    return [txSig, coinWallet];
+  };
+
+  it("BOILERPLATE: Sets up a funded flash vault and callback", async () => {
+    const admin = program.provider.wallet.publicKey;
+    await program.rpc.addHookProgram(flashCallback.programId, {
+      accounts: { config: PROTOCOL_FEES.config, admin },
+    });
+    const [vault, bump] = await anchor.web3.PublicKey.findProgramAddress(
+      [Buffer.from("flash_vault"), ORDERBOOK_ENV.usdc.toBuffer()],
+      program.programId
+    );
+    await program.rpc.createFlashVault(bump, {
+      accounts: {
+        config: PROTOCOL_FEES.config,
+        flashVault: vault,
+        mint: ORDERBOOK_ENV.usdc,
+        admin,
+        systemProgram: anchor.web3.SystemProgram.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+      },
+    });
+    const fund = new Transaction();
+    fund.add(
+      require("@solana/spl-token").Token.createTransferInstruction(
+        TOKEN_PROGRAM_ID,
+        ORDERBOOK_ENV.godUsdc,
+        vault,
+        admin,
+        [],
+        100 * 10 ** 6
+      )
+    );
+    await program.provider.send(fund);
+  });
+
+  it("Flash swaps with a callback repaying the input owed", async () => {
+    const vault = await flashVault(ORDERBOOK_ENV.usdc);
+    const protocolFeeVault = feeVault(ORDERBOOK_ENV.usdc);
+    let txSig, coinWallet;
+    const [tokenAChange, usdcChange, vaultChange, protocolFeeChange] =
+      await withBalanceChange(
+        program.provider,
+        [ORDERBOOK_ENV.godA, ORDERBOOK_ENV.godUsdc, vault, protocolFeeVault],
+        async () => {
+          // The callback pays the token A owed out of the provider's own.
+          [txSig, coinWallet] = await flashSwapUsdc((coinWallet) => [
+            { pubkey: ORDERBOOK_ENV.godA, isWritable: true, isSigner: false },
+            { pubkey: coinWallet, isWritable: true, isSigner: false },
+            {
+              pubkey: program.provider.wallet.publicKey,
+              isWritable: false,
+              isSigner: true,
+            },
+            { pubkey: TOKEN_PROGRAM_ID, isWritable: false, isSigner: false },
+          ]);
+        }
+      );
+
+    const flashSwapped = (await cpiEvents(program, txSig)).find(
+      (event) => event.name === "FlashSwapped"
+    );
+    assert.ok(
+      flashSwapped.data.callbackProgram.equals(flashCallback.programId)
+    );
+    assert.ok(flashSwapped.data.amount.toNumber() === 6 * 10 ** 6);
+    assert.ok(
+      tokenAChange === -flashSwapped.data.fromAmount.toNumber() / 10 ** 6
+    );
+    // The flash vault is repaid exactly, and the output lent isn't taken from
+    // the protocol fees, which only grow.
+    assert.ok(vaultChange === 0);
+    assert.ok(protocolFeeChange >= 0);
+    assert.ok(usdcChange >= 0);
+    // All of the token A owed was sold.
+    const coinWalletAccount = await serumCmn.getTokenAccount(
+      program.provider,
+      coinWallet
+    );
+    assert.ok(coinWalletAccount.amount.toNumber() === 0);
+  });
+
+  it("Aborts a flash swap whose callback doesn't repay the input owed", async () => {
+    await assert.rejects(
+      flashSwapUsdc(() => []),
+      (err) => {
+        assert.strictEqual(err.msg, "Flash swap didn't repay the amount lent");
+        return true;
+      }
+    );
+  });
+
+  it("Cranks an event queue for a tip via consume_events", async () => {
+    const marketA = ORDERBOOK_ENV.marketA;
+    const marketB = ORDERBOOK_ENV.marketB;
+    const [crankTip, bump] = await anchor.web3.PublicKey.findProgramAddress(
+      [Buffer.from("crank_tip")],
+      program.programId
+    );
+    const [tipVault, vaultBump] =
+      await anchor.web3.PublicKey.findProgramAddress(
+        [Buffer.from("crank_tip_vault")],
+        program.programId
+      );
+    // 1 per event, for up to 4 events per crank of at least 2, and up to 100
+    // per slot.
+    await program.rpc.createCrankTip(
+      bump,
+      vaultBump,
+      new BN(1),
+      4,
+      2,
+      new BN(100),
+      {
+        accounts: {
+          config: PROTOCOL_FEES.config,
+          crankTip,
+          tipVault,
+          mint: ORDERBOOK_ENV.usdc,
+          admin: program.provider.wallet.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: anchor.web3.SystemProgram.programId,
+          rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+        },
+      }
+    );
+    // Only cranks of market A are tipped.
+    await program.rpc.setTippedMarkets([marketA._decoded.ownAddress], {
+      accounts: {
+        config: PROTOCOL_FEES.config,
+        crankTip,
+        admin: program.provider.wallet.publicKey,
+      },
+    });
+    const fund = new Transaction();
+    fund.add(
+      require("@solana/spl-token").Token.createTransferInstruction(
+        TOKEN_PROGRAM_ID,
+        ORDERBOOK_ENV.godUsdc,
+        tipVault,
+        program.provider.wallet.publicKey,
+        [],
+        100
+      )
+    );
+    await program.provider.send(fund);
+
+    // Cranks the market's queue, given every open orders account with an
+    // event in it, sorted as the DEX expects.
+    const consumeEvents = async (market, limit) => {
+      const events = await market.loadEventQueue(program.provider.connection);
+      const openOrders = [
+        ...new Set(events.map((event) => event.openOrders.toString())),
+      ]
+        .map((address) => new anchor.web3.PublicKey(address))
+        .sort((a, b) => a.toBuffer().swap64().compare(b.toBuffer().swap64()));
+      const txSig = await program.rpc.consumeEvents(limit, {
+        accounts: {
+          crankTip,
+          market: market._decoded.ownAddress,
+          eventQueue: market._decoded.eventQueue,
+          tipVault,
+          destination: ORDERBOOK_ENV.godUsdc,
+          dexProgram: utils.DEX_PID,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          eventCpi: EVENT_CPI,
+        },
+        remainingAccounts: openOrders.map((pubkey) => ({
+          pubkey,
+          isWritable: true,
+          isSigner: false,
+        })),
+      });
+      const [consumed] = await cpiEvents(program, txSig);
+      assert.ok(consumed.name === "EventsConsumed");
+      return consumed.data;
+    };
+
+    // Anyone may list a market and trade with themselves on it, so the
+    // markets not set by the admin aren't tipped.
+    const untipped = await consumeEvents(marketB, 65535);
+    assert.ok(untipped.eventsConsumed.toNumber() >= 2);
+    assert.ok(untipped.tip.toNumber() === 0);
+
+    const events = await marketA.loadEventQueue(program.provider.connection);
+    assert.ok(events.length >= 3);
+
+    // Cranking one event at a time, e.g., of one's own trades, isn't tipped.
+    const single = await consumeEvents(marketA, 1);
+    assert.ok(single.eventsConsumed.toNumber() === 1);
+    assert.ok(single.tip.toNumber() === 0);
+
+    // A batch is tipped per event, up to the cap.
+    const batch = await consumeEvents(marketA, 65535);
+    assert.ok(batch.eventsConsumed.toNumber() === events.length - 1);
+    assert.ok(
+      batch.tip.toNumber() === Math.min(batch.eventsConsumed.toNumber(), 4)
+    );
+
+    // Nothing left to crank, so no tip.
+    const empty = await consumeEvents(marketA, 1);
+    assert.ok(empty.eventsConsumed.toNumber() === 0);
+    assert.ok(empty.tip.toNumber() === 0);
+  });
+
+  it("Rejects partial fills unless allowed", async () => {
+    // More than all the bids resting on the book.
+    await assert.rejects(
+      program.rpc.swapV2(
+        swapArgs({
+          side: Side.Ask,
+          amount: new BN(1000 * 10 ** 6),
+          minExchangeRate: {
+            rate: new BN(1.0),
+            fromDecimals: 6,
+            quoteDecimals: 6,
+            strict: false,
+          },
+          allowPartial: false,
+        }),
+        {
+          accounts: SWAP_A_USDC_ACCOUNTS,
+        }
+      ),
+      (err) => {
+        assert.strictEqual(err.msg, "Order was only partially filled");
+        return true;
+      }
+    );
+  });
+
+  it("Rejects swaps with too much price impact", async () => {
+    // Walks several bid levels below the best bid.
+    await assert.rejects(
+      program.rpc.swapV2(
+        swapArgs({
+          side: Side.Ask,
+          amount: new BN(100 * 10 ** 6),
+          minExchangeRate: {
+            rate: new BN(1.0),
+            fromDecimals: 6,
+            quoteDecimals: 6,
+            strict: false,
+          },
+          maxPriceImpactBps: 1,
+        }),
+        {
+          accounts: SWAP_A_USDC_ACCOUNTS,
+        }
+      ),
+      (err) => {
+        assert.strictEqual(
+          err.msg,
+          "Price impact of the swap exceeds the maximum"
+        );
+        return true;
+      }
+    );
+  });
+
+  it("Rejects degenerate exchange rates", async () => {
+    const swap = (minExchangeRate) =>
+      program.rpc.swap(
+        Side.Ask,
+        new BN(1 * 10 ** 6),
+        minExchangeRate,
+        {
+          accounts: SWAP_A_USDC_ACCOUNTS,
+        }
+      );
+
+    await assert.rejects(
+      swap({ rate: new BN(0), fromDecimals: 6, quoteDecimals: 6, strict: false }),
+      (err) => {
+        assert.strictEqual(err.msg, "Minimum exchange rate must be non-zero");
+        return true;
+      }
+    );
+    await assert.rejects(
+      swap({ rate: new BN(1), fromDecimals: 255, quoteDecimals: 6, strict: false }),
+      (err) => {
+        assert.strictEqual(err.msg, "Exchange rate decimals exceed the maximum");
+        return true;
+      }
+    );
+    await assert.rejects(
+      swap({ rate: new BN(1), fromDecimals: 6, quoteDecimals: 6, strict: true }),
+      (err) => {
+        assert.strictEqual(
+          err.msg,
+          "Strict exchange rates only apply to transitive swaps"
+        );
+        return true;
+      }
+    );
+  });
+
+  it("Rejects swap_v2 swaps past their deadline", async () => {
+    await assert.rejects(
+      program.rpc.swapV2(
+        {
+          side: Side.Ask,
+          amount: new BN(1 * 10 ** 6),
+          minExchangeRate: {
+            rate: new BN(1),
+            fromDecimals: 6,
+            quoteDecimals: 6,
+            strict: false,
+          },
+          maxQuoteAmount: null,
+          platformFeeBps: null,
+          oracleBand: null,
+          tag: null,
+          hookAccounts: null,
+          allowZeroOutput: null,
+          allowPartial: null,
+          maxPriceImpactBps: null,
+          limitPrice: null,
+          deadline: new BN(1),
+          maxEventQueueLen: null,
+          outputSplit: null,
+        },
+        {
+          accounts: SWAP_A_USDC_ACCOUNTS,
+        }
+      ),
+      (err) => {
+        assert.strictEqual(err.msg, "Swap deadline has passed");
+        return true;
+      }
+    );
+  });
+
+  it("Aborts swap_v2 swaps on markets with a congested event queue", async () => {
+    // The fills of the previous swaps haven't been consumed.
+    await assert.rejects(
+      program.rpc.swapV2(
+        {
+          side: Side.Ask,
+          amount: new BN(1 * 10 ** 6),
+          minExchangeRate: {
+            rate: new BN(1),
+            fromDecimals: 6,
+            quoteDecimals: 6,
+            strict: false,
+          },
+          maxQuoteAmount: null,
+          platformFeeBps: null,
+          oracleBand: null,
+          tag: null,
+          hookAccounts: null,
+          allowZeroOutput: null,
+          allowPartial: null,
+          maxPriceImpactBps: null,
+          limitPrice: null,
+          deadline: null,
+          maxEventQueueLen: new BN(0),
+          outputSplit: null,
+        },
+        {
+          accounts: SWAP_A_USDC_ACCOUNTS,
+        }
+      ),
+      (err) => {
+        assert.strictEqual(err.msg, "Market's event queue is full");
+        return true;
+      }
+    );
+  });
+
+  it("Caps the quote currency spent by swap_v2 bids", async () => {
+    const [tokenAChange, usdcChange] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godA, ORDERBOOK_ENV.godUsdc],
+      async () => {
+        await program.rpc.swapV2(
+          swapArgs({
+            side: Side.Bid,
+            amount: new BN(10 * 10 ** 6),
+            minExchangeRate: {
+              rate: new BN(1),
+              fromDecimals: 6,
+              quoteDecimals: 6,
+              strict: false,
+            },
+            maxQuoteAmount: new BN(5 * 10 ** 6),
+          }),
+          {
+            accounts: SWAP_USDC_A_ACCOUNTS,
+          }
+        );
+      }
+    );
+    assert.ok(tokenAChange > 0);
+    assert.ok(-usdcChange <= 5);
+  });
+
+  it("Swaps the entire balance of the order payer", async () => {
+    const Token = require("@solana/spl-token").Token;
+    const wallet = await new Token(
+      program.provider.connection,
+      ORDERBOOK_ENV.mintA,
+      TOKEN_PROGRAM_ID,
+      program.provider.wallet.payer
+    ).createAccount(program.provider.wallet.publicKey);
+    const fund = new Transaction();
+    fund.add(
+      Token.createTransferInstruction(
+        TOKEN_PROGRAM_ID,
+        ORDERBOOK_ENV.godA,
+        wallet,
+        program.provider.wallet.publicKey,
+        [],
+        2 * 10 ** 6
+      )
+    );
+    await program.provider.send(fund);
+
+    const [tokenAChange, usdcChange] = await withBalanceChange(
+      program.provider,
+      [wallet, ORDERBOOK_ENV.godUsdc],
+      async () => {
+        await program.rpc.swapV2(
+          swapArgs({
+            side: Side.Ask,
+            amount: ENTIRE_BALANCE,
+            minExchangeRate: {
+              rate: new BN(1),
+              fromDecimals: 6,
+              quoteDecimals: 6,
+              strict: false,
+            },
+          }),
+          {
+            accounts: {
+              ...SWAP_A_USDC_ACCOUNTS,
+              market: {
+                ...SWAP_A_USDC_ACCOUNTS.market,
+                orderPayerTokenAccount: wallet,
+                coinWallet: wallet,
+              },
+            },
+          }
+        );
+      }
+    );
+    assert.ok(tokenAChange === -2);
+    assert.ok(usdcChange > 0);
+  });
+
+  // Owner of the wallets the provider swaps out of as a delegate.
+  const delegator = new Account();
+  // Accounts used for USDC -> A swaps by the provider as a delegate of the
+  // delegator's USDC wallet, into the delegator's A wallet.
+  let SWAP_DELEGATED_ACCOUNTS;
+
+  it("BOILERPLATE: Delegates part of a funded USDC wallet to the provider", async () => {
+    const Token = require("@solana/spl-token").Token;
+    const delegate = program.provider.wallet.publicKey;
+    const createAccount = (mint) =>
+      new Token(
+        program.provider.connection,
+        mint,
+        TOKEN_PROGRAM_ID,
+        program.provider.wallet.payer
+      ).createAccount(delegator.publicKey);
+    const usdcWallet = await createAccount(ORDERBOOK_ENV.usdc);
+    const coinWallet = await createAccount(ORDERBOOK_ENV.mintA);
+
+    // 20 USDC, of which 8 are delegated.
+    const tx = new Transaction();
+    tx.add(
+      Token.createTransferInstruction(
+        TOKEN_PROGRAM_ID,
+        ORDERBOOK_ENV.godUsdc,
+        usdcWallet,
+        delegate,
+        [],
+        20 * 10 ** 6
+      ),
+      Token.createApproveInstruction(
+        TOKEN_PROGRAM_ID,
+        usdcWallet,
+        delegate,
+        delegator.publicKey,
+        [],
+        8 * 10 ** 6
+      )
+    );
+    await program.provider.send(tx, [delegator]);
+
+    SWAP_DELEGATED_ACCOUNTS = {
+      ...SWAP_USDC_A_ACCOUNTS,
+      market: {
+        ...SWAP_USDC_A_ACCOUNTS.market,
+        orderPayerTokenAccount: usdcWallet,
+        coinWallet,
+      },
+      pcWallet: usdcWallet,
+      spendingPolicy: await utils.getSpendingPolicy(
+        program.programId,
+        delegator.publicKey,
+        delegate
+      ),
+    };
+  });
+
+  // Swaps `amount` USDC for A via swap_v2.
+  const swapUsdcForA = (amount, accounts) =>
+    program.rpc.swapV2(
+      swapArgs({
+        side: Side.Bid,
+        amount,
+        minExchangeRate: {
+          rate: new BN(1),
+          fromDecimals: 6,
+          quoteDecimals: 6,
+          strict: false,
+        },
+      }),
+      { accounts }
+    );
+
+  it("Rejects delegated swaps into wallets of another owner", async () => {
+    await assert.rejects(
+      swapUsdcForA(new BN(1), {
+        ...SWAP_DELEGATED_ACCOUNTS,
+        market: {
+          ...SWAP_DELEGATED_ACCOUNTS.market,
+          // Owned by the delegate rather than the delegator.
+          coinWallet: ORDERBOOK_ENV.godA,
+        },
+      }),
+      (err) => {
+        assert.strictEqual(
+          err.msg,
+          "Wallets used by a delegate must be owned by the order payer's owner"
+        );
+        return true;
+      }
+    );
+  });
+
+  it("Rejects swaps out of wallets not delegated to the authority", async () => {
+    const marketMaker = ORDERBOOK_ENV.marketMaker;
+    const usdcWallet = marketMaker.tokens[ORDERBOOK_ENV.usdc.toString()];
+    await assert.rejects(
+      swapUsdcForA(new BN(1 * 10 ** 6), {
+        ...SWAP_USDC_A_ACCOUNTS,
+        market: {
+          ...SWAP_USDC_A_ACCOUNTS.market,
+          orderPayerTokenAccount: usdcWallet,
+          coinWallet: marketMaker.tokens[ORDERBOOK_ENV.mintA.toString()],
+        },
+        pcWallet: usdcWallet,
+        spendingPolicy: await utils.getSpendingPolicy(
+          program.programId,
+          marketMaker.account.publicKey,
+          program.provider.wallet.publicKey
+        ),
+      }),
+      (err) => {
+        assert.strictEqual(
+          err.msg,
+          "Authority is neither the owner nor the delegate of the order payer"
+        );
+        return true;
+      }
+    );
+  });
+
+  it("Swaps out of a wallet delegated to the authority", async () => {
+    const { orderPayerTokenAccount, coinWallet } =
+      SWAP_DELEGATED_ACCOUNTS.market;
+    const [tokenAChange, usdcChange] = await withBalanceChange(
+      program.provider,
+      [coinWallet, orderPayerTokenAccount],
+      async () => {
+        await swapUsdcForA(new BN(5 * 10 ** 6), SWAP_DELEGATED_ACCOUNTS);
+      }
+    );
+    assert.ok(tokenAChange > 0);
+    assert.ok(-usdcChange <= 5);
+
+    // The entire balance is capped by what's left of the delegated amount.
+    const delegated = (
+      await serumCmn.getTokenAccount(program.provider, orderPayerTokenAccount)
+    ).delegatedAmount;
+    const [, entireBalanceChange] = await withBalanceChange(
+      program.provider,
+      [coinWallet, orderPayerTokenAccount],
+      async () => {
+        await swapUsdcForA(ENTIRE_BALANCE, SWAP_DELEGATED_ACCOUNTS);
+      }
+    );
+    assert.ok(-entireBalanceChange <= delegated.toNumber() / 10 ** 6);
+    assert.ok(entireBalanceChange < 0);
+  });
+
+  it("Rejects swaps exceeding the amount delegated to the authority", async () => {
+    await assert.rejects(
+      swapUsdcForA(new BN(10 * 10 ** 6), SWAP_DELEGATED_ACCOUNTS),
+      (err) => {
+        assert.strictEqual(
+          err.msg,
+          "Order exceeds the amount delegated to the authority"
+        );
+        return true;
+      }
+    );
+  });
+
+  const mockOracle = anchor.workspace.MockOracle;
+  // Mock Pyth price account of A in USDC.
+  const priceAccount = new Account();
+
+  // Sets the oracle price to `price` USDC per A, as published `age` slots
+  // ago.
+  const setOraclePrice = (price, age = 0) =>
+    mockOracle.rpc.setPrice(new BN(price * 10 ** 8), -8, new BN(age), {
+      accounts: { priceAccount: priceAccount.publicKey },
+    });
+
+  // Sells 1 A for USDC within `maxDeviationBps` of the oracle price.
+  const swapWithinOracleBand = (maxDeviationBps) =>
+    program.rpc.swapV2(
+      swapArgs({
+        side: Side.Ask,
+        amount: new BN(1 * 10 ** 6),
+        minExchangeRate: {
+          rate: new BN(1),
+          fromDecimals: 6,
+          quoteDecimals: 6,
+          strict: false,
+        },
+        oracleBand: { maxDeviationBps, baseDecimals: 6, quoteDecimals: 6 },
+      }),
+      {
+        accounts: SWAP_A_USDC_ACCOUNTS,
+        remainingAccounts: [
+          {
+            pubkey: priceAccount.publicKey,
+            isWritable: false,
+            isSigner: false,
+          },
+        ],
+      }
+    );
+
+  it("BOILERPLATE: Sets up a mock oracle price account", async () => {
+    // Size of a Pyth v2 price account.
+    const space = 3312;
+    const tx = new Transaction();
+    tx.add(
+      anchor.web3.SystemProgram.createAccount({
+        fromPubkey: program.provider.wallet.publicKey,
+        newAccountPubkey: priceAccount.publicKey,
+        space,
+        lamports:
+          await program.provider.connection.getMinimumBalanceForRentExemption(
+            space
+          ),
+        programId: mockOracle.programId,
+      })
+    );
+    await program.provider.send(tx, [priceAccount]);
+  });
+
+  it("Swaps within the band around the oracle price", async () => {
+    // ~6 USDC per A resting on the book.
+    await setOraclePrice(6);
+    const [usdcChange] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godUsdc],
+      async () => {
+        await swapWithinOracleBand(200);
+      }
+    );
+    assert.ok(usdcChange > 0);
+  });
+
+  it("Aborts swaps deviating from the oracle price beyond the band", async () => {
+    await setOraclePrice(7);
+    await assert.rejects(swapWithinOracleBand(200), (err) => {
+      assert.strictEqual(
+        err.msg,
+        "Execution price deviates from the oracle price beyond the band"
+      );
+      return true;
+    });
+  });
+
+  it("Aborts swaps bounded by a stale oracle price", async () => {
+    await setOraclePrice(6, 100);
+    await assert.rejects(swapWithinOracleBand(200), (err) => {
+      assert.strictEqual(err.msg, "Oracle price is older than the maximum age");
+      return true;
+    });
+  });
+
+  it("Logs the amounts of swaps exceeding the slippage tolerance", async () => {
+    // Well above the ~6 USDC per A resting on the book.
+    const { events } = await program.simulate.swap(
+      Side.Ask,
+      new BN(1 * 10 ** 6),
+      { rate: new BN(100 * 10 ** 6), fromDecimals: 6, quoteDecimals: 6, strict: false },
+      {
+        accounts: SWAP_A_USDC_ACCOUNTS,
+      }
+    );
+    const failure = events.find((event) => event.name === "SwapFailed").data;
+    assert.ok(failure.fromAmount.eq(new BN(1 * 10 ** 6)));
+    assert.ok(failure.toAmount.gt(new BN(0)));
+    assert.ok(failure.effectiveToAmount.lt(failure.minExpectedAmount));
+  });
+
+  it("Stops chunked swaps at the worst price", async () => {
+    // Above every bid resting on the book, so no chunk fills.
+    const worstPrice = new BN(6.1 * 10 ** 9);
+    await assert.rejects(
+      program.rpc.swapChunked(
+        Side.Ask,
+        new BN(3 * 10 ** 6),
+        { rate: new BN(1.0), fromDecimals: 6, quoteDecimals: 6, strict: false },
+        { count: 3, worstPrice },
+        0,
+        null,
+        {
+          accounts: SWAP_A_USDC_ACCOUNTS,
+        }
+      ),
+      (err) => {
+        assert.strictEqual(err.msg, "No tokens received when swapping");
+        return true;
+      }
+    );
+  });
+
+  it("Rejects deposits into programs not whitelisted by the admin", async () => {
+    await assert.rejects(
+      program.rpc.swapAndDeposit(
+        Side.Ask,
+        new BN(1 * 10 ** 6),
+        { rate: new BN(1.0), fromDecimals: 6, quoteDecimals: 6, strict: false },
+        { data: Buffer.alloc(8), amountOffset: 0, numAccounts: 0 },
+        {
+          accounts: SWAP_A_USDC_ACCOUNTS,
+          remainingAccounts: [
+            { pubkey: TOKEN_PROGRAM_ID, isWritable: false, isSigner: false },
+          ],
+        }
+      ),
+      (err) => {
+        assert.strictEqual(err.msg, "Hook program is not whitelisted");
+        return true;
+      }
+    );
+  });
+
+  it("Swaps into an escrow locked until the unlock time", async () => {
+    const authority = program.provider.wallet.publicKey;
+    const nonce = new BN(0);
+    const [escrow, bump] = await anchor.web3.PublicKey.findProgramAddress(
+      [
+        Buffer.from("escrow"),
+        authority.toBuffer(),
+        nonce.toArrayLike(Buffer, "le", 8),
+      ],
+      program.programId
+    );
+    const [vault, vaultBump] = await anchor.web3.PublicKey.findProgramAddress(
+      [Buffer.from("escrow_vault"), escrow.toBuffer()],
+      program.programId
+    );
+    const unlockTs = new BN(Math.floor(Date.now() / 1000) + 60 * 60);
+    await program.rpc.swapToEscrow(
+      bump,
+      vaultBump,
+      nonce,
+      {
+        side: Side.Ask,
+        amount: new BN(1 * 10 ** 6),
+        minExchangeRate: {
+          rate: new BN(1.0),
+          fromDecimals: 6,
+          quoteDecimals: 6,
+          strict: false,
+        },
+        beneficiary: authority,
+        unlockTs,
+      },
+      {
+        accounts: {
+          swap: SWAP_A_USDC_ACCOUNTS,
+          escrow,
+          escrowVault: vault,
+          mint: ORDERBOOK_ENV.usdc,
+          payer: authority,
+          systemProgram: anchor.web3.SystemProgram.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+        },
+      }
+    );
+
+    const escrowAccount = await program.account.escrow.fetch(escrow);
+    const vaultAccount = await serumCmn.getTokenAccount(program.provider, vault);
+    assert.ok(escrowAccount.beneficiary.equals(authority));
+    assert.ok(escrowAccount.unlockTs.eq(unlockTs));
+    assert.ok(vaultAccount.amount.eq(escrowAccount.amount));
+    assert.ok(vaultAccount.amount.gt(new BN(0)));
+
+    await assert.rejects(
+      program.rpc.withdrawEscrow({
+        accounts: {
+          escrow,
+          beneficiary: authority,
+          vault,
+          destination: ORDERBOOK_ENV.godUsdc,
+          payer: authority,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        },
+      }),
+      (err) => {
+        assert.strictEqual(err.msg, "Escrow is still locked");
+        return true;
+      }
+    );
+  });
+
+  it("Executes a recurring swap once per interval", async () => {
+    const owner = program.provider.wallet.publicKey;
+    const nonce = new BN(0);
+    const [dca, bump] = await anchor.web3.PublicKey.findProgramAddress(
+      [Buffer.from("dca"), owner.toBuffer(), nonce.toArrayLike(Buffer, "le", 8)],
+      program.programId
+    );
+    const [inputVault, inputVaultBump] =
+      await anchor.web3.PublicKey.findProgramAddress(
+        [
+          Buffer.from("dca_vault"),
+          dca.toBuffer(),
+          ORDERBOOK_ENV.mintA.toBuffer(),
+        ],
+        program.programId
+      );
+    const [outputVault, outputVaultBump] =
+      await anchor.web3.PublicKey.findProgramAddress(
+        [Buffer.from("dca_vault"), dca.toBuffer(), ORDERBOOK_ENV.usdc.toBuffer()],
+        program.programId
+      );
+    const openOrders = new Account();
+    await program.rpc.createDca(
+      bump,
+      inputVaultBump,
+      outputVaultBump,
+      nonce,
+      {
+        side: Side.Ask,
+        amountPerInterval: new BN(1 * 10 ** 6),
+        interval: new BN(60 * 60),
+        minExchangeRate: {
+          rate: new BN(1.0),
+          fromDecimals: 6,
+          quoteDecimals: 6,
+          strict: false,
+        },
+        crankFee: new BN(1000),
+        depositAmount: new BN(3 * 10 ** 6),
+      },
+      {
+        accounts: {
+          dca,
+          inputVault,
+          outputVault,
+          inputMint: ORDERBOOK_ENV.mintA,
+          outputMint: ORDERBOOK_ENV.usdc,
+          ownerWallet: ORDERBOOK_ENV.godA,
+          destination: ORDERBOOK_ENV.godUsdc,
+          openOrders: openOrders.publicKey,
+          market: ORDERBOOK_ENV.marketA._decoded.ownAddress,
+          dexProgram: utils.DEX_PID,
+          owner,
+          systemProgram: anchor.web3.SystemProgram.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+          eventCpi: EVENT_CPI,
+        },
+        signers: [openOrders],
+      }
+    );
+
+    // The schedule owns its vaults.
+    const spendingPolicy = await utils.getSpendingPolicy(
+      program.programId,
+      dca,
+      dca
+    );
+    const executeDca = () =>
+      program.rpc.executeDca({
+        accounts: {
+          dca,
+          inputVault,
+          outputVault,
+          destination: ORDERBOOK_ENV.godUsdc,
+          crankerWallet: ORDERBOOK_ENV.godA,
+          swap: {
+            ...SWAP_A_USDC_ACCOUNTS,
+            market: {
+              ...SWAP_A_USDC_ACCOUNTS.market,
+              openOrders: openOrders.publicKey,
+              orderPayerTokenAccount: inputVault,
+              coinWallet: inputVault,
+            },
+            pcWallet: outputVault,
+            spendingPolicy,
+          },
+          tokenProgram: TOKEN_PROGRAM_ID,
+        },
+      });
+    const [usdcChange] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godUsdc],
+      executeDca
+    );
+    assert.ok(usdcChange > 0);
+    const dcaAccount = await program.account.dca.fetch(dca);
+    assert.ok(dcaAccount.executions.eq(new BN(1)));
+
+    await assert.rejects(executeDca(), (err) => {
+      assert.strictEqual(err.msg, "Schedule is not due yet");
+      return true;
+    });
+  });
+
+  it("Executes a TWAP order one slice at a time", async () => {
+    const owner = program.provider.wallet.publicKey;
+    const nonce = new BN(0);
+    const [twap, bump] = await anchor.web3.PublicKey.findProgramAddress(
+      [Buffer.from("twap"), owner.toBuffer(), nonce.toArrayLike(Buffer, "le", 8)],
+      program.programId
+    );
+    const [inputVault, inputVaultBump] =
+      await anchor.web3.PublicKey.findProgramAddress(
+        [
+          Buffer.from("twap_vault"),
+          twap.toBuffer(),
+          ORDERBOOK_ENV.mintA.toBuffer(),
+        ],
+        program.programId
+      );
+    const [outputVault, outputVaultBump] =
+      await anchor.web3.PublicKey.findProgramAddress(
+        [Buffer.from("twap_vault"), twap.toBuffer(), ORDERBOOK_ENV.usdc.toBuffer()],
+        program.programId
+      );
+    const openOrders = new Account();
+    const startTs = new BN(Math.floor(Date.now() / 1000) - 60);
+    await program.rpc.createTwapOrder(
+      bump,
+      inputVaultBump,
+      outputVaultBump,
+      nonce,
+      {
+        side: Side.Ask,
+        amount: new BN(2 * 10 ** 6),
+        numSlices: 2,
+        startTs,
+        endTs: startTs.add(new BN(2 * 60 * 60)),
+        minExchangeRate: {
+          rate: new BN(1.0),
+          fromDecimals: 6,
+          quoteDecimals: 6,
+          strict: false,
+        },
+      },
+      {
+        accounts: {
+          twap,
+          inputVault,
+          outputVault,
+          inputMint: ORDERBOOK_ENV.mintA,
+          outputMint: ORDERBOOK_ENV.usdc,
+          ownerWallet: ORDERBOOK_ENV.godA,
+          destination: ORDERBOOK_ENV.godUsdc,
+          openOrders: openOrders.publicKey,
+          market: ORDERBOOK_ENV.marketA._decoded.ownAddress,
+          dexProgram: utils.DEX_PID,
+          owner,
+          systemProgram: anchor.web3.SystemProgram.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+          eventCpi: EVENT_CPI,
+        },
+        signers: [openOrders],
+      }
+    );
+
+    // The schedule owns its vaults.
+    const spendingPolicy = await utils.getSpendingPolicy(
+      program.programId,
+      twap,
+      twap
+    );
+    const executeSlice = () =>
+      program.rpc.executeTwapSlice({
+        accounts: {
+          twap,
+          inputVault,
+          outputVault,
+          destination: ORDERBOOK_ENV.godUsdc,
+          swap: {
+            ...SWAP_A_USDC_ACCOUNTS,
+            market: {
+              ...SWAP_A_USDC_ACCOUNTS.market,
+              openOrders: openOrders.publicKey,
+              orderPayerTokenAccount: inputVault,
+              coinWallet: inputVault,
+            },
+            pcWallet: outputVault,
+            spendingPolicy,
+          },
+          tokenProgram: TOKEN_PROGRAM_ID,
+        },
+      });
+    const [usdcChange] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godUsdc],
+      executeSlice
+    );
+    assert.ok(usdcChange > 0);
+    await assert.rejects(executeSlice(), (err) => {
+      assert.strictEqual(err.msg, "Schedule is not due yet");
+      return true;
+    });
+
+    // The second slice is returned.
+    const [tokenAChange] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godA],
+      () =>
+        program.rpc.cancelTwapOrder({
+          accounts: {
+            twap,
+            inputVault,
+            outputVault,
+            ownerWallet: ORDERBOOK_ENV.godA,
+            destination: ORDERBOOK_ENV.godUsdc,
+            openOrders: openOrders.publicKey,
+            market: ORDERBOOK_ENV.marketA._decoded.ownAddress,
+            dexProgram: utils.DEX_PID,
+            owner,
+            tokenProgram: TOKEN_PROGRAM_ID,
+          },
+        })
+    );
+    assert.ok(tokenAChange === 1);
+  });
+
+  it("Rejects TWAP orders ending before they start", async () => {
+    const owner = program.provider.wallet.publicKey;
+    const nonce = new BN(1);
+    const [twap, bump] = await anchor.web3.PublicKey.findProgramAddress(
+      [Buffer.from("twap"), owner.toBuffer(), nonce.toArrayLike(Buffer, "le", 8)],
+      program.programId
+    );
+    const [inputVault, inputVaultBump] =
+      await anchor.web3.PublicKey.findProgramAddress(
+        [
+          Buffer.from("twap_vault"),
+          twap.toBuffer(),
+          ORDERBOOK_ENV.mintA.toBuffer(),
+        ],
+        program.programId
+      );
+    const [outputVault, outputVaultBump] =
+      await anchor.web3.PublicKey.findProgramAddress(
+        [Buffer.from("twap_vault"), twap.toBuffer(), ORDERBOOK_ENV.usdc.toBuffer()],
+        program.programId
+      );
+    const openOrders = new Account();
+    const startTs = new BN(Math.floor(Date.now() / 1000));
+    await assert.rejects(
+      program.rpc.createTwapOrder(
+        bump,
+        inputVaultBump,
+        outputVaultBump,
+        nonce,
+        {
+          side: Side.Ask,
+          amount: new BN(2 * 10 ** 6),
+          numSlices: 2,
+          startTs,
+          endTs: startTs.sub(new BN(60)),
+          minExchangeRate: {
+            rate: new BN(1.0),
+            fromDecimals: 6,
+            quoteDecimals: 6,
+            strict: false,
+          },
+        },
+        {
+          accounts: {
+            twap,
+            inputVault,
+            outputVault,
+            inputMint: ORDERBOOK_ENV.mintA,
+            outputMint: ORDERBOOK_ENV.usdc,
+            ownerWallet: ORDERBOOK_ENV.godA,
+            destination: ORDERBOOK_ENV.godUsdc,
+            openOrders: openOrders.publicKey,
+            market: ORDERBOOK_ENV.marketA._decoded.ownAddress,
+            dexProgram: utils.DEX_PID,
+            owner,
+            systemProgram: anchor.web3.SystemProgram.programId,
+            tokenProgram: TOKEN_PROGRAM_ID,
+            rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+            eventCpi: EVENT_CPI,
+          },
+          signers: [openOrders],
+        }
+      ),
+      (err) => {
+        assert.strictEqual(
+          err.msg,
+          "Schedule amounts and intervals must be non-zero"
+        );
+        return true;
+      }
+    );
+  });
+
+  it("Rejects quote-to-quote swaps between markets of different bases", async () => {
+    const marketB = ORDERBOOK_ENV.marketB;
+    await assert.rejects(
+      program.rpc.swapQuoteToQuote(
+        new BN(1 * 10 ** 6),
+        { rate: new BN(1.0), fromDecimals: 6, quoteDecimals: 6, strict: false },
+        0,
+        null,
+        {
+          accounts: {
+            from: SWAP_USDC_A_ACCOUNTS.market,
+            to: {
+              market: marketB._decoded.ownAddress,
+              requestQueue: marketB._decoded.requestQueue,
+              eventQueue: marketB._decoded.eventQueue,
+              bids: marketB._decoded.bids,
+              asks: marketB._decoded.asks,
+              coinVault: marketB._decoded.baseVault,
+              pcVault: marketB._decoded.quoteVault,
+              vaultSigner: marketBVaultSigner,
+              // User params.
+              openOrders: openOrdersB.publicKey,
+              orderPayerTokenAccount: ORDERBOOK_ENV.godB,
+              coinWallet: ORDERBOOK_ENV.godB,
+              circuitBreaker: marketBCircuitBreaker,
+              marketStats: program.programId,
+            },
+            authority: program.provider.wallet.publicKey,
+            fromPcWallet: ORDERBOOK_ENV.godUsdc,
+            toPcWallet: ORDERBOOK_ENV.godUsdc,
+            config: PROTOCOL_FEES.config,
+            whitelist: PROTOCOL_FEES.whitelist,
+            userStats: program.programId,
+            spendingPolicy: SPENDING_POLICY,
+            feeVault: feeVault(ORDERBOOK_ENV.usdc),
+            platformFeeAccount: ORDERBOOK_ENV.godUsdc,
+            dexProgram: utils.DEX_PID,
+            tokenProgram: TOKEN_PROGRAM_ID,
+            rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+            eventCpi: EVENT_CPI,
+          },
+        }
+      ),
+      (err) => {
+        assert.strictEqual(
+          err.msg,
+          "Both markets must share the base currency wallet"
+        );
+        return true;
+      }
+    );
+  });
+
+  it("Rejects arbitrage buying and selling on the same market", async () => {
+    await assert.rejects(
+      program.rpc.arbitrage(new BN(1 * 10 ** 6), new BN(0), {
+        accounts: {
+          buy: SWAP_USDC_A_ACCOUNTS.market,
+          sell: SWAP_A_USDC_ACCOUNTS.market,
+          authority: program.provider.wallet.publicKey,
+          pcWallet: ORDERBOOK_ENV.godUsdc,
+          config: PROTOCOL_FEES.config,
+          whitelist: PROTOCOL_FEES.whitelist,
+          userStats: program.programId,
+          spendingPolicy: SPENDING_POLICY,
+          feeVault: feeVault(ORDERBOOK_ENV.usdc),
+          dexProgram: utils.DEX_PID,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+          eventCpi: EVENT_CPI,
+        },
+      }),
+      (err) => {
+        assert.strictEqual(
+          err.msg,
+          "Arbitrage must buy and sell on different markets"
+        );
+        return true;
+      }
+    );
+  });
+
+  // Second A/USDC market, bidding above the first market's offers, its DEX
+  // vault PDA and swap program circuit breaker PDA.
+  let MARKET_A2, marketA2VaultSigner, marketA2CircuitBreaker;
+  // Accounts used to arbitrage market A/USDC against the second one.
+  let ARBITRAGE_ACCOUNTS;
+  const openOrdersA2 = new anchor.web3.Account();
+
+  it("BOILERPLATE: Sets up a second A/USDC market to arbitrage", async () => {
+    const marketMaker = ORDERBOOK_ENV.marketMaker;
+    MARKET_A2 = await utils.setupMarket({
+      baseMint: ORDERBOOK_ENV.mintA,
+      quoteMint: ORDERBOOK_ENV.usdc,
+      marketMaker: {
+        account: marketMaker.account,
+        baseToken: marketMaker.tokens[ORDERBOOK_ENV.mintA.toString()],
+        quoteToken: marketMaker.tokens[ORDERBOOK_ENV.usdc.toString()],
+      },
+      bids: [[6.5, 50.0]],
+      asks: [[7.0, 50.0]],
+      provider: program.provider,
+    });
+    const marketA2 = MARKET_A2;
+    [marketA2VaultSigner] = await utils.getVaultOwnerAndNonce(
+      marketA2._decoded.ownAddress
+    );
+    marketA2CircuitBreaker = await utils.getCircuitBreaker(
+      program.programId,
+      marketA2._decoded.ownAddress
+    );
+    ARBITRAGE_ACCOUNTS = {
+      buy: SWAP_USDC_A_ACCOUNTS.market,
+      sell: {
+        market: marketA2._decoded.ownAddress,
+        requestQueue: marketA2._decoded.requestQueue,
+        eventQueue: marketA2._decoded.eventQueue,
+        bids: marketA2._decoded.bids,
+        asks: marketA2._decoded.asks,
+        coinVault: marketA2._decoded.baseVault,
+        pcVault: marketA2._decoded.quoteVault,
+        vaultSigner: marketA2VaultSigner,
+        // User params.
+        openOrders: openOrdersA2.publicKey,
+        orderPayerTokenAccount: ORDERBOOK_ENV.godA,
+        coinWallet: ORDERBOOK_ENV.godA,
+        circuitBreaker: marketA2CircuitBreaker,
+        marketStats: program.programId,
+      },
+      authority: program.provider.wallet.publicKey,
+      pcWallet: ORDERBOOK_ENV.godUsdc,
+      config: PROTOCOL_FEES.config,
+      whitelist: PROTOCOL_FEES.whitelist,
+      userStats: program.programId,
+      spendingPolicy: SPENDING_POLICY,
+      feeVault: feeVault(ORDERBOOK_ENV.usdc),
+      dexProgram: utils.DEX_PID,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+      eventCpi: EVENT_CPI,
+    };
+    await program.provider.send(
+      new Transaction().add(
+        await OpenOrders.makeCreateAccountTransaction(
+          program.provider.connection,
+          marketA2._decoded.ownAddress,
+          program.provider.wallet.publicKey,
+          openOrdersA2.publicKey,
+          utils.DEX_PID
+        )
+      ),
+      [openOrdersA2]
+    );
+  });
+
+  it("Arbitrages a price gap between two markets", async () => {
+    // Buys about 1 A at most at 6.167 USDC and sells it at 6.5 USDC.
+    const amount = new BN(6.5 * 10 ** 6);
+    let txSig;
+    const [tokenAChange, usdcChange] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godA, ORDERBOOK_ENV.godUsdc],
+      async () => {
+        txSig = await program.rpc.arbitrage(amount, new BN(0), {
+          accounts: ARBITRAGE_ACCOUNTS,
+        });
+      }
+    );
+
+    const arbitrage = (await cpiEvents(program, txSig)).find(
+      (event) => event.name === "DidArbitrage"
+    );
+    assert.ok(arbitrage.data.profit.toNumber() > 0);
+    assert.ok(
+      arbitrage.data.profit.toNumber() ===
+        arbitrage.data.toAmount.toNumber() -
+          arbitrage.data.fromAmount.toNumber()
+    );
+    assert.ok(usdcChange === arbitrage.data.profit.toNumber() / 10 ** 6);
+    // Nothing is left over, since both markets share the lot size.
+    assert.ok(arbitrage.data.spillAmount.toNumber() === 0);
+    assert.ok(tokenAChange === 0);
+  });
+
+  it("Aborts an arbitrage not gaining more than the minimum profit", async () => {
+    await assert.rejects(
+      program.rpc.arbitrage(
+        new BN(6.5 * 10 ** 6),
+        // More than the price gap can yield.
+        new BN(1 * 10 ** 6),
+        { accounts: ARBITRAGE_ACCOUNTS }
+      ),
+      (err) => {
+        assert.strictEqual(
+          err.msg,
+          "Arbitrage profit doesn't exceed the minimum"
+        );
+        return true;
+      }
+    );
+  });
+
+  it("Rejects arbitrage with a wallet not holding the markets' mints", async () => {
+    await assert.rejects(
+      program.rpc.arbitrage(new BN(6.5 * 10 ** 6), new BN(0), {
+        // Token B rather than USDC.
+        accounts: { ...ARBITRAGE_ACCOUNTS, pcWallet: ORDERBOOK_ENV.godB },
+      }),
+      (err) => {
+        assert.strictEqual(
+          err.msg,
+          "Wallet doesn't hold the market's base or quote mint"
+        );
+        return true;
+      }
+    );
+  });
+
+  // Swaps on both A/USDC markets via swap_split, paying with the given order
+  // payer. Amounts are native.
+  const swapSplit = (side, amount, weights, minOutput, orderPayerTokenAccount) =>
+    program.rpc.swapSplit(
+      side,
+      new BN(amount),
+      weights.map((weight) => new BN(weight)),
+      new BN(minOutput),
+      null,
+      {
+        accounts: {
+          authority: program.provider.wallet.publicKey,
+          pcWallet: ORDERBOOK_ENV.godUsdc,
+          config: PROTOCOL_FEES.config,
+          whitelist: PROTOCOL_FEES.whitelist,
+          userStats: program.programId,
+          spendingPolicy: SPENDING_POLICY,
+          feeVault: feeVault(
+            side === Side.Bid ? ORDERBOOK_ENV.mintA : ORDERBOOK_ENV.usdc
+          ),
+          dexProgram: utils.DEX_PID,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+          eventCpi: EVENT_CPI,
+        },
+        remainingAccounts: [
+          ...marketAccountMetas({
+            market: ORDERBOOK_ENV.marketA,
+            vaultSigner: marketAVaultSigner,
+            openOrders: openOrdersA.publicKey,
+            orderPayerTokenAccount,
+            coinWallet: ORDERBOOK_ENV.godA,
+            circuitBreaker: marketACircuitBreaker,
+            marketStats: program.programId,
+          }),
+          ...marketAccountMetas({
+            market: MARKET_A2,
+            vaultSigner: marketA2VaultSigner,
+            openOrders: openOrdersA2.publicKey,
+            orderPayerTokenAccount,
+            coinWallet: ORDERBOOK_ENV.godA,
+            circuitBreaker: marketA2CircuitBreaker,
+            marketStats: program.programId,
+          }),
+        ],
+      }
+    );
+
+  it("Splits a swap from USDC to Token A across two markets", async () => {
+    const [tokenAChange, usdcChange] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godA, ORDERBOOK_ENV.godUsdc],
+      async () => {
+        await swapSplit(
+          Side.Bid,
+          20 * 10 ** 6,
+          [1, 1],
+          1 * 10 ** 6,
+          ORDERBOOK_ENV.godUsdc
+        );
+      }
+    );
+
+    // About 1.6 A on the first market and 1.4 A on the second.
+    assert.ok(tokenAChange >= 1);
+    assert.ok(usdcChange < 0);
+    assert.ok(-usdcChange <= 20);
+  });
+
+  it("Splits a swap from Token A to USDC across two markets", async () => {
+    const [tokenAChange, usdcChange] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godA, ORDERBOOK_ENV.godUsdc],
+      async () => {
+        // The minimum is above either market's output, but not their total.
+        await swapSplit(
+          Side.Ask,
+          2 * 10 ** 6,
+          [1, 1],
+          10 * 10 ** 6,
+          ORDERBOOK_ENV.godA
+        );
+      }
+    );
+
+    assert.ok(tokenAChange === -2);
+    assert.ok(usdcChange >= 10);
+  });
+
+  it("Skips a split sale's share smaller than a lot", async () => {
+    // The first market's share is under its 0.1 A lot size, the second's is
+    // rounded down to 1.9 A.
+    const [tokenAChange, usdcChange] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godA, ORDERBOOK_ENV.godUsdc],
+      async () => {
+        await swapSplit(
+          Side.Ask,
+          2 * 10 ** 6,
+          [1, 1000],
+          1 * 10 ** 6,
+          ORDERBOOK_ENV.godA
+        );
+      }
+    );
+
+    assert.ok(tokenAChange === -1.9);
+    assert.ok(usdcChange > 1);
+  });
+
+  it("Aborts a split swap receiving less than the minimum output in total", async () => {
+    await assert.rejects(
+      // Selling 2 A yields about 12.5 USDC in total.
+      swapSplit(
+        Side.Ask,
+        2 * 10 ** 6,
+        [1, 1],
+        20 * 10 ** 6,
+        ORDERBOOK_ENV.godA
+      ),
+      (err) => {
+        assert.strictEqual(err.msg, "Slippage tolerance exceeded");
+        return true;
+      }
+    );
+  });
+
+  it("Rejects split swap weights leaving a market no share", async () => {
+    await assert.rejects(
+      // 5 native units split 1 to 1,000 leave the first market nothing.
+      swapSplit(Side.Bid, 5, [1, 1000], 0, ORDERBOOK_ENV.godUsdc),
+      (err) => {
+        assert.strictEqual(
+          err.msg,
+          "Weights must be non-zero, match the number of markets and give each a share"
+        );
+        return true;
+      }
+    );
+  });
+
+  it("Restricts swaps to whitelisted markets", async () => {
+    const admin = program.provider.wallet.publicKey;
+    const [, bump] = await anchor.web3.PublicKey.findProgramAddress(
+      [Buffer.from("whitelist")],
+      program.programId
+    );
+    await program.rpc.createWhitelist(bump, {
+      accounts: {
+        config: PROTOCOL_FEES.config,
+        whitelist: PROTOCOL_FEES.whitelist,
+        admin,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      },
+    });
+    await program.rpc.addMarket(ORDERBOOK_ENV.marketB._decoded.ownAddress, {
+      accounts: {
+        config: PROTOCOL_FEES.config,
+        whitelist: PROTOCOL_FEES.whitelist,
+        admin,
+      },
+    });
+
+    // Market A isn't whitelisted.
+    await assert.rejects(
+      program.rpc.swap(
+        Side.Ask,
+        new BN(1 * 10 ** 6),
+        { rate: new BN(1.0), fromDecimals: 6, quoteDecimals: 6, strict: false },
+        {
+          accounts: SWAP_A_USDC_ACCOUNTS,
+        }
+      ),
+      (err) => {
+        assert.strictEqual(err.msg, "Market is not whitelisted");
+        return true;
+      }
+    );
+
+    await program.rpc.addMarket(ORDERBOOK_ENV.marketA._decoded.ownAddress, {
+      accounts: {
+        config: PROTOCOL_FEES.config,
+        whitelist: PROTOCOL_FEES.whitelist,
+        admin,
+      },
+    });
+    const [tokenAChange] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godA],
+      async () => {
+        await program.rpc.swap(
+          Side.Ask,
+          new BN(1 * 10 ** 6),
+          {
+            rate: new BN(1.0),
+            fromDecimals: 6,
+            quoteDecimals: 6,
+            strict: false,
+          },
+          {
+            accounts: SWAP_A_USDC_ACCOUNTS,
+          }
+        );
+      }
+    );
+    assert.ok(tokenAChange === -1);
+
+    // Disable the whitelist for any subsequent tests.
+    await program.rpc.setWhitelistEnabled(false, {
+      accounts: {
+        config: PROTOCOL_FEES.config,
+        whitelist: PROTOCOL_FEES.whitelist,
+        admin,
+      },
+    });
+  });
+
+  it("Trips the circuit breaker once the notional cap is exceeded", async () => {
+    const admin = program.provider.wallet.publicKey;
+    const [, bump] = await anchor.web3.PublicKey.findProgramAddress(
+      [
+        Buffer.from("circuit_breaker"),
+        ORDERBOOK_ENV.marketA._decoded.ownAddress.toBuffer(),
+      ],
+      program.programId
+    );
+    // At most 10 USDC per 1000 slots.
+    await program.rpc.createCircuitBreaker(
+      bump,
+      new BN(1000),
+      new BN(10 * 10 ** 6),
+      {
+        accounts: {
+          config: PROTOCOL_FEES.config,
+          circuitBreaker: marketACircuitBreaker,
+          market: ORDERBOOK_ENV.marketA._decoded.ownAddress,
+          admin,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        },
+      }
+    );
+
+    const swapA = (amount, circuitBreaker = marketACircuitBreaker) =>
+      program.rpc.swap(
+        Side.Ask,
+        new BN(amount * 10 ** 6),
+        { rate: new BN(1.0), fromDecimals: 6, quoteDecimals: 6, strict: false },
+        {
+          accounts: {
+            ...SWAP_A_USDC_ACCOUNTS,
+            market: { ...SWAP_A_USDC_ACCOUNTS.market, circuitBreaker },
+          },
+        }
+      );
+
+    // The breaker can't be skipped once the admin has created one.
+    await assert.rejects(swapA(1, program.programId), (err) => {
+      assert.strictEqual(
+        err.msg,
+        "Circuit breaker must be the market's PDA with a non-zero window"
+      );
+      return true;
+    });
+
+    // ~6 USDC notional.
+    await swapA(1);
+    await assert.rejects(swapA(1), (err) => {
+      assert.strictEqual(
+        err.msg,
+        "Market's notional volume cap for the window is exceeded"
+      );
+      return true;
+    });
+
+    // Lift the cap for any subsequent tests.
+    await program.rpc.closeCircuitBreaker({
+      accounts: {
+        config: PROTOCOL_FEES.config,
+        circuitBreaker: marketACircuitBreaker,
+        admin,
+      },
+    });
+  });
+
+  it("Tracks the volume of authorities opting into user stats", async () => {
+    const authority = program.provider.wallet.publicKey;
+    const [userStats, bump] = await anchor.web3.PublicKey.findProgramAddress(
+      [Buffer.from("user_stats"), authority.toBuffer()],
+      program.programId
+    );
+    await program.rpc.initUserStats(bump, {
+      accounts: {
+        userStats,
+        authority,
+        payer: authority,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      },
+    });
+
+    const [usdcChange] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godUsdc],
+      async () => {
+        await program.rpc.swap(
+          Side.Ask,
+          new BN(1 * 10 ** 6),
+          {
+            rate: new BN(1.0),
+            fromDecimals: 6,
+            quoteDecimals: 6,
+            strict: false,
+          },
+          {
+            accounts: { ...SWAP_A_USDC_ACCOUNTS, userStats },
+          }
+        );
+      }
+    );
+
+    const stats = await program.account.userStats.fetch(userStats);
+    assert.ok(stats.swapCount.eq(new BN(1)));
+    assert.ok(stats.volumes.length === 1);
+    assert.ok(stats.volumes[0].mint.equals(ORDERBOOK_ENV.usdc));
+    assert.ok(
+      stats.volumes[0].volume.toNumber() === Math.round(usdcChange * 10 ** 6)
+    );
+  });
+
+  // Market of Token A quoted in a second quote currency, USDT, for swaps
+  // across quote currencies, along with the provider's open orders on it.
+  let USDT, godUsdt, MARKET_A_USDT;
+  const openOrdersAUsdt = new anchor.web3.Account();
+
+  // `MarketAccounts` of the provider for the given market.
+  const marketAccounts = async (
+    market,
+    openOrders,
+    orderPayerTokenAccount,
+    coinWallet
+  ) => ({
+    market: market._decoded.ownAddress,
+    requestQueue: market._decoded.requestQueue,
+    eventQueue: market._decoded.eventQueue,
+    bids: market._decoded.bids,
+    asks: market._decoded.asks,
+    coinVault: market._decoded.baseVault,
+    pcVault: market._decoded.quoteVault,
+    vaultSigner: (
+      await utils.getVaultOwnerAndNonce(market._decoded.ownAddress)
+    )[0],
+    // User params.
+    openOrders: openOrders.publicKey,
+    orderPayerTokenAccount,
+    coinWallet,
+    circuitBreaker: await utils.getCircuitBreaker(
+      program.programId,
+      market._decoded.ownAddress
+    ),
+    marketStats: program.programId,
+  });
+
+  // User stats PDA of the provider.
+  const userStatsAddress = async () =>
+    (
+      await anchor.web3.PublicKey.findProgramAddress(
+        [
+          Buffer.from("user_stats"),
+          program.provider.wallet.publicKey.toBuffer(),
+        ],
+        program.programId
+      )
+    )[0];
+
+  it("BOILERPLATE: Sets up an A/USDT market", async () => {
+    [USDT, godUsdt] = await serumCmn.createMintAndVault(
+      program.provider,
+      new BN(1000000000000000),
+      undefined,
+      6
+    );
+    const marketMaker = await utils.fundAccount({
+      provider: program.provider,
+      mints: [
+        [ORDERBOOK_ENV.godA, ORDERBOOK_ENV.mintA],
+        [godUsdt, USDT],
+      ].map(([god, mint]) => ({
+        god,
+        mint,
+        amount: 100000 * 10 ** 6,
+        decimals: 6,
+      })),
+    });
+    const setupMarket = (baseMint, quoteMint, bids, asks) =>
+      utils.setupMarket({
+        baseMint,
+        quoteMint,
+        marketMaker: {
+          account: marketMaker.account,
+          baseToken: marketMaker.tokens[baseMint.toString()],
+          quoteToken: marketMaker.tokens[quoteMint.toString()],
+        },
+        bids,
+        asks,
+        provider: program.provider,
+      });
+    MARKET_A_USDT = await setupMarket(
+      ORDERBOOK_ENV.mintA,
+      USDT,
+      [[6.0, 50.0]],
+      [[6.1, 50.0]]
+    );
+    PROTOCOL_FEES.feeVaults[USDT.toString()] = (
+      await utils.setupProtocolFees({ program, mints: [USDT] })
+    ).feeVaults[USDT.toString()];
+
+    const tx = new Transaction();
+    tx.add(
+      await OpenOrders.makeCreateAccountTransaction(
+        program.provider.connection,
+        MARKET_A_USDT._decoded.ownAddress,
+        program.provider.wallet.publicKey,
+        openOrdersAUsdt.publicKey,
+        utils.DEX_PID
+      )
+    );
+    await program.provider.send(tx, [openOrdersAUsdt]);
+  });
+
+  it("Swaps from USDC to USDT across two markets of Token A", async () => {
+    const userStats = await userStatsAddress();
+    const statsBefore = await program.account.userStats.fetch(userStats);
+    let txSig;
+    const [usdcChange, usdtChange, tokenAChange] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godUsdc, godUsdt, ORDERBOOK_ENV.godA],
+      async () => {
+        txSig = await program.rpc.swapQuoteToQuote(
+          new BN(10 * 10 ** 6),
+          {
+            rate: new BN(0.9 * 10 ** 6),
+            fromDecimals: 6,
+            quoteDecimals: 6,
+            strict: false,
+          },
+          0,
+          null,
+          {
+            accounts: {
+              from: SWAP_USDC_A_ACCOUNTS.market,
+              to: await marketAccounts(
+                MARKET_A_USDT,
+                openOrdersAUsdt,
+                ORDERBOOK_ENV.godA,
+                ORDERBOOK_ENV.godA
+              ),
+              authority: program.provider.wallet.publicKey,
+              fromPcWallet: ORDERBOOK_ENV.godUsdc,
+              toPcWallet: godUsdt,
+              config: PROTOCOL_FEES.config,
+              whitelist: PROTOCOL_FEES.whitelist,
+              userStats,
+              spendingPolicy: SPENDING_POLICY,
+              feeVault: feeVault(USDT),
+              platformFeeAccount: godUsdt,
+              dexProgram: utils.DEX_PID,
+              tokenProgram: TOKEN_PROGRAM_ID,
+              rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+              eventCpi: EVENT_CPI,
+            },
+          }
+        );
+      }
+    );
+
+    // ~6.1 USDC per A bought and 6 USDT per A sold.
+    assert.ok(usdcChange < 0 && usdcChange >= -10);
+    assert.ok(usdtChange >= 9);
+    // Every lot of A bought is sold.
+    assert.ok(tokenAChange === 0);
+
+    const stats = await program.account.userStats.fetch(userStats);
+    assert.ok(stats.swapCount.eq(statsBefore.swapCount.addn(1)));
+    const events = await cpiEvents(program, txSig);
+    const legs = events.filter((event) => event.name === "DidSwapLeg");
+    assert.ok(legs.length === 2);
+    const swap = events.find((event) => event.name === "DidSwap");
+    assert.ok(swap.data.takerFee.isZero());
+  });
+
+  it("Enforces the spending policy of the funds swapped", async () => {
+    const owner = program.provider.wallet.publicKey;
+    const authority = owner;
+    const [spendingPolicy, bump] =
+      await anchor.web3.PublicKey.findProgramAddress(
+        [
+          Buffer.from("spending_policy"),
+          owner.toBuffer(),
+          authority.toBuffer(),
+        ],
+        program.programId
+      );
+    await program.rpc.createSpendingPolicy(bump, {
+      accounts: {
+        spendingPolicy,
+        authority,
+        owner,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      },
+    });
+    assert.ok(spendingPolicy.equals(SPENDING_POLICY));
+    const swapA = (accounts = SWAP_A_USDC_ACCOUNTS) =>
+      program.rpc.swap(
+        Side.Ask,
+        new BN(1 * 10 ** 6),
+        { rate: new BN(1.0), fromDecimals: 6, quoteDecimals: 6, strict: false },
+        { accounts }
+      );
+
+    // The policy can't be left out.
+    await assert.rejects(
+      swapA({
+        ...SWAP_A_USDC_ACCOUNTS,
+        spendingPolicy: anchor.web3.Keypair.generate().publicKey,
+      }),
+      (err) => {
+        assert.strictEqual(err.msg, "Spending policy is not the authority's");
+        return true;
+      }
+    );
+
+    // Swaps quoted in USDC fail until it has a limit.
+    await assert.rejects(swapA(), (err) => {
+      assert.strictEqual(
+        err.msg,
+        "Spending policy has no limit for the quote mint"
+      );
+      return true;
+    });
+
+    // ~6 USDC per swap, so only one fits in the window.
+    await program.rpc.setSpendingLimit(
+      ORDERBOOK_ENV.usdc,
+      new BN(10 * 10 ** 6),
+      new BN(10 * 10 ** 6),
+      { accounts: { spendingPolicy, owner } }
+    );
+    await swapA();
+    await assert.rejects(swapA(), (err) => {
+      assert.strictEqual(err.msg, "Swap exceeds the authority's spending limit");
+      return true;
+    });
+
+    await program.rpc.closeSpendingPolicy({
+      accounts: { spendingPolicy, owner },
+    });
+  });
+
+  it("Executes proposed swaps once their timelock has passed", async () => {
+    const authority = program.provider.wallet.publicKey;
+    const args = {
+      side: Side.Ask,
+      amount: new BN(1 * 10 ** 6),
+      minExchangeRate: {
+        rate: new BN(1.0),
+        fromDecimals: 6,
+        quoteDecimals: 6,
+        strict: false,
+      },
+      maxQuoteAmount: null,
+      platformFeeBps: null,
+      oracleBand: null,
+      tag: null,
+      hookAccounts: null,
+      allowZeroOutput: null,
+      allowPartial: null,
+      maxPriceImpactBps: null,
+      limitPrice: null,
+      deadline: null,
+      maxEventQueueLen: null,
+      outputSplit: null,
+    };
+    const swapKeys = program.instruction
+      .swapV2(args, { accounts: SWAP_A_USDC_ACCOUNTS })
+      .keys.map((meta) => ({ ...meta, isSigner: false, isWritable: false }));
+    const propose = async (nonce, executableAfter) => {
+      const [proposal, bump] = await anchor.web3.PublicKey.findProgramAddress(
+        [
+          Buffer.from("swap_proposal"),
+          authority.toBuffer(),
+          new BN(nonce).toArrayLike(Buffer, "le", 8),
+        ],
+        program.programId
+      );
+      await program.rpc.proposeSwap(
+        bump,
+        new BN(nonce),
+        args,
+        new BN(executableAfter),
+        {
+          accounts: {
+            proposal,
+            authority,
+            proposer: authority,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          },
+          remainingAccounts: swapKeys,
+        }
+      );
+      return proposal;
+    };
+    const execute = (proposal) =>
+      program.rpc.executeSwapProposal({
+        accounts: { swap: SWAP_A_USDC_ACCOUNTS, proposal, proposer: authority },
+      });
+
+    const locked = await propose(0, Date.now() / 1000 + 60 * 60);
+    await assert.rejects(execute(locked), (err) => {
+      assert.strictEqual(err.msg, "Swap proposal isn't executable yet");
+      return true;
+    });
+    await program.rpc.cancelSwapProposal({
+      accounts: { proposal: locked, proposer: authority, signer: authority },
+    });
+
+    const proposal = await propose(1, 0);
+    const [usdcChange] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godUsdc],
+      async () => {
+        await execute(proposal);
+      }
+    );
+    assert.ok(usdcChange > 0);
+    assert.ok(
+      (await program.provider.connection.getAccountInfo(proposal)) === null
+    );
+  });
+
+  it("Swaps as authorized by an off-chain signed permit", async () => {
+    const signer = program.provider.wallet.payer;
+    const [permitAuthority, bump] =
+      await anchor.web3.PublicKey.findProgramAddress(
+        [Buffer.from("permit_authority"), signer.publicKey.toBuffer()],
+        program.programId
+      );
+    await program.rpc.createPermitAuthority(bump, signer.publicKey, {
+      accounts: {
+        permitAuthority,
+        payer: signer.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      },
+    });
+    const openOrders = new Account();
+    await program.rpc.initPermitOpenOrders({
+      accounts: {
+        permitAuthority,
+        openOrders: openOrders.publicKey,
+        market: ORDERBOOK_ENV.marketA._decoded.ownAddress,
+        dexProgram: utils.DEX_PID,
+        payer: signer.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+        eventCpi: EVENT_CPI,
+      },
+      signers: [openOrders],
+    });
+
+    // The signer approves the permit authority to swap its A.
+    const amount = new BN(1 * 10 ** 6);
+    const approveTx = new Transaction();
+    approveTx.add(
+      require("@solana/spl-token").Token.createApproveInstruction(
+        TOKEN_PROGRAM_ID,
+        ORDERBOOK_ENV.godA,
+        permitAuthority,
+        signer.publicKey,
+        [],
+        amount.toNumber()
+      )
+    );
+    await program.provider.send(approveTx);
+
+    // Of the signer, owning the wallets, over the permit authority.
+    const spendingPolicy = await utils.getSpendingPolicy(
+      program.programId,
+      signer.publicKey,
+      permitAuthority
+    );
+    const swapWithPermit = (permit, pcWallet = ORDERBOOK_ENV.godUsdc) => {
+      const tx = new Transaction();
+      tx.add(
+        anchor.web3.Ed25519Program.createInstructionWithPrivateKey({
+          privateKey: signer.secretKey,
+          message: program.coder.types.encode("Permit", permit),
+        })
+      );
+      tx.add(
+        program.instruction.swapWithPermit(permit, {
+          accounts: {
+            permitAuthority,
+            swap: {
+              ...SWAP_A_USDC_ACCOUNTS,
+              market: {
+                ...SWAP_A_USDC_ACCOUNTS.market,
+                openOrders: openOrders.publicKey,
+              },
+              pcWallet,
+              spendingPolicy,
+            },
+            instructions: anchor.web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+          },
+        })
+      );
+      return program.provider.send(tx);
+    };
+    const permit = {
+      authority: permitAuthority,
+      market: ORDERBOOK_ENV.marketA._decoded.ownAddress,
+      side: Side.Ask,
+      amount,
+      minExchangeRate: {
+        rate: new BN(1.0),
+        fromDecimals: 6,
+        quoteDecimals: 6,
+        strict: false,
+      },
+      nonce: new BN(0),
+      expiry: new BN(Date.now() / 1000 + 60 * 60),
+    };
+
+    // The relayer can't redirect the proceeds to a wallet not the signer's.
+    await assert.rejects(swapWithPermit(permit, feeVault(ORDERBOOK_ENV.usdc)));
+
+    const [usdcChange] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godUsdc],
+      async () => {
+        await swapWithPermit(permit);
+      }
+    );
+    assert.ok(usdcChange > 0);
+
+    // Permits can't be replayed.
+    await assert.rejects(swapWithPermit(permit));
+    const account = await program.account.permitAuthority.fetch(
+      permitAuthority
+    );
+    assert.ok(account.nonce.eq(new BN(1)));
+  });
+
+  it("Reveals and executes committed swaps in a later slot", async () => {
+    const authority = program.provider.wallet.publicKey;
+    const nonce = new BN(0);
+    const [commitment, bump] = await anchor.web3.PublicKey.findProgramAddress(
+      [
+        Buffer.from("swap_commitment"),
+        authority.toBuffer(),
+        nonce.toArrayLike(Buffer, "le", 8),
+      ],
+      program.programId
+    );
+    const args = {
+      side: Side.Ask,
+      amount: new BN(1 * 10 ** 6),
+      minExchangeRate: {
+        rate: new BN(1.0),
+        fromDecimals: 6,
+        quoteDecimals: 6,
+        strict: false,
+      },
+      maxQuoteAmount: null,
+      platformFeeBps: null,
+      oracleBand: null,
+      tag: null,
+      hookAccounts: null,
+      allowZeroOutput: null,
+      allowPartial: null,
+      maxPriceImpactBps: null,
+      limitPrice: null,
+      deadline: null,
+      maxEventQueueLen: null,
+      outputSplit: null,
+    };
+    const salt = require("crypto").randomBytes(32);
+    const hash = require("crypto")
+      .createHash("sha256")
+      .update(ORDERBOOK_ENV.marketA._decoded.ownAddress.toBuffer())
+      .update(program.coder.types.encode("SwapArgsV2", args))
+      .update(salt)
+      .digest();
+    await program.rpc.commitSwap(bump, nonce, [...hash], {
+      accounts: {
+        commitment,
+        authority,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      },
+    });
+    const reveal = (salt) =>
+      program.rpc.revealAndSwap(args, [...salt], {
+        accounts: { swap: SWAP_A_USDC_ACCOUNTS, commitment, authority },
+      });
+
+    await assert.rejects(reveal(Buffer.alloc(32)));
+
+    // Wait for a later slot than the commitment's.
+    const { slot } = await program.account.swapCommitment.fetch(commitment);
+    while ((await program.provider.connection.getSlot()) <= slot.toNumber()) {
+      await new Promise((resolve) => setTimeout(resolve, 100));
+    }
+    const [usdcChange] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godUsdc],
+      async () => {
+        await reveal(salt);
+      }
+    );
+    assert.ok(usdcChange > 0);
+    assert.ok(
+      (await program.provider.connection.getAccountInfo(commitment)) === null
+    );
+  });
+
+  it("Splits the output of swap_v2 swaps across recipients", async () => {
+    const recipients = [];
+    for (let i = 0; i < 2; i += 1) {
+      recipients.push(
+        await serumCmn.createTokenAccount(
+          program.provider,
+          ORDERBOOK_ENV.usdc,
+          new Account().publicKey
+        )
+      );
+    }
+
+    const [usdcChange, firstShare, secondShare] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godUsdc, ...recipients],
+      async () => {
+        await program.rpc.swapV2(
+          {
+            side: Side.Ask,
+            amount: new BN(1 * 10 ** 6),
+            minExchangeRate: {
+              rate: new BN(1.0),
+              fromDecimals: 6,
+              quoteDecimals: 6,
+              strict: false,
+            },
+            maxQuoteAmount: null,
+            platformFeeBps: null,
+            oracleBand: null,
+            tag: null,
+            hookAccounts: null,
+            allowZeroOutput: null,
+            allowPartial: null,
+            maxPriceImpactBps: null,
+            limitPrice: null,
+            deadline: null,
+            maxEventQueueLen: null,
+            outputSplit: [7500, 2500],
+          },
+          {
+            accounts: SWAP_A_USDC_ACCOUNTS,
+            remainingAccounts: recipients.map((pubkey) => ({
+              pubkey,
+              isWritable: true,
+              isSigner: false,
+            })),
+          }
+        );
+      }
+    );
+    assert.ok(secondShare > 0);
+    assert.ok(Math.abs(firstShare - 3 * secondShare) <= 0.000003);
+    // Only the rounding remains with the authority.
+    assert.ok(usdcChange >= 0 && usdcChange <= 0.000002);
+  });
+
+  it("Pays merchants an exact amount via pay_with_swap", async () => {
+    const merchantUsdc = await serumCmn.createTokenAccount(
+      program.provider,
+      ORDERBOOK_ENV.usdc,
+      new Account().publicKey
+    );
+    const reference = new Account().publicKey;
+
+    // ~6 USDC per A, so well below the maximum.
+    let txSig;
+    const [aChange, merchantChange] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godA, merchantUsdc],
+      async () => {
+        txSig = await program.rpc.payWithSwap(
+          Side.Ask,
+          new BN(5 * 10 ** 6),
+          new BN(2 * 10 ** 6),
+          {
+            accounts: {
+              swap: SWAP_A_USDC_ACCOUNTS,
+              merchant: merchantUsdc,
+              reference,
+            },
+          }
+        );
+      }
+    );
+    assert.ok(merchantChange === 5);
+    assert.ok(aChange < 0 && aChange > -1);
+
+    const events = await cpiEvents(program, txSig);
+    const payment = events.find((event) => event.name === "PaymentSettled").data;
+    assert.ok(payment.reference.equals(reference));
+    assert.ok(payment.merchant.equals(merchantUsdc));
+    assert.ok(payment.amount.eq(new BN(5 * 10 ** 6)));
+    assert.ok(payment.fromAmount.toNumber() === -aChange * 10 ** 6);
+
+    await assert.rejects(
+      program.rpc.payWithSwap(
+        Side.Ask,
+        new BN(5 * 10 ** 6),
+        new BN(0.1 * 10 ** 6),
+        {
+          accounts: {
+            swap: SWAP_A_USDC_ACCOUNTS,
+            merchant: merchantUsdc,
+            reference,
+          },
+        }
+      ),
+      (err) => {
+        assert.strictEqual(
+          err.msg,
+          "Payment would spend more than the maximum amount"
+        );
+        return true;
+      }
+    );
+  });
+
+  it("Emits swap events via self-CPI", async () => {
+    const tag = Buffer.alloc(32, "order-1");
+    const txSig = await program.rpc.swapV2(
+      swapArgs({
+        side: Side.Ask,
+        amount: new BN(1 * 10 ** 6),
+        minExchangeRate: {
+          rate: new BN(1.0),
+          fromDecimals: 6,
+          quoteDecimals: 6,
+          strict: false,
+        },
+        tag: [...tag],
+      }),
+      {
+        accounts: SWAP_A_USDC_ACCOUNTS,
+      }
+    );
+
+    const events = await cpiEvents(program, txSig);
+    assert.ok(events.length === 1);
+    assert.ok(events[0].name === "DidSwap");
+    assert.ok(
+      events[0].data.authority.equals(program.provider.wallet.publicKey)
+    );
+    assert.ok(events[0].data.fromMint.equals(ORDERBOOK_ENV.mintA));
+    assert.ok(events[0].data.toMint.equals(ORDERBOOK_ENV.usdc));
+    assert.deepStrictEqual(events[0].data.side, Side.Ask);
+    assert.ok(events[0].data.effectivePrice.toNumber() > 0);
+    assert.ok(events[0].data.orderBaseQty.toNumber() > 0);
+    assert.ok(events[0].data.orderQuoteQty.toNumber() === 0);
+    assert.ok(Buffer.from(events[0].data.tag).equals(tag));
+    assert.ok(events[0].data.takerFee.toNumber() > 0);
+    assert.ok(
+      events[0].data.referrerRebate.toNumber() ===
+        Math.floor(events[0].data.takerFee.toNumber() / 5)
+    );
+  });
+
+  it("Snapshots the top of the book in swap events", async () => {
+    const txSig = await program.rpc.swap(
+      Side.Ask,
+      new BN(1 * 10 ** 6),
+      { rate: new BN(1.0), fromDecimals: 6, quoteDecimals: 6, strict: false },
+      {
+        accounts: SWAP_A_USDC_ACCOUNTS,
+      }
+    );
+
+    const [didSwap] = await cpiEvents(program, txSig);
+    const { book, effectivePrice } = didSwap.data;
+    assert.ok(book.bestBid.gt(new BN(0)));
+    // Only the side of the book the sale fills against is read.
+    assert.ok(book.bestAsk.isZero());
+    // The sale fills at the best bid at most, before fees.
+    assert.ok(effectivePrice.lte(book.bestBid));
+    assert.ok(book.slot.toNumber() > 0);
+  });
+
+  it("Tracks the price and volume of markets with stats", async () => {
+    const market = ORDERBOOK_ENV.marketA._decoded.ownAddress;
+    const [marketStats, bump] = await anchor.web3.PublicKey.findProgramAddress(
+      [Buffer.from("market_stats"), market.toBuffer()],
+      program.programId
+    );
+    await program.rpc.initMarketStats(bump, {
+      accounts: {
+        marketStats,
+        market,
+        payer: program.provider.wallet.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      },
+    });
+
+    const [tokenAChange, usdcChange] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godA, ORDERBOOK_ENV.godUsdc],
+      async () => {
+        await program.rpc.swap(
+          Side.Ask,
+          new BN(1 * 10 ** 6),
+          {
+            rate: new BN(1.0),
+            fromDecimals: 6,
+            quoteDecimals: 6,
+            strict: false,
+          },
+          {
+            accounts: {
+              ...SWAP_A_USDC_ACCOUNTS,
+              market: { ...SWAP_A_USDC_ACCOUNTS.market, marketStats },
+            },
+          }
+        );
+      }
+    );
+
+    const stats = await program.account.marketStats.fetch(marketStats);
+    assert.ok(stats.market.equals(market));
+    assert.ok(stats.lastSlot.toNumber() > 0);
+    assert.ok(stats.lastPrice.toNumber() > 0);
+    assert.ok(
+      stats.baseVolume.toNumber() === Math.round(-tokenAChange * 10 ** 6)
+    );
+    assert.ok(stats.quoteVolume.toNumber() >= Math.round(usdcChange * 10 ** 6));
+  });
+
+  it("Credits referral rebates to a registered referrer", async () => {
+    const authority = program.provider.wallet.publicKey;
+    const [referrer, bump] = await anchor.web3.PublicKey.findProgramAddress(
+      [
+        Buffer.from("referrer"),
+        authority.toBuffer(),
+        ORDERBOOK_ENV.usdc.toBuffer(),
+      ],
+      program.programId
+    );
+    const [vault, vaultBump] = await anchor.web3.PublicKey.findProgramAddress(
+      [Buffer.from("referrer_vault"), referrer.toBuffer()],
+      program.programId
+    );
+    await program.rpc.registerReferrer(bump, vaultBump, {
+      accounts: {
+        referrer,
+        vault,
+        mint: ORDERBOOK_ENV.usdc,
+        authority,
+        systemProgram: anchor.web3.SystemProgram.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+      },
+    });
+
+    await program.rpc.swap(
+      Side.Ask,
+      new BN(10 * 10 ** 6),
+      { rate: new BN(1.0), fromDecimals: 6, quoteDecimals: 6, strict: false },
+      {
+        accounts: { ...SWAP_A_USDC_ACCOUNTS, referral: vault },
+        remainingAccounts: [
+          { pubkey: referrer, isWritable: true, isSigner: false },
+        ],
+      }
+    );
+
+    const vaultAccount = await serumCmn.getTokenAccount(
+      program.provider,
+      vault
+    );
+    let referrerAccount = await program.account.referrer.fetch(referrer);
+    assert.ok(referrerAccount.earned.gt(new BN(0)));
+    assert.ok(referrerAccount.earned.eq(vaultAccount.amount));
+
+    const [claimed] = await withBalanceChange(
+      program.provider,
+      [ORDERBOOK_ENV.godUsdc],
+      async () => {
+        await program.rpc.claimReferralFees({
+          accounts: {
+            referrer,
+            authority,
+            vault,
+            destination: ORDERBOOK_ENV.godUsdc,
+            tokenProgram: TOKEN_PROGRAM_ID,
+          },
+        });
+      }
+    );
+    referrerAccount = await program.account.referrer.fetch(referrer);
+    assert.ok(
+      Math.round(claimed * 10 ** 6) === referrerAccount.earned.toNumber()
+    );
+    assert.ok(referrerAccount.claimed.eq(referrerAccount.earned));
+  });
+
+  it("Splits referral rebates across the referrer's recipients", async () => {
+    const authority = program.provider.wallet.publicKey;
+    const [referrer] = await anchor.web3.PublicKey.findProgramAddress(
+      [
+        Buffer.from("referrer"),
+        authority.toBuffer(),
+        ORDERBOOK_ENV.usdc.toBuffer(),
+      ],
+      program.programId
+    );
+    const [vault] = await anchor.web3.PublicKey.findProgramAddress(
+      [Buffer.from("referrer_vault"), referrer.toBuffer()],
+      program.programId
+    );
+    const partnerUsdc = await serumCmn.createTokenAccount(
+      program.provider,
+      ORDERBOOK_ENV.usdc,
+      new Account().publicKey
+    );
+
+    // Share half of each rebate with the partner.
+    await program.rpc.setReferralSplits(
+      [{ recipient: partnerUsdc, bps: 5000 }],
+      {
+        accounts: { referrer, authority },
+      }
+    );
+
+    const earnedBefore = (await program.account.referrer.fetch(referrer))
+      .earned;
+    const [partnerChange] = await withBalanceChange(
+      program.provider,
+      [partnerUsdc],
+      async () => {
+        await program.rpc.swap(
+          Side.Ask,
+          new BN(10 * 10 ** 6),
+          {
+            rate: new BN(1.0),
+            fromDecimals: 6,
+            quoteDecimals: 6,
+            strict: false,
+          },
+          {
+            accounts: { ...SWAP_A_USDC_ACCOUNTS, referral: vault },
+            remainingAccounts: [
+              { pubkey: referrer, isWritable: true, isSigner: false },
+              { pubkey: partnerUsdc, isWritable: true, isSigner: false },
+            ],
+          }
+        );
+      }
+    );
+    const earned = (await program.account.referrer.fetch(referrer)).earned.sub(
+      earnedBefore
+    );
+
+    assert.ok(partnerChange > 0);
+    // The referrer keeps the rounding remainder.
+    assert.ok(earned.toNumber() >= Math.round(partnerChange * 10 ** 6));
+  });
+});
+
+// Side rust enum used for the program's RPC API.
+const Side = {
+  Bid: { bid: {} },
+  Ask: { ask: {} },
+};
+
+// `SwapArgsV2` with the given arguments, and no other options.
+function swapArgs(args) {
+  return {
+    maxQuoteAmount: null,
+    platformFeeBps: null,
+    oracleBand: null,
+    tag: null,
+    hookAccounts: null,
+    allowZeroOutput: null,
+    allowPartial: null,
+    maxPriceImpactBps: null,
+    limitPrice: null,
+    deadline: null,
+    maxEventQueueLen: null,
+    outputSplit: null,
+    ...args,
+  };
+}
+
+// `SwapTransitiveArgsV2` with the given arguments, and no other options.
+function swapTransitiveArgs(args) {
+  return {
+    reswap: null,
+    legRates: null,
+    maxQuoteAmount: null,
+    platformFeeBps: null,
+    oracleBands: null,
+    tag: null,
+    allowZeroOutput: null,
+    transferSpill: null,
+    spillHandling: null,
+    ...args,
+  };
+}
+
+// Account meta for a protocol fee vault passed via remaining accounts.
+function feeVaultMeta(pubkey) {
+  return { pubkey, isWritable: true, isSigner: false };
+}
+
+// Account metas for a `MarketAccounts` group passed via remaining accounts,
+// in the order the program deserializes them.
+function marketAccountMetas({
+  market,
+  vaultSigner,
+  openOrders,
+  orderPayerTokenAccount,
+  coinWallet,
+  circuitBreaker,
+  marketStats,
+}) {
+  return [
+    market._decoded.ownAddress,
+    openOrders,
+    market._decoded.requestQueue,
+    market._decoded.eventQueue,
+    market._decoded.bids,
+    market._decoded.asks,
+    orderPayerTokenAccount,
+    market._decoded.baseVault,
+    market._decoded.quoteVault,
+    vaultSigner,
+    coinWallet,
+    circuitBreaker,
+    marketStats,
+  ].map((pubkey) => ({
+    pubkey,
+    isWritable: !pubkey.equals(vaultSigner),
+    isSigner: false,
+  }));
+}
+
+// Events emitted by the swap program via self-CPI in the given transaction.
+async function cpiEvents(program, txSig) {
+  const tx = await program.provider.connection.getConfirmedTransaction(
+    txSig,
+    "confirmed"
+  );
+  const accountKeys = tx.transaction.compileMessage().accountKeys;
+  return (
+    tx.meta.innerInstructions
+      .flatMap((inner) => inner.instructions)
+      .filter((ix) => accountKeys[ix.programIdIndex].equals(program.programId))
+      // Skip the instruction discriminator and the argument's length prefix.
+      .map((ix) => anchor.utils.bytes.bs58.decode(ix.data).slice(12))
+      .map((data) => program.coder.events.decode(data.toString("base64")))
+  );
+}
+
+// Compute units consumed by the swap program's top level instruction, given
+// the log messages of its transaction.
+function computeUnits(program, logMessages) {
+  const prefix = `Program ${program.programId} consumed `;
+  // Self-CPIs log first, since they complete before the instruction does.
+  const log = logMessages.filter((log) => log.startsWith(prefix)).pop();
+  return parseInt(log.slice(prefix.length).split(" ")[0]);
+}
 
 // Executes a closure. Returning the change in balances from before and after
 // its execution.
@@ -329,6 +329,8 @@ async function listMarket({
   quoteLotSize,
   dexProgramId,
   feeRateBps,
+  // Optional open orders authority, listing a permissioned market.
+  openOrdersAuthority,
 }) {
   const market = new Account();
   const requestQueue = new Account();
@@ -372,15 +374,41 @@ async function listMarket({
     })
   );
 
+  // Permissioned markets append their authorities and padding to the state.
+  const marketSpace =
+    Market.getLayout(dexProgramId).span + (openOrdersAuthority ? 1088 : 0);
+  const initializeMarket = DexInstructions.initializeMarket({
+    market: market.publicKey,
+    requestQueue: requestQueue.publicKey,
+    eventQueue: eventQueue.publicKey,
+    bids: bids.publicKey,
+    asks: asks.publicKey,
+    baseVault: baseVault.publicKey,
+    quoteVault: quoteVault.publicKey,
+    baseMint,
+    quoteMint,
+    baseLotSize: new BN(baseLotSize),
+    quoteLotSize: new BN(quoteLotSize),
+    feeRateBps,
+    vaultSignerNonce,
+    quoteDustThreshold,
+    programId: dexProgramId,
+  });
+  if (openOrdersAuthority) {
+    initializeMarket.keys.push({
+      pubkey: openOrdersAuthority,
+      isSigner: false,
+      isWritable: false,
+    });
+  }
+
   const tx2 = new Transaction();
   tx2.add(
     SystemProgram.createAccount({
       fromPubkey: wallet.publicKey,
       newAccountPubkey: market.publicKey,
-      lamports: await connection.getMinimumBalanceForRentExemption(
-        Market.getLayout(dexProgramId).span
-      ),
-      space: Market.getLayout(dexProgramId).span,
+      lamports: await connection.getMinimumBalanceForRentExemption(marketSpace),
+      space: marketSpace,
       programId: dexProgramId,
     }),
     SystemProgram.createAccount({
@@ -411,23 +439,7 @@ async function listMarket({
       space: 65536 + 12,
       programId: dexProgramId,
     }),
-    DexInstructions.initializeMarket({
-      market: market.publicKey,
-      requestQueue: requestQueue.publicKey,
-      eventQueue: eventQueue.publicKey,
-      bids: bids.publicKey,
-      asks: asks.publicKey,
-      baseVault: baseVault.publicKey,
-      quoteVault: quoteVault.publicKey,
-      baseMint,
-      quoteMint,
-      baseLotSize: new BN(baseLotSize),
-      quoteLotSize: new BN(quoteLotSize),
-      feeRateBps,
-      vaultSignerNonce,
-      quoteDustThreshold,
-      programId: dexProgramId,
-    })
+    initializeMarket
   );
 
   const signedTransactions = await signTransactions({
@@ -500,11 +512,100 @@ async function getVaultOwnerAndNonce(marketPublicKey, dexProgramId = DEX_PID) {
   throw new Error("Unable to find nonce");
 }
 
+// Initializes the swap program's config (if not yet initialized by another
+// test) and creates a protocol fee vault for each of the given mints.
+//
+// Returns the config and whitelist addresses along with the fee vaults, keyed
+// by mint.
+async function setupProtocolFees({ program, mints }) {
+  const [config, configBump] = await PublicKey.findProgramAddress(
+    [Buffer.from("config")],
+    program.programId
+  );
+  const configAccount = await program.provider.connection.getAccountInfo(
+    config
+  );
+  if (configAccount === null) {
+    // The provider deployed the program, so it's the upgrade authority.
+    const [programData] = await PublicKey.findProgramAddress(
+      [program.programId.toBuffer()],
+      new PublicKey("BPFLoaderUpgradeab1e11111111111111111111111")
+    );
+    await program.rpc.initializeConfig(configBump, 0, {
+      accounts: {
+        config,
+        admin: program.provider.wallet.publicKey,
+        programData,
+        systemProgram: SystemProgram.programId,
+      },
+    });
+  }
+
+  const [whitelist] = await PublicKey.findProgramAddress(
+    [Buffer.from("whitelist")],
+    program.programId
+  );
+
+  const feeVaults = {};
+  for (const mint of mints) {
+    const [feeVault, feeVaultBump] = await PublicKey.findProgramAddress(
+      [Buffer.from("fee_vault"), mint.toBuffer()],
+      program.programId
+    );
+    await program.rpc.createFeeVault(feeVaultBump, {
+      accounts: {
+        config,
+        feeVault,
+        mint,
+        payer: program.provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        rent: web3.SYSVAR_RENT_PUBKEY,
+      },
+    });
+    feeVaults[mint.toString()] = feeVault;
+  }
+
+  return { config, whitelist, feeVaults };
+}
+
+// Circuit breaker PDA of the swap program for the given market.
+async function getCircuitBreaker(programId, market) {
+  const [circuitBreaker] = await PublicKey.findProgramAddress(
+    [Buffer.from("circuit_breaker"), market.toBuffer()],
+    programId
+  );
+  return circuitBreaker;
+}
+
+// Spending policy PDA of the `owner` over the swaps of the `authority`.
+async function getSpendingPolicy(programId, owner, authority) {
+  const [spendingPolicy] = await PublicKey.findProgramAddress(
+    [Buffer.from("spending_policy"), owner.toBuffer(), authority.toBuffer()],
+    programId
+  );
+  return spendingPolicy;
+}
+
+// Accounts the swap program needs to emit events via self-CPI.
+async function getEventCpi(programId) {
+  const [eventAuthority] = await PublicKey.findProgramAddress(
+    [Buffer.from("__event_authority")],
+    programId
+  );
+  return { eventAuthority, program: programId };
+}
+
 module.exports = {
   fundAccount,
+  listMarket,
   setupMarket,
   initOrderbook,
   setupTwoMarkets,
+  setupProtocolFees,
+  getCircuitBreaker,
+  getSpendingPolicy,
+  getEventCpi,
   DEX_PID,
   getVaultOwnerAndNonce,
 };